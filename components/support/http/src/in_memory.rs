@@ -0,0 +1,164 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::sync::{Arc, Mutex};
+
+use serde::ser::Serialize;
+use serde_json;
+
+use error::{ErrorKind, Result};
+use header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use {Method, Request, Response, StatusCode, Transport};
+
+/// A canned response for the `InMemoryTransport`.
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl MockResponse {
+    /// Panics if `status` isn't a valid status code, since this is only
+    /// meant for tests.
+    pub fn new(status: u16) -> Self {
+        MockResponse {
+            status: StatusCode::from_u16(status).expect("Invalid status code"),
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn header(mut self, name: &'static str, value: &str) -> Self {
+        self.headers.insert(name, HeaderValue::from_str(value).expect("Invalid header value"));
+        self
+    }
+
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn json<T: Serialize>(mut self, body: &T) -> Self {
+        self.headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        self.body(serde_json::to_vec(body).expect("Couldn't serialize mock response"))
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    responses: Vec<(Method, String, MockResponse)>,
+    requests: Vec<Request>,
+}
+
+/// A transport for tests, which returns canned responses instead of making
+/// requests, and remembers the requests it was given. Clones share the same
+/// responses and requests, so a test can keep a clone after handing the
+/// transport to a client.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryTransport {
+    state: Arc<Mutex<State>>,
+}
+
+impl InMemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues `response` for the next `method` request to a URL with `path`
+    /// (ignoring the host and query). Each response is only returned once,
+    /// and responses queued for the same method and path are returned in
+    /// order.
+    pub fn respond(&self, method: Method, path: &str, response: MockResponse) {
+        let mut state = self.state.lock().unwrap();
+        state.responses.push((method, path.into(), response));
+    }
+
+    /// Returns the requests made so far, oldest first.
+    pub fn requests(&self) -> Vec<Request> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Returns true if there are responses that haven't been requested yet.
+    pub fn has_pending_responses(&self) -> bool {
+        !self.state.lock().unwrap().responses.is_empty()
+    }
+}
+
+impl Transport for InMemoryTransport {
+    fn execute(&self, request: Request) -> Result<Response> {
+        let mut state = self.state.lock().unwrap();
+        state.requests.push(request.clone());
+        let index = state.responses.iter().position(|(method, path, _)| {
+            *method == request.method && path == request.url.path()
+        });
+        match index {
+            Some(index) => {
+                let (_, _, response) = state.responses.remove(index);
+                Ok(Response {
+                    url: request.url,
+                    status: response.status,
+                    headers: response.headers,
+                    body: response.body,
+                })
+            }
+            None => {
+                warn!("No mock response for {} {}", request.method, request.url);
+                Err(ErrorKind::UnexpectedRequest(
+                    format!("{} {}", request.method, request.url.path())).into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Url;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_in_memory_transport() {
+        let transport = InMemoryTransport::new();
+        transport.respond(Method::GET, "/a", MockResponse::new(200).body("first"));
+        transport.respond(Method::GET, "/a", MockResponse::new(503).header("Retry-After", "10"));
+        transport.respond(Method::PUT, "/a", MockResponse::new(200).json(&json!({"ok": true})));
+
+        let clone = transport.clone();
+        let resp = clone.execute(Request::get(url("https://example.com/a?x=1")))
+            .expect("should respond");
+        assert!(resp.is_success());
+        assert_eq!(resp.text(), "first");
+
+        let resp = clone.execute(Request::put(url("https://example.com/a")))
+            .expect("should respond");
+        assert_eq!(resp.json::<serde_json::Value>().unwrap(), json!({"ok": true}));
+
+        let resp = clone.execute(Request::get(url("https://example.com/a")))
+            .expect("should respond");
+        assert_eq!(resp.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.header("retry-after"), Some("10"));
+
+        let err = clone.execute(Request::get(url("https://example.com/a")))
+            .expect_err("should have run out of responses");
+        match err.kind() {
+            ErrorKind::UnexpectedRequest(req) => assert_eq!(req, "GET /a"),
+            kind => panic!("unexpected error {:?}", kind),
+        }
+
+        assert!(!transport.has_pending_responses());
+        let paths: Vec<String> = transport.requests().iter()
+            .map(|req| format!("{} {}", req.method, req.url))
+            .collect();
+        assert_eq!(paths, vec![
+            "GET https://example.com/a?x=1",
+            "PUT https://example.com/a",
+            "GET https://example.com/a",
+            "GET https://example.com/a",
+        ]);
+    }
+}
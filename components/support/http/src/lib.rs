@@ -0,0 +1,141 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A small HTTP abstraction shared by our components, so that apps can route
+//! our requests through their own network stack, and tests can run without a
+//! network at all. Components make requests through a `Transport`; we
+//! provide one backed by reqwest, which is the default, and an in-memory one
+//! for tests.
+
+extern crate failure;
+#[macro_use]
+extern crate failure_derive;
+#[macro_use]
+extern crate log;
+extern crate reqwest;
+extern crate serde;
+#[cfg_attr(test, macro_use)]
+extern crate serde_json;
+
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+
+pub mod error;
+mod in_memory;
+mod reqwest_transport;
+
+pub use error::{Error, ErrorKind, Result};
+pub use in_memory::{InMemoryTransport, MockResponse};
+pub use reqwest_transport::ReqwestTransport;
+
+// Re-exported so that callers use the same versions of these types as we do.
+pub use reqwest::{header, Method, StatusCode, Url};
+use header::{HeaderMap, HeaderValue, IntoHeaderName, CONTENT_TYPE};
+
+/// Makes HTTP requests. Implementations should only fail if they couldn't
+/// get a response at all; HTTP error statuses are returned as responses.
+pub trait Transport: fmt::Debug + Send {
+    fn execute(&self, request: Request) -> Result<Response>;
+}
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    pub url: Url,
+    pub headers: HeaderMap,
+    pub body: Option<Vec<u8>>,
+}
+
+impl Request {
+    pub fn new(method: Method, url: Url) -> Self {
+        Request {
+            method,
+            url,
+            headers: HeaderMap::new(),
+            body: None,
+        }
+    }
+
+    pub fn get(url: Url) -> Self {
+        Request::new(Method::GET, url)
+    }
+
+    pub fn post(url: Url) -> Self {
+        Request::new(Method::POST, url)
+    }
+
+    pub fn put(url: Url) -> Self {
+        Request::new(Method::PUT, url)
+    }
+
+    pub fn delete(url: Url) -> Self {
+        Request::new(Method::DELETE, url)
+    }
+
+    pub fn header<K: IntoHeaderName>(mut self, name: K, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+
+    /// Sets the body to `body` serialized as JSON, and the content type to
+    /// match.
+    pub fn json<T: Serialize>(self, body: &T) -> serde_json::Result<Self> {
+        let bytes = serde_json::to_vec(body)?;
+        Ok(self.header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+               .body(bytes))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    /// The URL of the request this is a response to.
+    pub url: Url,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    #[inline]
+    pub fn is_success(&self) -> bool {
+        self.status.is_success()
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_slice(&self.body)
+    }
+
+    /// Returns the body as a string, replacing invalid UTF-8. Mostly useful
+    /// for logging.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    /// Returns the value of the header as a string, if it's present and
+    /// valid.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_json() {
+        let request = Request::post(Url::parse("https://example.com/").unwrap())
+            .json(&json!({"hello": "world"}))
+            .expect("should serialize");
+        assert_eq!(request.headers[CONTENT_TYPE], "application/json");
+        assert_eq!(request.body, Some(br#"{"hello":"world"}"#.to_vec()));
+    }
+}
@@ -0,0 +1,52 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use reqwest::Client;
+
+use error::Result;
+use {Request, Response, Transport};
+
+/// The default transport, which makes requests with reqwest.
+#[derive(Debug)]
+pub struct ReqwestTransport {
+    client: Client,
+}
+
+impl Default for ReqwestTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReqwestTransport {
+    pub fn new() -> Self {
+        ReqwestTransport { client: Client::new() }
+    }
+
+    /// Uses an existing reqwest client; for example, one configured with a
+    /// timeout.
+    pub fn from_client(client: Client) -> Self {
+        ReqwestTransport { client }
+    }
+}
+
+impl Transport for ReqwestTransport {
+    fn execute(&self, request: Request) -> Result<Response> {
+        let mut builder = self.client
+            .request(request.method, request.url)
+            .headers(request.headers);
+        if let Some(body) = request.body {
+            builder = builder.body(body);
+        }
+        let mut resp = builder.send()?;
+        let mut body = Vec::new();
+        resp.copy_to(&mut body)?;
+        Ok(Response {
+            url: resp.url().clone(),
+            status: resp.status(),
+            headers: resp.headers().clone(),
+            body,
+        })
+    }
+}
@@ -12,6 +12,7 @@ use failure::SyncFailure;
 #[cfg(feature = "browserid")]
 use hawk;
 use hex;
+use http_support;
 #[cfg(feature = "browserid")]
 use openssl;
 use reqwest;
@@ -132,6 +133,9 @@ pub enum ErrorKind {
         info: String,
     },
 
+    #[fail(display = "Unexpected HTTP status: {}", _0)]
+    UnexpectedStatus(u16),
+
     // Basically reimplement error_chain's foreign_links. (Ugh, this sucks)
     #[fail(display = "Hex decode error: {}", _0)]
     HexDecodeError(#[fail(cause)] hex::FromHexError),
@@ -152,6 +156,9 @@ pub enum ErrorKind {
     #[fail(display = "Network error: {}", _0)]
     RequestError(#[fail(cause)] reqwest::Error),
 
+    #[fail(display = "Transport error: {}", _0)]
+    TransportError(#[fail(cause)] http_support::Error),
+
     #[fail(display = "Malformed URL error: {}", _0)]
     MalformedUrl(#[fail(cause)] reqwest::UrlError),
 
@@ -190,6 +197,7 @@ impl_from_error! {
     (JsonError, ::serde_json::Error),
     (UTF8DecodeError, ::std::string::FromUtf8Error),
     (RequestError, ::reqwest::Error),
+    (TransportError, ::http_support::Error),
     (MalformedUrl, ::reqwest::UrlError),
     (HeaderParseError, ::reqwest::header::ToStrError),
     (MalformedHeader, ::reqwest::header::InvalidHeaderValue)
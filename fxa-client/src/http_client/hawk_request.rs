@@ -4,7 +4,8 @@
 
 use hawk::{Credentials, Key, PayloadHasher, RequestBuilder, SHA256};
 use hex;
-use reqwest::{header::{self, HeaderValue}, Client, Method, Request};
+use http_support::header::{self, HeaderValue};
+use http_support::{Method, Request};
 use serde_json;
 use url::Url;
 
@@ -58,14 +59,15 @@ impl<'a> HAWKRequestBuilder<'a> {
             hawk_header = format!("Hawk {}", header);
         }
 
-        let mut request_builder = Client::new().request(self.method, self.url);
-        request_builder = request_builder.header(header::AUTHORIZATION, HeaderValue::from_str(&hawk_header)?);
+        let mut request = Request::new(self.method, self.url)
+            .header(header::AUTHORIZATION, HeaderValue::from_str(&hawk_header)?);
 
         if let Some(body) = self.body {
-            request_builder = request_builder.header(header::CONTENT_TYPE, "application/json");
-            request_builder = request_builder.body(body);
+            request = request
+                .header(header::CONTENT_TYPE, HeaderValue::from_static("application/json"))
+                .body(body);
         }
 
-        request_builder.build().map_err(|e| e.into())
+        Ok(request)
     }
 }
@@ -4,12 +4,13 @@
 
 #[cfg(feature = "browserid")]
 use hex;
-use reqwest;
-use reqwest::{header, Client as ReqwestClient, Method, Request, Response, StatusCode};
+use http_support::header::{self, HeaderValue};
+#[cfg(feature = "browserid")]
+use http_support::Method;
+use http_support::{Request, Response, StatusCode, Transport};
 #[cfg(feature = "browserid")]
 use ring::{digest, hkdf, hmac};
 use serde_json;
-use std;
 #[cfg(feature = "browserid")]
 use util::Xorable;
 
@@ -36,11 +37,13 @@ const SIGN_DURATION_MS: u64 = 24 * 60 * 60 * 1000;
 
 pub struct Client<'a> {
     config: &'a Config,
+    transport: &'a Transport,
 }
 
 impl<'a> Client<'a> {
-    pub fn new(config: &'a Config) -> Client<'a> {
-        Client { config }
+    /// Creates a client that makes its requests through `transport`.
+    pub fn new(config: &'a Config, transport: &'a Transport) -> Client<'a> {
+        Client { config, transport }
     }
 
     #[cfg(feature = "browserid")]
@@ -81,26 +84,23 @@ impl<'a> Client<'a> {
 
     #[cfg(feature = "browserid")]
     pub fn login(&self, email: &str, auth_pwd: &str, get_keys: bool) -> Result<LoginResponse> {
-        let url = self.config.auth_url_path("v1/account/login")?;
+        let mut url = self.config.auth_url_path("v1/account/login")?;
+        url.query_pairs_mut()
+            .append_pair("keys", &get_keys.to_string());
         let parameters = json!({
           "email": email,
           "authPW": auth_pwd
         });
-        let client = ReqwestClient::new();
-        let request = client
-            .request(Method::POST, url)
-            .query(&[("keys", get_keys)])
-            .body(parameters.to_string())
-            .build()?;
-        Client::make_request(request)?.json().map_err(|e| e.into())
+        let request = Request::post(url).body(parameters.to_string());
+        self.make_request(request)?.json().map_err(|e| e.into())
     }
 
     #[cfg(feature = "browserid")]
     pub fn account_status(&self, uid: &String) -> Result<AccountStatusResponse> {
-        let url = self.config.auth_url_path("v1/account/status")?;
-        let client = ReqwestClient::new();
-        let request = client.get(url).query(&[("uid", uid)]).build()?;
-        Client::make_request(request)?.json().map_err(|e| e.into())
+        let mut url = self.config.auth_url_path("v1/account/status")?;
+        url.query_pairs_mut().append_pair("uid", uid);
+        let request = Request::get(url);
+        self.make_request(request)?.json().map_err(|e| e.into())
     }
 
     #[cfg(feature = "browserid")]
@@ -115,7 +115,7 @@ impl<'a> Client<'a> {
         );
         let key_request_key = &key[(KEY_LENGTH * 2)..(KEY_LENGTH * 3)];
         let request = HAWKRequestBuilder::new(Method::GET, url, &key).build()?;
-        let json: serde_json::Value = Client::make_request(request)?.json()?;
+        let json: serde_json::Value = self.make_request(request)?.json()?;
         let bundle = match json["bundle"].as_str() {
             Some(bundle) => bundle,
             None => panic!("Invalid JSON"),
@@ -152,7 +152,7 @@ impl<'a> Client<'a> {
         let url = self.config.auth_url_path("v1/recovery_email/status")?;
         let key = Client::derive_key_from_session_token(session_token)?;
         let request = HAWKRequestBuilder::new(Method::GET, url, &key).build()?;
-        Client::make_request(request)?.json().map_err(|e| e.into())
+        self.make_request(request)?.json().map_err(|e| e.into())
     }
 
     pub fn profile(
@@ -161,18 +161,18 @@ impl<'a> Client<'a> {
         etag: Option<String>,
     ) -> Result<Option<ResponseAndETag<ProfileResponse>>> {
         let url = self.config.userinfo_endpoint()?;
-        let client = ReqwestClient::new();
-        let mut builder = client.request(Method::GET, url)
-            .header(header::AUTHORIZATION, format!("Bearer {}", profile_access_token));
+        let bearer = format!("Bearer {}", profile_access_token);
+        let mut request = Request::get(url)
+            .header(header::AUTHORIZATION, HeaderValue::from_str(&bearer)?);
         if let Some(etag) = etag {
-            builder = builder.header(header::IF_NONE_MATCH, format!("\"{}\"", etag));
+            let if_none_match = format!("\"{}\"", etag);
+            request = request.header(header::IF_NONE_MATCH, HeaderValue::from_str(&if_none_match)?);
         }
-        let request = builder.build()?;
-        let mut resp = Client::make_request(request)?;
-        if resp.status() == StatusCode::NOT_MODIFIED {
+        let resp = self.make_request(request)?;
+        if resp.status == StatusCode::NOT_MODIFIED {
             return Ok(None);
         }
-        let etag = resp.headers().get(header::ETAG).and_then(|v| v.to_str().ok()).map(|s| s.to_owned());
+        let etag = resp.header(header::ETAG.as_str()).map(|s| s.to_owned());
         Ok(Some(ResponseAndETag {
             etag,
             response: resp.json()?,
@@ -201,7 +201,7 @@ impl<'a> Client<'a> {
         let request = HAWKRequestBuilder::new(Method::POST, url, &key)
             .body(parameters)
             .build()?;
-        Client::make_request(request)?.json().map_err(|e| e.into())
+        self.make_request(request)?.json().map_err(|e| e.into())
     }
 
     pub fn oauth_token_with_code(
@@ -235,13 +235,8 @@ impl<'a> Client<'a> {
 
     fn make_oauth_token_request(&self, body: serde_json::Value) -> Result<OAuthTokenResponse> {
         let url = self.config.token_endpoint()?;
-        let client = ReqwestClient::new();
-        let request = client
-            .request(Method::POST, url)
-            .header(header::CONTENT_TYPE, "application/json")
-            .body(body.to_string())
-            .build()?;
-        Client::make_request(request)?.json().map_err(|e| e.into())
+        let request = Request::post(url).json(&body)?;
+        self.make_request(request)?.json().map_err(|e| e.into())
     }
 
     #[cfg(feature = "browserid")]
@@ -256,7 +251,7 @@ impl<'a> Client<'a> {
         let request = HAWKRequestBuilder::new(Method::POST, url, &key)
             .body(parameters)
             .build()?;
-        Client::make_request(request)?.json().map_err(|e| e.into())
+        self.make_request(request)?.json().map_err(|e| e.into())
     }
 
     #[cfg(feature = "browserid")]
@@ -290,16 +285,14 @@ impl<'a> Client<'a> {
         out.to_vec()
     }
 
-    fn make_request(request: Request) -> Result<Response> {
-        let client = ReqwestClient::new();
-        let mut resp = client.execute(request)?;
-        let status = resp.status();
+    fn make_request(&self, request: Request) -> Result<Response> {
+        let resp = self.transport.execute(request)?;
+        let status = resp.status;
 
         if status.is_success() || status == StatusCode::NOT_MODIFIED {
             Ok(resp)
         } else {
-            let json: std::result::Result<serde_json::Value, reqwest::Error> = resp.json();
-            match json {
+            match resp.json::<serde_json::Value>() {
                 Ok(json) => Err(ErrorKind::RemoteError {
                     code: json["code"].as_u64().unwrap_or(0),
                     errno: json["errno"].as_u64().unwrap_or(0),
@@ -307,7 +300,7 @@ impl<'a> Client<'a> {
                     message: json["message"].as_str().unwrap_or("").to_string(),
                     info: json["info"].as_str().unwrap_or("").to_string(),
                 }.into()),
-                Err(_) => Err(resp.error_for_status().unwrap_err().into()),
+                Err(_) => Err(ErrorKind::UnexpectedStatus(status.as_u16()).into()),
             }
         }
     }
@@ -424,7 +417,8 @@ mod tests {
     //     let auth_pwd = auth_pwd(email, pwd);
 
     //     let config = Config::stable_dev().unwrap();
-    //     let client = Client::new(&config);
+    //     let transport = ReqwestTransport::new();
+    //     let client = Client::new(&config, &transport);
 
     //     let resp = client.login(&email, &auth_pwd, false).unwrap();
     //     println!("Session Token obtained: {}", &resp.session_token);
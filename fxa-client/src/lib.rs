@@ -10,6 +10,7 @@ extern crate failure_derive;
 #[cfg(feature = "browserid")]
 extern crate hawk;
 extern crate hex;
+extern crate http_support;
 #[macro_use]
 extern crate lazy_static;
 #[macro_use]
@@ -44,6 +45,7 @@ use errors::*;
 #[cfg(feature = "browserid")]
 use http_client::browser_id::jwt_utils;
 use http_client::{Client, OAuthTokenResponse, ProfileResponse};
+use http_support::{ReqwestTransport, Transport};
 use ring::digest;
 use ring::rand::{SecureRandom, SystemRandom};
 use scoped_keys::ScopedKeysFlow;
@@ -122,6 +124,7 @@ pub struct FirefoxAccount {
     flow_store: HashMap<String, OAuthFlow>,
     persist_callback: Option<PersistCallback>,
     profile_cache: Option<CachedResponse<ProfileResponse>>,
+    transport: Box<Transport>,
 }
 
 pub struct SyncKeys(pub String, pub String);
@@ -152,6 +155,7 @@ impl FirefoxAccount {
             flow_store: HashMap::new(),
             persist_callback: None,
             profile_cache: None,
+            transport: Box::new(ReqwestTransport::new()),
         }
     }
 
@@ -207,6 +211,13 @@ impl FirefoxAccount {
         }
     }
 
+    /// Makes all requests for this account through `transport`, instead of
+    /// the default reqwest-based transport. This lets apps use their own
+    /// network stack, and tests run without a network.
+    pub fn set_transport(&mut self, transport: Box<Transport>) {
+        self.transport = transport;
+    }
+
     pub fn to_json(&self) -> Result<String> {
         let state = State::V1(self.state.clone());
         serde_json::to_string(&state).map_err(|e| e.into())
@@ -223,7 +234,7 @@ impl FirefoxAccount {
 
     #[cfg(feature = "browserid")]
     pub fn advance(&mut self) {
-        let client = Client::new(&self.state.config);
+        let client = Client::new(&self.state.config, &*self.transport);
         let state_machine = LoginStateMachine::new(client);
         let state = mem::replace(&mut self.state.login_state, Unknown);
         self.state.login_state = state_machine.advance(state);
@@ -271,7 +282,7 @@ impl FirefoxAccount {
         let resp;
         {
             if let Some(refresh_token) = refresh_token {
-                let client = Client::new(&self.state.config);
+                let client = Client::new(&self.state.config, &*self.transport);
                 resp = client.oauth_token_with_refresh_token(
                     &self.state.client_id,
                     &refresh_token,
@@ -283,7 +294,7 @@ impl FirefoxAccount {
                     if let Some(session_token) =
                         FirefoxAccount::session_token_from_state(&self.state.login_state)
                     {
-                        let client = Client::new(&self.state.config);
+                        let client = Client::new(&self.state.config, &*self.transport);
                         resp = client.oauth_token_with_session_token(
                             &self.state.client_id,
                             session_token,
@@ -302,6 +313,22 @@ impl FirefoxAccount {
         Ok(Some(self.handle_oauth_token_response(resp, None)?))
     }
 
+    /// Marks cached OAuth tokens with this `access_token` as expired, so
+    /// that the next `get_oauth_token` call refreshes them. Call this when a
+    /// server rejects a token that we thought was still valid.
+    pub fn clear_access_token(&mut self, access_token: &str) {
+        let mut changed = false;
+        for info in self.state.oauth_cache.values_mut() {
+            if info.access_token == access_token {
+                info.expires_at = 0;
+                changed = true;
+            }
+        }
+        if changed {
+            self.maybe_call_persist_callback();
+        }
+    }
+
     pub fn begin_pairing_flow(&mut self, pairing_url: &str, scopes: &[&str]) -> Result<String> {
         let mut url = self.state.config.content_url_path("/pair/supp")?;
         let pairing_url = Url::parse(pairing_url)?;
@@ -363,7 +390,7 @@ impl FirefoxAccount {
                 Some(flow) => flow,
                 None => return Err(ErrorKind::UnknownOAuthState.into()),
             };
-            let client = Client::new(&self.state.config);
+            let client = Client::new(&self.state.config, &*self.transport);
             resp = client.oauth_token_with_code(&code, &flow.code_verifier, &self.state.client_id)?;
         }
         let oauth_flow = match self.flow_store.remove(state) {
@@ -460,7 +487,7 @@ impl FirefoxAccount {
             }
             etag = Some(cached_profile.etag.clone());
         }
-        let client = Client::new(&self.state.config);
+        let client = Client::new(&self.state.config, &*self.transport);
         match client.profile(&profile_access_token, etag)? {
             Some(response_and_etag) => {
                 if let Some(etag) = response_and_etag.etag {
@@ -539,7 +566,7 @@ impl FirefoxAccount {
 
     #[cfg(feature = "browserid")]
     pub fn sign_out(mut self) {
-        let client = Client::new(&self.state.config);
+        let client = Client::new(&self.state.config, &*self.transport);
         client.sign_out();
         self.state.login_state = self.state.login_state.to_separated();
     }
@@ -548,6 +575,7 @@ impl FirefoxAccount {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use http_support::{InMemoryTransport, Method, MockResponse};
     use std::borrow::Cow;
 
     #[test]
@@ -688,6 +716,87 @@ mod tests {
         fxa.oauth_cache_store(&oauth_info);
         fxa.oauth_cache_find(&["profile"]).unwrap();
     }
+
+    #[test]
+    fn test_clear_access_token() {
+        let mut fxa =
+            FirefoxAccount::new(Config::stable_dev().unwrap(), "12345678", "https://foo.bar");
+        let oauth_info = OAuthInfo {
+            access_token: "abcdef".to_string(),
+            keys: None,
+            refresh_token: Some("refresh".to_string()),
+            expires_at: util::now_secs() + 3600,
+            scopes: vec!["https://identity.mozilla.com/apps/oldsync".to_string()],
+        };
+        fxa.oauth_cache_store(&oauth_info);
+
+        fxa.clear_access_token("ghijkl");
+        let cached = fxa.oauth_cache_find(&["https://identity.mozilla.com/apps/oldsync"]).unwrap();
+        assert_eq!(cached.expires_at, oauth_info.expires_at);
+
+        fxa.clear_access_token("abcdef");
+        let cached = fxa.oauth_cache_find(&["https://identity.mozilla.com/apps/oldsync"]).unwrap();
+        assert_eq!(cached.expires_at, 0);
+        // We keep the refresh token, so that we can get a new access token.
+        assert_eq!(cached.refresh_token, Some("refresh".to_string()));
+    }
+
+    #[test]
+    fn test_get_profile_with_transport() {
+        let config: Config = serde_json::from_value(json!({
+            "content_url": "https://stable.dev.lcip.org/",
+            "auth_url": "https://stable.dev.lcip.org/auth/",
+            "oauth_url": "https://oauth-stable.dev.lcip.org/",
+            "profile_url": "https://stable.dev.lcip.org/profile/",
+            "token_server_endpoint_url": "https://stable.dev.lcip.org/syncserver/token/1.0/sync/1.5",
+            "authorization_endpoint": "https://oauth-stable.dev.lcip.org/v1/authorization",
+            "issuer": "https://dev.lcip.org/",
+            "jwks_uri": "https://oauth-stable.dev.lcip.org/v1/jwks",
+            "token_endpoint": "https://oauth-stable.dev.lcip.org/v1/token",
+            "userinfo_endpoint": "https://stable.dev.lcip.org/profile/v1/profile",
+        })).unwrap();
+        let mut fxa = FirefoxAccount::new(config, "12345678", "https://foo.bar");
+        let transport = InMemoryTransport::new();
+        fxa.set_transport(Box::new(transport.clone()));
+
+        // An expired token, which we'll need to refresh first.
+        fxa.oauth_cache_store(&OAuthInfo {
+            access_token: "abcdef".to_string(),
+            keys: None,
+            refresh_token: Some("refresh".to_string()),
+            expires_at: 1,
+            scopes: vec!["profile".to_string()],
+        });
+        transport.respond(Method::POST, "/v1/token", MockResponse::new(200).json(&json!({
+            "access_token": "ghijkl",
+            "expires_in": 3600,
+            "scope": "profile",
+        })));
+        transport.respond(Method::GET, "/profile/v1/profile", MockResponse::new(200).json(&json!({
+            "uid": "12345ab",
+            "email": "foo@bar.com",
+            "locale": "en-US",
+            "displayName": null,
+            "avatar": "https://foo.avatar",
+            "avatarDefault": true,
+            "amrValues": [],
+            "twoFactorAuthentication": false,
+        })));
+
+        let profile = fxa.get_profile(false).expect("should fetch profile");
+        assert_eq!(profile.uid, "12345ab");
+        assert_eq!(profile.email, "foo@bar.com");
+        assert!(!transport.has_pending_responses());
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 2);
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].body.as_ref().unwrap()).unwrap();
+        assert_eq!(body["grant_type"], "refresh_token");
+        assert_eq!(body["refresh_token"], "refresh");
+        assert_eq!(requests[1].url.as_str(), "https://stable.dev.lcip.org/profile/v1/profile");
+        assert_eq!(requests[1].headers["authorization"], "Bearer ghijkl");
+    }
 }
 
 pub struct OAuthFlow {
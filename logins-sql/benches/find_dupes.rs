@@ -0,0 +1,67 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Compares finding dupes for incoming records one at a time with finding
+// them in bulk, as `LoginDb::reconcile` does. Run with
+// `cargo bench -p logins-sql --bench find_dupes`.
+
+extern crate logins_sql;
+
+use std::time::{Duration, Instant};
+use logins_sql::{Login, PasswordEngine};
+use logins_sql::bench::{find_dupes_batched, find_dupes_one_at_a_time};
+
+const NUM_LOCAL_LOGINS: usize = 5000;
+const NUM_RUNS: u32 = 5;
+
+// Adds `count` logins to `engine`, and returns twice as many incoming logins
+// with new GUIDs, half of which are dupes of the local ones.
+fn setup(engine: &PasswordEngine, count: usize) -> Vec<Login> {
+    let mut incoming = Vec::with_capacity(count * 2);
+    for i in 0..count {
+        let hostname = format!("https://{}.example.com", i % (count / 4 + 1));
+        let local = Login {
+            form_submit_url: Some(format!("{}/login", hostname)),
+            hostname,
+            username: format!("user{}", i),
+            password: "p4ssw0rd".into(),
+            .. Login::default()
+        };
+        engine.add(local.clone()).unwrap();
+        incoming.push(Login { id: format!("dupe{:08}", i), .. local.clone() });
+        incoming.push(Login {
+            id: format!("new{:09}", i),
+            username: format!("other{}", i),
+            .. local
+        });
+    }
+    incoming
+}
+
+fn time(f: impl Fn() -> usize) -> (usize, Duration) {
+    let mut total = Duration::from_secs(0);
+    let mut count = 0;
+    for _ in 0..NUM_RUNS {
+        let start = Instant::now();
+        count = f();
+        total += start.elapsed();
+    }
+    (count, total / NUM_RUNS)
+}
+
+fn main() {
+    let engine = PasswordEngine::new_in_memory(Some("secret")).unwrap();
+    let incoming = setup(&engine, NUM_LOCAL_LOGINS);
+
+    let (one_at_a_time_count, one_at_a_time) = time(|| {
+        find_dupes_one_at_a_time(&engine, &incoming).unwrap()
+    });
+    let (batched_count, batched) = time(|| find_dupes_batched(&engine, &incoming).unwrap());
+    assert_eq!(one_at_a_time_count, batched_count);
+
+    println!("Finding dupes for {} incoming logins ({} found), averaged over {} runs:",
+             incoming.len(), batched_count, NUM_RUNS);
+    println!("  one at a time: {:?}", one_at_a_time);
+    println!("  batched:       {:?}", batched);
+}
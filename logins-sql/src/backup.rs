@@ -0,0 +1,149 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Passphrase-protected backups of logins, which can be imported into
+//! another `LoginDb` with `logins_from_backup`. Unlike CSV exports, these
+//! keep every field, including GUIDs and timestamps.
+//!
+//! A backup is a JSON object with the PBKDF2 parameters, and the logins
+//! (as a JSON array) encrypted with AES-256-GCM, using a key derived from
+//! the passphrase.
+
+use base64;
+use openssl::hash::MessageDigest;
+use openssl::pkcs5;
+use openssl::rand;
+use openssl::symm::{self, Cipher};
+use serde_json;
+use error::*;
+use login::Login;
+
+const BACKUP_VERSION: u32 = 1;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+// We'll accept backups made with more iterations than we use, in case we
+// increase `PBKDF2_ITERATIONS` later, but not so many that deriving the key
+// would hang.
+const MAX_PBKDF2_ITERATIONS: u32 = 10 * PBKDF2_ITERATIONS;
+const SALT_LEN: usize = 16;
+const IV_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Backup {
+    version: u32,
+    iterations: u32,
+    salt: String,
+    iv: String,
+    ciphertext: String,
+    tag: String,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> Result<Vec<u8>> {
+    let mut key = vec![0u8; Cipher::aes_256_gcm().key_len()];
+    pkcs5::pbkdf2_hmac(passphrase.as_bytes(), salt, iterations as usize,
+                       MessageDigest::sha256(), &mut key)?;
+    Ok(key)
+}
+
+fn backup_error(message: &str) -> Error {
+    ErrorKind::InvalidBackup(message.into()).into()
+}
+
+/// Encrypts `logins` with a key derived from `passphrase`, returning the
+/// backup as a string.
+pub fn logins_to_backup(logins: &[Login], passphrase: &str) -> Result<String> {
+    let mut salt = vec![0u8; SALT_LEN];
+    rand::rand_bytes(&mut salt)?;
+    let mut iv = vec![0u8; IV_LEN];
+    rand::rand_bytes(&mut iv)?;
+    let key = derive_key(passphrase, &salt, PBKDF2_ITERATIONS)?;
+
+    let plaintext = serde_json::to_vec(logins)?;
+    let mut tag = vec![0u8; TAG_LEN];
+    let ciphertext = symm::encrypt_aead(Cipher::aes_256_gcm(), &key, Some(&iv), &[],
+                                        &plaintext, &mut tag)?;
+    Ok(serde_json::to_string(&Backup {
+        version: BACKUP_VERSION,
+        iterations: PBKDF2_ITERATIONS,
+        salt: base64::encode(&salt),
+        iv: base64::encode(&iv),
+        ciphertext: base64::encode(&ciphertext),
+        tag: base64::encode(&tag),
+    })?)
+}
+
+/// Decrypts a backup made by `logins_to_backup`. Fails with `WrongPassphrase`
+/// if we can't decrypt the logins, and `InvalidBackup` if the backup is
+/// corrupt; otherwise returns the result of parsing each login.
+pub fn logins_from_backup(backup: &str, passphrase: &str) -> Result<Vec<Result<Login>>> {
+    let backup: Backup = serde_json::from_str(backup)?;
+    if backup.version != BACKUP_VERSION {
+        throw!(ErrorKind::InvalidBackup(format!("Unsupported version {}", backup.version)));
+    }
+    if backup.iterations < PBKDF2_ITERATIONS || backup.iterations > MAX_PBKDF2_ITERATIONS {
+        throw!(ErrorKind::InvalidBackup(format!("Unsupported iteration count {}", backup.iterations)));
+    }
+    let decode = |field: &str| base64::decode(field).map_err(|_| backup_error("Corrupt backup"));
+    let salt = decode(&backup.salt)?;
+    let iv = decode(&backup.iv)?;
+    let ciphertext = decode(&backup.ciphertext)?;
+    let tag = decode(&backup.tag)?;
+
+    let key = derive_key(passphrase, &salt, backup.iterations)?;
+    let plaintext = symm::decrypt_aead(Cipher::aes_256_gcm(), &key, Some(&iv), &[],
+                                       &ciphertext, &tag)
+        .map_err(|_| Error::from(ErrorKind::WrongPassphrase))?;
+    let logins: Vec<serde_json::Value> = serde_json::from_slice(&plaintext)?;
+    Ok(logins.into_iter().map(|login| Ok(serde_json::from_value(login)?)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let logins = vec![Login {
+            id: "{guid-1}".into(),
+            hostname: "https://example.com".into(),
+            form_submit_url: Some("https://example.com".into()),
+            username: "alice".into(),
+            password: "p4ssw0rd".into(),
+            username_field: "user".into(),
+            password_field: "pass".into(),
+            time_created: 1,
+            time_last_used: 2,
+            time_password_changed: 3,
+            times_used: 4,
+            .. Login::default()
+        }];
+        let backup = logins_to_backup(&logins, "correct horse battery staple").unwrap();
+        assert!(!backup.contains("p4ssw0rd"));
+
+        let restored = logins_from_backup(&backup, "correct horse battery staple").unwrap()
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(restored, logins);
+
+        match logins_from_backup(&backup, "wrong passphrase").unwrap_err().kind() {
+            ErrorKind::WrongPassphrase => {}
+            e => panic!("Unexpected error {:?}", e),
+        }
+        assert!(logins_from_backup("{}", "correct horse battery staple").is_err());
+    }
+
+    #[test]
+    fn test_iterations() {
+        let backup = logins_to_backup(&[], "passphrase").unwrap();
+        for &iterations in &[0, PBKDF2_ITERATIONS - 1, MAX_PBKDF2_ITERATIONS + 1, u32::max_value()] {
+            let mut value: serde_json::Value = serde_json::from_str(&backup).unwrap();
+            value["iterations"] = iterations.into();
+            match logins_from_backup(&value.to_string(), "passphrase").unwrap_err().kind() {
+                ErrorKind::InvalidBackup(_) => {}
+                e => panic!("Unexpected error for {} iterations: {:?}", iterations, e),
+            }
+        }
+    }
+}
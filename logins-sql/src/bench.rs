@@ -0,0 +1,28 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Hooks for the benchmarks in `benches/`, which can only use our public
+//! API. These aren't part of that API, and might change at any time.
+
+use engine::PasswordEngine;
+use error::Result;
+use login::Login;
+
+/// Looks for a local dupe of each of `logins` with a separate query, like we
+/// used to when reconciling. Returns how many have dupes.
+pub fn find_dupes_one_at_a_time(engine: &PasswordEngine, logins: &[Login]) -> Result<usize> {
+    let mut count = 0;
+    for login in logins {
+        if engine.db.find_dupe(login)?.is_some() {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Looks for local dupes of all of `logins` at once, like we do when
+/// reconciling now. Returns how many have dupes.
+pub fn find_dupes_batched(engine: &PasswordEngine, logins: &[Login]) -> Result<usize> {
+    Ok(engine.db.find_dupes(logins)?.len())
+}
@@ -5,14 +5,19 @@
 use rusqlite::{Connection, types::{ToSql, FromSql}};
 use std::time::SystemTime;
 use std::path::Path;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use error::*;
 use schema;
 use login::{LocalLogin, MirrorLogin, Login, SyncStatus, SyncLoginData};
-use sync::{self, ServerTimestamp, IncomingChangeset, Store, OutgoingChangeset, Payload};
+use sync::{self, ServerTimestamp, IncomingChangeset, Store, OutgoingChangeset, Payload,
+           GlobalState, Sync15StorageClient, SyncEngine};
+use failure;
+use sync::telemetry;
 use update_plan::UpdatePlan;
 use sql_support::{self, ConnExt};
 use util;
+use lookup::{LookupOptions, OriginKey};
+use import::{ImportSummary, ImportFailure};
 use std::ops::Deref;
 
 pub struct LoginDb {
@@ -190,9 +195,15 @@ impl LoginDb {
         Ok(sync_data)
     }
 
-    // It would be nice if this were a batch-ish api (e.g. takes a slice of records and finds dupes
-    // for each one if they exist)... I can't think of how to write that query, though.
-    fn find_dupe(&self, l: &Login) -> Result<Option<Login>> {
+    // See `find_dupes` for a batch version of this, which is much faster when
+    // checking many records.
+    pub(crate) fn find_dupe(&self, l: &Login) -> Result<Option<Login>> {
+        self.find_dupe_in(l, "loginsL", "is_deleted = 0")
+    }
+
+    // Like `find_dupe`, but looks in `table` (either `loginsL` or `loginsM`)
+    // for rows where `live_condition` holds.
+    fn find_dupe_in(&self, l: &Login, table: &str, live_condition: &str) -> Result<Option<Login>> {
         let form_submit_host_port = l.form_submit_url.as_ref().and_then(|s| util::url_host_port(&s));
         let args = &[
             (":hostname", &l.hostname as &ToSql),
@@ -202,11 +213,14 @@ impl LoginDb {
         ];
         let mut query = format!("
             SELECT {common}
-            FROM loginsL
-            WHERE hostname IS :hostname
+            FROM {table}
+            WHERE {live_condition}
+              AND hostname IS :hostname
               AND httpRealm IS :http_realm
               AND username IS :username",
             common = schema::COMMON_COLS,
+            table = table,
+            live_condition = live_condition,
         );
         if form_submit_host_port.is_some() {
             // Stolen from iOS
@@ -217,12 +231,119 @@ impl LoginDb {
         Ok(self.try_query_row(&query, args, |row| Login::from_row(row), false)?)
     }
 
+    // Finds local dupes for many logins at once, returning a map of each
+    // login's GUID to the local record `find_dupe` would return for it. We
+    // can't express `find_dupe`'s conditions as a single query, so instead we
+    // fetch every local record with the same hostname as one of `logins`, and
+    // check them here with `is_dupe`.
+    pub(crate) fn find_dupes<'a, I>(&self, logins: I) -> Result<HashMap<String, Login>>
+        where I: IntoIterator<Item = &'a Login> {
+        let logins: Vec<&Login> = logins.into_iter().collect();
+        let hostnames: Vec<&str> = logins.iter()
+            .map(|l| l.hostname.as_str())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+
+        let mut local_by_hostname: HashMap<String, Vec<Login>> = HashMap::new();
+        sql_support::each_chunk(&hostnames, |chunk, _| -> Result<()> {
+            let mut stmt = self.db.prepare(&format!("
+                SELECT {common_cols}
+                FROM loginsL
+                WHERE is_deleted = 0
+                  AND hostname IN ({vars})",
+                common_cols = schema::COMMON_COLS,
+                vars = sql_support::repeat_sql_vars(chunk.len())))?;
+            let rows = stmt.query_and_then(chunk, Login::from_row)?;
+            for local in rows {
+                let local = local?;
+                local_by_hostname.entry(local.hostname.clone()).or_insert_with(Vec::new).push(local);
+            }
+            Ok(())
+        })?;
+
+        let mut dupes = HashMap::new();
+        for login in logins {
+            let dupe = local_by_hostname.get(&login.hostname)
+                .and_then(|candidates| candidates.iter().find(|local| is_dupe(login, local)));
+            if let Some(dupe) = dupe {
+                dupes.insert(login.id.clone(), dupe.clone());
+            }
+        }
+        Ok(dupes)
+    }
+
     pub fn get_all(&self) -> Result<Vec<Login>> {
         let mut stmt = self.db.prepare_cached(&GET_ALL_SQL)?;
         let rows = stmt.query_and_then(&[], Login::from_row)?;
         rows.collect::<Result<_>>()
     }
 
+    // Returns the logins saved for `origin`, or for the origins that
+    // `options` allows, by looking up candidate `hostname`s with the
+    // `(is_deleted, hostname)` and `(is_overridden, hostname)` indexes.
+    // The SQL is only a prefilter - `OriginKey::matches_login` decides. Since
+    // it compares `hostname`s exactly, we only find logins whose `hostname`
+    // is already normalized; see the `lookup` module for details.
+    fn get_by_origin_key(&self, origin: &OriginKey, options: LookupOptions) -> Result<Vec<Login>> {
+        let candidates = origin.candidate_origins(options);
+        let patterns = origin.subdomain_patterns(options);
+        let query = get_by_hostname_sql(candidates.len(), patterns.len());
+        // The conditions appear once for each table.
+        let params: Vec<&ToSql> = candidates.iter().chain(patterns.iter())
+            .chain(candidates.iter()).chain(patterns.iter())
+            .map(|s| s as &ToSql)
+            .collect();
+        let mut stmt = self.db.prepare_cached(&query)?;
+        let rows = stmt.query_and_then(&params, Login::from_row)?;
+        let mut logins = Vec::new();
+        for login in rows {
+            let login = login?;
+            if origin.matches_login(&login, options) {
+                logins.push(login);
+            }
+        }
+        Ok(logins)
+    }
+
+    /// Returns the logins saved for `origin`, which can be any URL on the
+    /// site. Returns nothing if `origin` doesn't have a host.
+    pub fn get_by_origin(&self, origin: &str, options: LookupOptions) -> Result<Vec<Login>> {
+        match OriginKey::parse(origin) {
+            Some(key) => self.get_by_origin_key(&key, options),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Returns the logins saved for `origin` with a form that submits to
+    /// `form_action_origin`.
+    pub fn get_by_form_action_origin(
+        &self,
+        origin: &str,
+        form_action_origin: &str,
+        options: LookupOptions
+    ) -> Result<Vec<Login>> {
+        let (key, action_key) = match (OriginKey::parse(origin), OriginKey::parse(form_action_origin)) {
+            (Some(key), Some(action_key)) => (key, action_key),
+            _ => return Ok(Vec::new()),
+        };
+        let mut logins = self.get_by_origin_key(&key, options)?;
+        logins.retain(|login| action_key.matches_form_action(login, options));
+        Ok(logins)
+    }
+
+    /// Returns the logins saved for `origin` with HTTP auth for `http_realm`.
+    pub fn get_by_http_realm(
+        &self,
+        origin: &str,
+        http_realm: &str,
+        options: LookupOptions
+    ) -> Result<Vec<Login>> {
+        let mut logins = self.get_by_origin(origin, options)?;
+        logins.retain(|login| login.http_realm.as_ref().map(|s| s.as_str()) == Some(http_realm));
+        Ok(logins)
+    }
+
     pub fn get_by_id(&self, id: &str) -> Result<Option<Login>> {
         self.try_query_row(&GET_BY_GUID_SQL,
                            &[(":guid", &id as &ToSql)],
@@ -274,6 +395,13 @@ impl LoginDb {
         login.time_last_used = now_ms;
         login.times_used = 1;
 
+        self.insert_new_login(&login, now_ms)?;
+        Ok(login)
+    }
+
+    // Inserts `login` into `loginsL` as a new, unsynced record, keeping its
+    // metadata as-is.
+    fn insert_new_login(&self, login: &Login, now_ms: i64) -> Result<()> {
         let sql = format!("
             INSERT OR IGNORE INTO loginsL (
                 hostname,
@@ -327,9 +455,91 @@ impl LoginDb {
         if rows_changed == 0 {
             error!("Record {:?} already exists (use `update` to update records, not add)",
                    login.id);
-            throw!(ErrorKind::DuplicateGuid(login.id));
+            throw!(ErrorKind::DuplicateGuid(login.id.clone()));
         }
-        Ok(login)
+        Ok(())
+    }
+
+    /// Adds logins from another password manager, keeping their metadata
+    /// (like `timeCreated`). Logins that we already have, and logins for
+    /// which `records` has an error, are skipped. Either all the logins are
+    /// added, or, if this returns an error, none are.
+    pub fn import(&self, records: Vec<Result<Login>>) -> Result<ImportSummary> {
+        self.in_transaction(|| self.import_in_transaction(records))
+    }
+
+    // Runs `f` in a transaction, which is committed if `f` succeeds, and
+    // rolled back if either `f` or the commit fails.
+    fn in_transaction<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        self.execute_all(&["BEGIN IMMEDIATE"])?;
+        let result = f().and_then(|result| {
+            self.execute_all(&["COMMIT"])?;
+            Ok(result)
+        });
+        if let Err(ref e) = result {
+            // Report the original error, even if we can't roll back.
+            if let Err(rollback_error) = self.execute_all(&["ROLLBACK"]) {
+                warn!("Failed to roll back after {}: {}", e, rollback_error);
+            }
+        }
+        result
+    }
+
+    fn import_in_transaction(&self, records: Vec<Result<Login>>) -> Result<ImportSummary> {
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let mut summary = ImportSummary::default();
+        for (index, record) in records.into_iter().enumerate() {
+            let mut login = match record.and_then(|login| login.check_valid().map(|_| login)) {
+                Ok(login) => login,
+                Err(e) => {
+                    summary.num_failed += 1;
+                    summary.failures.push(ImportFailure { index, reason: e.to_string() });
+                    continue;
+                }
+            };
+            // We check the mirror too, since unlike incoming records, imported
+            // logins might duplicate ones we've synced.
+            if self.find_dupe(&login)?.is_some() ||
+               self.find_dupe_in(&login, "loginsM", "is_overridden = 0")?.is_some() {
+                summary.num_skipped += 1;
+                continue;
+            }
+            // Keep the login's GUID if we can, so that importing from a
+            // profile that syncs to the same account doesn't duplicate its
+            // logins on the server.
+            if login.id.is_empty() || self.guid_in_use(&login.id)? {
+                login.id = sync::util::random_guid()
+                    .expect("Failed to generate random bytes for GUID");
+            }
+            if login.time_created == 0 {
+                login.time_created = now_ms;
+            }
+            if login.time_password_changed == 0 {
+                login.time_password_changed = login.time_created;
+            }
+            if login.time_last_used == 0 {
+                login.time_last_used = login.time_created;
+            }
+            self.insert_new_login(&login, now_ms)?;
+            summary.num_imported += 1;
+        }
+        info!("Imported {} logins, skipped {}, failed to import {}",
+              summary.num_imported, summary.num_skipped, summary.num_failed);
+        Ok(summary)
+    }
+
+    // Unlike `exists`, this includes deleted and overridden records, since
+    // their GUIDs can't be reused.
+    fn guid_in_use(&self, id: &str) -> Result<bool> {
+        Ok(self.db.query_row_named("
+            SELECT EXISTS(
+                SELECT 1 FROM loginsL WHERE guid = :guid
+                UNION ALL
+                SELECT 1 FROM loginsM WHERE guid = :guid
+            )",
+            &[(":guid", &id as &ToSql)],
+            |row| row.get(0)
+        )?)
     }
 
     pub fn update(&self, login: Login) -> Result<()> {
@@ -439,6 +649,87 @@ impl LoginDb {
         Ok(exists)
     }
 
+    /// Returns groups of logins that are probably duplicates of each other:
+    /// logins for the same origin and username (and HTTP realm, for HTTP
+    /// auth logins), but with different GUIDs. Each group has the login with
+    /// the newest password first, which is the one `merge_duplicates` keeps.
+    pub fn find_duplicates(&self) -> Result<Vec<Vec<Login>>> {
+        let mut groups: HashMap<DuplicateKey, Vec<Login>> = HashMap::new();
+        for login in self.get_all()? {
+            groups.entry(DuplicateKey::new(&login)).or_insert_with(Vec::new).push(login);
+        }
+        let mut duplicates: Vec<Vec<Login>> = groups.into_iter()
+            .map(|(_, group)| group)
+            .filter(|group| group.len() > 1)
+            .collect();
+        for group in &mut duplicates {
+            group.sort_by(|a, b| b.time_password_changed.cmp(&a.time_password_changed)
+                .then_with(|| a.id.cmp(&b.id)));
+        }
+        duplicates.sort_by(|a, b| a[0].hostname.cmp(&b[0].hostname)
+            .then_with(|| a[0].username.cmp(&b[0].username))
+            .then_with(|| a[0].id.cmp(&b[0].id)));
+        Ok(duplicates)
+    }
+
+    /// Merges the logins with the given `ids`, which must be duplicates as
+    /// reported by `find_duplicates`, into one. We keep the login with the
+    /// newest password, add up how many times they've been used, and delete
+    /// the others, so that the merge is synced. Returns the merged login.
+    pub fn merge_duplicates(&self, ids: &[&str]) -> Result<Login> {
+        self.in_transaction(|| self.merge_duplicates_in_transaction(ids))
+    }
+
+    fn merge_duplicates_in_transaction(&self, ids: &[&str]) -> Result<Login> {
+        let mut logins = Vec::with_capacity(ids.len());
+        for id in ids {
+            match self.get_by_id(id)? {
+                Some(login) => logins.push(login),
+                None => return Err(ErrorKind::NoSuchRecord(id.to_string()).into()),
+            }
+        }
+        let ids: HashSet<&str> = ids.iter().cloned().collect();
+        let key = logins.first().map(DuplicateKey::new);
+        if ids.len() < 2 || logins.iter().any(|login| Some(DuplicateKey::new(login)) != key) {
+            throw!(ErrorKind::NotDuplicates(ids.into_iter().map(String::from).collect()));
+        }
+
+        let mut merged = logins.iter()
+            .max_by(|a, b| a.time_password_changed.cmp(&b.time_password_changed)
+                .then_with(|| b.id.cmp(&a.id)))
+            .cloned()
+            .unwrap();
+        merged.times_used = logins.iter().map(|login| login.times_used).sum();
+        merged.time_created = logins.iter().map(|login| login.time_created).min().unwrap();
+        merged.time_last_used = logins.iter().map(|login| login.time_last_used).max().unwrap();
+
+        self.ensure_local_overlay_exists(&merged.id)?;
+        self.mark_mirror_overridden(&merged.id)?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        self.execute_named_cached(&format!("
+            UPDATE loginsL
+            SET local_modified = :now_millis,
+                timesUsed      = :times_used,
+                timeCreated    = :time_created,
+                timeLastUsed   = :time_last_used,
+                sync_status    = max(sync_status, {changed})
+            WHERE guid = :guid",
+            changed = SyncStatus::Changed as u8),
+            &[(":now_millis", &now_ms as &ToSql),
+              (":times_used", &merged.times_used as &ToSql),
+              (":time_created", &merged.time_created as &ToSql),
+              (":time_last_used", &merged.time_last_used as &ToSql),
+              (":guid", &merged.id as &ToSql)])?;
+
+        for login in &logins {
+            if login.id != merged.id {
+                self.delete(&login.id)?;
+            }
+        }
+        info!("Merged {} duplicate logins into {}", logins.len(), merged.id);
+        Ok(merged)
+    }
+
     fn mark_mirror_overridden(&self, guid: &str) -> Result<()> {
         self.execute_named_cached("
             UPDATE loginsM SET
@@ -519,9 +810,19 @@ impl LoginDb {
         Ok(())
     }
 
-    fn reconcile(&self, records: Vec<SyncLoginData>, server_now: ServerTimestamp) -> Result<UpdatePlan> {
+    fn reconcile(
+        &self,
+        records: Vec<SyncLoginData>,
+        server_now: ServerTimestamp,
+        telem: &mut telemetry::EngineIncoming,
+    ) -> Result<UpdatePlan> {
         let mut plan = UpdatePlan::default();
 
+        // Only records that we've never seen before can be dupes.
+        let dupes = self.find_dupes(records.iter()
+            .filter(|record| record.mirror.is_none() && record.local.is_none())
+            .filter_map(|record| record.inbound.0.as_ref()))?;
+
         for mut record in records {
             debug!("Processing remote change {}", record.guid());
             let upstream = if let Some(inbound) = record.inbound.0.take() {
@@ -529,6 +830,7 @@ impl LoginDb {
             } else {
                 debug!("Processing inbound deletion (always prefer)");
                 plan.plan_delete(record.guid.clone());
+                telem.applied(1);
                 continue;
             };
             let upstream_time = record.inbound.1;
@@ -537,22 +839,27 @@ impl LoginDb {
                     debug!("  Conflict between remote and local, Resolving with 3WM");
                     plan.plan_three_way_merge(
                         local, mirror, upstream, upstream_time, server_now);
+                    telem.reconciled(1);
                 }
                 (Some(_mirror), None) => {
                     debug!("  Forwarding mirror to remote");
                     plan.plan_mirror_update(upstream, upstream_time);
+                    telem.applied(1);
                 }
                 (None, Some(local)) => {
                     debug!("  Conflicting record without shared parent, using newer");
                     plan.plan_two_way_merge(&local.login, (upstream, upstream_time));
+                    telem.reconciled(1);
                 }
                 (None, None) => {
-                    if let Some(dupe) = self.find_dupe(&upstream)? {
+                    if let Some(dupe) = dupes.get(&upstream.id) {
                         debug!("  Incoming record {} was is a dupe of local record {}", upstream.id, dupe.id);
-                        plan.plan_two_way_merge(&dupe, (upstream, upstream_time));
+                        plan.plan_two_way_merge(dupe, (upstream, upstream_time));
+                        telem.reconciled(1);
                     } else {
                         debug!("  No dupe found, inserting into mirror");
                         plan.plan_mirror_insert(upstream, upstream_time, false);
+                        telem.applied(1);
                     }
                 }
             }
@@ -589,10 +896,11 @@ impl LoginDb {
 
     fn do_apply_incoming(
         &mut self,
-        inbound: IncomingChangeset
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::EngineIncoming,
     ) -> Result<OutgoingChangeset> {
         let data = self.fetch_login_data(&inbound.changes)?;
-        let plan = self.reconcile(data, inbound.timestamp)?;
+        let plan = self.reconcile(data, inbound.timestamp, telem)?;
         self.execute_plan(plan)?;
         Ok(self.fetch_outgoing(inbound.timestamp)?)
     }
@@ -632,6 +940,14 @@ impl LoginDb {
     pub fn get_global_state(&self) -> Result<Option<String>> {
         self.get_meta::<String>(schema::GLOBAL_STATE_META_KEY)
     }
+
+    pub fn set_token_cache(&self, token_cache: &str) -> Result<()> {
+        self.put_meta(schema::TOKEN_CACHE_META_KEY, &token_cache)
+    }
+
+    pub fn get_token_cache(&self) -> Result<Option<String>> {
+        self.get_meta::<String>(schema::TOKEN_CACHE_META_KEY)
+    }
 }
 
 impl Store for LoginDb {
@@ -639,9 +955,10 @@ impl Store for LoginDb {
 
     fn apply_incoming(
         &mut self,
-        inbound: IncomingChangeset
+        inbound: IncomingChangeset,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
     ) -> Result<OutgoingChangeset> {
-        self.do_apply_incoming(inbound)
+        self.do_apply_incoming(inbound, incoming_telemetry)
     }
 
     fn sync_finished(
@@ -654,6 +971,124 @@ impl Store for LoginDb {
             new_timestamp
         )
     }
+
+    fn reset(&mut self) -> Result<()> {
+        LoginDb::reset(self)
+    }
+
+    fn all_local_records(&self) -> Result<Option<Vec<Payload>>> {
+        let records = self.get_all()?
+            .into_iter()
+            .map(|login| Ok(Payload::from_record(login)?))
+            .collect::<Result<_>>()?;
+        Ok(Some(records))
+    }
+
+    // Logins on the server are duplicates if `find_duplicates` would merge
+    // them locally.
+    fn validation_key(&self, record: &Payload) -> String {
+        match record.clone().into_record::<Login>() {
+            Ok(login) => DuplicateKey::new(&login).to_key(),
+            Err(_) => record.id.clone(),
+        }
+    }
+}
+
+impl SyncEngine for LoginDb {
+    fn collection_name(&self) -> &'static str {
+        "passwords"
+    }
+
+    fn sync(
+        &mut self,
+        client: &Sync15StorageClient,
+        state: &GlobalState,
+        telem_engine: &mut telemetry::Engine,
+    ) -> ::std::result::Result<(), failure::Error> {
+        let ts = self.get_last_sync()?.unwrap_or_default();
+        sync::synchronize(client, state, self, "passwords".into(), ts, true, telem_engine)?;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> ::std::result::Result<(), failure::Error> {
+        LoginDb::reset(self)?;
+        Ok(())
+    }
+
+    fn wipe(&mut self) -> ::std::result::Result<(), failure::Error> {
+        LoginDb::wipe(self)?;
+        Ok(())
+    }
+}
+
+// Returns true if `local` is a dupe of `incoming`, using the same conditions
+// as `LoginDb::find_dupe_in`. `local` must have the same hostname.
+fn is_dupe(incoming: &Login, local: &Login) -> bool {
+    if local.http_realm != incoming.http_realm || local.username != incoming.username {
+        return false;
+    }
+    let form_submit_host_port = incoming.form_submit_url.as_ref().and_then(|s| util::url_host_port(&s));
+    match (form_submit_host_port, local.form_submit_url.as_ref()) {
+        (Some(host_port), Some(url)) => url.is_empty() || url.contains(&host_port),
+        (Some(_), None) => false,
+        (None, url) => url.is_none(),
+    }
+}
+
+// Logins with the same key are reported by `LoginDb::find_duplicates`. We
+// compare normalized origins, so that `https://example.com` and
+// `https://example.com:443/` are the same.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct DuplicateKey {
+    origin: String,
+    username: String,
+    http_realm: Option<String>,
+}
+
+impl DuplicateKey {
+    fn new(login: &Login) -> DuplicateKey {
+        DuplicateKey {
+            origin: OriginKey::parse(&login.hostname)
+                .map_or_else(|| login.hostname.clone(), |origin| origin.origin()),
+            username: login.username.clone(),
+            http_realm: login.http_realm.clone(),
+        }
+    }
+
+    // Returns the key as a string, for `Store::validation_key`. A username or
+    // realm can contain any character, so rather than joining the parts with
+    // a separator, each is prefixed with its length in bytes and a `:`. A
+    // missing realm is written as `-`, so that it's different from an empty
+    // one. For example, `https://example.com`, `user`, and no realm is
+    // `19:https://example.com4:user-`.
+    fn to_key(&self) -> String {
+        let realm = match self.http_realm {
+            Some(ref realm) => format!("{}:{}", realm.len(), realm),
+            None => "-".to_string(),
+        };
+        format!("{}:{}{}:{}{}", self.origin.len(), self.origin,
+                self.username.len(), self.username, realm)
+    }
+}
+
+// Selects the live logins with one of `num_hostnames` `hostname`s, or a
+// `hostname` that's `LIKE` one of `num_patterns` patterns.
+fn get_by_hostname_sql(num_hostnames: usize, num_patterns: usize) -> String {
+    let mut conditions = vec![
+        format!("hostname IN ({})", sql_support::repeat_sql_vars(num_hostnames)),
+    ];
+    for _ in 0..num_patterns {
+        conditions.push("hostname LIKE ? ESCAPE '\\'".to_string());
+    }
+    format!("
+        SELECT {common_cols} FROM loginsL
+        WHERE is_deleted = 0 AND ({conditions})
+        UNION ALL
+        SELECT {common_cols} FROM loginsM
+        WHERE is_overridden = 0 AND ({conditions})",
+        common_cols = schema::COMMON_COLS,
+        conditions = conditions.join(" OR "),
+    )
 }
 
 lazy_static! {
@@ -697,3 +1132,338 @@ lazy_static! {
         &*CLONE_ENTIRE_MIRROR_SQL,
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sync::{KeyBundle, SetupStateMachine};
+    use sync::mock_server::MockSyncServer;
+    use sync::validation;
+
+    fn login(id: &str, password: &str) -> Login {
+        Login {
+            id: id.into(),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com/login".into()),
+            username: "coolperson21".into(),
+            password: password.into(),
+            username_field: "user_input".into(),
+            password_field: "pass_input".into(),
+            .. Login::default()
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let server = MockSyncServer::new();
+        let client = server.client();
+        let root_key = KeyBundle::new_random().unwrap();
+        let state = SetupStateMachine::for_full_sync(&client, &root_key)
+            .to_ready(GlobalState::default())
+            .expect("should get to ready");
+
+        // Logins with the same username would be duplicates.
+        let login = |id: &str, password: &str| Login { username: id.into(), ..login(id, password) };
+        let mut db = LoginDb::open_in_memory(Some("secret")).unwrap();
+        for id in &["aaaaaaaaaaaa", "bbbbbbbbbbbb", "cccccccccccc"] {
+            db.add(login(id, "p4ssw0rd")).unwrap();
+        }
+        let mut telem_engine = telemetry::Engine::new("passwords");
+        sync::synchronize(&client, &state, &mut db, "passwords".into(), ServerTimestamp(0.0),
+                          true, &mut telem_engine).expect("should sync");
+
+        let report = validation::validate(&client, &state, &db, "passwords").unwrap();
+        assert!(report.is_ok(), "Unexpected problems: {:?}", report);
+        assert_eq!(report.server_records, 3);
+        assert_eq!(report.local_records, 3);
+
+        // Make some local changes without syncing them.
+        db.delete("aaaaaaaaaaaa").unwrap();
+        db.update(login("bbbbbbbbbbbb", "hunter2")).unwrap();
+        db.add(login("dddddddddddd", "p4ssw0rd")).unwrap();
+
+        let report = validation::validate(&client, &state, &db, "passwords").unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.missing_locally, vec!["aaaaaaaaaaaa"]);
+        assert_eq!(report.missing_on_server, vec!["dddddddddddd"]);
+        assert_eq!(report.differences.len(), 1);
+        assert_eq!(report.differences[0].id, "bbbbbbbbbbbb");
+        assert!(report.differences[0].fields.contains(&"password".to_string()));
+        assert!(report.server_duplicates.is_empty());
+        assert!(report.local_duplicates.is_empty());
+
+        // Once it's synced, the same login under a different GUID is a
+        // duplicate on the server.
+        db.add(Login { id: "eeeeeeeeeeee".into(), ..login("cccccccccccc", "p4ssw0rd") }).unwrap();
+        let state = SetupStateMachine::for_full_sync(&client, &root_key)
+            .to_ready(state)
+            .expect("should get to ready");
+        let ts = db.get_last_sync().unwrap().unwrap_or_default();
+        sync::synchronize(&client, &state, &mut db, "passwords".into(), ts, true,
+                          &mut telemetry::Engine::new("passwords")).expect("should sync");
+        let state = SetupStateMachine::for_full_sync(&client, &root_key)
+            .to_ready(state)
+            .expect("should get to ready");
+        let report = validation::validate(&client, &state, &db, "passwords").unwrap();
+        assert_eq!(report.server_duplicates, vec![vec!["cccccccccccc", "eeeeeeeeeeee"]]);
+        assert!(report.local_duplicates.is_empty());
+    }
+
+    #[test]
+    fn test_get_by_origin() {
+        let server = MockSyncServer::new();
+        let client = server.client();
+        let root_key = KeyBundle::new_random().unwrap();
+        let state = SetupStateMachine::for_full_sync(&client, &root_key)
+            .to_ready(GlobalState::default())
+            .expect("should get to ready");
+
+        let mut db = LoginDb::open_in_memory(Some("secret")).unwrap();
+        let with_form = |id: &str, hostname: &str, action: &str| Login {
+            hostname: hostname.into(),
+            form_submit_url: Some(action.into()),
+            .. login(id, "p4ssw0rd")
+        };
+        db.add(with_form("https0000000", "https://example.com", "https://example.com/login")).unwrap();
+        db.add(with_form("http00000000", "http://example.com", "http://example.com/login")).unwrap();
+        db.add(with_form("subdomain000", "https://accounts.example.com", "")).unwrap();
+        db.add(with_form("otherdomain0", "https://notexample.com", "https://example.com")).unwrap();
+        db.add(Login {
+            hostname: "https://example.com".into(),
+            form_submit_url: None,
+            http_realm: Some("Secret Area".into()),
+            .. login("httprealm000", "p4ssw0rd")
+        }).unwrap();
+
+        // Sync, so that we look up logins in both the mirror and the local
+        // table.
+        let mut telem_engine = telemetry::Engine::new("passwords");
+        sync::synchronize(&client, &state, &mut db, "passwords".into(), ServerTimestamp(0.0),
+                          true, &mut telem_engine).expect("should sync");
+        db.add(with_form("unsynced0000", "https://example.com", "https://login.example.com")).unwrap();
+
+        let ids = |logins: Vec<Login>| {
+            let mut ids = logins.into_iter().map(|login| login.id).collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+        let default = LookupOptions::default();
+        let all = LookupOptions { include_subdomains: true, allow_http_upgrade: true };
+
+        assert_eq!(ids(db.get_by_origin("https://example.com/path?q=1", default).unwrap()),
+                   vec!["httprealm000", "https0000000", "unsynced0000"]);
+        assert_eq!(ids(db.get_by_origin("https://EXAMPLE.com:443", all).unwrap()),
+                   vec!["http00000000", "httprealm000", "https0000000", "subdomain000",
+                        "unsynced0000"]);
+        assert_eq!(ids(db.get_by_origin("http://example.com", all).unwrap()),
+                   vec!["http00000000"]);
+        assert!(db.get_by_origin("not a url", all).unwrap().is_empty());
+
+        assert_eq!(ids(db.get_by_form_action_origin(
+                       "https://example.com", "https://example.com", default).unwrap()),
+                   vec!["https0000000"]);
+        // Logins with an empty form action match any form.
+        assert_eq!(ids(db.get_by_form_action_origin(
+                       "https://example.com", "https://example.com", all).unwrap()),
+                   vec!["http00000000", "https0000000", "subdomain000"]);
+        assert_eq!(ids(db.get_by_form_action_origin(
+                       "https://example.com", "https://login.example.com", default).unwrap()),
+                   vec!["unsynced0000"]);
+
+        assert_eq!(ids(db.get_by_http_realm("https://example.com", "Secret Area", default).unwrap()),
+                   vec!["httprealm000"]);
+        assert!(db.get_by_http_realm("https://example.com", "Other Area", all).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_get_by_origin_uses_indexes() {
+        let db = LoginDb::open_in_memory(Some("secret")).unwrap();
+        let query = format!("EXPLAIN QUERY PLAN {}", get_by_hostname_sql(2, 0));
+        let mut stmt = db.prepare(&query).unwrap();
+        let params: [&ToSql; 4] = [&"https://example.com", &"http://example.com",
+                                   &"https://example.com", &"http://example.com"];
+        let details = stmt.query_map(&params, |row| row.get::<_, String>(3))
+            .unwrap()
+            .collect::<::std::result::Result<Vec<_>, _>>()
+            .unwrap();
+        let plan = details.join("\n");
+        assert!(plan.contains("idx_loginsL_is_deleted_hostname"), "Unexpected plan: {}", plan);
+        assert!(plan.contains("idx_loginsM_is_overridden_hostname"), "Unexpected plan: {}", plan);
+    }
+
+    #[test]
+    fn test_import() {
+        let server = MockSyncServer::new();
+        let client = server.client();
+        let root_key = KeyBundle::new_random().unwrap();
+        let state = SetupStateMachine::for_full_sync(&client, &root_key)
+            .to_ready(GlobalState::default())
+            .expect("should get to ready");
+
+        let mut db = LoginDb::open_in_memory(Some("secret")).unwrap();
+        db.add(login("syncedlogin0", "p4ssw0rd")).unwrap();
+        let mut telem_engine = telemetry::Engine::new("passwords");
+        sync::synchronize(&client, &state, &mut db, "passwords".into(), ServerTimestamp(0.0),
+                          true, &mut telem_engine).expect("should sync");
+        db.add(Login { username: "local".into(), .. login("locallogin00", "p4ssw0rd") }).unwrap();
+
+        let imported = |id: &str, username: &str| Login {
+            username: username.into(),
+            time_created: 1_500_000_000_000,
+            times_used: 5,
+            .. login(id, "hunter2")
+        };
+        let summary = db.import(vec![
+            // Already synced, but with a different GUID.
+            Ok(imported("importdupe00", "coolperson21")),
+            // Already added locally.
+            Ok(imported("locallogin00", "local")),
+            Ok(imported("importnew000", "new")),
+            // Duplicates the login we just imported.
+            Ok(imported("importnew001", "new")),
+            // Has a GUID that we already use.
+            Ok(imported("syncedlogin0", "guidclash")),
+            Ok(Login { hostname: "".into(), .. imported("invalid00000", "invalid") }),
+            Err(ErrorKind::ImportError("Couldn't parse".into()).into()),
+        ]).unwrap();
+        assert_eq!(summary.num_imported, 2);
+        assert_eq!(summary.num_skipped, 3);
+        assert_eq!(summary.num_failed, 2);
+        assert_eq!(summary.failures.iter().map(|f| f.index).collect::<Vec<_>>(), vec![5, 6]);
+
+        let new = db.get_by_id("importnew000").unwrap().expect("should import");
+        assert_eq!(new.password, "hunter2");
+        assert_eq!(new.time_created, 1_500_000_000_000);
+        assert_eq!(new.time_password_changed, 1_500_000_000_000);
+        assert_eq!(new.times_used, 5);
+
+        let logins = db.get_all().unwrap();
+        assert_eq!(logins.len(), 4);
+        let clash = logins.iter().find(|l| l.username == "guidclash").expect("should import");
+        assert_ne!(clash.id, "syncedlogin0");
+
+        // Imported logins should be uploaded on the next sync.
+        let outgoing = db.fetch_outgoing(ServerTimestamp(0.0)).unwrap();
+        let mut outgoing_ids = outgoing.changes.iter().map(|p| p.id.clone()).collect::<Vec<_>>();
+        outgoing_ids.sort();
+        let mut expected = vec!["locallogin00".to_string(), "importnew000".into(), clash.id.clone()];
+        expected.sort();
+        assert_eq!(outgoing_ids, expected);
+    }
+
+    #[test]
+    fn test_merge_duplicates() {
+        let db = LoginDb::open_in_memory(Some("secret")).unwrap();
+        let old = Login {
+            time_created: 1000,
+            time_password_changed: 1000,
+            time_last_used: 5000,
+            times_used: 3,
+            .. login("old000000000", "0ld-p4ssw0rd")
+        };
+        let new = Login {
+            hostname: "https://www.example.com:443/".into(),
+            form_submit_url: Some("https://accounts.example.com".into()),
+            time_created: 2000,
+            time_password_changed: 3000,
+            time_last_used: 4000,
+            times_used: 2,
+            .. login("new000000000", "n3w-p4ssw0rd")
+        };
+        let other = Login {
+            username: "someoneelse".into(),
+            .. login("other0000000", "p4ssw0rd")
+        };
+        let summary = db.import(vec![Ok(old.clone()), Ok(new.clone()), Ok(other.clone())]).unwrap();
+        assert_eq!(summary.num_imported, 3);
+
+        let duplicates = db.find_duplicates().unwrap();
+        assert_eq!(duplicates.len(), 1);
+        let ids: Vec<&str> = duplicates[0].iter().map(|l| l.id.as_str()).collect();
+        assert_eq!(ids, vec!["new000000000", "old000000000"]);
+
+        match db.merge_duplicates(&["old000000000", "other0000000"]).unwrap_err().kind() {
+            ErrorKind::NotDuplicates(_) => {}
+            e => panic!("Unexpected error {:?}", e),
+        }
+        assert!(db.merge_duplicates(&["old000000000"]).is_err());
+        assert!(db.exists("old000000000").unwrap());
+
+        let merged = db.merge_duplicates(&ids).unwrap();
+        assert_eq!(merged, Login {
+            time_created: 1000,
+            time_last_used: 5000,
+            times_used: 5,
+            .. new
+        });
+        assert_eq!(db.get_by_id("new000000000").unwrap(), Some(merged));
+        assert!(!db.exists("old000000000").unwrap());
+        assert!(db.exists("other0000000").unwrap());
+        assert!(db.find_duplicates().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_key_to_key() {
+        let key = |hostname: &str, username: &str, http_realm: Option<&str>| {
+            DuplicateKey::new(&Login {
+                hostname: hostname.into(),
+                username: username.into(),
+                http_realm: http_realm.map(|realm| realm.into()),
+                .. Login::default()
+            }).to_key()
+        };
+        assert_eq!(key("https://example.com:443/", "user", None),
+                   "19:https://example.com4:user-");
+        assert_eq!(key("https://example.com", "user", Some("Realm")),
+                   "19:https://example.com4:user5:Realm");
+        // Parts can't run into each other, and an empty realm isn't a
+        // missing one.
+        assert_ne!(key("https://example.com", "a:1", Some("b")),
+                   key("https://example.com", "a", Some("1:b")));
+        assert_ne!(key("https://example.com", "user", Some("")),
+                   key("https://example.com", "user", None));
+    }
+
+    // Adds `count` local logins to `db`, and returns twice as many incoming
+    // logins with new GUIDs, half of which are dupes of the local ones.
+    fn dupe_test_logins(db: &LoginDb, count: usize) -> Vec<Login> {
+        let mut incoming = Vec::with_capacity(count * 2);
+        for i in 0..count {
+            let hostname = format!("https://{}.example.com", i % (count / 4 + 1));
+            let form_submit_url = match i % 3 {
+                0 => Some(format!("{}/login", hostname)),
+                1 => Some("".to_string()),
+                _ => None,
+            };
+            let local = Login {
+                id: format!("local{:07}", i),
+                hostname: hostname.clone(),
+                http_realm: form_submit_url.as_ref().map_or(Some("Realm".into()), |_| None),
+                form_submit_url,
+                username: format!("user{}", i),
+                .. login("", "p4ssw0rd")
+            };
+            db.add(local.clone()).unwrap();
+            incoming.push(Login { id: format!("dupe{:08}", i), .. local.clone() });
+            incoming.push(Login {
+                id: format!("new{:09}", i),
+                username: format!("other{}", i),
+                .. local
+            });
+        }
+        incoming
+    }
+
+    #[test]
+    fn test_find_dupes() {
+        let db = LoginDb::open_in_memory(Some("secret")).unwrap();
+        let incoming = dupe_test_logins(&db, 50);
+        let dupes = db.find_dupes(&incoming).unwrap();
+        // Logins with an empty `formSubmitURL` aren't dupes of themselves.
+        assert_eq!(dupes.len(), (0..50).filter(|i| i % 3 != 1).count());
+        for login in &incoming {
+            assert_eq!(dupes.get(&login.id), db.find_dupe(login).unwrap().as_ref(),
+                       "Wrong dupe for {}", login.id);
+        }
+    }
+}
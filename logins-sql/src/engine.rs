@@ -3,9 +3,17 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 use login::Login;
 use error::*;
-use sync::{self, Sync15StorageClient, Sync15StorageClientInit, GlobalState, KeyBundle};
+use sync::{self, Sync15StorageClient, Sync15StorageClientInit, GlobalState, KeyBundle,
+           SetupStateMachine, SyncEngine, CachedToken};
+use sync::validation::{self, ValidationReport};
 use db::LoginDb;
+use lookup::LookupOptions;
+use import::{self, ImportSummary};
+use export;
+use backup;
 use std::path::Path;
+use std::io::{Read, Write};
+use std::time::SystemTime;
 use serde_json;
 use rusqlite;
 
@@ -22,7 +30,7 @@ pub(crate) struct SyncInfo {
 // state, and the login DB.
 pub struct PasswordEngine {
     sync: Option<SyncInfo>,
-    db: LoginDb,
+    pub(crate) db: LoginDb,
 }
 
 impl PasswordEngine {
@@ -45,6 +53,34 @@ impl PasswordEngine {
         self.db.get_by_id(id)
     }
 
+    /// Returns the logins to offer on a page from `origin`, for autofill.
+    /// `origin` can be any URL on the page's site.
+    pub fn get_by_origin(&self, origin: &str, options: LookupOptions) -> Result<Vec<Login>> {
+        self.db.get_by_origin(origin, options)
+    }
+
+    /// Like `get_by_origin`, but only returns logins for forms that submit
+    /// to `form_action_origin`.
+    pub fn get_by_form_action_origin(
+        &self,
+        origin: &str,
+        form_action_origin: &str,
+        options: LookupOptions
+    ) -> Result<Vec<Login>> {
+        self.db.get_by_form_action_origin(origin, form_action_origin, options)
+    }
+
+    /// Like `get_by_origin`, but only returns HTTP auth logins for
+    /// `http_realm`.
+    pub fn get_by_http_realm(
+        &self,
+        origin: &str,
+        http_realm: &str,
+        options: LookupOptions
+    ) -> Result<Vec<Login>> {
+        self.db.get_by_http_realm(origin, http_realm, options)
+    }
+
     pub fn touch(&self, id: &str) -> Result<()> {
         self.db.touch(id)
     }
@@ -70,22 +106,124 @@ impl PasswordEngine {
         self.db.add(login).map(|record| record.id)
     }
 
+    /// Returns groups of logins that look like duplicates. See
+    /// `LoginDb::find_duplicates`.
+    pub fn find_duplicates(&self) -> Result<Vec<Vec<Login>>> {
+        self.db.find_duplicates()
+    }
+
+    /// Merges a group of duplicates into one login, deleting the others.
+    /// See `LoginDb::merge_duplicates`.
+    pub fn merge_duplicates(&self, ids: &[&str]) -> Result<Login> {
+        self.db.merge_duplicates(ids)
+    }
+
+    /// Adds logins from another password manager, skipping ones we already
+    /// have. See `LoginDb::import`.
+    pub fn import(&self, logins: Vec<Login>) -> Result<ImportSummary> {
+        self.db.import(logins.into_iter().map(Ok).collect())
+    }
+
+    /// Imports logins from a CSV file, like the ones Firefox and Chrome
+    /// export. Rows we can't parse are counted as failures.
+    pub fn import_csv(&self, reader: impl Read) -> Result<ImportSummary> {
+        self.db.import(import::logins_from_csv(reader)?)
+    }
+
+    /// Imports logins from a Firefox desktop profile's `logins.json`. `key`
+    /// is the decrypted key from the profile's `key4.db`, which we don't
+    /// read ourselves.
+    pub fn import_desktop_logins(&self, logins_json: &str, key: &[u8]) -> Result<ImportSummary> {
+        self.db.import(import::logins_from_desktop_json(logins_json, key)?)
+    }
+
+    /// Writes all our logins to `writer` as CSV, in the same format as
+    /// desktop Firefox. The passwords aren't encrypted.
+    pub fn export_csv(&self, writer: impl Write) -> Result<()> {
+        export::logins_to_csv(&self.db.get_all()?, writer)
+    }
+
+    /// Returns a backup of all our logins, encrypted with `passphrase`,
+    /// which can be restored with `import_backup`.
+    pub fn export_backup(&self, passphrase: &str) -> Result<String> {
+        backup::logins_to_backup(&self.db.get_all()?, passphrase)
+    }
+
+    /// Imports the logins from a backup made by `export_backup`, keeping
+    /// their GUIDs and timestamps.
+    pub fn import_backup(&self, data: &str, passphrase: &str) -> Result<ImportSummary> {
+        self.db.import(backup::logins_from_backup(data, passphrase)?)
+    }
+
     // This is basiclaly exposed just for sync_pass_sql, but it doesn't seem
     // unreasonable.
     pub fn conn(&self) -> &rusqlite::Connection {
         &self.db.db
     }
 
+    /// Returns the earliest time the next sync should start, if the server
+    /// asked us to back off. `sync` fails with a backoff error if it's called
+    /// before then.
+    pub fn next_sync_after(&self) -> Result<Option<SystemTime>> {
+        if let Some(sync_info) = &self.sync {
+            return Ok(sync_info.state.next_sync_after);
+        }
+        Ok(self.db.get_global_state()?
+            .and_then(|persisted| serde_json::from_str::<GlobalState>(&persisted).ok())
+            .and_then(|state| state.next_sync_after))
+    }
+
+    /// Compares the logins on the server with our local logins, to help
+    /// figure out why logins are missing. Doesn't change anything locally
+    /// or on the server.
+    pub fn validate(
+        &self,
+        storage_init: &Sync15StorageClientInit,
+        root_sync_key: &KeyBundle
+    ) -> Result<ValidationReport> {
+        let client = self.new_client(storage_init)?;
+        let state = match &self.sync {
+            Some(sync_info) => sync_info.state.clone(),
+            None => self.db.get_global_state()?
+                .and_then(|persisted| serde_json::from_str::<GlobalState>(&persisted).ok())
+                .unwrap_or_default(),
+        };
+        // Validation shouldn't upload anything, even if the server needs a
+        // fresh start.
+        let state = SetupStateMachine::for_readonly_sync(&client, root_sync_key)
+            .to_ready(state)?;
+        validation::validate(&client, &state, &self.db, "passwords")
+    }
+
+    // Makes a storage client that reuses the tokenserver token from our last
+    // sync, if it's still valid.
+    fn new_client(&self, storage_init: &Sync15StorageClientInit) -> Result<Sync15StorageClient> {
+        let client = Sync15StorageClient::new(storage_init.clone())?;
+        if let Some(persisted) = self.db.get_token_cache()? {
+            match CachedToken::from_json(&persisted) {
+                Ok(cached) => {
+                    if client.restore_cached_token(cached)? {
+                        info!("Using the persisted tokenserver token");
+                    }
+                }
+                // As with the global state, the JSON contains keys, so don't
+                // log the error.
+                Err(_) => error!("Failed to parse the persisted tokenserver token! Ignoring it"),
+            }
+        }
+        Ok(client)
+    }
+
     pub fn sync(
         &mut self,
         storage_init: &Sync15StorageClientInit,
         root_sync_key: &KeyBundle
     ) -> Result<()> {
 
-        // Note: If `to_ready` (or anything else with a ?) fails below, this
-        // `take()` means we end up with `state.sync.is_none()`, which means the
-        // next sync will redownload meta/global, crypto/keys, etc. without
-        // needing to. Apparently this is both okay and by design.
+        // Note: If anything with a ? fails below, this `take()` means we end
+        // up with `state.sync.is_none()`, which means the next sync will
+        // redownload meta/global, crypto/keys, etc. without needing to.
+        // Apparently this is both okay and by design.
         let maybe_sync_info = self.sync.take().map(Ok);
 
         // `maybe_sync_info` is None if we haven't called `sync` since
@@ -109,7 +247,7 @@ impl PasswordEngine {
                 info!("No previously persisted global state, using default");
                 GlobalState::default()
             };
-            let client = Sync15StorageClient::new(storage_init.clone())?;
+            let client = self.new_client(storage_init)?;
             Ok(SyncInfo {
                 state,
                 client,
@@ -130,56 +268,60 @@ impl PasswordEngine {
         // re-initialize the sync state).
         if storage_init != &sync_info.last_client_init {
             info!("Detected change in storage client init, updating");
-            sync_info.client = Sync15StorageClient::new(storage_init.clone())?;
+            sync_info.client = self.new_client(storage_init)?;
             sync_info.last_client_init = storage_init.clone();
         }
 
-        // Advance the state machine to the point where it can perform a full
-        // sync. This may involve uploading meta/global, crypto/keys etc.
-        {
-            // Scope borrow of `sync_info.client`
-            let mut state_machine =
-                sync::SetupStateMachine::for_full_sync(&sync_info.client, &root_sync_key);
-            info!("Advancing state machine to ready (full)");
-            let next_sync_state = state_machine.to_ready(sync_info.state)?;
-            sync_info.state = next_sync_state;
-        }
-
-        // Reset our local state if necessary.
-        if sync_info.state.engines_that_need_local_reset().contains("passwords") {
-            info!("Passwords sync ID changed; engine needs local reset");
-            self.db.reset()?;
-        }
+        // Advance the state machine to ready, reset our local state if
+        // necessary, and sync. `sync_multiple` refuses to sync if the server
+        // asked us to back off, and always hands the state back, so that we
+        // can persist any new backoff.
+        let mut telem = sync::telemetry::SyncTelemetry::new();
+        let result = {
+            let mut engines: [&mut SyncEngine; 1] = [&mut self.db];
+            sync::sync_multiple(
+                &mut engines,
+                sync_info.state,
+                &sync_info.client,
+                root_sync_key,
+                &mut telem
+            )
+        };
+        // Nothing submits the telemetry yet, so just log it.
+        info!("Sync telemetry: {}", serde_json::to_string(&telem)?);
 
         // Persist the current sync state in the DB.
         info!("Updating persisted global state");
-        let s = sync_info.state.to_persistable_string();
+        let s = result.global_state.to_persistable_string();
         self.db.set_global_state(&s)?;
-
-        info!("Syncing passwords engine!");
-
-        let ts = self.db.get_last_sync()?.unwrap_or_default();
-
-        // We don't use `?` here so that we can restore the value of of
-        // `self.sync` even if sync fails.
-        let result = sync::synchronize(
-            &sync_info.client,
-            &sync_info.state,
-            &mut self.db,
-            "passwords".into(),
-            ts,
-            true
-        );
-
-        match &result {
-            Ok(()) => info!("Sync was successful!"),
-            Err(e) => warn!("Sync failed! {:?}", e),
+        if let Some(token) = sync_info.client.cached_token() {
+            self.db.set_token_cache(&token.to_json()?)?;
         }
 
         // Restore our value of `sync_info` even if the sync failed.
-        self.sync = Some(sync_info);
-
-        Ok(result?)
+        let sync::SyncResult { service_result, global_state, engine_results } = result;
+        self.sync = Some(SyncInfo {
+            state: global_state,
+            client: sync_info.client,
+            last_client_init: sync_info.last_client_init,
+        });
+        service_result?;
+
+        match engine_results.into_iter().next().map(|r| r.result) {
+            Some(Err(e)) => {
+                // Our engine usually fails with one of our errors, but can
+                // also pass along errors from the sync adapter.
+                let e = match e.downcast::<Error>() {
+                    Ok(e) => return Err(e),
+                    Err(e) => e,
+                };
+                Err(match e.downcast::<sync::Error>() {
+                    Ok(e) => ErrorKind::SyncAdapterError(e).into(),
+                    Err(e) => ErrorKind::EngineError(e).into(),
+                })
+            },
+            _ => Ok(()),
+        }
     }
 }
 
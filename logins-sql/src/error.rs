@@ -2,13 +2,15 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use failure::{Fail, Context, Backtrace};
+use failure::{self, Fail, Context, Backtrace};
 use std::{self, fmt};
 use std::boxed::Box;
 use rusqlite;
 use serde_json;
 use sync;
 use url;
+use csv;
+use openssl;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -79,9 +81,16 @@ pub enum ErrorKind {
     #[fail(display = "No record with guid exists (when one was required): {:?}", _0)]
     NoSuchRecord(String),
 
+    #[fail(display = "Logins aren't duplicates of each other: {:?}", _0)]
+    NotDuplicates(Vec<String>),
+
     #[fail(display = "Error synchronizing: {}", _0)]
     SyncAdapterError(#[fail(cause)] sync::Error),
 
+    // `failure::Error` doesn't implement `Fail`, so this can't be a cause.
+    #[fail(display = "Error syncing passwords: {}", _0)]
+    EngineError(failure::Error),
+
     #[fail(display = "Error parsing JSON data: {}", _0)]
     JsonError(#[fail(cause)] serde_json::Error),
 
@@ -90,6 +99,21 @@ pub enum ErrorKind {
 
     #[fail(display = "Error parsing URL: {}", _0)]
     UrlParseError(#[fail(cause)] url::ParseError),
+
+    #[fail(display = "Error reading CSV: {}", _0)]
+    CsvError(#[fail(cause)] csv::Error),
+
+    #[fail(display = "Couldn't import logins: {}", _0)]
+    ImportError(String),
+
+    #[fail(display = "Invalid backup: {}", _0)]
+    InvalidBackup(String),
+
+    #[fail(display = "Couldn't decrypt backup; the passphrase is wrong, or the backup is corrupt")]
+    WrongPassphrase,
+
+    #[fail(display = "Crypto error: {}", _0)]
+    OpensslError(#[fail(cause)] openssl::error::ErrorStack),
 }
 
 macro_rules! impl_from_error {
@@ -115,6 +139,8 @@ impl_from_error! {
     (JsonError, serde_json::Error),
     (UrlParseError, url::ParseError),
     (SqlError, rusqlite::Error),
+    (CsvError, csv::Error),
+    (OpensslError, openssl::error::ErrorStack),
     (InvalidLogin, InvalidLogin)
 }
 
@@ -0,0 +1,90 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Exporting logins as CSV, in the same format as desktop Firefox, so that
+//! other password managers (and `import::logins_from_csv`) can read them.
+
+use std::io::Write;
+use csv;
+use error::*;
+use login::Login;
+
+const CSV_HEADER: &[&str] = &[
+    "url",
+    "username",
+    "password",
+    "httpRealm",
+    "formActionOrigin",
+    "guid",
+    "timeCreated",
+    "timeLastUsed",
+    "timePasswordChanged",
+];
+
+/// Writes `logins` to `writer` as CSV. The output contains passwords in
+/// plain text, so callers should make that clear to the user.
+pub fn logins_to_csv<W: Write>(logins: &[Login], writer: W) -> Result<()> {
+    // Desktop quotes every field, so we do too.
+    let mut writer = csv::WriterBuilder::new()
+        .quote_style(csv::QuoteStyle::Always)
+        .from_writer(writer);
+    writer.write_record(CSV_HEADER)?;
+    for login in logins {
+        writer.write_record(&[
+            login.hostname.as_str(),
+            login.username.as_str(),
+            login.password.as_str(),
+            login.http_realm.as_ref().map_or("", |s| s.as_str()),
+            login.form_submit_url.as_ref().map_or("", |s| s.as_str()),
+            login.id.as_str(),
+            login.time_created.to_string().as_str(),
+            login.time_last_used.to_string().as_str(),
+            login.time_password_changed.to_string().as_str(),
+        ])?;
+    }
+    writer.flush().map_err(csv::Error::from)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use import;
+
+    #[test]
+    fn test_csv_round_trip() {
+        let logins = vec![Login {
+            id: "{guid-1}".into(),
+            hostname: "https://example.com".into(),
+            form_submit_url: Some("https://example.com".into()),
+            username: "alice".into(),
+            password: "p4ss,\"w0rd\"".into(),
+            time_created: 1,
+            time_last_used: 2,
+            time_password_changed: 3,
+            .. Login::default()
+        }, Login {
+            id: "{guid-2}".into(),
+            hostname: "https://example.com".into(),
+            http_realm: Some("My Realm".into()),
+            password: "hunter2".into(),
+            time_created: 4,
+            time_last_used: 5,
+            time_password_changed: 6,
+            .. Login::default()
+        }];
+        let mut csv = Vec::new();
+        logins_to_csv(&logins, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().next().unwrap(),
+                   "\"url\",\"username\",\"password\",\"httpRealm\",\"formActionOrigin\",\
+                    \"guid\",\"timeCreated\",\"timeLastUsed\",\"timePasswordChanged\"");
+
+        let imported = import::logins_from_csv(csv.as_bytes()).unwrap()
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(imported, logins);
+    }
+}
@@ -0,0 +1,391 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Parsing logins exported from other password managers, so that
+//! `LoginDb::import` can add them. We support:
+//!
+//! - CSV files with a header row, like the ones Firefox and Chrome export.
+//!   We only need the `url` (or `hostname` or `origin`) and `password`
+//!   columns, and use any others we recognize.
+//! - A Firefox desktop profile's `logins.json`. The usernames and passwords
+//!   in these are encrypted with a key from the profile's `key4.db` (or
+//!   `key3.db`), which the caller needs to decrypt for us.
+
+use std::collections::HashMap;
+use std::io::Read;
+use base64;
+use csv;
+use openssl::symm::{self, Cipher};
+use serde_json;
+use url::Url;
+use error::*;
+use login::Login;
+
+/// The result of importing a batch of logins.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ImportSummary {
+    /// The number of logins we added.
+    pub num_imported: usize,
+    /// The number of logins we skipped because we already had them.
+    pub num_skipped: usize,
+    /// The number of logins we couldn't parse, or that were invalid.
+    pub num_failed: usize,
+    /// Why each failed login failed. These don't include the logins, since
+    /// they're probably sensitive.
+    pub failures: Vec<ImportFailure>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ImportFailure {
+    /// The login's position in the import, starting at 0. For CSV files,
+    /// this doesn't count the header row.
+    pub index: usize,
+    pub reason: String,
+}
+
+// Returns the origin of `url`, which is how we store hostnames. Chrome
+// exports full URLs, with paths.
+fn origin(url: &str) -> Result<String> {
+    let origin = Url::parse(url)?.origin();
+    if !origin.is_tuple() {
+        throw!(ErrorKind::ImportError(format!("URL has no origin: {}", url)));
+    }
+    Ok(origin.ascii_serialization())
+}
+
+// The columns we understand, and the names they go by in different exports.
+const CSV_COLUMNS: &[(&str, &[&str])] = &[
+    ("hostname", &["url", "hostname", "origin"]),
+    ("username", &["username"]),
+    ("password", &["password"]),
+    ("httpRealm", &["httprealm"]),
+    ("formSubmitURL", &["formactionorigin", "formsubmiturl"]),
+    ("guid", &["guid"]),
+    ("timeCreated", &["timecreated"]),
+    ("timeLastUsed", &["timelastused"]),
+    ("timePasswordChanged", &["timepasswordchanged"]),
+];
+
+fn login_from_csv_record(
+    columns: &HashMap<&str, usize>,
+    record: &csv::StringRecord,
+) -> Result<Login> {
+    let field = |name: &str| -> Option<&str> {
+        columns.get(name)
+            .and_then(|&index| record.get(index))
+            .filter(|value| !value.is_empty())
+    };
+    let time = |name: &str| -> Result<i64> {
+        match field(name) {
+            Some(value) => value.parse::<i64>().map_err(|_| {
+                Error::from(ErrorKind::ImportError(format!("Invalid {}", name)))
+            }),
+            None => Ok(0),
+        }
+    };
+    let hostname = origin(field("hostname").unwrap_or_default())?;
+    let http_realm = field("httpRealm").map(|s| s.to_string());
+    let form_submit_url = match field("formSubmitURL") {
+        Some(url) => Some(origin(url)?),
+        // Logins need either a realm or a form, and exports that don't
+        // distinguish them are for forms on the login's own site.
+        None if http_realm.is_none() => Some(hostname.clone()),
+        None => None,
+    };
+    Ok(Login {
+        id: field("guid").unwrap_or_default().to_string(),
+        hostname,
+        form_submit_url,
+        http_realm,
+        username: field("username").unwrap_or_default().to_string(),
+        password: field("password").unwrap_or_default().to_string(),
+        time_created: time("timeCreated")?,
+        time_last_used: time("timeLastUsed")?,
+        time_password_changed: time("timePasswordChanged")?,
+        .. Login::default()
+    })
+}
+
+/// Parses a CSV export. Fails if the file isn't CSV, or doesn't have the
+/// columns we need; otherwise returns the result of parsing each row.
+pub fn logins_from_csv<R: Read>(reader: R) -> Result<Vec<Result<Login>>> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(reader);
+    let mut columns = HashMap::new();
+    for (index, header) in reader.headers()?.iter().enumerate() {
+        let header = header.trim().to_lowercase();
+        for &(name, aliases) in CSV_COLUMNS {
+            if aliases.contains(&header.as_str()) && !columns.contains_key(name) {
+                columns.insert(name, index);
+            }
+        }
+    }
+    for name in &["hostname", "password"] {
+        if !columns.contains_key(name) {
+            throw!(ErrorKind::ImportError(format!("CSV file has no {} column", name)));
+        }
+    }
+    Ok(reader.records().map(|record| {
+        login_from_csv_record(&columns, &record?)
+    }).collect())
+}
+
+// The OIDs of the ciphers that NSS uses to encrypt logins.
+const OID_DES_EDE3_CBC: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x03, 0x07];
+const OID_AES_256_CBC: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2a];
+
+const DER_SEQUENCE: u8 = 0x30;
+const DER_OCTET_STRING: u8 = 0x04;
+const DER_OID: u8 = 0x06;
+
+fn der_error() -> Error {
+    ErrorKind::ImportError("Malformed encrypted field".into()).into()
+}
+
+// Reads a DER value with the tag `expected`, returning its contents and the
+// rest of `data`. This only handles what NSS produces.
+fn read_der(data: &[u8], expected: u8) -> Result<(&[u8], &[u8])> {
+    if data.len() < 2 || data[0] != expected {
+        return Err(der_error());
+    }
+    let (len, header_len) = match data[1] {
+        len @ 0...0x7f => (len as usize, 2),
+        0x81...0x84 => {
+            let num_bytes = (data[1] & 0x7f) as usize;
+            if data.len() < 2 + num_bytes {
+                return Err(der_error());
+            }
+            let len = data[2..2 + num_bytes].iter().fold(0usize, |len, &b| (len << 8) | b as usize);
+            (len, 2 + num_bytes)
+        }
+        _ => return Err(der_error()),
+    };
+    if data.len() - header_len < len {
+        return Err(der_error());
+    }
+    Ok((&data[header_len..header_len + len], &data[header_len + len..]))
+}
+
+/// Decrypts a field encrypted with NSS's secret decoder ring, like
+/// `encryptedUsername` and `encryptedPassword` in `logins.json`. `key` is
+/// the profile's decrypted 3DES or AES-256 key.
+pub fn decrypt_desktop_field(encrypted: &str, key: &[u8]) -> Result<String> {
+    let data = base64::decode(encrypted).map_err(|_| der_error())?;
+    // SEQUENCE { OCTET STRING key_id, SEQUENCE { OID cipher, OCTET STRING iv },
+    //            OCTET STRING ciphertext }
+    let (contents, _) = read_der(&data, DER_SEQUENCE)?;
+    let (_key_id, rest) = read_der(contents, DER_OCTET_STRING)?;
+    let (algorithm, rest) = read_der(rest, DER_SEQUENCE)?;
+    let (ciphertext, _) = read_der(rest, DER_OCTET_STRING)?;
+    let (oid, algorithm) = read_der(algorithm, DER_OID)?;
+    let (iv, _) = read_der(algorithm, DER_OCTET_STRING)?;
+    let cipher = match oid {
+        OID_DES_EDE3_CBC => Cipher::des_ede3_cbc(),
+        OID_AES_256_CBC => Cipher::aes_256_cbc(),
+        _ => return Err(ErrorKind::ImportError("Unsupported cipher".into()).into()),
+    };
+    if key.len() != cipher.key_len() {
+        throw!(ErrorKind::ImportError(format!(
+            "Key has the wrong length for the cipher; got {}, expected {}",
+            key.len(), cipher.key_len())));
+    }
+    let plaintext = symm::decrypt(cipher, key, Some(iv), ciphertext).map_err(|_| {
+        Error::from(ErrorKind::ImportError("Couldn't decrypt field; is the key right?".into()))
+    })?;
+    String::from_utf8(plaintext).map_err(|_| {
+        Error::from(ErrorKind::ImportError("Decrypted field isn't UTF-8".into()))
+    })
+}
+
+// A login from a desktop `logins.json`. We ignore the fields we don't use.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DesktopLogin {
+    hostname: String,
+    #[serde(default)]
+    http_realm: Option<String>,
+    #[serde(rename = "formSubmitURL")]
+    #[serde(default)]
+    form_submit_url: Option<String>,
+    #[serde(default)]
+    username_field: String,
+    #[serde(default)]
+    password_field: String,
+    encrypted_username: String,
+    encrypted_password: String,
+    #[serde(default)]
+    guid: Option<String>,
+    // 1 if the username and password are encrypted. 0 means they're only
+    // base64 encoded, which very old profiles did.
+    #[serde(default)]
+    enc_type: u8,
+    #[serde(default)]
+    time_created: i64,
+    #[serde(default)]
+    time_last_used: i64,
+    #[serde(default)]
+    time_password_changed: i64,
+    #[serde(default)]
+    times_used: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct DesktopLoginsFile {
+    logins: Vec<serde_json::Value>,
+}
+
+impl DesktopLogin {
+    fn decrypt(&self, field: &str, key: &[u8]) -> Result<String> {
+        if self.enc_type == 0 {
+            let bytes = base64::decode(field).map_err(|_| der_error())?;
+            return String::from_utf8(bytes).map_err(|_| {
+                Error::from(ErrorKind::ImportError("Field isn't UTF-8".into()))
+            });
+        }
+        decrypt_desktop_field(field, key)
+    }
+
+    fn into_login(self, key: &[u8]) -> Result<Login> {
+        Ok(Login {
+            id: self.guid.clone().unwrap_or_default(),
+            username: self.decrypt(&self.encrypted_username, key)?,
+            password: self.decrypt(&self.encrypted_password, key)?,
+            hostname: self.hostname,
+            http_realm: self.http_realm,
+            form_submit_url: self.form_submit_url,
+            username_field: self.username_field,
+            password_field: self.password_field,
+            time_created: self.time_created,
+            time_last_used: self.time_last_used,
+            time_password_changed: self.time_password_changed,
+            times_used: self.times_used,
+        })
+    }
+}
+
+/// Parses a desktop profile's `logins.json`, decrypting the usernames and
+/// passwords with `key`. Fails if the file isn't valid; otherwise returns
+/// the result of parsing each login.
+pub fn logins_from_desktop_json(json: &str, key: &[u8]) -> Result<Vec<Result<Login>>> {
+    let file: DesktopLoginsFile = serde_json::from_str(json)?;
+    Ok(file.logins.into_iter().map(|value| {
+        let login: DesktopLogin = serde_json::from_value(value)?;
+        login.into_login(key)
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Encrypts `plaintext` the way NSS does.
+    fn encrypt_desktop_field(plaintext: &str, key: &[u8], iv: &[u8]) -> String {
+        let ciphertext = symm::encrypt(Cipher::des_ede3_cbc(), key, Some(iv),
+                                       plaintext.as_bytes()).unwrap();
+        let der = |tag: u8, contents: &[u8]| -> Vec<u8> {
+            assert!(contents.len() < 0x80);
+            let mut value = vec![tag, contents.len() as u8];
+            value.extend_from_slice(contents);
+            value
+        };
+        let algorithm = [der(DER_OID, OID_DES_EDE3_CBC), der(DER_OCTET_STRING, iv)].concat();
+        let contents = [
+            der(DER_OCTET_STRING, &[0xf8; 16]),
+            der(DER_SEQUENCE, &algorithm),
+            der(DER_OCTET_STRING, &ciphertext),
+        ].concat();
+        base64::encode(&der(DER_SEQUENCE, &contents))
+    }
+
+    #[test]
+    fn test_csv() {
+        let csv = "\
+\"url\",\"username\",\"password\",\"httpRealm\",\"formActionOrigin\",\"guid\",\"timeCreated\"
+\"https://example.com\",\"alice\",\"p4ss,w0rd\",,\"https://example.com\",\"{guid-1}\",\"1500000000000\"
+\"https://example.com\",\"bob\",\"hunter2\",\"My Realm\",,,
+\"https://example.com\",\"carol\",\"s3cret\",,,,not-a-time
+\"not a url\",\"dave\",\"s3cret\",,,,
+";
+        let logins = logins_from_csv(csv.as_bytes()).unwrap();
+        assert_eq!(logins.len(), 4);
+
+        let login = logins[0].as_ref().unwrap();
+        assert_eq!(login.id, "{guid-1}");
+        assert_eq!(login.hostname, "https://example.com");
+        assert_eq!(login.username, "alice");
+        assert_eq!(login.password, "p4ss,w0rd");
+        assert_eq!(login.form_submit_url, Some("https://example.com".into()));
+        assert_eq!(login.http_realm, None);
+        assert_eq!(login.time_created, 1_500_000_000_000);
+
+        let login = logins[1].as_ref().unwrap();
+        assert_eq!(login.http_realm, Some("My Realm".into()));
+        assert_eq!(login.form_submit_url, None);
+
+        assert!(logins[2].is_err());
+        assert!(logins[3].is_err());
+    }
+
+    #[test]
+    fn test_chrome_csv() {
+        let csv = "name,url,username,password\n\
+                   example.com,https://www.example.com/login?next=/,alice,p4ssw0rd\n";
+        let logins = logins_from_csv(csv.as_bytes()).unwrap();
+        let login = logins[0].as_ref().unwrap();
+        assert_eq!(login.hostname, "https://www.example.com");
+        assert_eq!(login.form_submit_url, Some("https://www.example.com".into()));
+        assert_eq!(login.id, "");
+
+        assert!(logins_from_csv("name,username\nfoo,bar\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_desktop_json() {
+        let key = [7u8; 24];
+        let iv = [3u8; 8];
+        let json = json!({
+            "nextId": 3,
+            "logins": [{
+                "id": 1,
+                "hostname": "https://example.com",
+                "httpRealm": null,
+                "formSubmitURL": "https://example.com",
+                "usernameField": "user",
+                "passwordField": "pass",
+                "encryptedUsername": encrypt_desktop_field("alice", &key, &iv),
+                "encryptedPassword": encrypt_desktop_field("p4ssw0rd", &key, &iv),
+                "guid": "{guid-1}",
+                "encType": 1,
+                "timeCreated": 1500000000000i64,
+                "timeLastUsed": 1500000000001i64,
+                "timePasswordChanged": 1500000000002i64,
+                "timesUsed": 5,
+            }, {
+                "id": 2,
+                "hostname": "https://example.com",
+                "httpRealm": null,
+                "formSubmitURL": "https://example.com",
+                "encryptedUsername": "bm90IGRlcg==",
+                "encryptedPassword": "bm90IGRlcg==",
+                "encType": 1,
+            }],
+        }).to_string();
+
+        let logins = logins_from_desktop_json(&json, &key).unwrap();
+        assert_eq!(logins.len(), 2);
+        let login = logins[0].as_ref().unwrap();
+        assert_eq!(login.id, "{guid-1}");
+        assert_eq!(login.username, "alice");
+        assert_eq!(login.password, "p4ssw0rd");
+        assert_eq!(login.username_field, "user");
+        assert_eq!(login.times_used, 5);
+        assert_eq!(login.time_password_changed, 1_500_000_000_002);
+        assert!(logins[1].is_err());
+
+        // The wrong key should fail each login, not the whole import.
+        let logins = logins_from_desktop_json(&json, &[8u8; 24]).unwrap();
+        assert!(logins.iter().all(|login| login.is_err()));
+
+        assert!(logins_from_desktop_json("{}", &key).is_err());
+    }
+}
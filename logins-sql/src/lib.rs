@@ -27,8 +27,14 @@ extern crate url;
 extern crate rusqlite;
 
 extern crate serde;
+
+#[cfg_attr(test, macro_use)]
 extern crate serde_json;
 
+extern crate base64;
+extern crate csv;
+extern crate openssl;
+
 #[macro_use]
 extern crate serde_derive;
 
@@ -47,6 +53,13 @@ mod util;
 mod db;
 mod engine;
 mod update_plan;
+mod lookup;
+mod import;
+mod export;
+mod backup;
+
+#[doc(hidden)]
+pub mod bench;
 
 #[cfg(feature = "ffi")]
 mod ffi;
@@ -54,6 +67,8 @@ mod ffi;
 pub use error::*;
 pub use login::*;
 pub use engine::*;
+pub use lookup::LookupOptions;
+pub use import::{ImportSummary, ImportFailure};
 
 
 
@@ -0,0 +1,173 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Matching saved logins against the page being autofilled.
+//!
+//! Logins store their `hostname` (and `formSubmitURL`) as origins, like
+//! `https://example.com:8443`. We normalize the requested origin to a scheme
+//! and `util::url_host_port`, so that its path, default port and case don't
+//! matter, and look up saved logins by the `hostname`s that normalized origin
+//! would be stored as. We don't normalize `hostname`s when we save them,
+//! though, so we won't find logins saved with a different case or an explicit
+//! default port, like `https://Example.com:443`. Browsers already store
+//! normalized origins, so this only matters for logins written by hand.
+
+use url::Url;
+use login::Login;
+use util;
+
+/// Controls which saved logins `PasswordEngine::get_by_origin` and friends
+/// consider a match. By default, only logins saved for exactly the same
+/// scheme, host and port match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LookupOptions {
+    /// Also match logins saved for subdomains of the requested host. For
+    /// example, a login for `https://accounts.example.com` would match
+    /// `https://example.com`.
+    pub include_subdomains: bool,
+    /// Also match logins saved for the `http` version of an `https` origin,
+    /// since the site has presumably been upgraded since we saved them. We
+    /// never match `https` logins for `http` origins.
+    pub allow_http_upgrade: bool,
+}
+
+// The parts of an origin we compare when matching logins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct OriginKey {
+    scheme: String,
+    host_port: String,
+}
+
+impl OriginKey {
+    pub fn parse(url: &str) -> Option<OriginKey> {
+        let scheme = Url::parse(url).ok()?.scheme().to_string();
+        let host_port = util::url_host_port(url)?;
+        Some(OriginKey { scheme, host_port })
+    }
+
+    // The origin as we'd expect it to be stored in `hostname`.
+    fn with_scheme(&self, scheme: &str) -> String {
+        format!("{}://{}", scheme, self.host_port)
+    }
+
+    /// The origin, normalized, as we'd expect it to be stored in `hostname`.
+    pub fn origin(&self) -> String {
+        self.with_scheme(&self.scheme)
+    }
+
+    fn schemes(&self, options: LookupOptions) -> Vec<&str> {
+        if options.allow_http_upgrade && self.scheme == "https" {
+            vec!["https", "http"]
+        } else {
+            vec![self.scheme.as_str()]
+        }
+    }
+
+    /// The exact `hostname`s that match this origin. We look these up with
+    /// the `hostname` indexes.
+    pub fn candidate_origins(&self, options: LookupOptions) -> Vec<String> {
+        self.schemes(options).into_iter().map(|scheme| self.with_scheme(scheme)).collect()
+    }
+
+    /// `LIKE` patterns for the `hostname`s of subdomains of this origin, or
+    /// nothing if we're not matching subdomains. These can't use an index,
+    /// so they scan the table.
+    pub fn subdomain_patterns(&self, options: LookupOptions) -> Vec<String> {
+        if !options.include_subdomains {
+            return Vec::new();
+        }
+        self.schemes(options).into_iter().map(|scheme| {
+            format!("{}://%.{}", scheme, escape_like(&self.host_port))
+        }).collect()
+    }
+
+    /// Returns true if a login saved for `saved` should be used for this
+    /// origin.
+    pub fn matches(&self, saved: &OriginKey, options: LookupOptions) -> bool {
+        let scheme_matches = saved.scheme == self.scheme ||
+            (options.allow_http_upgrade && self.scheme == "https" && saved.scheme == "http");
+        let host_matches = saved.host_port == self.host_port ||
+            (options.include_subdomains &&
+             saved.host_port.ends_with(&format!(".{}", self.host_port)));
+        scheme_matches && host_matches
+    }
+
+    pub fn matches_login(&self, login: &Login, options: LookupOptions) -> bool {
+        OriginKey::parse(&login.hostname).map_or(false, |saved| self.matches(&saved, options))
+    }
+
+    /// Returns true if `login` was saved for a form that submits to this
+    /// origin. An empty `formSubmitURL` matches any form, as on desktop.
+    /// We don't match subdomains of form actions.
+    pub fn matches_form_action(&self, login: &Login, options: LookupOptions) -> bool {
+        match login.form_submit_url {
+            Some(ref url) if url.is_empty() => true,
+            Some(ref url) => {
+                let options = LookupOptions { include_subdomains: false, ..options };
+                OriginKey::parse(url).map_or(false, |saved| self.matches(&saved, options))
+            }
+            None => false,
+        }
+    }
+}
+
+// Escapes `%`, `_` and our escape character, for `LIKE ... ESCAPE '\'`.
+fn escape_like(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '%' || c == '_' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(url: &str) -> OriginKey {
+        OriginKey::parse(url).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(key("https://Example.com:443/login?next=1"), key("https://example.com"));
+        assert_ne!(key("https://example.com:8443"), key("https://example.com"));
+        assert!(OriginKey::parse("not a url").is_none());
+        assert!(OriginKey::parse("data:text/plain,hello").is_none());
+    }
+
+    #[test]
+    fn test_matches() {
+        let default = LookupOptions::default();
+        let upgrade = LookupOptions { allow_http_upgrade: true, ..default };
+        let subdomains = LookupOptions { include_subdomains: true, ..default };
+        let origin = key("https://example.com");
+
+        assert!(origin.matches(&key("https://example.com"), default));
+        assert!(!origin.matches(&key("http://example.com"), default));
+        assert!(!origin.matches(&key("https://www.example.com"), default));
+
+        assert!(origin.matches(&key("http://example.com"), upgrade));
+        assert!(!key("http://example.com").matches(&key("https://example.com"), upgrade));
+
+        assert!(origin.matches(&key("https://www.example.com"), subdomains));
+        assert!(!origin.matches(&key("https://notexample.com"), subdomains));
+        assert!(!origin.matches(&key("https://www.example.com:8443"), subdomains));
+    }
+
+    #[test]
+    fn test_candidates() {
+        let options = LookupOptions { allow_http_upgrade: true, include_subdomains: true };
+        let origin = key("https://example.com:8443");
+        assert_eq!(origin.candidate_origins(options),
+                   vec!["https://example.com:8443", "http://example.com:8443"]);
+        assert_eq!(origin.subdomain_patterns(options),
+                   vec!["https://%.example.com:8443", "http://%.example.com:8443"]);
+        assert!(origin.subdomain_patterns(LookupOptions::default()).is_empty());
+        assert_eq!(escape_like("50%_off\\"), "50\\%\\_off\\\\");
+    }
+}
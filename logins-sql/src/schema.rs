@@ -78,7 +78,7 @@
 //! This table was added (by this rust crate) in version 4, and so is not
 //! present in firefox-ios.
 //!
-//! Currently it is used to store two items:
+//! Currently it is used to store three items:
 //!
 //! 1. The last sync timestamp is stored under [LAST_SYNC_META_KEY], a
 //!    `sync15_adapter::ServerTimestamp` stored in integer milliseconds.
@@ -87,6 +87,10 @@
 //!    [GLOBAL_STATE_META_KEY]. This is a `sync15_adapter::GlobalState` stored as
 //!    JSON.
 //!
+//! 3. The last tokenserver token is stored under [TOKEN_CACHE_META_KEY], so
+//!    that we don't need a new one every time the app starts. This is a
+//!    `sync15_adapter::CachedToken` stored as JSON.
+//!
 
 use error::*;
 use sql_support::ConnExt;
@@ -211,6 +215,7 @@ const UPDATE_MIRROR_TIMESTAMPS_TO_MILLIS_SQL: &'static str = "
 
 pub(crate) static LAST_SYNC_META_KEY:    &'static str = "last_sync_time";
 pub(crate) static GLOBAL_STATE_META_KEY: &'static str = "global_state";
+pub(crate) static TOKEN_CACHE_META_KEY:  &'static str = "token_cache";
 
 pub(crate) fn init(db: &db::LoginDb) -> Result<()> {
     let user_version = db.query_one::<i64>("PRAGMA user_version")?;
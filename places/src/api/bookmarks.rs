@@ -0,0 +1,832 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// The bookmarks API. This is loosely modeled on desktop's `PlacesUtils.bookmarks`,
+// although it's far less complete.
+
+use std::cmp::{min, max};
+use std::collections::HashMap;
+
+use rusqlite::{Connection, Row};
+use url::Url;
+
+use db::PlacesDb;
+use db::schema::BOOKMARK_ROOTS;
+use error::*;
+use frecency;
+use sql_support::ConnExt;
+use storage::{self, RowId};
+use sync;
+use types::{BookmarkType, SyncGuid, SyncStatus, Timestamp};
+
+// The guids of the built-in roots. These are the same as desktop.
+pub const ROOT_GUID: &'static str = "root________";
+pub const MENU_GUID: &'static str = "menu________";
+pub const TOOLBAR_GUID: &'static str = "toolbar_____";
+pub const UNFILED_GUID: &'static str = "unfiled_____";
+pub const MOBILE_GUID: &'static str = "mobile______";
+
+#[inline]
+pub fn is_root_guid(guid: &SyncGuid) -> bool {
+    BOOKMARK_ROOTS.iter().any(|&(_, root_guid, _)| guid.0 == root_guid)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BookmarkPosition {
+    Specific(u32),
+    Append,
+}
+
+/// Structures which can be used to insert a bookmark, folder or separator.
+#[derive(Debug, Clone)]
+pub struct InsertableBookmark {
+    pub parent_guid: SyncGuid,
+    pub position: BookmarkPosition,
+    pub date_added: Option<Timestamp>,
+    pub last_modified: Option<Timestamp>,
+    pub guid: Option<SyncGuid>,
+    pub url: Url,
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct InsertableSeparator {
+    pub parent_guid: SyncGuid,
+    pub position: BookmarkPosition,
+    pub date_added: Option<Timestamp>,
+    pub last_modified: Option<Timestamp>,
+    pub guid: Option<SyncGuid>,
+}
+
+#[derive(Debug, Clone)]
+pub struct InsertableFolder {
+    pub parent_guid: SyncGuid,
+    pub position: BookmarkPosition,
+    pub date_added: Option<Timestamp>,
+    pub last_modified: Option<Timestamp>,
+    pub guid: Option<SyncGuid>,
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum InsertableItem {
+    Bookmark(InsertableBookmark),
+    Separator(InsertableSeparator),
+    Folder(InsertableFolder),
+}
+
+// We allow all "common" fields from the sub-types to be accessed via the
+// enum.
+macro_rules! impl_common_accessor {
+    ($func:ident, $field:ident, $retval:ty) => {
+        pub fn $func(&self) -> &$retval {
+            match *self {
+                InsertableItem::Bookmark(ref b) => &b.$field,
+                InsertableItem::Separator(ref s) => &s.$field,
+                InsertableItem::Folder(ref f) => &f.$field,
+            }
+        }
+    }
+}
+
+impl InsertableItem {
+    fn bookmark_type(&self) -> BookmarkType {
+        match *self {
+            InsertableItem::Bookmark(_) => BookmarkType::Bookmark,
+            InsertableItem::Separator(_) => BookmarkType::Separator,
+            InsertableItem::Folder(_) => BookmarkType::Folder,
+        }
+    }
+
+    impl_common_accessor!(parent_guid, parent_guid, SyncGuid);
+    impl_common_accessor!(position, position, BookmarkPosition);
+    impl_common_accessor!(date_added, date_added, Option<Timestamp>);
+    impl_common_accessor!(last_modified, last_modified, Option<Timestamp>);
+    impl_common_accessor!(guid, guid, Option<SyncGuid>);
+}
+
+pub fn insert_bookmark(db: &mut PlacesDb, bm: &InsertableItem) -> Result<SyncGuid> {
+    let tx = db.db.transaction()?;
+    let guid = insert_bookmark_in_tx(&tx, bm)?;
+    tx.commit()?;
+    Ok(guid)
+}
+
+fn insert_bookmark_in_tx(conn: &Connection, bm: &InsertableItem) -> Result<SyncGuid> {
+    let parent = match get_raw_bookmark(conn, bm.parent_guid())? {
+        Some(p) => p,
+        None => return Err(InvalidPlaceInfo::InvalidParent(bm.parent_guid().0.clone()).into()),
+    };
+    if parent.bookmark_type != BookmarkType::Folder {
+        return Err(InvalidPlaceInfo::InvalidParent(parent.guid.0).into());
+    }
+    let guid = match *bm.guid() {
+        Some(ref guid) => {
+            if guid.0.len() != 12 {
+                return Err(InvalidPlaceInfo::InvalidGuid(guid.0.clone()).into());
+            }
+            if get_raw_bookmark(conn, guid)?.is_some() {
+                return Err(ErrorKind::DuplicateGuid(guid.0.clone()).into());
+            }
+            guid.clone()
+        },
+        None => SyncGuid(sync::util::random_guid().expect("Failed to generate random bytes for GUID")),
+    };
+    let position = make_room_for_child(conn, parent.row_id, *bm.position(), None)?;
+    let date_added = bm.date_added().unwrap_or_else(Timestamp::now);
+    // last_modified can't be before date_added.
+    let last_modified = max(bm.last_modified().unwrap_or(date_added), date_added);
+
+    let (place_id, title) = match *bm {
+        InsertableItem::Bookmark(ref b) => (Some(fetch_or_insert_place(conn, &b.url)?), b.title.clone()),
+        InsertableItem::Separator(_) => (None, None),
+        InsertableItem::Folder(ref f) => (None, f.title.clone()),
+    };
+
+    conn.execute_named_cached("
+        INSERT INTO moz_bookmarks
+            (fk, type, parent, position, title, dateAdded, lastModified,
+             guid, syncStatus, syncChangeCounter)
+        VALUES
+            (:fk, :type, :parent, :position, :title, :dateAdded, :lastModified,
+             :guid, :syncStatus, 1)",
+        &[(":fk", &place_id),
+          (":type", &bm.bookmark_type()),
+          (":parent", &parent.row_id),
+          (":position", &position),
+          (":title", &title),
+          (":dateAdded", &date_added),
+          (":lastModified", &last_modified),
+          (":guid", &guid),
+          (":syncStatus", &SyncStatus::New)]
+    )?;
+    // If the guid was previously deleted, it isn't any more.
+    conn.execute_named_cached(
+        "DELETE FROM moz_bookmarks_deleted WHERE guid = :guid",
+        &[(":guid", &guid)]
+    )?;
+    note_folder_changed(conn, parent.row_id, last_modified)?;
+    if let Some(place_id) = place_id {
+        update_place_frecency(conn, place_id)?;
+    }
+    Ok(guid)
+}
+
+/// Changes to make to an existing item. Fields which are `None` are left
+/// unchanged. Changing the parent or position moves the item.
+#[derive(Debug, Clone, Default)]
+pub struct BookmarkUpdateInfo {
+    pub guid: SyncGuid,
+    pub title: Option<String>,
+    pub url: Option<Url>,
+    pub parent_guid: Option<SyncGuid>,
+    pub position: Option<BookmarkPosition>,
+}
+
+pub fn update_bookmark(db: &mut PlacesDb, update: &BookmarkUpdateInfo) -> Result<()> {
+    let tx = db.db.transaction()?;
+    update_bookmark_in_tx(&tx, update)?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn update_bookmark_in_tx(conn: &Connection, update: &BookmarkUpdateInfo) -> Result<()> {
+    let item = get_raw_bookmark_for_change(conn, &update.guid)?;
+    let now = Timestamp::now();
+
+    if let Some(ref title) = update.title {
+        if item.bookmark_type == BookmarkType::Separator {
+            return Err(InvalidPlaceInfo::IllegalChange("separators have no title".into()).into());
+        }
+        conn.execute_named_cached(
+            "UPDATE moz_bookmarks SET title = :title WHERE id = :id",
+            &[(":title", title), (":id", &item.row_id)]
+        )?;
+    }
+
+    if let Some(ref url) = update.url {
+        if item.bookmark_type != BookmarkType::Bookmark {
+            return Err(InvalidPlaceInfo::IllegalChange("only bookmarks have a url".into()).into());
+        }
+        let place_id = fetch_or_insert_place(conn, url)?;
+        if Some(place_id) != item.place_id {
+            // The triggers take care of foreign_count, but we need to
+            // update frecency, and maybe clean up the old place.
+            conn.execute_named_cached(
+                "UPDATE moz_bookmarks SET fk = :fk WHERE id = :id",
+                &[(":fk", &place_id), (":id", &item.row_id)]
+            )?;
+            update_place_frecency(conn, place_id)?;
+            if let Some(old_place_id) = item.place_id {
+                remove_or_update_place(conn, old_place_id)?;
+            }
+        }
+    }
+
+    if update.title.is_some() || update.url.is_some() {
+        note_item_changed(conn, item.row_id, now)?;
+    }
+
+    if update.parent_guid.is_some() || update.position.is_some() {
+        let parent_guid = match update.parent_guid {
+            Some(ref guid) => guid.clone(),
+            None => item.parent_guid.clone().expect("only the root has no parent"),
+        };
+        let position = update.position.unwrap_or(BookmarkPosition::Append);
+        move_bookmark_in_tx(conn, &item, &parent_guid, position, now)?;
+    }
+    Ok(())
+}
+
+pub fn move_bookmark(db: &mut PlacesDb,
+                     guid: &SyncGuid,
+                     new_parent_guid: &SyncGuid,
+                     position: BookmarkPosition) -> Result<()> {
+    let tx = db.db.transaction()?;
+    {
+        let item = get_raw_bookmark_for_change(&tx, guid)?;
+        move_bookmark_in_tx(&tx, &item, new_parent_guid, position, Timestamp::now())?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+fn move_bookmark_in_tx(conn: &Connection,
+                       item: &RawBookmark,
+                       new_parent_guid: &SyncGuid,
+                       position: BookmarkPosition,
+                       now: Timestamp) -> Result<()> {
+    let new_parent = match get_raw_bookmark(conn, new_parent_guid)? {
+        Some(p) => p,
+        None => return Err(InvalidPlaceInfo::InvalidParent(new_parent_guid.0.clone()).into()),
+    };
+    if new_parent.bookmark_type != BookmarkType::Folder {
+        return Err(InvalidPlaceInfo::InvalidParent(new_parent.guid.0).into());
+    }
+    if item.bookmark_type == BookmarkType::Folder && is_ancestor(conn, item.row_id, new_parent.row_id)? {
+        return Err(InvalidPlaceInfo::InvalidParent(
+            format!("{} is a descendant of {}", new_parent.guid.0, item.guid.0)).into());
+    }
+    let old_parent_id = item.parent_id.expect("only the root has no parent");
+
+    // Close the gap at the old position, then make room at the new one.
+    conn.execute_named_cached("
+        UPDATE moz_bookmarks SET position = position - 1
+        WHERE parent = :parent AND position > :position",
+        &[(":parent", &old_parent_id), (":position", &item.position)]
+    )?;
+    let new_position = make_room_for_child(conn, new_parent.row_id, position, Some(item.row_id))?;
+    conn.execute_named_cached("
+        UPDATE moz_bookmarks SET parent = :parent, position = :position
+        WHERE id = :id",
+        &[(":parent", &new_parent.row_id), (":position", &new_position), (":id", &item.row_id)]
+    )?;
+    note_item_changed(conn, item.row_id, now)?;
+    note_folder_changed(conn, old_parent_id, now)?;
+    if old_parent_id != new_parent.row_id {
+        note_folder_changed(conn, new_parent.row_id, now)?;
+    }
+    Ok(())
+}
+
+/// Delete the item with the specified guid, including all children if it's
+/// a folder. Returns false if the item doesn't exist.
+pub fn delete_bookmark(db: &mut PlacesDb, guid: &SyncGuid) -> Result<bool> {
+    let tx = db.db.transaction()?;
+    let existed = delete_bookmark_in_tx(&tx, guid)?;
+    tx.commit()?;
+    Ok(existed)
+}
+
+fn delete_bookmark_in_tx(conn: &Connection, guid: &SyncGuid) -> Result<bool> {
+    let item = match get_raw_bookmark(conn, guid)? {
+        Some(item) => item,
+        None => return Ok(false),
+    };
+    if item.parent_id.is_none() || is_root_guid(&item.guid) {
+        return Err(InvalidPlaceInfo::CannotUpdateRoot(item.guid.0).into());
+    }
+    let parent_id = item.parent_id.expect("checked above");
+
+    // Collect the places referenced by the item and its descendants, so we
+    // can fix them up after the delete.
+    let place_ids = {
+        let mut stmt = conn.prepare_cached(&format!("
+            {descendants}
+            SELECT DISTINCT b.fk FROM moz_bookmarks b
+            JOIN descendants d ON d.id = b.id
+            WHERE b.fk NOT NULL", descendants = DESCENDANTS_CTE))?;
+        let rows = stmt.query_and_then_named(&[(":id", &item.row_id)], |row| -> Result<RowId> {
+            Ok(row.get_checked(0)?)
+        })?;
+        let mut ids = Vec::new();
+        for id in rows {
+            ids.push(id?);
+        }
+        ids
+    };
+
+    // Items which have been uploaded need a tombstone so the deletion is
+    // synced. Items which have never been uploaded can just vanish.
+    let now = Timestamp::now();
+    conn.execute_named_cached(&format!("
+        {descendants}
+        INSERT OR REPLACE INTO moz_bookmarks_deleted (guid, dateRemoved)
+        SELECT guid, :now FROM moz_bookmarks
+        WHERE id IN (SELECT id FROM descendants) AND syncStatus = {normal}",
+        descendants = DESCENDANTS_CTE,
+        normal = SyncStatus::Normal as u8),
+        &[(":id", &item.row_id), (":now", &now)]
+    )?;
+
+    // We don't rely on `ON DELETE CASCADE` as foreign keys may not be enabled.
+    conn.execute_named_cached(&format!("
+        {descendants}
+        DELETE FROM moz_bookmarks WHERE id IN (SELECT id FROM descendants)",
+        descendants = DESCENDANTS_CTE),
+        &[(":id", &item.row_id)]
+    )?;
+    conn.execute_named_cached("
+        UPDATE moz_bookmarks SET position = position - 1
+        WHERE parent = :parent AND position > :position",
+        &[(":parent", &parent_id), (":position", &item.position)]
+    )?;
+    note_folder_changed(conn, parent_id, now)?;
+    for place_id in place_ids {
+        remove_or_update_place(conn, place_id)?;
+    }
+    Ok(true)
+}
+
+/// A bookmark tree, as returned by `fetch_tree`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookmarkNode {
+    pub guid: SyncGuid,
+    pub date_added: Timestamp,
+    pub last_modified: Timestamp,
+    pub title: Option<String>,
+    pub url: Url,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeparatorNode {
+    pub guid: SyncGuid,
+    pub date_added: Timestamp,
+    pub last_modified: Timestamp,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FolderNode {
+    pub guid: SyncGuid,
+    pub date_added: Timestamp,
+    pub last_modified: Timestamp,
+    pub title: Option<String>,
+    pub children: Vec<BookmarkTreeNode>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BookmarkTreeNode {
+    Bookmark(BookmarkNode),
+    Separator(SeparatorNode),
+    Folder(FolderNode),
+}
+
+impl BookmarkTreeNode {
+    pub fn guid(&self) -> &SyncGuid {
+        match *self {
+            BookmarkTreeNode::Bookmark(ref b) => &b.guid,
+            BookmarkTreeNode::Separator(ref s) => &s.guid,
+            BookmarkTreeNode::Folder(ref f) => &f.guid,
+        }
+    }
+
+    pub fn bookmark_type(&self) -> BookmarkType {
+        match *self {
+            BookmarkTreeNode::Bookmark(_) => BookmarkType::Bookmark,
+            BookmarkTreeNode::Separator(_) => BookmarkType::Separator,
+            BookmarkTreeNode::Folder(_) => BookmarkType::Folder,
+        }
+    }
+}
+
+// A row from the query in `fetch_tree`.
+struct FetchedTreeRow {
+    guid: SyncGuid,
+    parent_guid: Option<SyncGuid>,
+    bookmark_type: BookmarkType,
+    title: Option<String>,
+    date_added: Timestamp,
+    last_modified: Timestamp,
+    url: Option<String>,
+}
+
+impl FetchedTreeRow {
+    fn from_row(row: &Row) -> Result<Self> {
+        Ok(Self {
+            guid: row.get_checked("guid")?,
+            parent_guid: row.get_checked("parentGuid")?,
+            bookmark_type: row.get_checked("type")?,
+            title: row.get_checked("title")?,
+            date_added: row.get_checked("dateAdded")?,
+            last_modified: row.get_checked("lastModified")?,
+            url: row.get_checked("url")?,
+        })
+    }
+
+    fn into_node(self, children_by_parent: &mut HashMap<SyncGuid, Vec<FetchedTreeRow>>) -> Result<BookmarkTreeNode> {
+        Ok(match self.bookmark_type {
+            BookmarkType::Bookmark => BookmarkTreeNode::Bookmark(BookmarkNode {
+                url: Url::parse(&self.url.unwrap_or_default())?,
+                guid: self.guid,
+                date_added: self.date_added,
+                last_modified: self.last_modified,
+                title: self.title,
+            }),
+            BookmarkType::Separator => BookmarkTreeNode::Separator(SeparatorNode {
+                guid: self.guid,
+                date_added: self.date_added,
+                last_modified: self.last_modified,
+            }),
+            BookmarkType::Folder => {
+                let child_rows = children_by_parent.remove(&self.guid).unwrap_or_default();
+                let mut children = Vec::with_capacity(child_rows.len());
+                for child in child_rows {
+                    children.push(child.into_node(children_by_parent)?);
+                }
+                BookmarkTreeNode::Folder(FolderNode {
+                    guid: self.guid,
+                    date_added: self.date_added,
+                    last_modified: self.last_modified,
+                    title: self.title,
+                    children,
+                })
+            }
+        })
+    }
+}
+
+/// Fetch the item with the specified guid and, if it's a folder, all of its
+/// descendants.
+pub fn fetch_tree(db: &PlacesDb, item_guid: &SyncGuid) -> Result<Option<BookmarkTreeNode>> {
+    let sql = "
+        WITH RECURSIVE descendants(id, level) AS (
+            SELECT id, 0 FROM moz_bookmarks WHERE guid = :item_guid
+            UNION ALL
+            SELECT b.id, d.level + 1 FROM moz_bookmarks b
+            JOIN descendants d ON b.parent = d.id
+        )
+        SELECT b.guid, p.guid AS parentGuid, b.type, b.title,
+               b.dateAdded, b.lastModified, h.url
+        FROM moz_bookmarks b
+        JOIN descendants d ON b.id = d.id
+        LEFT JOIN moz_bookmarks p ON p.id = b.parent
+        LEFT JOIN moz_places h ON h.id = b.fk
+        ORDER BY d.level, b.parent, b.position";
+    let mut stmt = db.db.prepare(sql)?;
+    let mut rows = stmt.query_and_then_named(&[(":item_guid", item_guid)], FetchedTreeRow::from_row)?;
+    // The first row is the item itself.
+    let root = match rows.next() {
+        Some(row) => row?,
+        None => return Ok(None),
+    };
+    let mut children_by_parent: HashMap<SyncGuid, Vec<FetchedTreeRow>> = HashMap::new();
+    for row in rows {
+        let row = row?;
+        let parent_guid = row.parent_guid.clone().expect("only the root has no parent");
+        children_by_parent.entry(parent_guid).or_insert_with(Vec::new).push(row);
+    }
+    Ok(Some(root.into_node(&mut children_by_parent)?))
+}
+
+// The minimal information we need about an item to modify it.
+#[derive(Debug)]
+struct RawBookmark {
+    row_id: RowId,
+    guid: SyncGuid,
+    parent_id: Option<RowId>,
+    parent_guid: Option<SyncGuid>,
+    position: u32,
+    bookmark_type: BookmarkType,
+    place_id: Option<RowId>,
+}
+
+impl RawBookmark {
+    fn from_row(row: &Row) -> Result<Self> {
+        Ok(Self {
+            row_id: row.get_checked("id")?,
+            guid: row.get_checked("guid")?,
+            parent_id: row.get_checked("parent")?,
+            parent_guid: row.get_checked("parentGuid")?,
+            position: row.get_checked("position")?,
+            bookmark_type: row.get_checked("type")?,
+            place_id: row.get_checked("fk")?,
+        })
+    }
+}
+
+fn get_raw_bookmark(conn: &Connection, guid: &SyncGuid) -> Result<Option<RawBookmark>> {
+    Ok(conn.try_query_row("
+        SELECT b.id, b.guid, b.parent, b.position, b.type, b.fk,
+               p.guid AS parentGuid
+        FROM moz_bookmarks b
+        LEFT JOIN moz_bookmarks p ON p.id = b.parent
+        WHERE b.guid = :guid",
+        &[(":guid", guid)],
+        RawBookmark::from_row,
+        true
+    )?)
+}
+
+// Like get_raw_bookmark, but the item must exist and must not be a root.
+fn get_raw_bookmark_for_change(conn: &Connection, guid: &SyncGuid) -> Result<RawBookmark> {
+    let item = match get_raw_bookmark(conn, guid)? {
+        Some(item) => item,
+        None => return Err(InvalidPlaceInfo::NoSuchGuid(guid.0.clone()).into()),
+    };
+    if item.parent_id.is_none() || is_root_guid(&item.guid) {
+        return Err(InvalidPlaceInfo::CannotUpdateRoot(item.guid.0).into());
+    }
+    Ok(item)
+}
+
+// A CTE which selects the item with id `:id` and all of its descendants.
+const DESCENDANTS_CTE: &str = "
+    WITH RECURSIVE descendants(id) AS (
+        SELECT :id
+        UNION ALL
+        SELECT b.id FROM moz_bookmarks b
+        JOIN descendants d ON b.parent = d.id
+    )";
+
+// Is `ancestor` the same as, or an ancestor of, `item`?
+fn is_ancestor(conn: &Connection, ancestor: RowId, item: RowId) -> Result<bool> {
+    Ok(conn.query_row_named("
+        WITH RECURSIVE ancestors(id) AS (
+            SELECT :item
+            UNION ALL
+            SELECT b.parent FROM moz_bookmarks b
+            JOIN ancestors a ON b.id = a.id
+            WHERE b.parent NOT NULL
+        )
+        SELECT EXISTS(SELECT 1 FROM ancestors WHERE id = :ancestor)",
+        &[(":item", &item), (":ancestor", &ancestor)],
+        |row| row.get::<_, bool>(0)
+    )?)
+}
+
+// Work out the actual position for a new child of `parent_id` and shift
+// the existing children to make room for it. `ignore_id` is the item being
+// moved, if any, which should not be counted as an existing child.
+fn make_room_for_child(conn: &Connection,
+                       parent_id: RowId,
+                       position: BookmarkPosition,
+                       ignore_id: Option<RowId>) -> Result<u32> {
+    let num_children: u32 = conn.query_row_named("
+        SELECT COUNT(*) FROM moz_bookmarks
+        WHERE parent = :parent AND id IS NOT :ignore_id",
+        &[(":parent", &parent_id), (":ignore_id", &ignore_id)],
+        |row| row.get::<_, u32>(0)
+    )?;
+    let position = match position {
+        BookmarkPosition::Specific(pos) => min(pos, num_children),
+        BookmarkPosition::Append => num_children,
+    };
+    if position < num_children {
+        conn.execute_named_cached("
+            UPDATE moz_bookmarks SET position = position + 1
+            WHERE parent = :parent AND position >= :position AND id IS NOT :ignore_id",
+            &[(":parent", &parent_id), (":position", &position), (":ignore_id", &ignore_id)]
+        )?;
+    }
+    Ok(position)
+}
+
+// Bump the change counter and modification time of an item.
+fn note_item_changed(conn: &Connection, item_id: RowId, now: Timestamp) -> Result<()> {
+    conn.execute_named_cached("
+        UPDATE moz_bookmarks
+        SET syncChangeCounter = syncChangeCounter + 1,
+            lastModified = MAX(lastModified, :now)
+        WHERE id = :id",
+        &[(":now", &now), (":id", &item_id)]
+    )?;
+    Ok(())
+}
+
+// The list of children is part of a folder's sync record, so adding,
+// removing or moving children counts as a change to the folder.
+#[inline]
+fn note_folder_changed(conn: &Connection, folder_id: RowId, now: Timestamp) -> Result<()> {
+    note_item_changed(conn, folder_id, now)
+}
+
+pub(crate) fn fetch_or_insert_place(conn: &Connection, url: &Url) -> Result<RowId> {
+    let existing: Option<RowId> = conn.try_query_row("
+        SELECT id FROM moz_places
+        WHERE url_hash = hash(:url) AND url = :url",
+        &[(":url", &url.as_str())],
+        |row| -> Result<RowId> { Ok(row.get_checked(0)?) },
+        true
+    )?;
+    Ok(match existing {
+        Some(id) => id,
+        None => storage::new_page_info(conn, url)?.row_id,
+    })
+}
+
+pub(crate) fn update_place_frecency(conn: &Connection, place_id: RowId) -> Result<()> {
+    let frecency = frecency::calculate_frecency(conn,
+        &frecency::DEFAULT_FRECENCY_SETTINGS,
+        place_id.0,
+        None)?;
+    conn.execute_named_cached(
+        "UPDATE moz_places SET frecency = :frecency WHERE id = :id",
+        &[(":frecency", &frecency), (":id", &place_id)]
+    )?;
+    Ok(())
+}
+
+// A place which is no longer bookmarked and has no visits is removed,
+// otherwise its frecency needs updating.
+pub(crate) fn remove_or_update_place(conn: &Connection, place_id: RowId) -> Result<()> {
+    let removed = conn.execute_named_cached("
+        DELETE FROM moz_places
+        WHERE id = :id AND foreign_count = 0
+          AND NOT EXISTS(SELECT 1 FROM moz_historyvisits WHERE place_id = :id)",
+        &[(":id", &place_id)]
+    )?;
+    if removed == 0 {
+        update_place_frecency(conn, place_id)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use env_logger;
+
+    fn insert_bookmark_into(db: &mut PlacesDb, parent: &str, position: BookmarkPosition, url: &str) -> SyncGuid {
+        insert_bookmark(db, &InsertableItem::Bookmark(InsertableBookmark {
+            parent_guid: parent.into(),
+            position,
+            date_added: None,
+            last_modified: None,
+            guid: None,
+            url: Url::parse(url).unwrap(),
+            title: None,
+        })).expect("should insert bookmark")
+    }
+
+    fn insert_folder_into(db: &mut PlacesDb, parent: &str, title: &str) -> SyncGuid {
+        insert_bookmark(db, &InsertableItem::Folder(InsertableFolder {
+            parent_guid: parent.into(),
+            position: BookmarkPosition::Append,
+            date_added: None,
+            last_modified: None,
+            guid: None,
+            title: Some(title.to_string()),
+        })).expect("should insert folder")
+    }
+
+    fn child_guids(db: &PlacesDb, folder: &SyncGuid) -> Vec<SyncGuid> {
+        match fetch_tree(db, folder).expect("should fetch").expect("should exist") {
+            BookmarkTreeNode::Folder(f) => f.children.iter().map(|c| c.guid().clone()).collect(),
+            _ => panic!("not a folder"),
+        }
+    }
+
+    fn foreign_count(db: &PlacesDb, url: &str) -> Option<i64> {
+        db.try_query_row(
+            "SELECT foreign_count FROM moz_places WHERE url = :url",
+            &[(":url", &url)],
+            |row| -> Result<i64> { Ok(row.get_checked(0)?) },
+            false
+        ).expect("should query")
+    }
+
+    #[test]
+    fn test_roots() {
+        let db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let children = child_guids(&db, &ROOT_GUID.into());
+        assert_eq!(children, vec![SyncGuid::from(MENU_GUID),
+                                  SyncGuid::from(TOOLBAR_GUID),
+                                  SyncGuid::from(UNFILED_GUID),
+                                  SyncGuid::from(MOBILE_GUID)]);
+        assert!(fetch_tree(&db, &"aaaaaaaaaaaa".into()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_insert_positions() {
+        let _ = env_logger::try_init();
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let url = "http://example.com/";
+        let a = insert_bookmark_into(&mut db, UNFILED_GUID, BookmarkPosition::Append, url);
+        let b = insert_bookmark_into(&mut db, UNFILED_GUID, BookmarkPosition::Specific(0), url);
+        let c = insert_bookmark_into(&mut db, UNFILED_GUID, BookmarkPosition::Specific(100), url);
+        assert_eq!(child_guids(&db, &UNFILED_GUID.into()), vec![b, a, c]);
+        assert_eq!(foreign_count(&db, url), Some(3));
+    }
+
+    #[test]
+    fn test_insert_invalid() {
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let bm = insert_bookmark_into(&mut db, UNFILED_GUID, BookmarkPosition::Append, "http://example.com/");
+        // Bookmarks can't be parents.
+        let result = insert_bookmark(&mut db, &InsertableItem::Separator(InsertableSeparator {
+            parent_guid: bm.clone(),
+            position: BookmarkPosition::Append,
+            date_added: None,
+            last_modified: None,
+            guid: None,
+        }));
+        assert!(result.is_err());
+        // Duplicate guids are rejected.
+        let result = insert_bookmark(&mut db, &InsertableItem::Separator(InsertableSeparator {
+            parent_guid: UNFILED_GUID.into(),
+            position: BookmarkPosition::Append,
+            date_added: None,
+            last_modified: None,
+            guid: Some(bm),
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_move() {
+        let _ = env_logger::try_init();
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let folder = insert_folder_into(&mut db, MENU_GUID, "folder");
+        let a = insert_bookmark_into(&mut db, UNFILED_GUID, BookmarkPosition::Append, "http://a.com/");
+        let b = insert_bookmark_into(&mut db, UNFILED_GUID, BookmarkPosition::Append, "http://b.com/");
+        let c = insert_bookmark_into(&mut db, UNFILED_GUID, BookmarkPosition::Append, "http://c.com/");
+
+        // Within the same folder.
+        move_bookmark(&mut db, &a, &UNFILED_GUID.into(), BookmarkPosition::Append).expect("should move");
+        assert_eq!(child_guids(&db, &UNFILED_GUID.into()), vec![b.clone(), c.clone(), a.clone()]);
+        move_bookmark(&mut db, &a, &UNFILED_GUID.into(), BookmarkPosition::Specific(1)).expect("should move");
+        assert_eq!(child_guids(&db, &UNFILED_GUID.into()), vec![b.clone(), a.clone(), c.clone()]);
+
+        // To a different folder.
+        move_bookmark(&mut db, &a, &folder, BookmarkPosition::Append).expect("should move");
+        assert_eq!(child_guids(&db, &UNFILED_GUID.into()), vec![b.clone(), c.clone()]);
+        assert_eq!(child_guids(&db, &folder), vec![a.clone()]);
+
+        // A folder can't be moved into itself or a descendant.
+        let child = insert_folder_into(&mut db, &folder.0, "child");
+        assert!(move_bookmark(&mut db, &folder, &folder, BookmarkPosition::Append).is_err());
+        assert!(move_bookmark(&mut db, &folder, &child, BookmarkPosition::Append).is_err());
+        // And roots can't be moved at all.
+        assert!(move_bookmark(&mut db, &MENU_GUID.into(), &folder, BookmarkPosition::Append).is_err());
+    }
+
+    #[test]
+    fn test_update() {
+        let _ = env_logger::try_init();
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let bm = insert_bookmark_into(&mut db, UNFILED_GUID, BookmarkPosition::Append, "http://a.com/");
+        update_bookmark(&mut db, &BookmarkUpdateInfo {
+            guid: bm.clone(),
+            title: Some("the title".into()),
+            url: Some(Url::parse("http://b.com/").unwrap()),
+            parent_guid: Some(TOOLBAR_GUID.into()),
+            ..BookmarkUpdateInfo::default()
+        }).expect("should update");
+
+        match fetch_tree(&db, &bm).unwrap().expect("should exist") {
+            BookmarkTreeNode::Bookmark(b) => {
+                assert_eq!(b.title, Some("the title".into()));
+                assert_eq!(b.url.as_str(), "http://b.com/");
+            },
+            _ => panic!("not a bookmark"),
+        }
+        assert_eq!(child_guids(&db, &TOOLBAR_GUID.into()), vec![bm]);
+        // The old place is no longer referenced, so is gone.
+        assert_eq!(foreign_count(&db, "http://a.com/"), None);
+        assert_eq!(foreign_count(&db, "http://b.com/"), Some(1));
+    }
+
+    #[test]
+    fn test_delete() {
+        let _ = env_logger::try_init();
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let folder = insert_folder_into(&mut db, MENU_GUID, "folder");
+        let a = insert_bookmark_into(&mut db, &folder.0, BookmarkPosition::Append, "http://a.com/");
+        let subfolder = insert_folder_into(&mut db, &folder.0, "subfolder");
+        insert_bookmark_into(&mut db, &subfolder.0, BookmarkPosition::Append, "http://b.com/");
+        let sibling = insert_folder_into(&mut db, MENU_GUID, "sibling");
+
+        assert!(delete_bookmark(&mut db, &a).expect("should delete"));
+        assert!(!delete_bookmark(&mut db, &a).expect("should work"));
+        assert_eq!(child_guids(&db, &folder), vec![subfolder.clone()]);
+        assert_eq!(foreign_count(&db, "http://a.com/"), None);
+
+        assert!(delete_bookmark(&mut db, &folder).expect("should delete"));
+        assert!(fetch_tree(&db, &subfolder).unwrap().is_none());
+        assert_eq!(foreign_count(&db, "http://b.com/"), None);
+        assert_eq!(child_guids(&db, &MENU_GUID.into()), vec![sibling]);
+
+        assert!(delete_bookmark(&mut db, &MENU_GUID.into()).is_err());
+    }
+}
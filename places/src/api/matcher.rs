@@ -25,6 +25,22 @@ pub struct SearchParams {
 /// A provider can be anything that returns URL suggestions: Places history
 /// and bookmarks, synced tabs, search engine suggestions, and search keywords.
 pub fn search_frecent(conn: &PlacesDb, params: SearchParams) -> Result<Vec<SearchResult>> {
+    search_frecent_with_providers(conn, params, &[])
+}
+
+/// An additional source of autocomplete matches that doesn't live in the
+/// Places database, like tabs open on other devices.
+pub trait SearchProvider {
+    fn search(&self, params: &SearchParams) -> Result<Vec<SearchResult>>;
+}
+
+/// Like `search_frecent`, but also queries `providers` after the Places
+/// providers, appending their matches.
+pub fn search_frecent_with_providers(
+    conn: &PlacesDb,
+    params: SearchParams,
+    providers: &[&SearchProvider]
+) -> Result<Vec<SearchResult>> {
     // TODO: Tokenize the query.
     let mut matches = Vec::new();
 
@@ -52,6 +68,10 @@ pub fn search_frecent(conn: &PlacesDb, params: SearchParams) -> Result<Vec<Searc
     // TODO: If we don't have enough results, re-run `Adaptive` and
     // `Suggestions`, this time with `MatchBehavior::Anywhere`.
 
+    for provider in providers {
+        matches.extend(provider.search(&params)?);
+    }
+
     Ok(matches)
 }
 
@@ -120,6 +140,8 @@ pub enum MatchReason {
     PreviousUse,
     Bookmark,
     Tags(String),
+    /// The match is a tab open on another device. Holds the device name.
+    RemoteTab(String),
 }
 
 #[derive(Debug, Clone)]
@@ -565,4 +587,32 @@ mod tests {
         }).expect("Should search by adaptive input history");
         println!("Matches by adaptive input history: {:?}", by_adaptive);
     }
+
+    struct FixedProvider(Url);
+
+    impl SearchProvider for FixedProvider {
+        fn search(&self, params: &SearchParams) -> Result<Vec<SearchResult>> {
+            Ok(vec![SearchResult {
+                search_string: params.search_string.clone(),
+                url: self.0.clone(),
+                title: "Fixed".into(),
+                icon_url: None,
+                frecency: 0,
+                reasons: vec![MatchReason::RemoteTab("Other device".into())],
+            }])
+        }
+    }
+
+    #[test]
+    fn search_providers() {
+        let conn = PlacesDb::open_in_memory(None).expect("no memory db");
+        let url = Url::parse("http://example.com/remote").unwrap();
+        let provider = FixedProvider(url.clone());
+        let matches = search_frecent_with_providers(&conn, SearchParams {
+            search_string: "remote".into(),
+            limit: 10,
+        }, &[&provider]).expect("Should search with providers");
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].url, url);
+    }
 }
@@ -0,0 +1,397 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// A structure-aware three-way merger for bookmark trees. This is a
+// (much simplified) version of the algorithm used by desktop.
+//
+// We walk the tree from the root. For each item we decide whose value to
+// take (local or remote), and for each folder we decide the order of its
+// children. An item which appears in different folders on each side is
+// placed in whichever folder changed most recently. Deletions win unless
+// the other side changed the item, in which case the item is revived.
+// Items which are left without a parent (eg, because their folder was
+// deleted on the other side) are moved to unfiled if they've changed, and
+// deleted otherwise.
+
+use std::collections::{HashMap, HashSet};
+
+use api::bookmarks::{is_root_guid, ROOT_GUID, UNFILED_GUID};
+use types::{BookmarkType, SyncGuid};
+use super::tree::{Item, Tree};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MergeState {
+    // The local value wins.
+    Local,
+    // The remote value wins.
+    Remote,
+}
+
+#[derive(Debug, Clone)]
+pub struct MergedNode {
+    pub guid: SyncGuid,
+    pub parent_guid: Option<SyncGuid>,
+    pub merge_state: MergeState,
+    // The winning value, with the merged children.
+    pub item: Item,
+}
+
+#[derive(Debug, Default)]
+pub struct MergeResult {
+    // All the items in the merged tree, parents before their children.
+    pub nodes: Vec<MergedNode>,
+    // Items which exist locally and need to be deleted.
+    pub delete_locally: HashSet<SyncGuid>,
+    // Items which exist on the server and need a tombstone uploaded.
+    pub delete_remotely: HashSet<SyncGuid>,
+}
+
+pub struct Merger<'t> {
+    local: &'t Tree,
+    remote: &'t Tree,
+    placed: HashSet<SyncGuid>,
+    merged: HashMap<SyncGuid, MergedNode>,
+    order: Vec<SyncGuid>,
+    delete_locally: HashSet<SyncGuid>,
+    delete_remotely: HashSet<SyncGuid>,
+}
+
+impl<'t> Merger<'t> {
+    pub fn new(local: &'t Tree, remote: &'t Tree) -> Self {
+        Merger {
+            local,
+            remote,
+            placed: HashSet::new(),
+            merged: HashMap::new(),
+            order: Vec::new(),
+            delete_locally: HashSet::new(),
+            delete_remotely: HashSet::new(),
+        }
+    }
+
+    pub fn merge(mut self) -> MergeResult {
+        let root_guid = SyncGuid::from(ROOT_GUID);
+        self.placed.insert(root_guid.clone());
+        self.merge_node(&root_guid, None);
+
+        // Anything we didn't place was either in a folder which was deleted,
+        // or in a folder we never reached.
+        let mut unplaced: Vec<SyncGuid> = self.local.items.keys()
+            .chain(self.remote.items.keys())
+            .filter(|guid| !self.placed.contains(*guid))
+            .cloned()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        unplaced.sort_by(|a, b| a.0.cmp(&b.0));
+
+        // Changed items are moved to unfiled. We do this first as moving a
+        // folder places its children too.
+        let unfiled_guid = SyncGuid::from(UNFILED_GUID);
+        for guid in &unplaced {
+            if self.placed.contains(guid) || self.is_deleted(guid) || !self.is_changed(guid) {
+                continue;
+            }
+            debug!("Moving unplaced item {:?} to unfiled", guid);
+            self.placed.insert(guid.clone());
+            self.merged.get_mut(&unfiled_guid)
+                .expect("unfiled is always merged")
+                .item.children.push(guid.clone());
+            self.merge_node(guid, Some(&unfiled_guid));
+        }
+        // And everything else is deleted.
+        for guid in unplaced {
+            if !self.placed.contains(&guid) {
+                debug!("Deleting unplaced item {:?}", guid);
+                self.note_deleted(&guid);
+            }
+        }
+
+        let Merger { mut merged, order, delete_locally, delete_remotely, .. } = self;
+        MergeResult {
+            nodes: order.into_iter().map(|guid| merged.remove(&guid).expect("merged node")).collect(),
+            delete_locally,
+            delete_remotely,
+        }
+    }
+
+    fn is_changed(&self, guid: &SyncGuid) -> bool {
+        self.local.get(guid).map(|item| item.changed).unwrap_or(false) ||
+        self.remote.get(guid).map(|item| item.changed).unwrap_or(false)
+    }
+
+    // An item deleted on one side stays deleted, unless the other side
+    // changed it. Deleted folders always stay deleted - any changed children
+    // end up in unfiled.
+    fn is_deleted(&self, guid: &SyncGuid) -> bool {
+        fn revives(item: Option<&Item>) -> bool {
+            item.map(|item| item.changed && item.kind != BookmarkType::Folder).unwrap_or(false)
+        }
+        if is_root_guid(guid) {
+            return false;
+        }
+        (self.local.deleted.contains(guid) && !revives(self.remote.get(guid))) ||
+        (self.remote.deleted.contains(guid) && !revives(self.local.get(guid)))
+    }
+
+    fn note_deleted(&mut self, guid: &SyncGuid) {
+        if self.local.items.contains_key(guid) {
+            self.delete_locally.insert(guid.clone());
+        }
+        if self.remote.items.contains_key(guid) {
+            self.delete_remotely.insert(guid.clone());
+        }
+    }
+
+    fn merge_node(&mut self, guid: &SyncGuid, parent_guid: Option<&SyncGuid>) {
+        // Note we don't borrow these via `self`, as we need to mutate it.
+        let (local_tree, remote_tree): (&'t Tree, &'t Tree) = (self.local, self.remote);
+        let local = local_tree.get(guid);
+        let remote = remote_tree.get(guid);
+        let (merge_state, value) = match (local, remote) {
+            (Some(l), None) => (MergeState::Local, l),
+            (None, Some(r)) => (MergeState::Remote, r),
+            (Some(l), Some(r)) => {
+                if l.changed && r.changed {
+                    // A value conflict - the newest change wins.
+                    if r.modified > l.modified {
+                        (MergeState::Remote, r)
+                    } else {
+                        (MergeState::Local, l)
+                    }
+                } else if r.changed {
+                    (MergeState::Remote, r)
+                } else {
+                    (MergeState::Local, l)
+                }
+            },
+            (None, None) => unreachable!("merging an item which doesn't exist"),
+        };
+        let mut item = value.clone();
+        item.children = Vec::new();
+        self.order.push(guid.clone());
+        self.merged.insert(guid.clone(), MergedNode {
+            guid: guid.clone(),
+            parent_guid: parent_guid.cloned(),
+            merge_state,
+            item,
+        });
+        if value.kind != BookmarkType::Folder {
+            return;
+        }
+
+        // A structure conflict - the children of the folder which changed
+        // most recently go first, followed by any new children from the
+        // other side.
+        let empty = Vec::new();
+        let local_children = local.filter(|l| l.kind == BookmarkType::Folder)
+            .map(|l| &l.children).unwrap_or(&empty);
+        let remote_children = remote.filter(|r| r.kind == BookmarkType::Folder)
+            .map(|r| &r.children).unwrap_or(&empty);
+        let remote_first = match (local, remote) {
+            (Some(l), Some(r)) if l.changed && r.changed => r.modified > l.modified,
+            (_, Some(r)) => r.changed || local.is_none(),
+            _ => false,
+        };
+        let candidates: Vec<SyncGuid> = if remote_first {
+            remote_children.iter().chain(local_children.iter()).cloned().collect()
+        } else {
+            local_children.iter().chain(remote_children.iter()).cloned().collect()
+        };
+
+        for child in candidates {
+            if self.placed.contains(&child) {
+                continue;
+            }
+            if self.is_deleted(&child) {
+                self.note_deleted(&child);
+                continue;
+            }
+            if !self.belongs_in(&child, guid) {
+                continue;
+            }
+            self.placed.insert(child.clone());
+            self.merged.get_mut(guid).expect("just inserted").item.children.push(child.clone());
+            self.merge_node(&child, Some(guid));
+        }
+    }
+
+    // Should `child` be placed in `folder`? It should unless it lives in a
+    // different folder on the other side, and that folder changed more
+    // recently.
+    fn belongs_in(&self, child: &SyncGuid, folder: &SyncGuid) -> bool {
+        let local_parent = self.local.parent_of(child);
+        let remote_parent = self.remote.parent_of(child);
+        let (lp, rp) = match (local_parent, remote_parent) {
+            (Some(lp), Some(rp)) if lp != rp => (lp, rp),
+            _ => return true,
+        };
+        let lp_item = self.local.get(lp);
+        let rp_item = self.remote.get(rp);
+        let lp_changed = lp_item.map(|item| item.changed).unwrap_or(false);
+        let rp_changed = rp_item.map(|item| item.changed).unwrap_or(false);
+        let winner = if rp_changed && !lp_changed {
+            rp
+        } else if lp_changed && !rp_changed {
+            lp
+        } else if lp_changed && rp_changed {
+            let lp_modified = lp_item.map(|item| item.modified).unwrap_or_default();
+            let rp_modified = rp_item.map(|item| item.modified).unwrap_or_default();
+            if rp_modified > lp_modified { rp } else { lp }
+        } else {
+            // Neither parent changed, which shouldn't really happen. The
+            // server is the source of truth.
+            rp
+        };
+        winner == folder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use api::bookmarks::{MENU_GUID, TOOLBAR_GUID, MOBILE_GUID};
+    use types::Timestamp;
+
+    fn folder(guid: &str, changed: bool, modified: u64, children: &[&str]) -> Item {
+        Item {
+            guid: guid.into(),
+            kind: BookmarkType::Folder,
+            title: None,
+            url: None,
+            date_added: Timestamp(0),
+            modified: Timestamp(modified),
+            changed,
+            children: children.iter().map(|c| SyncGuid::from(*c)).collect(),
+        }
+    }
+
+    fn bookmark(guid: &str, changed: bool, modified: u64, title: &str) -> Item {
+        Item {
+            guid: guid.into(),
+            kind: BookmarkType::Bookmark,
+            title: Some(title.into()),
+            url: Some("http://example.com/".into()),
+            date_added: Timestamp(0),
+            modified: Timestamp(modified),
+            changed,
+            children: Vec::new(),
+        }
+    }
+
+    // Makes a tree with the standard roots, plus the given items.
+    fn make_tree(items: Vec<Item>, deleted: &[&str]) -> Tree {
+        let mut tree = Tree::default();
+        for guid in &[ROOT_GUID, MENU_GUID, TOOLBAR_GUID, UNFILED_GUID, MOBILE_GUID] {
+            tree.items.insert(SyncGuid::from(*guid), folder(guid, false, 0, &[]));
+        }
+        tree.items.get_mut(&SyncGuid::from(ROOT_GUID)).unwrap().children =
+            vec![MENU_GUID.into(), TOOLBAR_GUID.into(), UNFILED_GUID.into(), MOBILE_GUID.into()];
+        for item in items {
+            tree.items.insert(item.guid.clone(), item);
+        }
+        tree.deleted = deleted.iter().map(|g| SyncGuid::from(*g)).collect();
+        tree.link_parents();
+        tree
+    }
+
+    fn children_of(result: &MergeResult, guid: &str) -> Vec<String> {
+        result.nodes.iter()
+            .find(|n| n.guid.0 == guid)
+            .expect("should be merged")
+            .item.children.iter().map(|c| c.0.clone()).collect()
+    }
+
+    fn find<'a>(result: &'a MergeResult, guid: &str) -> Option<&'a MergedNode> {
+        result.nodes.iter().find(|n| n.guid.0 == guid)
+    }
+
+    #[test]
+    fn test_new_on_both_sides() {
+        let local = make_tree(vec![
+            folder(MENU_GUID, true, 10, &["bookmarkAAAA"]),
+            bookmark("bookmarkAAAA", true, 10, "A"),
+        ], &[]);
+        let remote = make_tree(vec![
+            folder(MENU_GUID, true, 20, &["bookmarkBBBB"]),
+            bookmark("bookmarkBBBB", true, 20, "B"),
+        ], &[]);
+        let result = Merger::new(&local, &remote).merge();
+        // The remote menu is newer, so its children come first.
+        assert_eq!(children_of(&result, MENU_GUID), vec!["bookmarkBBBB", "bookmarkAAAA"]);
+        assert_eq!(find(&result, "bookmarkAAAA").unwrap().merge_state, MergeState::Local);
+        assert_eq!(find(&result, "bookmarkBBBB").unwrap().merge_state, MergeState::Remote);
+    }
+
+    #[test]
+    fn test_value_conflict() {
+        let local = make_tree(vec![
+            folder(MENU_GUID, false, 0, &["bookmarkAAAA"]),
+            bookmark("bookmarkAAAA", true, 20, "local"),
+        ], &[]);
+        let remote = make_tree(vec![
+            folder(MENU_GUID, false, 0, &["bookmarkAAAA"]),
+            bookmark("bookmarkAAAA", true, 10, "remote"),
+        ], &[]);
+        let result = Merger::new(&local, &remote).merge();
+        let node = find(&result, "bookmarkAAAA").unwrap();
+        assert_eq!(node.merge_state, MergeState::Local);
+        assert_eq!(node.item.title, Some("local".into()));
+    }
+
+    #[test]
+    fn test_moved_remotely() {
+        let local = make_tree(vec![
+            folder(MENU_GUID, false, 0, &["bookmarkAAAA"]),
+            bookmark("bookmarkAAAA", false, 0, "A"),
+        ], &[]);
+        let remote = make_tree(vec![
+            folder(MENU_GUID, true, 10, &[]),
+            folder(TOOLBAR_GUID, true, 10, &["bookmarkAAAA"]),
+            bookmark("bookmarkAAAA", false, 0, "A"),
+        ], &[]);
+        let result = Merger::new(&local, &remote).merge();
+        assert!(children_of(&result, MENU_GUID).is_empty());
+        assert_eq!(children_of(&result, TOOLBAR_GUID), vec!["bookmarkAAAA"]);
+    }
+
+    #[test]
+    fn test_deletions() {
+        // A deleted remotely, B deleted locally, C deleted remotely but
+        // changed locally.
+        let local = make_tree(vec![
+            folder(MENU_GUID, true, 10, &["bookmarkAAAA", "bookmarkCCCC"]),
+            bookmark("bookmarkAAAA", false, 0, "A"),
+            bookmark("bookmarkCCCC", true, 10, "C"),
+        ], &["bookmarkBBBB"]);
+        let remote = make_tree(vec![
+            folder(MENU_GUID, true, 5, &["bookmarkBBBB"]),
+            bookmark("bookmarkBBBB", false, 0, "B"),
+        ], &["bookmarkAAAA", "bookmarkCCCC"]);
+        let result = Merger::new(&local, &remote).merge();
+        assert_eq!(children_of(&result, MENU_GUID), vec!["bookmarkCCCC"]);
+        assert!(result.delete_locally.contains(&SyncGuid::from("bookmarkAAAA")));
+        assert!(result.delete_remotely.contains(&SyncGuid::from("bookmarkBBBB")));
+    }
+
+    #[test]
+    fn test_deleted_folder_with_new_child() {
+        // The folder was deleted remotely, but a new bookmark was added to
+        // it locally, so the bookmark is moved to unfiled.
+        let local = make_tree(vec![
+            folder(MENU_GUID, false, 0, &["folderAAAAAA"]),
+            folder("folderAAAAAA", true, 10, &["bookmarkBBBB", "bookmarkCCCC"]),
+            bookmark("bookmarkBBBB", false, 0, "B"),
+            bookmark("bookmarkCCCC", true, 10, "C"),
+        ], &[]);
+        let remote = make_tree(vec![
+            folder(MENU_GUID, true, 5, &[]),
+        ], &["folderAAAAAA", "bookmarkBBBB"]);
+        let result = Merger::new(&local, &remote).merge();
+        assert!(children_of(&result, MENU_GUID).is_empty());
+        assert_eq!(children_of(&result, UNFILED_GUID), vec!["bookmarkCCCC"]);
+        assert!(result.delete_locally.contains(&SyncGuid::from("folderAAAAAA")));
+        assert!(result.delete_locally.contains(&SyncGuid::from("bookmarkBBBB")));
+    }
+}
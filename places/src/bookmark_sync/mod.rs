@@ -0,0 +1,25 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// The bookmarks sync engine.
+//
+// Incoming records are first staged in a "mirror" (`moz_bookmarks_synced`),
+// which holds a copy of the tree on the server. Only once every incoming
+// record has been staged do we build the local and remote trees and run a
+// structure-aware three-way merge between them - so a partial download never
+// gets applied to the local tree. Local changes are tracked by the
+// `syncChangeCounter` column of `moz_bookmarks`, remote changes by the
+// `needsMerge` flag in the mirror, and the mirror itself acts as the shared
+// parent.
+
+mod merge;
+mod record;
+mod store;
+mod tree;
+
+pub use self::record::{BookmarkItemRecord, BookmarkRecord, FolderRecord, SeparatorRecord};
+pub use self::store::BookmarksStore;
+
+// The name of the collection on the server.
+pub const COLLECTION_NAME: &'static str = "bookmarks";
@@ -0,0 +1,184 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use api::bookmarks::{ROOT_GUID, MENU_GUID, TOOLBAR_GUID, UNFILED_GUID, MOBILE_GUID};
+use types::SyncGuid;
+
+// The records in the bookmarks collection. Note that the server uses
+// different ids for the roots than we use for their guids.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BookmarkItemRecord {
+    Bookmark(BookmarkRecord),
+    // Queries are just bookmarks with a `place:` URL as far as we're
+    // concerned.
+    Query(BookmarkRecord),
+    Folder(FolderRecord),
+    Separator(SeparatorRecord),
+}
+
+impl BookmarkItemRecord {
+    pub fn id(&self) -> &str {
+        match *self {
+            BookmarkItemRecord::Bookmark(ref b) => &b.id,
+            BookmarkItemRecord::Query(ref b) => &b.id,
+            BookmarkItemRecord::Folder(ref f) => &f.id,
+            BookmarkItemRecord::Separator(ref s) => &s.id,
+        }
+    }
+
+    pub fn parent_id(&self) -> Option<&str> {
+        match *self {
+            BookmarkItemRecord::Bookmark(ref b) => b.parent_id.as_ref(),
+            BookmarkItemRecord::Query(ref b) => b.parent_id.as_ref(),
+            BookmarkItemRecord::Folder(ref f) => f.parent_id.as_ref(),
+            BookmarkItemRecord::Separator(ref s) => s.parent_id.as_ref(),
+        }.map(|id| id.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BookmarkRecord {
+    pub id: String,
+
+    #[serde(rename = "parentid")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+
+    #[serde(rename = "parentName")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_name: Option<String>,
+
+    #[serde(rename = "dateAdded")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_added: Option<u64>,
+
+    #[serde(default)]
+    pub title: Option<String>,
+
+    #[serde(rename = "bmkUri")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyword: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FolderRecord {
+    pub id: String,
+
+    #[serde(rename = "parentid")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+
+    #[serde(rename = "parentName")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_name: Option<String>,
+
+    #[serde(rename = "dateAdded")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_added: Option<u64>,
+
+    #[serde(default)]
+    pub title: Option<String>,
+
+    #[serde(default)]
+    pub children: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SeparatorRecord {
+    pub id: String,
+
+    #[serde(rename = "parentid")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+
+    #[serde(rename = "parentName")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_name: Option<String>,
+
+    #[serde(rename = "dateAdded")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_added: Option<u64>,
+
+    // Not used by us, but desktop uses it for deduping.
+    #[serde(rename = "pos")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<u32>,
+}
+
+// The mapping between the ids the server uses for the roots and our guids.
+const ROOT_ID_MAP: &[(&str, &str)] = &[
+    ("places", ROOT_GUID),
+    ("menu", MENU_GUID),
+    ("toolbar", TOOLBAR_GUID),
+    ("unfiled", UNFILED_GUID),
+    ("mobile", MOBILE_GUID),
+];
+
+pub fn record_id_to_guid(id: &str) -> SyncGuid {
+    for &(record_id, guid) in ROOT_ID_MAP {
+        if id == record_id {
+            return guid.into();
+        }
+    }
+    id.into()
+}
+
+pub fn guid_to_record_id(guid: &SyncGuid) -> String {
+    for &(record_id, root_guid) in ROOT_ID_MAP {
+        if guid.0 == root_guid {
+            return record_id.into();
+        }
+    }
+    guid.0.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn test_root_ids() {
+        assert_eq!(record_id_to_guid("menu"), SyncGuid::from(MENU_GUID));
+        assert_eq!(record_id_to_guid("aaaaaaaaaaaa"), SyncGuid::from("aaaaaaaaaaaa"));
+        assert_eq!(guid_to_record_id(&ROOT_GUID.into()), "places");
+        assert_eq!(guid_to_record_id(&"aaaaaaaaaaaa".into()), "aaaaaaaaaaaa");
+    }
+
+    #[test]
+    fn test_record_json() {
+        let record: BookmarkItemRecord = serde_json::from_value(json!({
+            "id": "aaaaaaaaaaaa",
+            "type": "folder",
+            "parentid": "menu",
+            "title": "A folder",
+            "children": ["bbbbbbbbbbbb"],
+        })).expect("should deserialize");
+        match record {
+            BookmarkItemRecord::Folder(ref f) => {
+                assert_eq!(f.children, vec!["bbbbbbbbbbbb".to_string()]);
+                assert_eq!(f.title, Some("A folder".to_string()));
+            },
+            _ => panic!("not a folder"),
+        }
+        assert_eq!(record.parent_id(), Some("menu"));
+
+        let query: BookmarkItemRecord = serde_json::from_value(json!({
+            "id": "cccccccccccc",
+            "type": "query",
+            "bmkUri": "place:sort=8",
+        })).expect("should deserialize");
+        let json = serde_json::to_value(&query).unwrap();
+        assert_eq!(json["type"], "query");
+        assert_eq!(json["bmkUri"], "place:sort=8");
+    }
+}
@@ -0,0 +1,903 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::{HashMap, HashSet};
+
+use rusqlite::{Connection, Row};
+use serde_json::{self, Value as JsonValue};
+use url::Url;
+
+use sql_support::ConnExt;
+use failure;
+use sync::{self, Store, IncomingChangeset, OutgoingChangeset, Payload, ServerTimestamp,
+           GlobalState, Sync15StorageClient, SyncEngine};
+use sync::telemetry;
+
+use api::bookmarks::{self, ROOT_GUID};
+use db::PlacesDb;
+use db::schema;
+use error::*;
+use storage::{self, RowId};
+use types::{BookmarkType, SyncGuid, SyncStatus, Timestamp};
+use super::COLLECTION_NAME;
+use super::merge::{Merger, MergeResult, MergeState};
+use super::record::{BookmarkItemRecord, BookmarkRecord, FolderRecord, SeparatorRecord,
+                    record_id_to_guid, guid_to_record_id};
+use super::tree::{self, MirrorStructure, Tree};
+
+// A `sync15_adapter::Store` for bookmarks.
+pub struct BookmarksStore<'a> {
+    pub db: &'a mut PlacesDb,
+}
+
+impl<'a> BookmarksStore<'a> {
+    pub fn new(db: &'a mut PlacesDb) -> Self {
+        Self { db }
+    }
+
+    pub fn get_last_sync(&self) -> Result<Option<ServerTimestamp>> {
+        Ok(storage::get_meta::<i64>(&*self.db, schema::MOZ_META_KEY_BOOKMARKS_LAST_SYNC)?
+            .map(|millis| ServerTimestamp(millis as f64 / 1000.0)))
+    }
+
+    pub fn set_last_sync(&self, last_sync: ServerTimestamp) -> Result<()> {
+        debug!("Updating bookmarks last sync to {}", last_sync);
+        let last_sync_millis = last_sync.as_millis() as i64;
+        storage::put_meta(&*self.db, schema::MOZ_META_KEY_BOOKMARKS_LAST_SYNC, &last_sync_millis)
+    }
+
+    pub fn get_sync_id(&self) -> Result<Option<String>> {
+        storage::get_meta::<String>(&*self.db, schema::MOZ_META_KEY_BOOKMARKS_SYNC_ID)
+    }
+
+    pub fn set_sync_id(&self, sync_id: &str) -> Result<()> {
+        storage::put_meta(&*self.db, schema::MOZ_META_KEY_BOOKMARKS_SYNC_ID, &sync_id)
+    }
+
+    // Forget everything we know about the server - the mirror is discarded
+    // and every local item will be uploaded on the next sync.
+    pub fn reset(&mut self) -> Result<()> {
+        self.reset_sync_state(true)
+    }
+
+    // Like `reset`, but keeps our sync ID if `forget_sync_id` is false. We
+    // do this when we've just adopted a new sync ID from `meta/global`.
+    fn reset_sync_state(&mut self, forget_sync_id: bool) -> Result<()> {
+        info!("Resetting bookmarks sync state");
+        let tx = self.db.db.transaction()?;
+        tx.execute_all(&[
+            "DELETE FROM moz_bookmarks_synced",
+            "DELETE FROM moz_bookmarks_synced_structure",
+            "DELETE FROM moz_bookmarks_deleted",
+        ])?;
+        tx.execute_named_cached(
+            "UPDATE moz_bookmarks SET syncChangeCounter = 1, syncStatus = :status",
+            &[(":status", &SyncStatus::New)]
+        )?;
+        storage::delete_meta(&tx, schema::MOZ_META_KEY_BOOKMARKS_LAST_SYNC)?;
+        if forget_sync_id {
+            storage::delete_meta(&tx, schema::MOZ_META_KEY_BOOKMARKS_SYNC_ID)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    // Delete all bookmarks other than the roots, along with any places which
+    // are no longer needed.
+    pub fn wipe(&mut self) -> Result<()> {
+        info!("Wiping bookmarks");
+        {
+            let tx = self.db.db.transaction()?;
+            tx.execute_named_cached("
+                DELETE FROM moz_bookmarks
+                WHERE parent <> (SELECT id FROM moz_bookmarks WHERE guid = :root)",
+                &[(":root", &ROOT_GUID)]
+            )?;
+            tx.execute_all(&[
+                "DELETE FROM moz_places
+                 WHERE foreign_count = 0
+                   AND NOT EXISTS(SELECT 1 FROM moz_historyvisits WHERE place_id = moz_places.id)",
+            ])?;
+            tx.commit()?;
+        }
+        self.reset()
+    }
+
+    // Adopts the sync ID from `meta/global`, resetting if it changed and
+    // `synchronize` won't reset us anyway (see `HistoryStore::adopt_sync_id`).
+    fn adopt_sync_id(&mut self, remote_sync_id: &str, will_reset: bool) -> Result<()> {
+        let local_sync_id = self.get_sync_id()?;
+        if local_sync_id.as_ref().map(|id| id.as_str()) == Some(remote_sync_id) {
+            return Ok(());
+        }
+        if local_sync_id.is_some() && !will_reset {
+            info!("Bookmarks sync ID changed; engine needs local reset");
+            self.reset_sync_state(false)?;
+        }
+        self.set_sync_id(remote_sync_id)
+    }
+
+    // Sync bookmarks. The caller is responsible for getting `state` to ready
+    // (ie, via `sync::SetupStateMachine`).
+    pub fn sync(
+        &mut self,
+        client: &Sync15StorageClient,
+        state: &GlobalState,
+        telem_engine: &mut telemetry::Engine,
+    ) -> Result<()> {
+        let remote_sync_id = state.global.as_ref()
+            .and_then(|g| g.payload.engines.get(COLLECTION_NAME))
+            .map(|engine| engine.sync_id.clone());
+        if let Some(remote_sync_id) = remote_sync_id {
+            let will_reset = state.engines_that_need_local_reset().contains(COLLECTION_NAME);
+            self.adopt_sync_id(&remote_sync_id, will_reset)?;
+        }
+
+        let ts = self.get_last_sync()?.unwrap_or_default();
+        info!("Syncing bookmarks engine!");
+        // Bookmarks are uploaded atomically so other clients never see a
+        // partial tree.
+        let result = sync::synchronize(
+            client,
+            state,
+            self,
+            COLLECTION_NAME.into(),
+            ts,
+            true,
+            telem_engine
+        );
+        match &result {
+            Ok(()) => info!("Bookmarks sync was successful!"),
+            Err(e) => warn!("Bookmarks sync failed! {:?}", e),
+        }
+        result
+    }
+
+    fn do_apply_incoming(
+        &mut self,
+        inbound: IncomingChangeset,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> Result<OutgoingChangeset> {
+        let tx = self.db.db.transaction()?;
+        info!("Staging {} incoming bookmark records", inbound.changes.len());
+        for (payload, modified) in inbound.changes {
+            stage_incoming(&tx, payload, modified, incoming_telemetry)?;
+        }
+        // Everything is staged, so we can merge.
+        merge_mirror(&tx)?;
+        let outgoing = fetch_outgoing(&tx, inbound.collection, inbound.timestamp)?;
+        tx.commit()?;
+        Ok(outgoing)
+    }
+
+    fn mark_as_synchronized(&mut self, ids: &[String], ts: ServerTimestamp) -> Result<()> {
+        let tx = self.db.db.transaction()?;
+        let modified = Timestamp(ts.as_millis());
+        for id in ids {
+            let guid = record_id_to_guid(id);
+            let removed = tx.execute_named_cached(
+                "DELETE FROM moz_bookmarks_deleted WHERE guid = :guid",
+                &[(":guid", &guid)]
+            )?;
+            if removed > 0 {
+                stage_tombstone(&tx, &guid, modified, false)?;
+            } else {
+                update_mirror_from_local(&tx, &guid, modified)?;
+            }
+        }
+        tx.commit()?;
+        self.set_last_sync(ts)?;
+        Ok(())
+    }
+}
+
+impl<'a> Store for BookmarksStore<'a> {
+    type Error = Error;
+
+    fn apply_incoming(
+        &mut self,
+        inbound: IncomingChangeset,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> Result<OutgoingChangeset> {
+        self.do_apply_incoming(inbound, incoming_telemetry)
+    }
+
+    fn sync_finished(
+        &mut self,
+        new_timestamp: ServerTimestamp,
+        records_synced: &[String],
+    ) -> Result<()> {
+        self.mark_as_synchronized(records_synced, new_timestamp)
+    }
+
+    // `sync` has already adopted the new sync ID, so we keep it.
+    fn reset(&mut self) -> Result<()> {
+        self.reset_sync_state(false)
+    }
+}
+
+// The fields of bookmark records that we store in their own columns in the
+// mirror, or work out ourselves when uploading. We keep the others (like
+// tags, keywords, and the details of queries) as they are.
+const KNOWN_FIELDS: &[&str] = &[
+    "type", "parentid", "parentName", "dateAdded", "title", "bmkUri", "children", "pos",
+];
+
+// Returns the fields of `payload` that we don't store ourselves, as a JSON
+// object, or `None` if there aren't any.
+fn unknown_fields(payload: &Payload) -> Option<String> {
+    let fields: serde_json::Map<String, JsonValue> = payload.data.iter()
+        .filter(|&(name, _)| !KNOWN_FIELDS.contains(&name.as_str()))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    if fields.is_empty() {
+        None
+    } else {
+        Some(JsonValue::Object(fields).to_string())
+    }
+}
+
+// Adds the fields saved by `unknown_fields` back to an outgoing record.
+fn add_unknown_fields(payload: &mut Payload, unknown_fields: &str) -> Result<()> {
+    let fields: serde_json::Map<String, JsonValue> = serde_json::from_str(unknown_fields)?;
+    for (name, value) in fields {
+        if !payload.data.contains_key(&name) {
+            payload.data.insert(name, value);
+        }
+    }
+    Ok(())
+}
+
+fn stage_incoming(
+    conn: &Connection,
+    payload: Payload,
+    modified: ServerTimestamp,
+    incoming_telemetry: &mut telemetry::EngineIncoming,
+) -> Result<()> {
+    let guid = record_id_to_guid(payload.id());
+    if guid.0 == ROOT_GUID {
+        // We never sync the root itself.
+        return Ok(());
+    }
+    if guid.0.len() != 12 {
+        warn!("Ignoring bookmark record with invalid id {:?}", payload.id());
+        incoming_telemetry.failed(1);
+        return Ok(());
+    }
+    let modified = Timestamp(modified.as_millis());
+    if payload.is_tombstone() {
+        stage_tombstone(conn, &guid, modified, true)?;
+        incoming_telemetry.applied(1);
+        return Ok(());
+    }
+    let unknown_fields = unknown_fields(&payload);
+    let record: BookmarkItemRecord = match payload.into_record() {
+        Ok(record) => record,
+        Err(e) => {
+            warn!("Ignoring invalid bookmark record {:?}: {}", guid, e);
+            incoming_telemetry.failed(1);
+            return Ok(());
+        }
+    };
+    let parent_guid = record.parent_id().map(record_id_to_guid);
+    let (kind, title, url, date_added, children) = match record {
+        BookmarkItemRecord::Bookmark(b) | BookmarkItemRecord::Query(b) => {
+            match b.url.as_ref().map(|url| Url::parse(url)) {
+                Some(Ok(_)) => {},
+                _ => {
+                    warn!("Ignoring bookmark {:?} with invalid URL", guid);
+                    incoming_telemetry.failed(1);
+                    return Ok(());
+                }
+            }
+            (BookmarkType::Bookmark, b.title, b.url, b.date_added, None)
+        },
+        BookmarkItemRecord::Folder(f) =>
+            (BookmarkType::Folder, f.title, None, f.date_added, Some(f.children)),
+        BookmarkItemRecord::Separator(s) =>
+            (BookmarkType::Separator, None, None, s.date_added, None),
+    };
+    conn.execute_named_cached("
+        REPLACE INTO moz_bookmarks_synced
+            (guid, parentGuid, serverModified, needsMerge, isDeleted,
+             kind, dateAdded, title, url, unknownFields)
+        VALUES
+            (:guid, :parentGuid, :serverModified, 1, 0,
+             :kind, :dateAdded, :title, :url, :unknownFields)",
+        &[(":guid", &guid),
+          (":parentGuid", &parent_guid),
+          (":serverModified", &modified),
+          (":kind", &kind),
+          (":dateAdded", &(date_added.unwrap_or(0) as i64)),
+          (":title", &title),
+          (":url", &url),
+          (":unknownFields", &unknown_fields)]
+    )?;
+    conn.execute_named_cached(
+        "DELETE FROM moz_bookmarks_synced_structure WHERE parentGuid = :guid",
+        &[(":guid", &guid)]
+    )?;
+    if let Some(children) = children {
+        for (position, child_id) in children.iter().enumerate() {
+            conn.execute_named_cached("
+                INSERT OR IGNORE INTO moz_bookmarks_synced_structure
+                    (guid, parentGuid, position)
+                VALUES (:guid, :parentGuid, :position)",
+                &[(":guid", &record_id_to_guid(child_id)),
+                  (":parentGuid", &guid),
+                  (":position", &(position as i64))]
+            )?;
+        }
+    }
+    incoming_telemetry.applied(1);
+    Ok(())
+}
+
+fn stage_tombstone(conn: &Connection, guid: &SyncGuid, modified: Timestamp, needs_merge: bool) -> Result<()> {
+    conn.execute_named_cached("
+        REPLACE INTO moz_bookmarks_synced
+            (guid, serverModified, needsMerge, isDeleted)
+        VALUES
+            (:guid, :serverModified, :needsMerge, 1)",
+        &[(":guid", guid),
+          (":serverModified", &modified),
+          (":needsMerge", &needs_merge)]
+    )?;
+    conn.execute_named_cached(
+        "DELETE FROM moz_bookmarks_synced_structure WHERE parentGuid = :guid",
+        &[(":guid", guid)]
+    )?;
+    Ok(())
+}
+
+// Merges the local tree with the (fully staged) mirror and applies the
+// result locally. Items which need to be uploaded are left with a non-zero
+// change counter.
+fn merge_mirror(conn: &Connection) -> Result<()> {
+    let local = tree::fetch_local_tree(conn)?;
+    let (remote, structure) = tree::fetch_remote_tree(conn)?;
+    let result = Merger::new(&local, &remote).merge();
+    debug!("Merged {} items, deleting {} locally and {} remotely",
+           result.nodes.len(), result.delete_locally.len(), result.delete_remotely.len());
+    apply_merge_result(conn, &local, &structure, result)?;
+    conn.execute_named_cached(
+        "UPDATE moz_bookmarks_synced SET needsMerge = 0 WHERE needsMerge",
+        &[]
+    )?;
+    Ok(())
+}
+
+// Returns the (parent, position) of every non-root item in a tree, given
+// each folder's children.
+fn positions<'a, I>(folders: I) -> HashMap<SyncGuid, (SyncGuid, u32)>
+where I: Iterator<Item = (&'a SyncGuid, &'a Vec<SyncGuid>)>
+{
+    let mut result = HashMap::new();
+    for (parent, children) in folders {
+        for (position, child) in children.iter().enumerate() {
+            result.insert(child.clone(), (parent.clone(), position as u32));
+        }
+    }
+    result
+}
+
+fn apply_merge_result(conn: &Connection,
+                      local: &Tree,
+                      structure: &MirrorStructure,
+                      result: MergeResult) -> Result<()> {
+    let now = Timestamp::now();
+    let root_guid = SyncGuid::from(ROOT_GUID);
+    let local_positions = positions(local.items.values().map(|item| (&item.guid, &item.children)));
+    let merged_positions = positions(result.nodes.iter().map(|node| (&node.guid, &node.item.children)));
+    let mut changed_places: Vec<RowId> = Vec::new();
+
+    for node in &result.nodes {
+        let guid = &node.guid;
+        let local_item = local.get(guid);
+        let is_root = *guid == root_guid;
+
+        // Does the server need a new record for this item?
+        let needs_upload = !is_root && (
+            !structure.parents.contains_key(guid) ||
+            (node.merge_state == MergeState::Local && local_item.map(|i| i.changed).unwrap_or(false)) ||
+            structure.parents.get(guid) != Some(&node.parent_guid) ||
+            (node.item.kind == BookmarkType::Folder &&
+             structure.children.get(guid).unwrap_or(&Vec::new()) != &node.item.children)
+        );
+        let (sync_status, change_counter) = if needs_upload {
+            let status = if structure.parents.contains_key(guid) { SyncStatus::Normal } else { SyncStatus::New };
+            (status, 1)
+        } else {
+            (SyncStatus::Normal, 0)
+        };
+        let merged_position = merged_positions.get(guid);
+
+        let local_item = match local_item {
+            Some(item) => item,
+            None => {
+                // A new item from the server.
+                let (parent_guid, position) = merged_position.cloned()
+                    .expect("only the root has no parent, and it exists locally");
+                let place_id = match (node.item.kind, &node.item.url) {
+                    (BookmarkType::Bookmark, &Some(ref url)) => {
+                        let place_id = bookmarks::fetch_or_insert_place(conn, &Url::parse(url)?)?;
+                        changed_places.push(place_id);
+                        Some(place_id)
+                    },
+                    _ => None,
+                };
+                let date_added = if node.item.date_added.0 == 0 { now } else { node.item.date_added };
+                conn.execute_named_cached("
+                    INSERT INTO moz_bookmarks
+                        (fk, type, parent, position, title, dateAdded, lastModified,
+                         guid, syncStatus, syncChangeCounter)
+                    VALUES
+                        (:fk, :type, (SELECT id FROM moz_bookmarks WHERE guid = :parentGuid),
+                         :position, :title, :dateAdded, :lastModified,
+                         :guid, :syncStatus, :syncChangeCounter)",
+                    &[(":fk", &place_id),
+                      (":type", &node.item.kind),
+                      (":parentGuid", &parent_guid),
+                      (":position", &position),
+                      (":title", &node.item.title),
+                      (":dateAdded", &date_added),
+                      (":lastModified", &now),
+                      (":guid", guid),
+                      (":syncStatus", &sync_status),
+                      (":syncChangeCounter", &change_counter)]
+                )?;
+                continue;
+            }
+        };
+
+        if node.merge_state == MergeState::Remote &&
+           (local_item.title != node.item.title || local_item.url != node.item.url ||
+            local_item.kind != node.item.kind) {
+            let old_place_id: Option<RowId> = conn.query_row_named(
+                "SELECT fk FROM moz_bookmarks WHERE guid = :guid",
+                &[(":guid", guid)],
+                |row| row.get(0)
+            )?;
+            let place_id = match (node.item.kind, &node.item.url) {
+                (BookmarkType::Bookmark, &Some(ref url)) =>
+                    Some(bookmarks::fetch_or_insert_place(conn, &Url::parse(url)?)?),
+                _ => None,
+            };
+            conn.execute_named_cached("
+                UPDATE moz_bookmarks
+                SET type = :type, title = :title, fk = :fk, lastModified = :now
+                WHERE guid = :guid",
+                &[(":type", &node.item.kind),
+                  (":title", &node.item.title),
+                  (":fk", &place_id),
+                  (":now", &now),
+                  (":guid", guid)]
+            )?;
+            changed_places.extend(old_place_id);
+            changed_places.extend(place_id);
+        }
+
+        if let Some(&(ref parent_guid, position)) = merged_position {
+            if local_positions.get(guid) != Some(&(parent_guid.clone(), position)) {
+                conn.execute_named_cached("
+                    UPDATE moz_bookmarks
+                    SET parent = (SELECT id FROM moz_bookmarks WHERE guid = :parentGuid),
+                        position = :position
+                    WHERE guid = :guid",
+                    &[(":parentGuid", parent_guid), (":position", &position), (":guid", guid)]
+                )?;
+            }
+        }
+
+        if needs_upload {
+            conn.execute_named_cached("
+                UPDATE moz_bookmarks
+                SET syncChangeCounter = MAX(syncChangeCounter, 1)
+                WHERE guid = :guid",
+                &[(":guid", guid)]
+            )?;
+        } else {
+            conn.execute_named_cached("
+                UPDATE moz_bookmarks
+                SET syncChangeCounter = 0, syncStatus = :syncStatus
+                WHERE guid = :guid",
+                &[(":syncStatus", &SyncStatus::Normal), (":guid", guid)]
+            )?;
+        }
+    }
+
+    // Anything which was merged is no longer deleted.
+    for node in &result.nodes {
+        conn.execute_named_cached(
+            "DELETE FROM moz_bookmarks_deleted WHERE guid = :guid",
+            &[(":guid", &node.guid)]
+        )?;
+    }
+
+    for guid in &result.delete_locally {
+        let place_id: Option<RowId> = conn.try_query_row(
+            "SELECT fk FROM moz_bookmarks WHERE guid = :guid",
+            &[(":guid", guid)],
+            |row: &Row| -> Result<Option<RowId>> { Ok(row.get_checked(0)?) },
+            true
+        )?.and_then(|fk| fk);
+        conn.execute_named_cached(
+            "DELETE FROM moz_bookmarks WHERE guid = :guid",
+            &[(":guid", guid)]
+        )?;
+        changed_places.extend(place_id);
+    }
+
+    // Local tombstones for items the server doesn't have are no longer
+    // needed, and we need new ones for items we deleted while merging.
+    for guid in &local.deleted {
+        if !result.delete_remotely.contains(guid) {
+            conn.execute_named_cached(
+                "DELETE FROM moz_bookmarks_deleted WHERE guid = :guid",
+                &[(":guid", guid)]
+            )?;
+        }
+    }
+    for guid in &result.delete_remotely {
+        conn.execute_named_cached("
+            INSERT OR IGNORE INTO moz_bookmarks_deleted (guid, dateRemoved)
+            VALUES (:guid, :now)",
+            &[(":guid", guid), (":now", &now)]
+        )?;
+    }
+
+    let mut seen = HashSet::new();
+    for place_id in changed_places {
+        if seen.insert(place_id.0) {
+            bookmarks::remove_or_update_place(conn, place_id)?;
+        }
+    }
+    Ok(())
+}
+
+fn fetch_outgoing(conn: &Connection, collection: String, timestamp: ServerTimestamp) -> Result<OutgoingChangeset> {
+    let mut outgoing = OutgoingChangeset::new(collection, timestamp);
+    {
+        let mut stmt = conn.prepare("SELECT guid FROM moz_bookmarks_deleted")?;
+        let rows = stmt.query_and_then(&[], |row: &Row| -> Result<SyncGuid> {
+            Ok(row.get_checked(0)?)
+        })?;
+        for guid in rows {
+            outgoing.changes.push(Payload::new_tombstone(guid_to_record_id(&guid?)));
+        }
+    }
+    let mut stmt = conn.prepare("
+        SELECT b.id, b.guid, b.type, b.title, b.dateAdded, b.position, h.url,
+               p.guid AS parentGuid, p.title AS parentTitle, s.unknownFields
+        FROM moz_bookmarks b
+        LEFT JOIN moz_bookmarks p ON p.id = b.parent
+        LEFT JOIN moz_places h ON h.id = b.fk
+        LEFT JOIN moz_bookmarks_synced s ON s.guid = b.guid
+        WHERE b.syncChangeCounter > 0 AND b.guid != :root")?;
+    let rows = stmt.query_and_then_named(&[(":root", &ROOT_GUID)], |row: &Row| -> Result<_> {
+        let id: RowId = row.get_checked("id")?;
+        let guid: SyncGuid = row.get_checked("guid")?;
+        let kind: BookmarkType = row.get_checked("type")?;
+        let title: Option<String> = row.get_checked("title")?;
+        let date_added: Timestamp = row.get_checked("dateAdded")?;
+        let position: u32 = row.get_checked("position")?;
+        let url: Option<String> = row.get_checked("url")?;
+        let parent_guid: Option<SyncGuid> = row.get_checked("parentGuid")?;
+        let parent_name: Option<String> = row.get_checked("parentTitle")?;
+        let unknown_fields: Option<String> = row.get_checked("unknownFields")?;
+
+        let id_str = guid_to_record_id(&guid);
+        let parent_id = parent_guid.as_ref().map(guid_to_record_id);
+        let date_added = Some(date_added.0);
+        let record = match kind {
+            BookmarkType::Bookmark => {
+                let is_query = url.as_ref().map(|u| u.starts_with("place:")).unwrap_or(false);
+                // We don't store tags and keywords, so these come from
+                // the mirror's unknown fields.
+                let record = BookmarkRecord {
+                    id: id_str, parent_id, parent_name, date_added, title, url,
+                    tags: Vec::new(), keyword: None,
+                };
+                if is_query {
+                    BookmarkItemRecord::Query(record)
+                } else {
+                    BookmarkItemRecord::Bookmark(record)
+                }
+            },
+            BookmarkType::Folder => BookmarkItemRecord::Folder(FolderRecord {
+                id: id_str, parent_id, parent_name, date_added, title,
+                children: fetch_child_ids(conn, id)?,
+            }),
+            BookmarkType::Separator => BookmarkItemRecord::Separator(SeparatorRecord {
+                id: id_str, parent_id, parent_name, date_added,
+                position: Some(position),
+            }),
+        };
+        Ok((record, unknown_fields))
+    })?;
+    for row in rows {
+        let (record, unknown_fields) = row?;
+        let mut payload = Payload::from_record(record)?;
+        if let Some(unknown_fields) = unknown_fields {
+            add_unknown_fields(&mut payload, &unknown_fields)?;
+        }
+        outgoing.changes.push(payload);
+    }
+    info!("Uploading {} bookmark records", outgoing.changes.len());
+    Ok(outgoing)
+}
+
+fn fetch_child_ids(conn: &Connection, folder_id: RowId) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT guid FROM moz_bookmarks WHERE parent = :parent ORDER BY position")?;
+    let rows = stmt.query_and_then_named(&[(":parent", &folder_id)], |row: &Row| -> Result<SyncGuid> {
+        Ok(row.get_checked(0)?)
+    })?;
+    let mut result = Vec::new();
+    for guid in rows {
+        result.push(guid_to_record_id(&guid?));
+    }
+    Ok(result)
+}
+
+// After a successful upload, the mirror should match what we uploaded.
+fn update_mirror_from_local(conn: &Connection, guid: &SyncGuid, modified: Timestamp) -> Result<()> {
+    conn.execute_named_cached("
+        REPLACE INTO moz_bookmarks_synced
+            (guid, parentGuid, serverModified, needsMerge, isDeleted,
+             kind, dateAdded, title, url, unknownFields)
+        SELECT b.guid, p.guid, :serverModified, 0, 0,
+               b.type, b.dateAdded, b.title, h.url,
+               (SELECT unknownFields FROM moz_bookmarks_synced WHERE guid = :guid)
+        FROM moz_bookmarks b
+        LEFT JOIN moz_bookmarks p ON p.id = b.parent
+        LEFT JOIN moz_places h ON h.id = b.fk
+        WHERE b.guid = :guid",
+        &[(":guid", guid), (":serverModified", &modified)]
+    )?;
+    conn.execute_named_cached(
+        "DELETE FROM moz_bookmarks_synced_structure WHERE parentGuid = :guid",
+        &[(":guid", guid)]
+    )?;
+    conn.execute_named_cached("
+        INSERT INTO moz_bookmarks_synced_structure (guid, parentGuid, position)
+        SELECT c.guid, b.guid, c.position
+        FROM moz_bookmarks c
+        JOIN moz_bookmarks b ON c.parent = b.id
+        WHERE b.guid = :guid",
+        &[(":guid", guid)]
+    )?;
+    conn.execute_named_cached("
+        UPDATE moz_bookmarks
+        SET syncChangeCounter = 0, syncStatus = :syncStatus
+        WHERE guid = :guid",
+        &[(":syncStatus", &SyncStatus::Normal), (":guid", guid)]
+    )?;
+    Ok(())
+}
+
+impl<'a> SyncEngine for BookmarksStore<'a> {
+    fn collection_name(&self) -> &'static str {
+        COLLECTION_NAME
+    }
+
+    fn sync(
+        &mut self,
+        client: &Sync15StorageClient,
+        state: &GlobalState,
+        telem_engine: &mut telemetry::Engine,
+    ) -> ::std::result::Result<(), failure::Error> {
+        Ok(BookmarksStore::sync(self, client, state, telem_engine)?)
+    }
+
+    fn reset(&mut self) -> ::std::result::Result<(), failure::Error> {
+        BookmarksStore::reset(self)?;
+        Ok(())
+    }
+
+    fn wipe(&mut self) -> ::std::result::Result<(), failure::Error> {
+        BookmarksStore::wipe(self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use env_logger;
+    use serde_json;
+    use api::bookmarks::{BookmarkPosition, BookmarkTreeNode, InsertableBookmark, InsertableItem,
+                         MENU_GUID, UNFILED_GUID, fetch_tree, insert_bookmark, delete_bookmark};
+    use sync::telemetry::EngineIncoming;
+
+    fn incoming(records: Vec<serde_json::Value>) -> IncomingChangeset {
+        let mut changeset = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(0.0));
+        for record in records {
+            changeset.changes.push((Payload::from_json(record).unwrap(), ServerTimestamp(1.0)));
+        }
+        changeset
+    }
+
+    fn child_guids(db: &PlacesDb, folder: &str) -> Vec<String> {
+        match fetch_tree(db, &folder.into()).expect("should fetch").expect("should exist") {
+            BookmarkTreeNode::Folder(f) => f.children.iter().map(|c| c.guid().0.clone()).collect(),
+            _ => panic!("not a folder"),
+        }
+    }
+
+    fn outgoing_ids(outgoing: &OutgoingChangeset) -> Vec<String> {
+        let mut ids: Vec<String> = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+        ids.sort();
+        ids
+    }
+
+    fn insert_local_bookmark(db: &mut PlacesDb, url: &str) -> SyncGuid {
+        insert_bookmark(db, &InsertableItem::Bookmark(InsertableBookmark {
+            parent_guid: UNFILED_GUID.into(),
+            position: BookmarkPosition::Append,
+            date_added: None,
+            last_modified: None,
+            guid: None,
+            url: Url::parse(url).unwrap(),
+            title: Some("a bookmark".into()),
+        })).expect("should insert")
+    }
+
+    #[test]
+    fn test_first_sync() {
+        let _ = env_logger::try_init();
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let bm = insert_local_bookmark(&mut db, "http://example.com/");
+        let mut store = BookmarksStore::new(&mut db);
+        let outgoing = store.apply_incoming(incoming(vec![]), &mut EngineIncoming::new())
+            .expect("should apply");
+        let ids = outgoing_ids(&outgoing);
+        let mut expected: Vec<String> = vec![bm.0.clone(), "menu".into(), "mobile".into(),
+                                             "toolbar".into(), "unfiled".into()];
+        expected.sort();
+        assert_eq!(ids, expected);
+
+        // After a successful upload, there's nothing more to do.
+        store.sync_finished(ServerTimestamp(2.0), &ids).expect("should finish");
+        let outgoing = store.apply_incoming(incoming(vec![]), &mut EngineIncoming::new())
+            .expect("should apply");
+        assert!(outgoing.changes.is_empty(), "nothing to upload");
+        assert_eq!(store.get_last_sync().unwrap(), Some(ServerTimestamp(2.0)));
+    }
+
+    #[test]
+    fn test_unknown_fields_round_trip() {
+        let _ = env_logger::try_init();
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let mut store = BookmarksStore::new(&mut db);
+        store.apply_incoming(incoming(vec![
+            json!({"id": "unfiled", "type": "folder", "parentid": "places",
+                   "children": ["bookmarkAAAA", "queryBBBBBBB"]}),
+            json!({"id": "bookmarkAAAA", "type": "bookmark", "parentid": "unfiled",
+                   "title": "A", "bmkUri": "http://example.com/a",
+                   "tags": ["foo", "bar"], "keyword": "a"}),
+            json!({"id": "queryBBBBBBB", "type": "query", "parentid": "unfiled",
+                   "title": "B", "bmkUri": "place:tag=foo", "folderName": "foo",
+                   "queryId": "MostVisited"}),
+        ]), &mut EngineIncoming::new()).expect("should apply");
+
+        // Pretend both items changed locally, so that we upload them.
+        let reupload = |store: &BookmarksStore| -> Vec<Payload> {
+            store.db.execute_all(&[
+                "UPDATE moz_bookmarks SET syncChangeCounter = 1
+                 WHERE guid IN ('bookmarkAAAA', 'queryBBBBBBB')",
+            ]).unwrap();
+            // The roots haven't been uploaded yet, either.
+            let mut changes = fetch_outgoing(&store.db, COLLECTION_NAME.into(), ServerTimestamp(0.0))
+                .expect("should fetch outgoing")
+                .changes
+                .into_iter()
+                .filter(|p| p.id == "bookmarkAAAA" || p.id == "queryBBBBBBB")
+                .collect::<Vec<_>>();
+            changes.sort_by(|a, b| a.id.cmp(&b.id));
+            changes
+        };
+        let check = |changes: &[Payload]| {
+            assert_eq!(changes.len(), 2);
+            assert_eq!(changes[0].id, "bookmarkAAAA");
+            assert_eq!(changes[0].data["title"], "A");
+            assert_eq!(changes[0].data["tags"], json!(["foo", "bar"]));
+            assert_eq!(changes[0].data["keyword"], "a");
+            assert_eq!(changes[1].id, "queryBBBBBBB");
+            assert_eq!(changes[1].data["type"], "query");
+            assert_eq!(changes[1].data["folderName"], "foo");
+            assert_eq!(changes[1].data["queryId"], "MostVisited");
+        };
+        let changes = reupload(&store);
+        check(&changes);
+
+        // Uploading them mustn't lose the fields from the mirror.
+        let ids = changes.iter().map(|p| p.id.clone()).collect::<Vec<_>>();
+        store.sync_finished(ServerTimestamp(2.0), &ids).expect("should finish");
+        check(&reupload(&store));
+    }
+
+    #[test]
+    fn test_incoming_tree() {
+        let _ = env_logger::try_init();
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        {
+            let mut store = BookmarksStore::new(&mut db);
+            store.apply_incoming(incoming(vec![
+                json!({"id": "menu", "type": "folder", "parentid": "places",
+                       "children": ["folderAAAAAA"]}),
+                json!({"id": "folderAAAAAA", "type": "folder", "parentid": "menu",
+                       "title": "A folder", "children": ["bookmarkBBBB", "missingCCCCC"]}),
+                json!({"id": "bookmarkBBBB", "type": "bookmark", "parentid": "folderAAAAAA",
+                       "title": "B", "bmkUri": "http://example.com/b"}),
+                // An orphan - its parent doesn't exist.
+                json!({"id": "bookmarkDDDD", "type": "bookmark", "parentid": "folderEEEEEE",
+                       "title": "D", "bmkUri": "http://example.com/d"}),
+            ]), &mut EngineIncoming::new()).expect("should apply");
+        }
+        assert_eq!(child_guids(&db, MENU_GUID), vec!["folderAAAAAA"]);
+        assert_eq!(child_guids(&db, "folderAAAAAA"), vec!["bookmarkBBBB"]);
+        assert_eq!(child_guids(&db, UNFILED_GUID), vec!["bookmarkDDDD"]);
+
+        // Now a tombstone for the bookmark.
+        {
+            let mut store = BookmarksStore::new(&mut db);
+            store.apply_incoming(incoming(vec![
+                json!({"id": "bookmarkBBBB", "deleted": true}),
+            ]), &mut EngineIncoming::new()).expect("should apply");
+        }
+        assert!(child_guids(&db, "folderAAAAAA").is_empty());
+        let count: i64 = db.query_one(
+            "SELECT COUNT(*) FROM moz_places WHERE url = 'http://example.com/b'").unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_wipe() {
+        let _ = env_logger::try_init();
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        insert_local_bookmark(&mut db, "http://example.com/");
+        {
+            let mut store = BookmarksStore::new(&mut db);
+            let outgoing = store.apply_incoming(incoming(vec![]), &mut EngineIncoming::new())
+                .expect("should apply");
+            store.sync_finished(ServerTimestamp(2.0), &outgoing_ids(&outgoing)).expect("should finish");
+            BookmarksStore::wipe(&mut store).expect("should wipe");
+            assert_eq!(store.get_last_sync().unwrap(), None);
+        }
+        assert!(child_guids(&db, UNFILED_GUID).is_empty());
+        assert_eq!(child_guids(&db, ROOT_GUID).len(), 4);
+        let count: i64 = db.query_one("SELECT COUNT(*) FROM moz_places").unwrap();
+        assert_eq!(count, 0);
+        let count: i64 = db.query_one("SELECT COUNT(*) FROM moz_bookmarks_synced").unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_local_delete() {
+        let _ = env_logger::try_init();
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let bm = insert_local_bookmark(&mut db, "http://example.com/");
+        {
+            let mut store = BookmarksStore::new(&mut db);
+            let outgoing = store.apply_incoming(incoming(vec![]), &mut EngineIncoming::new())
+                .expect("should apply");
+            store.sync_finished(ServerTimestamp(2.0), &outgoing_ids(&outgoing)).expect("should finish");
+        }
+        assert!(delete_bookmark(&mut db, &bm).expect("should delete"));
+        let mut store = BookmarksStore::new(&mut db);
+        let outgoing = store.apply_incoming(incoming(vec![]), &mut EngineIncoming::new())
+            .expect("should apply");
+        let mut expected: Vec<String> = vec![bm.0.clone(), "unfiled".into()];
+        expected.sort();
+        assert_eq!(outgoing_ids(&outgoing), expected);
+        let tombstone = outgoing.changes.iter().find(|p| p.id == bm.0).unwrap();
+        assert!(tombstone.is_tombstone());
+
+        store.sync_finished(ServerTimestamp(3.0), &outgoing_ids(&outgoing)).expect("should finish");
+        let outgoing = store.apply_incoming(incoming(vec![]), &mut EngineIncoming::new())
+            .expect("should apply");
+        assert!(outgoing.changes.is_empty(), "nothing to upload");
+    }
+}
@@ -0,0 +1,291 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// In-memory representations of the local and remote bookmark trees, which
+// are what the merger works with.
+
+use std::collections::{HashMap, HashSet};
+
+use rusqlite::{Connection, Row};
+
+use api::bookmarks::{is_root_guid, ROOT_GUID, UNFILED_GUID};
+use db::schema::BOOKMARK_ROOTS;
+use error::*;
+use types::{BookmarkType, SyncGuid, Timestamp};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    pub guid: SyncGuid,
+    pub kind: BookmarkType,
+    pub title: Option<String>,
+    pub url: Option<String>,
+    pub date_added: Timestamp,
+    // For local items this is `lastModified`, for remote items it's the
+    // server modified time.
+    pub modified: Timestamp,
+    // Has the item changed since the last sync? For local items this means a
+    // non-zero change counter, for remote items it means `needsMerge`.
+    pub changed: bool,
+    // Only used for folders.
+    pub children: Vec<SyncGuid>,
+}
+
+#[derive(Debug, Default)]
+pub struct Tree {
+    pub items: HashMap<SyncGuid, Item>,
+    // The parent of every item other than the root, derived from the
+    // `children` of the folders.
+    pub parents: HashMap<SyncGuid, SyncGuid>,
+    // Items which have been deleted on this side.
+    pub deleted: HashSet<SyncGuid>,
+}
+
+impl Tree {
+    #[inline]
+    pub fn get(&self, guid: &SyncGuid) -> Option<&Item> {
+        self.items.get(guid)
+    }
+
+    #[inline]
+    pub fn parent_of(&self, guid: &SyncGuid) -> Option<&SyncGuid> {
+        self.parents.get(guid)
+    }
+
+    // Fills in `parents` from the children of each folder. The first folder
+    // to claim a child wins, so each item has exactly one parent.
+    pub fn link_parents(&mut self) {
+        let mut parents = HashMap::new();
+        let mut folders: Vec<&Item> = self.items.values()
+            .filter(|item| item.kind == BookmarkType::Folder)
+            .collect();
+        // Make this deterministic.
+        folders.sort_by(|a, b| a.guid.0.cmp(&b.guid.0));
+        for folder in folders {
+            for child in &folder.children {
+                if *child != folder.guid && !parents.contains_key(child) {
+                    parents.insert(child.clone(), folder.guid.clone());
+                }
+            }
+        }
+        self.parents = parents;
+    }
+}
+
+// Builds the local tree from moz_bookmarks and moz_bookmarks_deleted.
+pub fn fetch_local_tree(conn: &Connection) -> Result<Tree> {
+    let mut tree = Tree::default();
+    let mut children: Vec<(SyncGuid, SyncGuid)> = Vec::new();
+    {
+        let mut stmt = conn.prepare("
+            SELECT b.guid, b.type, b.title, b.dateAdded, b.lastModified,
+                   b.syncChangeCounter, h.url, p.guid AS parentGuid
+            FROM moz_bookmarks b
+            LEFT JOIN moz_bookmarks p ON p.id = b.parent
+            LEFT JOIN moz_places h ON h.id = b.fk
+            ORDER BY b.parent, b.position")?;
+        let rows = stmt.query_and_then(&[], |row: &Row| -> Result<_> {
+            let item = Item {
+                guid: row.get_checked("guid")?,
+                kind: row.get_checked("type")?,
+                title: row.get_checked("title")?,
+                url: row.get_checked("url")?,
+                date_added: row.get_checked("dateAdded")?,
+                modified: row.get_checked("lastModified")?,
+                changed: row.get_checked::<_, i64>("syncChangeCounter")? > 0,
+                children: Vec::new(),
+            };
+            Ok((item, row.get_checked::<_, Option<SyncGuid>>("parentGuid")?))
+        })?;
+        for row in rows {
+            let (item, parent_guid) = row?;
+            if let Some(parent_guid) = parent_guid {
+                children.push((parent_guid, item.guid.clone()));
+            }
+            tree.items.insert(item.guid.clone(), item);
+        }
+    }
+    // Rows were ordered by position, so this keeps the children in order.
+    for (parent_guid, child_guid) in children {
+        if let Some(parent) = tree.items.get_mut(&parent_guid) {
+            parent.children.push(child_guid);
+        }
+    }
+    {
+        let mut stmt = conn.prepare("SELECT guid FROM moz_bookmarks_deleted")?;
+        let rows = stmt.query_and_then(&[], |row: &Row| -> Result<SyncGuid> {
+            Ok(row.get_checked(0)?)
+        })?;
+        for guid in rows {
+            tree.deleted.insert(guid?);
+        }
+    }
+    tree.link_parents();
+    Ok(tree)
+}
+
+// The raw structure of the mirror, used to work out what needs to be
+// uploaded after a merge.
+#[derive(Debug, Default)]
+pub struct MirrorStructure {
+    // The `parentid` of every live item, as recorded on the server.
+    pub parents: HashMap<SyncGuid, Option<SyncGuid>>,
+    // The `children` of every folder, as recorded on the server.
+    pub children: HashMap<SyncGuid, Vec<SyncGuid>>,
+}
+
+// Builds the remote tree from the mirror. Missing roots are added, children
+// which we don't have a record for are ignored, and orphans (items which
+// aren't in the children of any folder) are moved to their `parentid`, or
+// to unfiled if that doesn't exist.
+pub fn fetch_remote_tree(conn: &Connection) -> Result<(Tree, MirrorStructure)> {
+    let mut tree = Tree::default();
+    let mut structure = MirrorStructure::default();
+    {
+        let mut stmt = conn.prepare("
+            SELECT guid, parentGuid, serverModified, needsMerge, isDeleted,
+                   kind, dateAdded, title, url
+            FROM moz_bookmarks_synced")?;
+        let rows = stmt.query_and_then(&[], |row: &Row| -> Result<_> {
+            let guid: SyncGuid = row.get_checked("guid")?;
+            let parent_guid: Option<SyncGuid> = row.get_checked("parentGuid")?;
+            let is_deleted: bool = row.get_checked("isDeleted")?;
+            let kind: Option<BookmarkType> = row.get_checked("kind")?;
+            let item = match kind {
+                Some(kind) if !is_deleted => Some(Item {
+                    guid: guid.clone(),
+                    kind,
+                    title: row.get_checked("title")?,
+                    url: row.get_checked("url")?,
+                    date_added: row.get_checked("dateAdded")?,
+                    modified: row.get_checked("serverModified")?,
+                    changed: row.get_checked("needsMerge")?,
+                    children: Vec::new(),
+                }),
+                _ => None,
+            };
+            Ok((guid, parent_guid, item))
+        })?;
+        for row in rows {
+            match row? {
+                (guid, parent_guid, Some(item)) => {
+                    structure.parents.insert(guid.clone(), parent_guid);
+                    tree.items.insert(guid, item);
+                },
+                (guid, _, None) => {
+                    tree.deleted.insert(guid);
+                },
+            }
+        }
+    }
+    {
+        let mut stmt = conn.prepare("
+            SELECT guid, parentGuid FROM moz_bookmarks_synced_structure
+            ORDER BY parentGuid, position")?;
+        let rows = stmt.query_and_then(&[], |row: &Row| -> Result<(SyncGuid, SyncGuid)> {
+            Ok((row.get_checked("guid")?, row.get_checked("parentGuid")?))
+        })?;
+        for row in rows {
+            let (guid, parent_guid) = row?;
+            structure.children.entry(parent_guid).or_insert_with(Vec::new).push(guid);
+        }
+    }
+
+    // The server doesn't necessarily have records for the roots (and never
+    // has one for the root of the tree), but the merger needs them.
+    for &(_, guid, _) in BOOKMARK_ROOTS {
+        let guid = SyncGuid::from(guid);
+        if !tree.items.contains_key(&guid) {
+            tree.items.insert(guid.clone(), Item {
+                guid,
+                kind: BookmarkType::Folder,
+                title: None,
+                url: None,
+                date_added: Timestamp(0),
+                modified: Timestamp(0),
+                changed: false,
+                children: Vec::new(),
+            });
+        }
+    }
+
+    let root_guid = SyncGuid::from(ROOT_GUID);
+    for (parent_guid, children) in &structure.children {
+        let is_folder = tree.items.get(parent_guid)
+            .map(|item| item.kind == BookmarkType::Folder)
+            .unwrap_or(false);
+        if !is_folder {
+            continue;
+        }
+        let known: Vec<SyncGuid> = children.iter()
+            .filter(|guid| {
+                let exists = tree.items.contains_key(*guid);
+                if !exists {
+                    warn!("Ignoring missing child {:?} of {:?}", guid, parent_guid);
+                }
+                // Roots can't be moved.
+                exists && !is_root_guid(guid)
+            })
+            .cloned()
+            .collect();
+        tree.items.get_mut(parent_guid).expect("checked above").children = known;
+    }
+    // The roots always live in the root, in this order.
+    tree.items.get_mut(&root_guid).expect("added above").children =
+        BOOKMARK_ROOTS.iter().skip(1).map(|&(_, guid, _)| SyncGuid::from(guid)).collect();
+    tree.link_parents();
+
+    // Anything without a parent is an orphan.
+    let mut orphans: Vec<SyncGuid> = tree.items.keys()
+        .filter(|guid| **guid != root_guid && !tree.parents.contains_key(*guid))
+        .cloned()
+        .collect();
+    orphans.sort_by(|a, b| a.0.cmp(&b.0));
+    for orphan in orphans {
+        let parent_guid = structure.parents.get(&orphan)
+            .and_then(|p| p.clone())
+            .filter(|p| *p != orphan && tree.items.get(p)
+                        .map(|item| item.kind == BookmarkType::Folder)
+                        .unwrap_or(false))
+            .unwrap_or_else(|| UNFILED_GUID.into());
+        debug!("Moving orphan {:?} to {:?}", orphan, parent_guid);
+        tree.items.get_mut(&parent_guid).expect("parent exists").children.push(orphan.clone());
+        tree.parents.insert(orphan, parent_guid);
+    }
+    Ok((tree, structure))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use api::bookmarks::MENU_GUID;
+    use db::PlacesDb;
+
+    #[test]
+    fn test_local_tree() {
+        let db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let tree = fetch_local_tree(&db).expect("should fetch");
+        let root = tree.get(&ROOT_GUID.into()).expect("should have a root");
+        assert_eq!(root.children.len(), 4);
+        assert_eq!(tree.parent_of(&UNFILED_GUID.into()), Some(&SyncGuid::from(ROOT_GUID)));
+    }
+
+    #[test]
+    fn test_remote_tree_orphans() {
+        let db = PlacesDb::open_in_memory(None).expect("no memory db");
+        db.execute_batch("
+            INSERT INTO moz_bookmarks_synced (guid, parentGuid, needsMerge, kind, url)
+            VALUES ('bookmarkAAAA', 'folderBBBBBB', 1, 1, 'http://example.com/'),
+                   ('bookmarkCCCC', 'menu________', 1, 1, 'http://example.com/');
+            INSERT INTO moz_bookmarks_synced_structure (guid, parentGuid, position)
+            VALUES ('missingDDDDD', 'menu________', 0);
+        ").expect("should insert");
+        let (tree, _) = fetch_remote_tree(&db).expect("should fetch");
+        // The parent doesn't exist, so it's moved to unfiled.
+        assert_eq!(tree.parent_of(&"bookmarkAAAA".into()), Some(&SyncGuid::from(UNFILED_GUID)));
+        // The parent exists but doesn't list it as a child.
+        assert_eq!(tree.parent_of(&"bookmarkCCCC".into()), Some(&SyncGuid::from(MENU_GUID)));
+        // Missing children are ignored.
+        assert!(tree.parent_of(&"missingDDDDD".into()).is_none());
+    }
+}
@@ -6,4 +6,4 @@
 pub mod db;
 pub use db::db::PlacesDb;
 
-mod schema;
+pub(crate) mod schema;
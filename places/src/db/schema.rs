@@ -11,8 +11,10 @@ use db::PlacesDb;
 use sql_support::ConnExt;
 
 use error::*;
+use types::{BookmarkType, Timestamp};
+use api::bookmarks;
 
-const VERSION: i64 = 1;
+const VERSION: i64 = 3;
 
 const CREATE_TABLE_PLACES_SQL: &str =
     "CREATE TABLE IF NOT EXISTS moz_places (
@@ -34,6 +36,10 @@ const CREATE_TABLE_PLACES_SQL: &str =
         description TEXT, -- XXXX - title above?
         preview_image_url TEXT,
         origin_id INTEGER, -- NOT NULL XXXX - not clear if there should always be a moz_origin
+        -- These are used by the history sync engine, and hold a `SyncStatus`
+        -- and the number of local changes since we last uploaded the place.
+        sync_status INTEGER NOT NULL DEFAULT 1, -- SyncStatus::New
+        sync_change_counter INTEGER NOT NULL DEFAULT 0,
 
         FOREIGN KEY(origin_id) REFERENCES moz_origins(id) ON DELETE CASCADE
     )";
@@ -66,21 +72,77 @@ const CREATE_TABLE_INPUTHISTORY_SQL: &str =
 // XXX - TODO - moz_annos
 // XXX - TODO - moz_anno_attributes
 // XXX - TODO - moz_items_annos
-// XXX - TODO - moz_bookmarks
-// XXX - TODO - moz_bookmarks_deleted
-
-// TODO: This isn't the complete `moz_bookmarks` definition, just enough to
-// test autocomplete.
+// Note that the column names here are camelCase to match desktop.
 const CREATE_TABLE_BOOKMARKS_SQL: &str =
     "CREATE TABLE moz_bookmarks (
         id INTEGER PRIMARY KEY,
-        fk INTEGER,
-        title TEXT,
+        fk INTEGER DEFAULT NULL, -- place_id, NULL for folders and separators.
+        type INTEGER NOT NULL, -- a `BookmarkType`
+        parent INTEGER, -- NULL only for the root.
+        position INTEGER NOT NULL,
+        title TEXT, -- NULL is different from empty - NULL means 'never set'.
+        dateAdded INTEGER NOT NULL DEFAULT 0,
         lastModified INTEGER NOT NULL DEFAULT 0,
+        guid TEXT NOT NULL UNIQUE CHECK(length(guid) == 12),
+
+        -- The sync engine needs to know if an item is new (ie, has never been
+        -- uploaded), and how many times it's been changed since it was last
+        -- uploaded. This is a `SyncStatus`.
+        syncStatus INTEGER NOT NULL DEFAULT 1, -- SyncStatus::New
+        syncChangeCounter INTEGER NOT NULL DEFAULT 1,
+
+        FOREIGN KEY(fk) REFERENCES moz_places(id) ON DELETE RESTRICT,
+        FOREIGN KEY(parent) REFERENCES moz_bookmarks(id) ON DELETE CASCADE
+    )";
 
-        FOREIGN KEY(fk) REFERENCES moz_places(id) ON DELETE RESTRICT
+// Tombstones for bookmarks which have been uploaded to the server and then
+// deleted locally. These are uploaded on the next sync.
+const CREATE_TABLE_BOOKMARKS_DELETED_SQL: &str =
+    "CREATE TABLE moz_bookmarks_deleted (
+        guid TEXT PRIMARY KEY,
+        dateRemoved INTEGER NOT NULL
+    ) WITHOUT ROWID";
+
+// The bookmarks "mirror" - this is a copy of what's on the server, and is
+// where incoming records are staged before being merged. Unlike desktop, we
+// keep this in the main database rather than an attached one.
+const CREATE_TABLE_BOOKMARKS_SYNCED_SQL: &str =
+    "CREATE TABLE moz_bookmarks_synced (
+        id INTEGER PRIMARY KEY,
+        guid TEXT UNIQUE NOT NULL,
+        parentGuid TEXT,
+        serverModified INTEGER NOT NULL DEFAULT 0, -- milliseconds
+        needsMerge INTEGER NOT NULL DEFAULT 0,
+        isDeleted INTEGER NOT NULL DEFAULT 0,
+        kind INTEGER, -- a `BookmarkType`, NULL for tombstones.
+        dateAdded INTEGER NOT NULL DEFAULT 0,
+        title TEXT,
+        url TEXT,
+        -- A JSON object with the record's fields that we don't store in
+        -- their own columns, like tags and keywords, so that we can upload
+        -- them again.
+        unknownFields TEXT
     )";
 
+// The children of each folder in the mirror, in order.
+const CREATE_TABLE_BOOKMARKS_SYNCED_STRUCTURE_SQL: &str =
+    "CREATE TABLE moz_bookmarks_synced_structure (
+        guid TEXT,
+        parentGuid TEXT,
+        position INTEGER NOT NULL,
+
+        PRIMARY KEY(parentGuid, guid)
+    ) WITHOUT ROWID";
+
+// The built-in roots. The ids and guids of these are fixed.
+pub(crate) const BOOKMARK_ROOTS: &[(i64, &str, &str)] = &[
+    // (id, guid, title)
+    (1, bookmarks::ROOT_GUID, ""),
+    (2, bookmarks::MENU_GUID, "menu"),
+    (3, bookmarks::TOOLBAR_GUID, "toolbar"),
+    (4, bookmarks::UNFILED_GUID, "unfiled"),
+    (5, bookmarks::MOBILE_GUID, "mobile"),
+];
 
 // Note: desktop has/had a 'keywords' table, but we intentionally do not.
 
@@ -111,6 +173,48 @@ const CREATE_TRIGGER_AFTER_INSERT_ON_PLACES: &str = "
     END
 ";
 
+// Keep moz_places.foreign_count in sync with the bookmarks which reference it.
+const CREATE_TRIGGER_AFTER_INSERT_ON_BOOKMARKS: &str = "
+    CREATE TEMP TRIGGER moz_bookmarks_afterinsert_trigger
+    AFTER INSERT ON moz_bookmarks FOR EACH ROW
+    WHEN NEW.fk NOT NULL
+    BEGIN
+        UPDATE moz_places SET foreign_count = foreign_count + 1
+        WHERE id = NEW.fk;
+    END
+";
+
+const CREATE_TRIGGER_AFTER_DELETE_ON_BOOKMARKS: &str = "
+    CREATE TEMP TRIGGER moz_bookmarks_afterdelete_trigger
+    AFTER DELETE ON moz_bookmarks FOR EACH ROW
+    WHEN OLD.fk NOT NULL
+    BEGIN
+        UPDATE moz_places SET foreign_count = foreign_count - 1
+        WHERE id = OLD.fk;
+    END
+";
+
+const CREATE_TRIGGER_AFTER_UPDATE_ON_BOOKMARKS: &str = "
+    CREATE TEMP TRIGGER moz_bookmarks_afterupdate_trigger
+    AFTER UPDATE OF fk ON moz_bookmarks FOR EACH ROW
+    WHEN OLD.fk IS NOT NEW.fk
+    BEGIN
+        UPDATE moz_places SET foreign_count = foreign_count + 1
+        WHERE id = NEW.fk;
+        UPDATE moz_places SET foreign_count = foreign_count - 1
+        WHERE id = OLD.fk;
+    END
+";
+
+// Incoming history records are staged here as they're downloaded, and
+// applied once they've all arrived. See `HistoryStore::stage_incoming`.
+const CREATE_TEMP_TABLE_HISTORY_STAGING: &str = "
+    CREATE TEMP TABLE moz_history_staging (
+        guid TEXT PRIMARY KEY,
+        payload TEXT NOT NULL
+    ) WITHOUT ROWID
+";
+
 // XXX - TODO - lots of desktop temp tables - but it's not clear they make sense here yet?
 
 // XXX - TODO - lots of favicon related tables - but it's not clear they make sense here yet?
@@ -149,13 +253,20 @@ const CREATE_IDX_MOZ_HISTORYVISITS_VISITDATE: &str = "CREATE INDEX dateindex ON
 const CREATE_IDX_MOZ_HISTORYVISITS_ISLOCAL: &str = "CREATE INDEX islocalindex ON moz_historyvisits(is_local)";
 
 
-// const CREATE_IDX_MOZ_BOOKMARKS_PLACETYPE: &str = "CREATE INDEX itemindex ON moz_bookmarks(fk, type)";
-// const CREATE_IDX_MOZ_BOOKMARKS_PARENTPOSITION: &str = "CREATE INDEX parentindex ON moz_bookmarks(parent, position)";
+const CREATE_IDX_MOZ_BOOKMARKS_PLACETYPE: &str = "CREATE INDEX itemindex ON moz_bookmarks(fk, type)";
+const CREATE_IDX_MOZ_BOOKMARKS_PARENTPOSITION: &str = "CREATE INDEX parentindex ON moz_bookmarks(parent, position)";
 const CREATE_IDX_MOZ_BOOKMARKS_PLACELASTMODIFIED: &str = "CREATE INDEX itemlastmodifiedindex ON moz_bookmarks(fk, lastModified)";
-// const CREATE_IDX_MOZ_BOOKMARKS_DATEADDED: &str = "CREATE INDEX dateaddedindex ON moz_bookmarks(dateAdded)";
-// const CREATE_IDX_MOZ_BOOKMARKS_GUID: &str = "CREATE UNIQUE INDEX guid_uniqueindex ON moz_bookmarks(guid)";
+const CREATE_IDX_MOZ_BOOKMARKS_DATEADDED: &str = "CREATE INDEX dateaddedindex ON moz_bookmarks(dateAdded)";
+// Note that the UNIQUE constraint on moz_bookmarks.guid already gives us an
+// index, so we don't need desktop's `guid_uniqueindex`.
+
+const CREATE_IDX_MOZ_BOOKMARKS_SYNCED_NEEDSMERGE: &str = "CREATE INDEX syncedneedsmergeindex ON moz_bookmarks_synced(needsMerge)";
 
 // Keys in the moz_meta table.
+pub(crate) static MOZ_META_KEY_HISTORY_LAST_SYNC: &'static str = "history_last_sync_time";
+pub(crate) static MOZ_META_KEY_HISTORY_SYNC_ID: &'static str = "history_sync_id";
+pub(crate) static MOZ_META_KEY_BOOKMARKS_LAST_SYNC: &'static str = "bookmarks_last_sync_time";
+pub(crate) static MOZ_META_KEY_BOOKMARKS_SYNC_ID: &'static str = "bookmarks_sync_id";
 // pub(crate) static MOZ_META_KEY_ORIGIN_FRECENCY_COUNT: &'static str = "origin_frecency_count";
 // pub(crate) static MOZ_META_KEY_ORIGIN_FRECENCY_SUM: &'static str = "origin_frecency_sum";
 // pub(crate) static MOZ_META_KEY_ORIGIN_FRECENCY_SUM_OF_SQUARES: &'static str = "origin_frecency_sum_of_squares";
@@ -164,9 +275,8 @@ const CREATE_IDX_MOZ_BOOKMARKS_PLACELASTMODIFIED: &str = "CREATE INDEX itemlastm
 pub fn init(db: &PlacesDb) -> Result<()> {
     let user_version = db.query_one::<i64>("PRAGMA user_version")?;
     if user_version == 0 {
-        return create(db);
-    }
-    if user_version != VERSION {
+        create(db)?;
+    } else if user_version != VERSION {
         if user_version < VERSION {
             upgrade(db, user_version)?;
         } else {
@@ -175,17 +285,76 @@ pub fn init(db: &PlacesDb) -> Result<()> {
                   user_version, VERSION)
         }
     }
+    // Temp tables and triggers don't persist, so need to be created each
+    // time the database is opened.
+    create_temp_tables(db)?;
     Ok(())
 }
 
 // https://github.com/mozilla-mobile/firefox-ios/blob/master/Storage/SQL/LoginsSchema.swift#L100
-fn upgrade(_db: &PlacesDb, from: i64) -> Result<()> {
+fn upgrade(db: &PlacesDb, from: i64) -> Result<()> {
     debug!("Upgrading schema from {} to {}", from, VERSION);
     if from == VERSION {
         return Ok(());
     }
-    // hrmph - do something here?
-    panic!("sorry, no upgrades yet - delete your db!");
+    db.execute_all(&["BEGIN IMMEDIATE"])?;
+    match upgrade_in_transaction(db, from) {
+        Ok(()) => {
+            db.execute_all(&["COMMIT"])?;
+            Ok(())
+        }
+        Err(e) => {
+            if let Err(rollback_error) = db.execute_all(&["ROLLBACK"]) {
+                warn!("Failed to roll back after {}: {}", e, rollback_error);
+            }
+            Err(e)
+        }
+    }
+}
+
+fn upgrade_in_transaction(db: &PlacesDb, from: i64) -> Result<()> {
+    if from < 1 {
+        return Err(ErrorKind::UnsupportedSchemaVersion(from).into());
+    }
+    if from < 2 {
+        upgrade_from_v1(db)?;
+    }
+    if from < 3 {
+        upgrade_from_v2(db)?;
+    }
+    db.execute_all(&[&format!("PRAGMA user_version = {version}", version = VERSION)])?;
+    Ok(())
+}
+
+// Version 1 didn't track history sync state.
+fn upgrade_from_v1(db: &PlacesDb) -> Result<()> {
+    db.execute_all(&[
+        "ALTER TABLE moz_places ADD COLUMN sync_status INTEGER NOT NULL DEFAULT 1",
+        "ALTER TABLE moz_places ADD COLUMN sync_change_counter INTEGER NOT NULL DEFAULT 0",
+    ])?;
+    Ok(())
+}
+
+// Version 2 only had a stub `moz_bookmarks` table, which nothing wrote to,
+// and didn't have the bookmarks mirror.
+fn upgrade_from_v2(db: &PlacesDb) -> Result<()> {
+    db.execute_all(&[
+        "DROP TABLE moz_bookmarks",
+        CREATE_TABLE_BOOKMARKS_SQL,
+        CREATE_TABLE_BOOKMARKS_DELETED_SQL,
+        CREATE_TABLE_BOOKMARKS_SYNCED_SQL,
+        CREATE_TABLE_BOOKMARKS_SYNCED_STRUCTURE_SQL,
+        CREATE_IDX_MOZ_BOOKMARKS_PLACETYPE,
+        CREATE_IDX_MOZ_BOOKMARKS_PARENTPOSITION,
+        CREATE_IDX_MOZ_BOOKMARKS_PLACELASTMODIFIED,
+        CREATE_IDX_MOZ_BOOKMARKS_DATEADDED,
+        CREATE_IDX_MOZ_BOOKMARKS_SYNCED_NEEDSMERGE,
+        // Nothing else references places yet, so the new table means
+        // nothing does.
+        "UPDATE moz_places SET foreign_count = 0",
+    ])?;
+    create_bookmark_roots(db)?;
+    Ok(())
 }
 
 pub fn create(db: &PlacesDb) -> Result<()> {
@@ -195,6 +364,9 @@ pub fn create(db: &PlacesDb) -> Result<()> {
         CREATE_TABLE_HISTORYVISITS_SQL,
         CREATE_TABLE_INPUTHISTORY_SQL,
         CREATE_TABLE_BOOKMARKS_SQL,
+        CREATE_TABLE_BOOKMARKS_DELETED_SQL,
+        CREATE_TABLE_BOOKMARKS_SYNCED_SQL,
+        CREATE_TABLE_BOOKMARKS_SYNCED_STRUCTURE_SQL,
         CREATE_TABLE_ORIGINS_SQL,
         CREATE_TABLE_META_SQL,
         CREATE_IDX_MOZ_PLACES_URL_HASH,
@@ -209,15 +381,172 @@ pub fn create(db: &PlacesDb) -> Result<()> {
         CREATE_IDX_MOZ_HISTORYVISITS_FROMVISIT,
         CREATE_IDX_MOZ_HISTORYVISITS_VISITDATE,
         CREATE_IDX_MOZ_HISTORYVISITS_ISLOCAL,
+        CREATE_IDX_MOZ_BOOKMARKS_PLACETYPE,
+        CREATE_IDX_MOZ_BOOKMARKS_PARENTPOSITION,
         CREATE_IDX_MOZ_BOOKMARKS_PLACELASTMODIFIED,
+        CREATE_IDX_MOZ_BOOKMARKS_DATEADDED,
+        CREATE_IDX_MOZ_BOOKMARKS_SYNCED_NEEDSMERGE,
         &format!("PRAGMA user_version = {version}",
                  version = VERSION),
     ])?;
 
+    create_bookmark_roots(db)?;
+
+    Ok(())
+}
+
+fn create_temp_tables(db: &PlacesDb) -> Result<()> {
     debug!("Creating temp tables and triggers");
     db.execute_all(&[
         CREATE_TRIGGER_AFTER_INSERT_ON_PLACES,
+        CREATE_TRIGGER_AFTER_INSERT_ON_BOOKMARKS,
+        CREATE_TRIGGER_AFTER_DELETE_ON_BOOKMARKS,
+        CREATE_TRIGGER_AFTER_UPDATE_ON_BOOKMARKS,
+        CREATE_TEMP_TABLE_HISTORY_STAGING,
     ])?;
 
     Ok(())
 }
+
+fn create_bookmark_roots(db: &PlacesDb) -> Result<()> {
+    let now = Timestamp::now();
+    for (position, &(id, guid, title)) in BOOKMARK_ROOTS.iter().enumerate() {
+        // The root itself has no parent; the others are its children.
+        let (parent, position): (Option<i64>, i64) = if id == 1 {
+            (None, 0)
+        } else {
+            (Some(1), position as i64 - 1)
+        };
+        db.execute_named_cached("
+            INSERT INTO moz_bookmarks
+                (id, type, parent, position, title, dateAdded, lastModified, guid)
+            VALUES
+                (:id, :type, :parent, :position, :title, :now, :now, :guid)",
+            &[(":id", &id),
+              (":type", &BookmarkType::Folder),
+              (":parent", &parent),
+              (":position", &position),
+              (":title", &title),
+              (":now", &now),
+              (":guid", &guid)]
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::Connection;
+
+    // The schema that the first version of this crate created, without the
+    // indexes we don't touch when upgrading.
+    const V1_SCHEMA: &str = "
+        CREATE TABLE moz_places (
+            id INTEGER PRIMARY KEY,
+            url LONGVARCHAR,
+            title LONGVARCHAR,
+            visit_count_local INTEGER DEFAULT 0,
+            visit_count_remote INTEGER DEFAULT 0,
+            hidden INTEGER DEFAULT 0 NOT NULL,
+            typed INTEGER DEFAULT 0 NOT NULL,
+            frecency INTEGER DEFAULT -1 NOT NULL,
+            last_visit_date_local INTEGER,
+            last_visit_date_remote INTEGER,
+            guid TEXT UNIQUE,
+            foreign_count INTEGER DEFAULT 0 NOT NULL,
+            url_hash INTEGER DEFAULT 0 NOT NULL,
+            description TEXT,
+            preview_image_url TEXT,
+            origin_id INTEGER,
+            FOREIGN KEY(origin_id) REFERENCES moz_origins(id) ON DELETE CASCADE
+        );
+        CREATE TABLE moz_historyvisits (
+            id INTEGER PRIMARY KEY,
+            is_local INTEGER NOT NULL,
+            from_visit INTEGER,
+            place_id INTEGER NOT NULL,
+            visit_date INTEGER,
+            visit_type INTEGER,
+            FOREIGN KEY(place_id) REFERENCES moz_places(id) ON DELETE CASCADE,
+            FOREIGN KEY(from_visit) REFERENCES moz_historyvisits(id)
+        );
+        CREATE TABLE moz_inputhistory (
+            place_id INTEGER NOT NULL,
+            input LONGVARCHAR NOT NULL,
+            use_count INTEGER,
+            PRIMARY KEY (place_id, input),
+            FOREIGN KEY(place_id) REFERENCES moz_places(id) ON DELETE CASCADE
+        );
+        CREATE TABLE moz_bookmarks (
+            id INTEGER PRIMARY KEY,
+            fk INTEGER,
+            title TEXT,
+            lastModified INTEGER NOT NULL DEFAULT 0,
+            FOREIGN KEY(fk) REFERENCES moz_places(id) ON DELETE RESTRICT
+        );
+        CREATE TABLE moz_origins (
+            id INTEGER PRIMARY KEY,
+            prefix TEXT NOT NULL,
+            host TEXT NOT NULL,
+            rev_host TEXT NOT NULL,
+            frecency INTEGER NOT NULL,
+            UNIQUE (prefix, host)
+        );
+        CREATE TABLE moz_meta (
+            key TEXT PRIMARY KEY,
+            value NOT NULL
+        ) WITHOUT ROWID;
+        CREATE INDEX itemlastmodifiedindex ON moz_bookmarks(fk, lastModified);
+        INSERT INTO moz_places (id, url, guid) VALUES (1, 'https://example.com/', 'placeAAAAAAA');
+        PRAGMA user_version = 1;
+    ";
+
+    #[test]
+    fn test_upgrade_from_v1() {
+        let conn = Connection::open_in_memory().expect("no memory db");
+        conn.execute_batch(V1_SCHEMA).expect("should create the v1 schema");
+        let db = PlacesDb::with_connection(conn, None).expect("should upgrade");
+
+        assert_eq!(db.query_one::<i64>("PRAGMA user_version").unwrap(), VERSION);
+        assert_eq!(db.query_one::<i64>(
+            "SELECT sync_status FROM moz_places WHERE guid = 'placeAAAAAAA'").unwrap(), 1);
+        assert_eq!(db.query_one::<i64>("SELECT COUNT(*) FROM moz_bookmarks").unwrap(),
+                   BOOKMARK_ROOTS.len() as i64);
+        assert_eq!(db.query_one::<i64>(
+            "SELECT COUNT(*) FROM moz_bookmarks_synced WHERE unknownFields IS NULL").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_upgrade_from_v2() {
+        let conn = Connection::open_in_memory().expect("no memory db");
+        conn.execute_batch(V1_SCHEMA).expect("should create the v1 schema");
+        conn.execute_batch("
+            ALTER TABLE moz_places ADD COLUMN sync_status INTEGER NOT NULL DEFAULT 1;
+            ALTER TABLE moz_places ADD COLUMN sync_change_counter INTEGER NOT NULL DEFAULT 0;
+            UPDATE moz_places SET sync_status = 2, sync_change_counter = 3;
+            PRAGMA user_version = 2;
+        ").expect("should create the v2 schema");
+        let db = PlacesDb::with_connection(conn, None).expect("should upgrade");
+
+        assert_eq!(db.query_one::<i64>("PRAGMA user_version").unwrap(), VERSION);
+        // Upgrading shouldn't touch the history sync state.
+        assert_eq!(db.query_one::<i64>(
+            "SELECT sync_status FROM moz_places WHERE guid = 'placeAAAAAAA'").unwrap(), 2);
+        assert_eq!(db.query_one::<i64>(
+            "SELECT sync_change_counter FROM moz_places WHERE guid = 'placeAAAAAAA'").unwrap(), 3);
+        assert_eq!(db.query_one::<i64>("SELECT COUNT(*) FROM moz_bookmarks").unwrap(),
+                   BOOKMARK_ROOTS.len() as i64);
+        assert_eq!(db.query_one::<i64>("SELECT COUNT(*) FROM moz_bookmarks_synced").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let conn = Connection::open_in_memory().expect("no memory db");
+        conn.execute_batch(&format!("{} PRAGMA user_version = -1;", V1_SCHEMA)).unwrap();
+        match PlacesDb::with_connection(conn, None).map(|_| ()).unwrap_err().kind() {
+            ErrorKind::UnsupportedSchemaVersion(-1) => {}
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+}
@@ -10,6 +10,7 @@ use std::boxed::Box;
 use rusqlite;
 use serde_json;
 use url;
+use sync;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -71,8 +72,11 @@ pub enum ErrorKind {
     #[fail(display = "No record with guid exists (when one was required): {:?}", _0)]
     NoSuchRecord(String),
 
-//    #[fail(display = "Error synchronizing: {}", _0)]
-//    SyncAdapterError(#[fail(cause)] sync::Error),
+    #[fail(display = "Can't upgrade from database schema version {}", _0)]
+    UnsupportedSchemaVersion(i64),
+
+    #[fail(display = "Error synchronizing: {}", _0)]
+    SyncAdapterError(#[fail(cause)] sync::Error),
 
     #[fail(display = "Error parsing JSON data: {}", _0)]
     JsonError(#[fail(cause)] serde_json::Error),
@@ -103,7 +107,7 @@ macro_rules! impl_from_error {
 }
 
 impl_from_error! {
-//    (SyncAdapterError, sync::Error),
+    (SyncAdapterError, sync::Error),
     (JsonError, serde_json::Error),
     (UrlParseError, url::ParseError),
     (SqlError, rusqlite::Error),
@@ -114,5 +118,20 @@ impl_from_error! {
 pub enum InvalidPlaceInfo {
     #[fail(display = "No url specified")]
     NoUrl,
+
+    #[fail(display = "No such item: {}", _0)]
+    NoSuchGuid(String),
+
+    #[fail(display = "Invalid guid: {}", _0)]
+    InvalidGuid(String),
+
+    #[fail(display = "Invalid parent: {}", _0)]
+    InvalidParent(String),
+
+    #[fail(display = "The root {} can't be modified", _0)]
+    CannotUpdateRoot(String),
+
+    #[fail(display = "Illegal change: {}", _0)]
+    IllegalChange(String),
 }
 
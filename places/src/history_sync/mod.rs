@@ -0,0 +1,27 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// The history sync engine. This syncs moz_places and moz_historyvisits with
+// the "history" collection on a Sync 1.5 server.
+
+mod record;
+mod store;
+
+pub use self::record::{HistoryRecord, HistoryRecordVisit};
+pub use self::store::HistoryStore;
+
+// The name of the collection on the server.
+pub const COLLECTION_NAME: &'static str = "history";
+
+// Desktop only uploads the 20 most recent visits for a place, so there's no
+// point in us sending more.
+pub const MAX_OUTGOING_VISITS: usize = 20;
+
+// The maximum number of places we will upload in a single sync. Desktop uses
+// 5000 for history.
+pub const MAX_OUTGOING_PLACES: usize = 5000;
+
+// How many incoming records we stage at once. Records are written to a temp
+// table as they're downloaded, so this is about how many we hold in memory.
+pub const INCOMING_BATCH_SIZE: usize = 1000;
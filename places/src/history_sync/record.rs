@@ -0,0 +1,78 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use types::{SyncGuid, Timestamp};
+
+// The history record format, as used by desktop and the other clients.
+// Note that visit dates are in *microseconds*, unlike our storage which uses
+// milliseconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryRecord {
+    pub id: SyncGuid,
+
+    #[serde(default)]
+    pub title: String,
+
+    pub hist_uri: String,
+
+    #[serde(default)]
+    pub visits: Vec<HistoryRecordVisit>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryRecordVisit {
+    // microseconds since the epoch.
+    pub date: u64,
+    #[serde(rename = "type")]
+    pub transition: u8,
+}
+
+impl HistoryRecordVisit {
+    // Visit dates on the server are in microseconds.
+    #[inline]
+    pub fn timestamp(&self) -> Timestamp {
+        Timestamp(self.date / 1000)
+    }
+
+    #[inline]
+    pub fn from_timestamp(date: Timestamp, transition: u8) -> Self {
+        HistoryRecordVisit { date: date.0 * 1000, transition }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn test_record_json() {
+        let record: HistoryRecord = serde_json::from_value(json!({
+            "id": "aaaaaaaaaaaa",
+            "histUri": "http://example.com/",
+            "title": "Example",
+            "visits": [{"date": 1_500_000_000_000_000u64, "type": 1}],
+        })).expect("should deserialize");
+        assert_eq!(record.id, SyncGuid("aaaaaaaaaaaa".into()));
+        assert_eq!(record.hist_uri, "http://example.com/");
+        assert_eq!(record.visits.len(), 1);
+        assert_eq!(record.visits[0].timestamp(), Timestamp(1_500_000_000_000));
+        assert_eq!(record.visits[0].transition, 1);
+
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["histUri"], "http://example.com/");
+        assert_eq!(json["visits"][0]["type"], 1);
+    }
+
+    #[test]
+    fn test_record_missing_fields() {
+        let record: HistoryRecord = serde_json::from_value(json!({
+            "id": "aaaaaaaaaaaa",
+            "histUri": "http://example.com/",
+        })).expect("should deserialize");
+        assert_eq!(record.title, "");
+        assert!(record.visits.is_empty());
+    }
+}
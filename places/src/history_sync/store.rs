@@ -0,0 +1,788 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::HashSet;
+
+use rusqlite::{Connection, Row};
+use rusqlite::types::ToSql;
+use url::Url;
+
+use sql_support::{self, ConnExt};
+use failure;
+use serde_json;
+use sync::{self, Store, StagingStore, IncomingChangeset, OutgoingChangeset, Payload,
+           RecordRejection, ServerTimestamp, GlobalState, Sync15StorageClient, SyncEngine};
+use sync::telemetry;
+
+use db::PlacesDb;
+use db::schema;
+use error::*;
+use frecency;
+use storage::{self, RowId};
+use types::{SyncGuid, SyncStatus, Timestamp, VisitTransition};
+use super::record::{HistoryRecord, HistoryRecordVisit};
+use super::{COLLECTION_NAME, INCOMING_BATCH_SIZE, MAX_OUTGOING_VISITS, MAX_OUTGOING_PLACES};
+
+// A `sync15_adapter::Store` for history. This borrows the PlacesDb for the
+// duration of a sync, which means other engines (eg, bookmarks) can have
+// their own store over the same database.
+pub struct HistoryStore<'a> {
+    pub db: &'a mut PlacesDb,
+}
+
+impl<'a> HistoryStore<'a> {
+    pub fn new(db: &'a mut PlacesDb) -> Self {
+        Self { db }
+    }
+
+    pub fn get_last_sync(&self) -> Result<Option<ServerTimestamp>> {
+        Ok(storage::get_meta::<i64>(&*self.db, schema::MOZ_META_KEY_HISTORY_LAST_SYNC)?
+            .map(|millis| ServerTimestamp(millis as f64 / 1000.0)))
+    }
+
+    pub fn set_last_sync(&self, last_sync: ServerTimestamp) -> Result<()> {
+        debug!("Updating history last sync to {}", last_sync);
+        let last_sync_millis = last_sync.as_millis() as i64;
+        storage::put_meta(&*self.db, schema::MOZ_META_KEY_HISTORY_LAST_SYNC, &last_sync_millis)
+    }
+
+    pub fn get_sync_id(&self) -> Result<Option<String>> {
+        storage::get_meta::<String>(&*self.db, schema::MOZ_META_KEY_HISTORY_SYNC_ID)
+    }
+
+    pub fn set_sync_id(&self, sync_id: &str) -> Result<()> {
+        storage::put_meta(&*self.db, schema::MOZ_META_KEY_HISTORY_SYNC_ID, &sync_id)
+    }
+
+    // Forget everything we know about the server - every place will be
+    // uploaded on the next sync and all records will be re-downloaded.
+    pub fn reset(&mut self) -> Result<()> {
+        self.reset_sync_state(true)
+    }
+
+    // Like `reset`, but keeps our sync ID if `forget_sync_id` is false. We
+    // do this when we've just adopted a new sync ID from `meta/global`;
+    // otherwise, we'd forget it and reset again on the next sync.
+    fn reset_sync_state(&mut self, forget_sync_id: bool) -> Result<()> {
+        info!("Resetting history sync state");
+        let tx = self.db.db.transaction()?;
+        tx.execute_named_cached(
+            "UPDATE moz_places SET sync_status = :status, sync_change_counter = 0",
+            &[(":status", &SyncStatus::New)]
+        )?;
+        storage::delete_meta(&tx, schema::MOZ_META_KEY_HISTORY_LAST_SYNC)?;
+        if forget_sync_id {
+            storage::delete_meta(&tx, schema::MOZ_META_KEY_HISTORY_SYNC_ID)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    // Delete all history. Bookmarked places are kept, but lose their visits.
+    pub fn wipe(&mut self) -> Result<()> {
+        info!("Wiping history");
+        {
+            let tx = self.db.db.transaction()?;
+            tx.execute_all(&[
+                "DELETE FROM moz_historyvisits",
+                "DELETE FROM moz_places WHERE foreign_count = 0",
+                "UPDATE moz_places
+                 SET visit_count_local = 0, visit_count_remote = 0,
+                     last_visit_date_local = NULL, last_visit_date_remote = NULL",
+            ])?;
+            tx.commit()?;
+        }
+        self.reset()
+    }
+
+    // Adopts the sync ID from `meta/global`, resetting if it changed. If
+    // `will_reset` is true, `synchronize` resets us anyway, so we leave it to
+    // that. We don't have a sync ID if we've never synced, or if we've
+    // already been reset, so there's nothing to reset then, either.
+    fn adopt_sync_id(&mut self, remote_sync_id: &str, will_reset: bool) -> Result<()> {
+        let local_sync_id = self.get_sync_id()?;
+        if local_sync_id.as_ref().map(|id| id.as_str()) == Some(remote_sync_id) {
+            return Ok(());
+        }
+        if local_sync_id.is_some() && !will_reset {
+            info!("History sync ID changed; engine needs local reset");
+            self.reset_sync_state(false)?;
+        }
+        self.set_sync_id(remote_sync_id)
+    }
+
+    // Sync history. The caller is responsible for getting `state` to ready
+    // (ie, via `sync::SetupStateMachine`).
+    pub fn sync(
+        &mut self,
+        client: &Sync15StorageClient,
+        state: &GlobalState,
+        telem_engine: &mut telemetry::Engine,
+    ) -> Result<()> {
+        let remote_sync_id = state.global.as_ref()
+            .and_then(|g| g.payload.engines.get(COLLECTION_NAME))
+            .map(|engine| engine.sync_id.clone());
+        if let Some(remote_sync_id) = remote_sync_id {
+            let will_reset = state.engines_that_need_local_reset().contains(COLLECTION_NAME);
+            self.adopt_sync_id(&remote_sync_id, will_reset)?;
+        }
+
+        // Discard anything left over from an interrupted sync.
+        self.db.execute_all(&["DELETE FROM temp.moz_history_staging"])?;
+
+        let ts = self.get_last_sync()?.unwrap_or_default();
+        info!("Syncing history engine!");
+        let result = sync::synchronize(
+            client,
+            state,
+            self,
+            COLLECTION_NAME.into(),
+            ts,
+            false,
+            telem_engine
+        );
+        match &result {
+            Ok(()) => info!("History sync was successful!"),
+            Err(e) => warn!("History sync failed! {:?}", e),
+        }
+        result
+    }
+
+    fn do_apply_incoming(
+        &mut self,
+        inbound: IncomingChangeset,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> Result<OutgoingChangeset> {
+        let tx = self.db.db.transaction()?;
+        let num_incoming = inbound.changes.len();
+        for (payload, _) in inbound.changes {
+            apply_payload(&tx, payload, incoming_telemetry)?;
+        }
+        info!("Applied {} incoming history records", num_incoming);
+        let outgoing = fetch_outgoing(&tx, inbound.collection, inbound.timestamp)?;
+        tx.commit()?;
+        Ok(outgoing)
+    }
+
+    fn do_stage_incoming(&mut self, records: Vec<(Payload, ServerTimestamp)>) -> Result<()> {
+        let tx = self.db.db.transaction()?;
+        for (payload, _) in records {
+            // If a record is downloaded twice, we only want the latest.
+            tx.execute_named_cached(
+                "INSERT OR REPLACE INTO temp.moz_history_staging(guid, payload)
+                 VALUES(:guid, :payload)",
+                &[(":guid", &payload.id.clone()), (":payload", &payload.into_json_string())]
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn do_apply_staged(
+        &mut self,
+        timestamp: ServerTimestamp,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> Result<OutgoingChangeset> {
+        let tx = self.db.db.transaction()?;
+        let mut num_incoming = 0;
+        {
+            let mut stmt = tx.prepare("SELECT payload FROM temp.moz_history_staging")?;
+            let rows = stmt.query_and_then(&[], |row: &Row| -> Result<String> {
+                Ok(row.get_checked(0)?)
+            })?;
+            for json in rows {
+                let payload = Payload::from_json(serde_json::from_str(&json?)?)?;
+                apply_payload(&tx, payload, incoming_telemetry)?;
+                num_incoming += 1;
+            }
+        }
+        tx.execute_all(&["DELETE FROM temp.moz_history_staging"])?;
+        info!("Applied {} staged history records", num_incoming);
+        let outgoing = fetch_outgoing(&tx, COLLECTION_NAME.into(), timestamp)?;
+        tx.commit()?;
+        Ok(outgoing)
+    }
+
+    fn mark_as_synchronized(&mut self, guids: &[&str], ts: ServerTimestamp) -> Result<()> {
+        let tx = self.db.db.transaction()?;
+        sql_support::each_chunk(guids, |chunk, _| -> Result<()> {
+            tx.execute(
+                &format!("
+                    UPDATE moz_places
+                    SET sync_change_counter = 0, sync_status = {normal}
+                    WHERE guid IN ({vars})",
+                    normal = SyncStatus::Normal as u8,
+                    vars = sql_support::repeat_sql_vars(chunk.len())),
+                chunk
+            )?;
+            Ok(())
+        })?;
+        tx.commit()?;
+        self.set_last_sync(ts)?;
+        Ok(())
+    }
+}
+
+impl<'a> Store for HistoryStore<'a> {
+    type Error = Error;
+
+    fn apply_incoming(
+        &mut self,
+        inbound: IncomingChangeset,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> Result<OutgoingChangeset> {
+        self.do_apply_incoming(inbound, incoming_telemetry)
+    }
+
+    fn sync_finished(
+        &mut self,
+        new_timestamp: ServerTimestamp,
+        records_synced: &[String],
+    ) -> Result<()> {
+        self.mark_as_synchronized(
+            &records_synced.iter().map(|r| r.as_str()).collect::<Vec<_>>(),
+            new_timestamp
+        )
+    }
+
+    // `sync` has already adopted the new sync ID, so we keep it.
+    fn reset(&mut self) -> Result<()> {
+        self.reset_sync_state(false)
+    }
+
+    fn as_staging_store(&mut self) -> Option<&mut StagingStore<Error = Error>> {
+        Some(self)
+    }
+
+    fn trim_rejected_record(
+        &mut self,
+        record: &Payload,
+        reason: &RecordRejection,
+    ) -> Option<Payload> {
+        match reason {
+            RecordRejection::TooLarge { size, limit } => trim_visits(record, *size, *limit),
+            RecordRejection::Unencryptable(_) => None,
+        }
+    }
+}
+
+// We stage incoming records in a temp table, so that large first syncs
+// don't need to hold every record in memory.
+impl<'a> StagingStore for HistoryStore<'a> {
+    type Error = Error;
+
+    fn incoming_batch_size(&self) -> usize {
+        INCOMING_BATCH_SIZE
+    }
+
+    fn stage_incoming(
+        &mut self,
+        records: Vec<(Payload, ServerTimestamp)>,
+        _incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> Result<()> {
+        self.do_stage_incoming(records)
+    }
+
+    fn apply_staged(
+        &mut self,
+        timestamp: ServerTimestamp,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> Result<OutgoingChangeset> {
+        self.do_apply_staged(timestamp, incoming_telemetry)
+    }
+}
+
+fn apply_payload(
+    conn: &Connection,
+    payload: Payload,
+    incoming_telemetry: &mut telemetry::EngineIncoming,
+) -> Result<()> {
+    if payload.is_tombstone() {
+        apply_tombstone(conn, &SyncGuid(payload.id))?;
+        incoming_telemetry.applied(1);
+        return Ok(());
+    }
+    let record: HistoryRecord = match payload.into_record() {
+        Ok(record) => record,
+        Err(e) => {
+            // Don't let one bad record break the sync for everything else.
+            warn!("Ignoring invalid history record: {}", e);
+            incoming_telemetry.failed(1);
+            return Ok(());
+        }
+    };
+    apply_record(conn, record)?;
+    incoming_telemetry.applied(1);
+    Ok(())
+}
+
+// Drops the oldest visits from a record that's `size` bytes, so that it fits
+// in `limit`. Most of a large record is its visits, so we assume they're all
+// about the same size, and leave a quarter of the limit to spare. Returns
+// `None` if dropping visits won't help.
+fn trim_visits(payload: &Payload, size: usize, limit: usize) -> Option<Payload> {
+    let mut record: HistoryRecord = match payload.clone().into_record() {
+        Ok(record) => record,
+        Err(e) => {
+            warn!("Can't trim invalid history record {}: {}", payload.id, e);
+            return None;
+        }
+    };
+    let keep = record.visits.len() * limit * 3 / (size * 4);
+    if keep == 0 || keep >= record.visits.len() {
+        return None;
+    }
+    record.visits.sort_by(|a, b| b.date.cmp(&a.date));
+    record.visits.truncate(keep);
+    debug!("Trimmed history record {} to {} visits", payload.id, keep);
+    Payload::from_record(record).ok()
+}
+
+// A remote client deleted the place. We remove all visits, but only remove
+// the place itself if nothing else (eg, a bookmark) references it.
+fn apply_tombstone(conn: &Connection, guid: &SyncGuid) -> Result<()> {
+    debug!("Applying history tombstone for {:?}", guid);
+    conn.execute_named_cached("
+        DELETE FROM moz_historyvisits
+        WHERE place_id = (SELECT id FROM moz_places WHERE guid = :guid)",
+        &[(":guid", guid)]
+    )?;
+    conn.execute_named_cached(
+        "DELETE FROM moz_places WHERE guid = :guid AND foreign_count = 0",
+        &[(":guid", guid)]
+    )?;
+    conn.execute_named_cached("
+        UPDATE moz_places
+        SET visit_count_local = 0, visit_count_remote = 0,
+            last_visit_date_local = NULL, last_visit_date_remote = NULL,
+            sync_status = :status, sync_change_counter = 0
+        WHERE guid = :guid",
+        &[(":guid", guid), (":status", &SyncStatus::Normal)]
+    )?;
+    Ok(())
+}
+
+// Find the place for an incoming record - either by GUID or, failing that,
+// by URL (in which case the local GUID is changed to match the incoming one).
+fn find_place(conn: &Connection, guid: &SyncGuid, url: &Url) -> Result<Option<(RowId, SyncGuid)>> {
+    Ok(conn.try_query_row("
+        SELECT id, guid FROM moz_places
+        WHERE guid = :guid OR (url_hash = hash(:url) AND url = :url)
+        ORDER BY guid = :guid DESC
+        LIMIT 1",
+        &[(":guid", guid), (":url", &url.as_str())],
+        |row: &Row| -> Result<_> {
+            Ok((row.get_checked::<_, RowId>("id")?, row.get_checked::<_, SyncGuid>("guid")?))
+        },
+        true
+    )?)
+}
+
+fn apply_record(conn: &Connection, record: HistoryRecord) -> Result<()> {
+    let url = match Url::parse(&record.hist_uri) {
+        Ok(url) => url,
+        Err(e) => {
+            warn!("Ignoring history record {:?} with invalid URL: {}", record.id, e);
+            return Ok(());
+        }
+    };
+    let row_id = match find_place(conn, &record.id, &url)? {
+        Some((row_id, guid)) => {
+            if guid != record.id {
+                debug!("Changing local guid {:?} to incoming guid {:?}", guid, record.id);
+                conn.execute_named_cached(
+                    "UPDATE moz_places SET guid = :guid WHERE id = :row_id",
+                    &[(":guid", &record.id), (":row_id", &row_id)]
+                )?;
+            }
+            row_id
+        },
+        None => {
+            conn.execute_named_cached("
+                INSERT INTO moz_places (guid, url, url_hash, title, sync_status)
+                VALUES (:guid, :url, hash(:url), :title, :status)",
+                &[(":guid", &record.id),
+                  (":url", &url.as_str()),
+                  (":title", &record.title),
+                  (":status", &SyncStatus::Normal)]
+            )?;
+            RowId(conn.last_insert_rowid())
+        }
+    };
+
+    let mut existing = fetch_visit_keys(conn, row_id)?;
+    for visit in &record.visits {
+        let transition = match VisitTransition::from_primitive(visit.transition as u32) {
+            Some(transition) => transition,
+            None => {
+                warn!("Ignoring visit with invalid transition type {}", visit.transition);
+                continue;
+            }
+        };
+        let date = visit.timestamp();
+        if date.0 == 0 {
+            continue;
+        }
+        if existing.insert((date.0, transition as u8)) {
+            storage::add_visit(conn, &row_id, &None, &date, &transition, &false)?;
+        }
+    }
+
+    conn.execute_named_cached("
+        UPDATE moz_places SET
+            title = CASE WHEN :title = '' THEN title ELSE :title END,
+            hidden = CASE WHEN :has_visits THEN 0 ELSE hidden END,
+            visit_count_remote = (SELECT COUNT(*) FROM moz_historyvisits
+                                  WHERE place_id = :row_id AND NOT is_local),
+            last_visit_date_remote = (SELECT MAX(visit_date) FROM moz_historyvisits
+                                      WHERE place_id = :row_id AND NOT is_local),
+            sync_status = :status
+        WHERE id = :row_id",
+        &[(":title", &record.title),
+          (":has_visits", &!record.visits.is_empty()),
+          (":status", &SyncStatus::Normal),
+          (":row_id", &row_id)]
+    )?;
+
+    let frecency = frecency::calculate_frecency(conn,
+        &frecency::DEFAULT_FRECENCY_SETTINGS,
+        row_id.0,
+        None)?;
+    conn.execute_named_cached(
+        "UPDATE moz_places SET frecency = :frecency WHERE id = :row_id",
+        &[(":frecency", &frecency), (":row_id", &row_id)]
+    )?;
+    Ok(())
+}
+
+// The (date, transition) pairs of the existing visits for a place, used to
+// avoid duplicating visits we already have.
+fn fetch_visit_keys(conn: &Connection, row_id: RowId) -> Result<HashSet<(u64, u8)>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT visit_date, visit_type FROM moz_historyvisits WHERE place_id = :row_id")?;
+    let rows = stmt.query_and_then_named(&[(":row_id", &row_id)], |row| -> Result<_> {
+        Ok((row.get_checked::<_, Timestamp>(0)?.0, row.get_checked::<_, u8>(1)?))
+    })?;
+    let mut result = HashSet::new();
+    for row in rows {
+        result.insert(row?);
+    }
+    Ok(result)
+}
+
+fn fetch_outgoing(conn: &Connection, collection: String, timestamp: ServerTimestamp) -> Result<OutgoingChangeset> {
+    let mut outgoing = OutgoingChangeset::new(collection, timestamp);
+    let mut stmt = conn.prepare_cached("
+        SELECT id, guid, url, title FROM moz_places h
+        WHERE (sync_change_counter > 0 OR sync_status != :normal)
+          AND EXISTS (SELECT 1 FROM moz_historyvisits WHERE place_id = h.id)
+        ORDER BY frecency DESC
+        LIMIT :max_places")?;
+    let places = stmt.query_and_then_named(&[
+        (":normal", &SyncStatus::Normal as &ToSql),
+        (":max_places", &(MAX_OUTGOING_PLACES as i64)),
+    ], |row| -> Result<_> {
+        Ok((
+            row.get_checked::<_, RowId>("id")?,
+            row.get_checked::<_, SyncGuid>("guid")?,
+            row.get_checked::<_, String>("url")?,
+            row.get_checked::<_, Option<String>>("title")?.unwrap_or_default(),
+        ))
+    })?;
+    for place in places {
+        let (row_id, guid, url, title) = place?;
+        let visits = fetch_outgoing_visits(conn, row_id)?;
+        let record = HistoryRecord { id: guid, title, hist_uri: url, visits };
+        outgoing.changes.push(Payload::from_record(record)?);
+    }
+    info!("Uploading {} history records", outgoing.changes.len());
+    Ok(outgoing)
+}
+
+fn fetch_outgoing_visits(conn: &Connection, row_id: RowId) -> Result<Vec<HistoryRecordVisit>> {
+    let mut stmt = conn.prepare_cached("
+        SELECT visit_date, visit_type FROM moz_historyvisits
+        WHERE place_id = :row_id
+        ORDER BY visit_date DESC
+        LIMIT :max_visits")?;
+    let rows = stmt.query_and_then_named(&[
+        (":row_id", &row_id as &ToSql),
+        (":max_visits", &(MAX_OUTGOING_VISITS as i64)),
+    ], |row| -> Result<_> {
+        Ok(HistoryRecordVisit::from_timestamp(
+            row.get_checked::<_, Timestamp>(0)?,
+            row.get_checked::<_, u8>(1)?))
+    })?;
+    let mut visits = Vec::new();
+    for visit in rows {
+        visits.push(visit?);
+    }
+    Ok(visits)
+}
+
+impl<'a> SyncEngine for HistoryStore<'a> {
+    fn collection_name(&self) -> &'static str {
+        COLLECTION_NAME
+    }
+
+    fn sync(
+        &mut self,
+        client: &Sync15StorageClient,
+        state: &GlobalState,
+        telem_engine: &mut telemetry::Engine,
+    ) -> ::std::result::Result<(), failure::Error> {
+        Ok(HistoryStore::sync(self, client, state, telem_engine)?)
+    }
+
+    fn reset(&mut self) -> ::std::result::Result<(), failure::Error> {
+        HistoryStore::reset(self)?;
+        Ok(())
+    }
+
+    fn wipe(&mut self) -> ::std::result::Result<(), failure::Error> {
+        HistoryStore::wipe(self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use env_logger;
+    use serde_json;
+    use observation::VisitObservation;
+    use sync::telemetry::EngineIncoming;
+
+    fn incoming(records: Vec<serde_json::Value>) -> IncomingChangeset {
+        let mut changeset = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(0.0));
+        for record in records {
+            changeset.changes.push((Payload::from_json(record).unwrap(), ServerTimestamp(0.0)));
+        }
+        changeset
+    }
+
+    fn get_place(db: &PlacesDb, guid: &str) -> Option<(String, i32, SyncStatus, i32)> {
+        db.try_query_row("
+            SELECT url, visit_count_remote, sync_status, sync_change_counter
+            FROM moz_places WHERE guid = :guid",
+            &[(":guid", &guid)],
+            |row| -> Result<_> {
+                Ok((row.get_checked(0)?, row.get_checked(1)?, row.get_checked(2)?, row.get_checked(3)?))
+            },
+            false
+        ).expect("should query")
+    }
+
+    #[test]
+    fn test_apply_incoming() {
+        let _ = env_logger::try_init();
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let record = json!({
+            "id": "aaaaaaaaaaaa",
+            "histUri": "http://example.com/",
+            "title": "Example",
+            "visits": [{"date": 1_500_000_000_000_000u64, "type": 1},
+                       {"date": 1_500_000_001_000_000u64, "type": 2}],
+        });
+        {
+            let mut store = HistoryStore::new(&mut db);
+            let mut telem = EngineIncoming::new();
+            let outgoing = store.apply_incoming(incoming(vec![
+                record.clone(),
+                json!({"id": "bbbbbbbbbbbb", "histUri": "not a url", "visits": "oops"}),
+            ]), &mut telem).expect("should apply");
+            assert!(outgoing.changes.is_empty(), "nothing to upload");
+            assert_eq!(telem.get_applied(), 1);
+            assert_eq!(telem.get_failed(), 1);
+            // Applying the same record again must not duplicate visits.
+            store.apply_incoming(incoming(vec![record]), &mut EngineIncoming::new())
+                .expect("should apply");
+        }
+        let (url, visit_count, status, counter) = get_place(&db, "aaaaaaaaaaaa").expect("should exist");
+        assert_eq!(url, "http://example.com/");
+        assert_eq!(visit_count, 2);
+        assert_eq!(status, SyncStatus::Normal);
+        assert_eq!(counter, 0);
+    }
+
+    #[test]
+    fn test_incoming_matches_url() {
+        let _ = env_logger::try_init();
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let url = Url::parse("http://example.com/").unwrap();
+        storage::apply_observation(&mut db, VisitObservation::new(url)
+            .with_visit_type(VisitTransition::Link)).expect("should apply");
+        {
+            let mut store = HistoryStore::new(&mut db);
+            store.apply_incoming(incoming(vec![json!({
+                "id": "bbbbbbbbbbbb",
+                "histUri": "http://example.com/",
+                "visits": [{"date": 1_500_000_000_000_000u64, "type": 1}],
+            })]), &mut EngineIncoming::new()).expect("should apply");
+        }
+        // We should have taken the guid from the server rather than
+        // creating a second place.
+        let count: i64 = db.query_one("SELECT COUNT(*) FROM moz_places").unwrap();
+        assert_eq!(count, 1);
+        assert!(get_place(&db, "bbbbbbbbbbbb").is_some());
+    }
+
+    #[test]
+    fn test_incoming_tombstone() {
+        let _ = env_logger::try_init();
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let mut store = HistoryStore::new(&mut db);
+        store.apply_incoming(incoming(vec![json!({
+            "id": "aaaaaaaaaaaa",
+            "histUri": "http://example.com/",
+            "visits": [{"date": 1_500_000_000_000_000u64, "type": 1}],
+        })]), &mut EngineIncoming::new()).expect("should apply");
+        store.apply_incoming(incoming(vec![json!({
+            "id": "aaaaaaaaaaaa",
+            "deleted": true,
+        })]), &mut EngineIncoming::new()).expect("should apply");
+        assert!(get_place(&store.db, "aaaaaaaaaaaa").is_none());
+    }
+
+    #[test]
+    fn test_outgoing() {
+        let _ = env_logger::try_init();
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let url = Url::parse("http://example.com/").unwrap();
+        storage::apply_observation(&mut db, VisitObservation::new(url)
+            .with_title("Example".to_string())
+            .with_visit_type(VisitTransition::Link)).expect("should apply");
+
+        let mut store = HistoryStore::new(&mut db);
+        let outgoing = store.apply_incoming(incoming(vec![]), &mut EngineIncoming::new())
+            .expect("should apply");
+        assert_eq!(outgoing.changes.len(), 1);
+        let payload = outgoing.changes[0].clone();
+        let guid = payload.id.clone();
+        let record: HistoryRecord = payload.into_record().expect("should be a history record");
+        assert_eq!(record.hist_uri, "http://example.com/");
+        assert_eq!(record.title, "Example");
+        assert_eq!(record.visits.len(), 1);
+        assert_eq!(record.visits[0].transition, VisitTransition::Link as u8);
+
+        store.sync_finished(ServerTimestamp(1234.0), &[guid]).expect("should finish");
+        let outgoing = store.apply_incoming(incoming(vec![]), &mut EngineIncoming::new())
+            .expect("should apply");
+        assert!(outgoing.changes.is_empty(), "everything was uploaded");
+        assert_eq!(store.get_last_sync().unwrap(), Some(ServerTimestamp(1234.0)));
+    }
+
+    #[test]
+    fn test_staged_incoming() {
+        let _ = env_logger::try_init();
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let url = Url::parse("http://example.com/deleted").unwrap();
+        storage::apply_observation(&mut db, VisitObservation::new(url)
+            .with_visit_type(VisitTransition::Link)).expect("should apply");
+        let deleted_guid: String = db.query_one("SELECT guid FROM moz_places").unwrap();
+
+        let mut store = HistoryStore::new(&mut db);
+        let batch = |records: Vec<serde_json::Value>| incoming(records).changes;
+        store.stage_incoming(batch(vec![
+            json!({
+                "id": "aaaaaaaaaaaa",
+                "histUri": "http://example.com/a",
+                "visits": [{"date": 1_500_000_000_000_000u64, "type": 1}],
+            }),
+            json!({"id": deleted_guid.clone(), "deleted": true}),
+        ]), &mut EngineIncoming::new()).expect("should stage");
+        // Nothing is applied until every record has been staged.
+        assert!(get_place(&store.db, "aaaaaaaaaaaa").is_none());
+        // A record downloaded twice replaces the first copy.
+        store.stage_incoming(batch(vec![json!({
+            "id": "aaaaaaaaaaaa",
+            "histUri": "http://example.com/a",
+            "visits": [{"date": 1_500_000_000_000_000u64, "type": 1},
+                       {"date": 1_500_000_001_000_000u64, "type": 1}],
+        })]), &mut EngineIncoming::new()).expect("should stage");
+
+        let mut telem = EngineIncoming::new();
+        let outgoing = store.apply_staged(ServerTimestamp(1234.0), &mut telem)
+            .expect("should apply");
+        assert!(outgoing.changes.is_empty(), "nothing to upload");
+        assert_eq!(telem.get_applied(), 2);
+        let (_, visit_count, _, _) = get_place(&store.db, "aaaaaaaaaaaa").expect("should exist");
+        assert_eq!(visit_count, 2);
+        assert!(get_place(&store.db, &deleted_guid).is_none());
+        let staged: i64 = store.db.query_one("SELECT COUNT(*) FROM temp.moz_history_staging")
+            .unwrap();
+        assert_eq!(staged, 0);
+    }
+
+    #[test]
+    fn test_trim_rejected_record() {
+        let _ = env_logger::try_init();
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let mut store = HistoryStore::new(&mut db);
+        let visits = (0..20u64)
+            .map(|i| json!({"date": 1_500_000_000_000_000u64 + i, "type": 1}))
+            .collect::<Vec<_>>();
+        let record = Payload::from_json(json!({
+            "id": "aaaaaaaaaaaa",
+            "histUri": "http://example.com/",
+            "title": "Example",
+            "visits": visits,
+        })).unwrap();
+
+        // We keep the newest visits that should fit, with room to spare.
+        let trimmed = store.trim_rejected_record(&record, &RecordRejection::TooLarge {
+            size: 2000,
+            limit: 1000,
+        }).expect("should trim");
+        let trimmed: HistoryRecord = trimmed.into_record().unwrap();
+        assert_eq!(trimmed.hist_uri, "http://example.com/");
+        let dates = trimmed.visits.iter().map(|v| v.date - 1_500_000_000_000_000).collect::<Vec<_>>();
+        assert_eq!(dates, vec![19, 18, 17, 16, 15, 14, 13]);
+
+        // If we'd have to drop every visit, there's no point uploading it.
+        assert!(store.trim_rejected_record(&record, &RecordRejection::TooLarge {
+            size: 100_000,
+            limit: 1000,
+        }).is_none());
+        assert!(store.trim_rejected_record(
+            &record, &RecordRejection::Unencryptable("oops".into())).is_none());
+    }
+
+    #[test]
+    fn test_sync_id_change_resets_once() {
+        let _ = env_logger::try_init();
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        let url = Url::parse("http://example.com/").unwrap();
+        storage::apply_observation(&mut db, VisitObservation::new(url)
+            .with_visit_type(VisitTransition::Link)).expect("should apply");
+        let mut store = HistoryStore::new(&mut db);
+
+        // Our first sync just adopts the sync ID.
+        store.adopt_sync_id("first", false).expect("should adopt");
+        assert_eq!(store.get_sync_id().unwrap(), Some("first".to_string()));
+        let outgoing = store.apply_incoming(incoming(vec![]), &mut EngineIncoming::new())
+            .expect("should apply");
+        let guids = outgoing.changes.iter().map(|p| p.id.clone()).collect::<Vec<_>>();
+        store.sync_finished(ServerTimestamp(1234.0), &guids).expect("should finish");
+
+        // If `synchronize` is going to reset us, we only adopt the new ID,
+        // and its reset must keep it, so the next sync doesn't reset again.
+        store.adopt_sync_id("second", true).expect("should adopt");
+        assert_eq!(store.get_last_sync().unwrap(), Some(ServerTimestamp(1234.0)));
+        Store::reset(&mut store).expect("should reset");
+        assert_eq!(store.get_last_sync().unwrap(), None);
+        assert_eq!(store.get_sync_id().unwrap(), Some("second".to_string()));
+        store.sync_finished(ServerTimestamp(2345.0), &[]).expect("should finish");
+        store.adopt_sync_id("second", false).expect("should adopt");
+        assert_eq!(store.get_last_sync().unwrap(), Some(ServerTimestamp(2345.0)));
+
+        // Otherwise, we reset ourselves.
+        store.adopt_sync_id("third", false).expect("should adopt");
+        assert_eq!(store.get_last_sync().unwrap(), None);
+        assert_eq!(store.get_sync_id().unwrap(), Some("third".to_string()));
+        let outgoing = store.apply_incoming(incoming(vec![]), &mut EngineIncoming::new())
+            .expect("should apply");
+        assert_eq!(outgoing.changes.len(), 1, "should reupload after reset");
+    }
+}
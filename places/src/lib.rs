@@ -22,6 +22,7 @@ extern crate url;
 extern crate rusqlite;
 
 extern crate serde;
+#[cfg_attr(test, macro_use)]
 extern crate serde_json;
 
 #[macro_use]
@@ -40,6 +41,8 @@ pub mod storage;
 pub mod hash;
 pub mod frecency;
 pub mod observation;
+pub mod history_sync;
+pub mod bookmark_sync;
 mod util;
 
 pub use error::*;
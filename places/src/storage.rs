@@ -9,7 +9,7 @@
 use std::{fmt, cmp};
 use url::{Url};
 use types::{SyncGuid, Timestamp, VisitTransition};
-use error::{Result};
+use error::{Result, Error};
 use observation::{VisitObservation};
 use frecency;
 
@@ -151,7 +151,7 @@ pub fn apply_observation_direct(db: &Connection, visit_ob: VisitObservation) ->
 
         let at = visit_ob.at.unwrap_or_else(|| Timestamp::now());
         let is_remote = visit_ob.is_remote.unwrap_or(false);
-        add_visit(db, &page_info.row_id, &None, &at, &visit_type, &is_remote)?;
+        add_visit(db, &page_info.row_id, &None, &at, &visit_type, &!is_remote)?;
         if is_remote {
             page_info.visit_count_remote += 1;
             updates.push(("visit_count_remote", ":visit_count_remote", &page_info.visit_count_remote));
@@ -181,6 +181,16 @@ pub fn apply_observation_direct(db: &Connection, visit_ob: VisitObservation) ->
                           WHERE id == :row_id", sets.join(","));
         db.execute_named_cached(&sql, &params)?;
     }
+    // Local changes need to be uploaded by the history sync engine. Visits
+    // which themselves came from sync don't.
+    if !visit_ob.is_remote.unwrap_or(false) &&
+       (visit_ob.visit_type.is_some() || visit_ob.title.is_some()) {
+        db.execute_named_cached("
+            UPDATE moz_places
+            SET sync_change_counter = sync_change_counter + 1
+            WHERE id = :row_id",
+            &[(":row_id", &page_info.row_id.0)])?;
+    }
     // This needs to happen after the other updates.
     if update_frecency {
         page_info.frecency = frecency::calculate_frecency(db,
@@ -200,7 +210,7 @@ pub fn apply_observation_direct(db: &Connection, visit_ob: VisitObservation) ->
     Ok(())
 }
 
-fn new_page_info(db: &impl ConnExt, url: &Url) -> Result<PageInfo> {
+pub(crate) fn new_page_info(db: &impl ConnExt, url: &Url) -> Result<PageInfo> {
     let guid = super::sync::util::random_guid().expect("according to logins-sql, this is fine :)");
     let sql = "INSERT INTO moz_places (guid, url, url_hash)
                VALUES (:guid, :url, hash(:url))";
@@ -226,12 +236,12 @@ fn new_page_info(db: &impl ConnExt, url: &Url) -> Result<PageInfo> {
 // Add a single visit - you must know the page rowid. Does not update the
 // page info - if you are calling this, you will also need to update the
 // parent page with the new visit count, frecency, etc.
-fn add_visit(db: &impl ConnExt,
-             page_id: &RowId,
-             from_visit: &Option<RowId>,
-             visit_date: &Timestamp,
-             visit_type: &VisitTransition,
-             is_local: &bool) -> Result<RowId> {
+pub(crate) fn add_visit(db: &impl ConnExt,
+                        page_id: &RowId,
+                        from_visit: &Option<RowId>,
+                        visit_date: &Timestamp,
+                        visit_type: &VisitTransition,
+                        is_local: &bool) -> Result<RowId> {
     let sql =
         "INSERT INTO moz_historyvisits
             (from_visit, place_id, visit_date, visit_type, is_local)
@@ -263,11 +273,36 @@ pub fn update_frecency(db: &mut PlacesDb, id: RowId, redirect: Option<bool>) ->
     Ok(())
 }
 
+// Helpers for the moz_meta table.
+pub(crate) fn put_meta(db: &impl ConnExt, key: &str, value: &ToSql) -> Result<()> {
+    db.execute_named_cached(
+        "REPLACE INTO moz_meta (key, value) VALUES (:key, :value)",
+        &[(":key", &key as &ToSql), (":value", value)]
+    )?;
+    Ok(())
+}
+
+pub(crate) fn get_meta<T: FromSql>(db: &impl ConnExt, key: &str) -> Result<Option<T>> {
+    let res = db.try_query_row(
+        "SELECT value FROM moz_meta WHERE key = :key",
+        &[(":key", &key as &ToSql)],
+        |row| Ok::<_, Error>(row.get_checked(0)?),
+        true
+    )?;
+    Ok(res)
+}
+
+pub(crate) fn delete_meta(db: &impl ConnExt, key: &str) -> Result<()> {
+    db.execute_named_cached("DELETE FROM moz_meta WHERE key = :key", &[(":key", &key as &ToSql)])?;
+    Ok(())
+}
+
 // Mini experiment with an "Origin" object that knows how to rev_host() itself,
 // that I don't want to throw away yet :) I'm really not sure exactly how
 // moz_origins fits in TBH :/
 #[cfg(test)]
 mod tests {
+    use super::*;
 
     struct Origin {
         prefix: String,
@@ -296,4 +331,27 @@ mod tests {
         assert_eq!(o.rev_host(), "moc.oof");
     }
 
+    #[test]
+    fn test_visit_is_local() {
+        let mut db = PlacesDb::open_in_memory(None).expect("no memory db");
+        for &(url, is_remote) in &[("https://local.example.com", false),
+                                   ("https://remote.example.com", true)] {
+            let url = Url::parse(url).unwrap();
+            apply_observation(&mut db, VisitObservation::new(url)
+                .with_visit_type(VisitTransition::Link)
+                .with_is_remote(is_remote)).expect("should apply");
+        }
+        let is_local = |url: &str| -> bool {
+            db.query_row_named("
+                SELECT v.is_local FROM moz_historyvisits v
+                JOIN moz_places h ON h.id = v.place_id
+                WHERE h.url = :url",
+                &[(":url", &url)],
+                |row| row.get(0)
+            ).expect("should have a visit")
+        };
+        assert!(is_local("https://local.example.com/"));
+        assert!(!is_local("https://remote.example.com/"));
+    }
+
 }
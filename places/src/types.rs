@@ -5,11 +5,11 @@
 use std::{fmt};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use rusqlite::{types::{ToSql, FromSql, ToSqlOutput, FromSqlResult, ValueRef}};
+use rusqlite::{types::{ToSql, FromSql, ToSqlOutput, FromSqlResult, FromSqlError, ValueRef}};
 use rusqlite::Result as RusqliteResult;
 
 // XXX - copied from logins - surprised it's not in `sync`
-#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize, Default)]
 pub struct SyncGuid(pub String);
 
 impl AsRef<str> for SyncGuid {
@@ -133,6 +133,77 @@ impl VisitTransition {
     }
 }
 
+// NOTE: These discriminator values are the same as those used by Desktop
+// Firefox and are what is written to the database.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BookmarkType {
+    Bookmark = 1,
+    Folder = 2,
+    Separator = 3,
+}
+
+impl BookmarkType {
+    #[inline]
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(BookmarkType::Bookmark),
+            2 => Some(BookmarkType::Folder),
+            3 => Some(BookmarkType::Separator),
+            _ => None,
+        }
+    }
+}
+
+impl ToSql for BookmarkType {
+    fn to_sql(&self) -> RusqliteResult<ToSqlOutput> {
+        Ok(ToSqlOutput::from(*self as u8))
+    }
+}
+
+impl FromSql for BookmarkType {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        let v = value.as_i64()?;
+        if v < 0 || v > i64::from(u8::max_value()) {
+            return Err(FromSqlError::OutOfRange(v));
+        }
+        BookmarkType::from_u8(v as u8).ok_or(FromSqlError::OutOfRange(v))
+    }
+}
+
+// The sync status of a place (and, eventually, a bookmark). These values are
+// written to the database, so must not change.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SyncStatus {
+    Unknown = 0,
+    New = 1,
+    Normal = 2,
+}
+
+impl SyncStatus {
+    #[inline]
+    pub fn from_u8(v: u8) -> Self {
+        match v {
+            1 => SyncStatus::New,
+            2 => SyncStatus::Normal,
+            _ => SyncStatus::Unknown,
+        }
+    }
+}
+
+impl FromSql for SyncStatus {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        value.as_i64().map(|v| SyncStatus::from_u8(v as u8))
+    }
+}
+
+impl ToSql for SyncStatus {
+    fn to_sql(&self) -> RusqliteResult<ToSqlOutput> {
+        Ok(ToSqlOutput::from(*self as u8))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -142,4 +213,17 @@ mod tests {
         assert_eq!(Some(VisitTransition::Link), VisitTransition::from_primitive(1));
         assert_eq!(None, VisitTransition::from_primitive(99));
     }
+
+    #[test]
+    fn test_sync_status() {
+        assert_eq!(SyncStatus::New, SyncStatus::from_u8(1));
+        assert_eq!(SyncStatus::Normal, SyncStatus::from_u8(2));
+        assert_eq!(SyncStatus::Unknown, SyncStatus::from_u8(99));
+    }
+
+    #[test]
+    fn test_bookmark_type() {
+        assert_eq!(Some(BookmarkType::Folder), BookmarkType::from_u8(2));
+        assert_eq!(None, BookmarkType::from_u8(0));
+    }
 }
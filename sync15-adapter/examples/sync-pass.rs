@@ -24,22 +24,14 @@ use std::fs;
 use std::collections::HashMap;
 use std::borrow::Cow;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex};
 
-use fxa_client::{FirefoxAccount, Config, OAuthInfo};
+use fxa_client::{FirefoxAccount, Config};
+use sync::fxa::{FxaAccessTokenRefresher, SyncCredentials, SYNC_SCOPE};
 use sync::{ServerTimestamp, OutgoingChangeset, Payload, Store};
 
 const CLIENT_ID: &str = "3c8bd3fe92e1ddf1";
 const REDIRECT_URI: &str = "http://localhost:13131/oauth/complete";
-const SYNC_SCOPE: &str = "https://identity.mozilla.com/apps/oldsync";
-
-
-#[derive(Debug, Deserialize)]
-struct ScopedKeyData {
-    k: String,
-    kty: String,
-    kid: String,
-    scope: String,
-}
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -302,8 +294,11 @@ impl PasswordEngine {
         state: &sync::GlobalState,
     ) -> Result<(), failure::Error> {
         let ts = self.last_sync;
-        sync::synchronize(client, state, self, "passwords".into(), ts, true)?;
-        Ok(())
+        let mut telem_engine = sync::telemetry::Engine::new("passwords");
+        let result = sync::synchronize(client, state, self, "passwords".into(), ts, true,
+                                       &mut telem_engine);
+        info!("Sync telemetry: {}", serde_json::to_string(&telem_engine)?);
+        result
     }
 
     pub fn reset(&mut self) -> Result<(), failure::Error> {
@@ -361,7 +356,8 @@ impl Store for PasswordEngine {
 
     fn apply_incoming(
         &mut self,
-        inbound: sync::IncomingChangeset
+        inbound: sync::IncomingChangeset,
+        incoming_telemetry: &mut sync::telemetry::EngineIncoming,
     ) -> Result<OutgoingChangeset, failure::Error> {
         info!("Remote collection has {} changes", inbound.changes.len());
 
@@ -377,6 +373,7 @@ impl Store for PasswordEngine {
               reconciled.apply_as_outgoing.len());
 
         self.apply_reconciled_changes(&reconciled.apply_as_incoming[..], inbound.timestamp)?;
+        incoming_telemetry.applied(reconciled.apply_as_incoming.len());
 
         Ok(OutgoingChangeset {
             changes: reconciled.apply_as_outgoing,
@@ -393,6 +390,10 @@ impl Store for PasswordEngine {
         self.save()?;
         Ok(())
     }
+
+    fn reset(&mut self) -> Result<(), failure::Error> {
+        PasswordEngine::reset(self)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -531,30 +532,20 @@ fn main() -> Result<(), failure::Error> {
     env_logger::init();
 
     let cfg = Config::import_from("https://oauth-sync.dev.lcip.org")?;
-    let tokenserver_url = cfg.token_server_endpoint_url()?;
-
     let mut acct = load_or_create_fxa_creds(cfg.clone())?;
-    let token: OAuthInfo;
-    match acct.get_oauth_token(&[SYNC_SCOPE])? {
-        Some(t) => token = t,
-        None => {
-            // The cached credentials did not have appropriate scope, sign in again.
-            println!("Credentials do not have appropriate scope, launching OAuth flow.");
-            acct = create_fxa_creds(cfg.clone())?;
-            token = acct.get_oauth_token(&[SYNC_SCOPE])?.unwrap();
-        }
+    if acct.get_oauth_token(&[SYNC_SCOPE])?.is_none() {
+        // The cached credentials did not have appropriate scope, sign in again.
+        println!("Credentials do not have appropriate scope, launching OAuth flow.");
+        acct = create_fxa_creds(cfg.clone())?;
     }
-    let keys: HashMap<String, ScopedKeyData> = serde_json::from_str(&token.keys.unwrap())?;
-    let key = keys.get(SYNC_SCOPE).unwrap();
-
-    let client = sync::Sync15StorageClient::new(sync::Sync15StorageClientInit {
-        key_id: key.kid.clone(),
-        access_token: token.access_token.clone(),
-        tokenserver_url,
-    })?;
+    let credentials = SyncCredentials::from_account(&mut acct)?;
+    let acct = Arc::new(Mutex::new(acct));
+
+    let client = sync::Sync15StorageClient::new(credentials.client_init.clone())?
+        .with_access_token_refresher(Box::new(FxaAccessTokenRefresher::new(acct.clone())));
     let mut state = sync::GlobalState::default();
 
-    let root_sync_key = sync::KeyBundle::from_ksync_base64(&key.k)?;
+    let root_sync_key = credentials.root_sync_key.clone();
 
     let mut state_machine = sync::SetupStateMachine::for_readonly_sync(&client, &root_sync_key);
     state = state_machine.to_ready(state)?;
@@ -3,10 +3,11 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use bso_record::{EncryptedBso, Payload};
-use client::Sync15StorageClient;
+use client::{Sync15StorageClient, DOWNLOAD_BATCH_SIZE};
 use error::{self, ErrorKind, Result};
 use key_bundle::KeyBundle;
-use request::{NormalResponseHandler, UploadInfo};
+use request::{InfoConfiguration, NormalResponseHandler, RecordRejection, RejectedRecord,
+              UploadInfo};
 use state::GlobalState;
 use util::ServerTimestamp;
 
@@ -68,20 +69,50 @@ impl IncomingChangeset {
         collection: String,
         since: ServerTimestamp,
     ) -> Result<IncomingChangeset> {
-        let records = client.get_encrypted_records(&collection, since)?;
         let timestamp = state.last_modified_or_zero(&collection);
         let mut result = IncomingChangeset::new(collection, timestamp);
-        result.changes.reserve(records.len());
         let key = state.key_for_collection(&result.collection)?;
-        for record in records {
-            // TODO: if we see a HMAC error, may need to update crypto/keys?
-            let decrypted = record.decrypt(&key)?;
-            result.changes.push(decrypted.into_timestamped_payload());
+        {
+            // Decrypt each batch as it arrives, so we don't hold on to the
+            // encrypted records, too.
+            let changes = &mut result.changes;
+            client.get_encrypted_records_in_batches(&result.collection, since,
+                                                    DOWNLOAD_BATCH_SIZE,
+                                                    |records| -> Result<()> {
+                changes.reserve(records.len());
+                for record in records {
+                    // TODO: if we see a HMAC error, may need to update crypto/keys?
+                    let decrypted = record.decrypt(&key)?;
+                    changes.push(decrypted.into_timestamped_payload());
+                }
+                Ok(())
+            })?;
         }
         Ok(result)
     }
 }
 
+// Encrypts an outgoing record, and checks that it's small enough for the
+// server to accept.
+fn encrypt_outgoing(
+    payload: &Payload,
+    collection: &str,
+    key: &KeyBundle,
+    config: &InfoConfiguration,
+) -> ::std::result::Result<EncryptedBso, RecordRejection> {
+    let bso = payload
+        .clone()
+        .into_bso(collection.into())
+        .encrypt(key)
+        .map_err(|e| RecordRejection::Unencryptable(e.to_string()))?;
+    let size = bso.payload.serialized_len();
+    let limit = config.record_payload_limit();
+    if size >= limit {
+        return Err(RecordRejection::TooLarge { size, limit });
+    }
+    Ok(bso)
+}
+
 #[derive(Debug, Clone)]
 pub struct CollectionUpdate<'a, 'b> {
     client: &'a Sync15StorageClient,
@@ -89,6 +120,7 @@ pub struct CollectionUpdate<'a, 'b> {
     collection: String,
     xius: ServerTimestamp,
     to_update: Vec<EncryptedBso>,
+    rejected: Vec<RejectedRecord>,
     fully_atomic: bool,
 }
 
@@ -107,6 +139,7 @@ impl<'a, 'b> CollectionUpdate<'a, 'b> {
             collection,
             xius,
             to_update: records,
+            rejected: Vec::new(),
             fully_atomic,
         }
     }
@@ -117,28 +150,73 @@ impl<'a, 'b> CollectionUpdate<'a, 'b> {
         changeset: OutgoingChangeset,
         fully_atomic: bool,
     ) -> Result<CollectionUpdate<'a, 'b>> {
-        let collection = changeset.collection.clone();
+        CollectionUpdate::new_from_changeset_with_trim(client, state, changeset, fully_atomic,
+                                                       |_, _| None)
+    }
+
+    /// Like `new_from_changeset`, but calls `trim` with each record that's
+    /// too large to upload, or couldn't be encrypted. `trim` can return a
+    /// smaller copy of the record to upload instead; if it returns `None`,
+    /// or the trimmed record is still rejected, the record is reported in
+    /// the upload info's `rejected` list, or fails a fully atomic upload.
+    pub fn new_from_changeset_with_trim<F>(
+        client: &'a Sync15StorageClient,
+        state: &'b GlobalState,
+        changeset: OutgoingChangeset,
+        fully_atomic: bool,
+        mut trim: F,
+    ) -> Result<CollectionUpdate<'a, 'b>>
+    where
+        F: FnMut(&Payload, &RecordRejection) -> Option<Payload>,
+    {
+        let RecordChangeset { changes, timestamp: xius, collection } = changeset;
         let key_bundle = state.key_for_collection(&collection)?;
-        let xius = changeset.timestamp;
         if xius < state.last_modified_or_zero(&collection) {
             // Not actually interrupted, but we know we'd fail the XIUS check.
             return Err(ErrorKind::BatchInterrupted.into());
         }
-        let to_update = changeset.encrypt(&key_bundle)?;
-        Ok(CollectionUpdate::new(
+        let mut to_update = Vec::with_capacity(changes.len());
+        let mut rejected = Vec::new();
+        for payload in changes {
+            let reason = match encrypt_outgoing(&payload, &collection, key_bundle, &state.config) {
+                Ok(bso) => {
+                    to_update.push(bso);
+                    continue;
+                }
+                Err(reason) => reason,
+            };
+            let retried = match trim(&payload, &reason) {
+                Some(trimmed) => {
+                    encrypt_outgoing(&trimmed, &collection, key_bundle, &state.config)
+                }
+                None => Err(reason),
+            };
+            match retried {
+                Ok(bso) => to_update.push(bso),
+                Err(reason) => {
+                    warn!("Not uploading record {}: {:?}", payload.id, reason);
+                    if fully_atomic {
+                        return Err(reason.into_error());
+                    }
+                    rejected.push(RejectedRecord { id: payload.id, reason });
+                }
+            }
+        }
+        Ok(CollectionUpdate {
             client,
             state,
             collection,
             xius,
             to_update,
+            rejected,
             fully_atomic,
-        ))
+        })
     }
 
     /// Returns a list of the IDs that failed if allowed_dropped_records is true, otherwise
     /// returns an empty vec.
     pub fn upload(self) -> error::Result<UploadInfo> {
-        let mut failed = vec![];
+        let mut rejected = self.rejected;
         let mut q = self.client.new_post_queue(
             &self.collection,
             &self.state.config,
@@ -148,14 +226,27 @@ impl<'a, 'b> CollectionUpdate<'a, 'b> {
 
         for record in self.to_update.into_iter() {
             let enqueued = q.enqueue(&record)?;
-            if !enqueued && self.fully_atomic {
-                return Err(ErrorKind::RecordTooLargeError.into());
+            if !enqueued {
+                // The post queue also counts the rest of the BSO against the
+                // request size limit, so it can refuse records that we
+                // thought were small enough.
+                if self.fully_atomic {
+                    return Err(ErrorKind::RecordTooLargeError.into());
+                }
+                rejected.push(RejectedRecord {
+                    id: record.id.clone(),
+                    reason: RecordRejection::TooLarge {
+                        size: record.payload.serialized_len(),
+                        limit: self.state.config.record_payload_limit(),
+                    },
+                });
             }
         }
 
         q.flush(true)?;
         let mut info = q.completed_upload_info();
-        info.failed_ids.append(&mut failed);
+        info.failed_ids.extend(rejected.iter().map(|r| r.id.clone()));
+        info.rejected = rejected;
         if self.fully_atomic {
             assert_eq!(info.failed_ids.len(), 0,
                        "Bug: Should have failed by now if we aren't allowing dropped records");
@@ -3,22 +3,34 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use std::cell::Cell;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
-use hyper::{Method};
-use reqwest::{Client, Request, Response, Url, header::{self, HeaderValue, ACCEPT, AUTHORIZATION}};
+use http_support::{Method, ReqwestTransport, Request, Response, Transport, Url};
+use http_support::header::{self, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::Client;
 use serde;
-use serde_json;
 
 use bso_record::{BsoRecord, EncryptedBso};
 use error::{self, ErrorKind};
 use record_types::MetaGlobalRecord;
 use request::{BatchPoster, CollectionRequest, InfoConfiguration, PostQueue, PostResponse,
-              PostResponseHandler, X_IF_UNMODIFIED_SINCE, X_WEAVE_TIMESTAMP, InfoCollections};
+              PostResponseHandler, RequestOrder, X_IF_UNMODIFIED_SINCE, X_WEAVE_TIMESTAMP,
+              InfoCollections, X_LAST_MODIFIED, X_WEAVE_BACKOFF, X_WEAVE_NEXT_OFFSET, RETRY_AFTER};
 use std::str::FromStr;
 use token;
 use util::ServerTimestamp;
 
+/// How long to back off for if the server returns a 503 without telling us
+/// how long to wait.
+const DEFAULT_BACKOFF_SECS: u64 = 30 * 60;
+
+/// The number of records we download at a time.
+pub const DOWNLOAD_BATCH_SIZE: usize = 1000;
+
+/// The most IDs we pass to the server in a single `DELETE`. The server
+/// rejects requests with more than 100 IDs.
+const DELETE_BATCH_SIZE: usize = 100;
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Sync15StorageClientInit {
     pub key_id: String,
@@ -37,13 +49,16 @@ pub trait SetupStorageClient {
     fn fetch_crypto_keys(&self) -> error::Result<EncryptedBso>;
     fn put_crypto_keys(&self, keys: &EncryptedBso) -> error::Result<()>;
     fn wipe_all_remote(&self) -> error::Result<()>;
+    fn wipe_remote_collection(&self, collection: &str) -> error::Result<()>;
 }
 
 #[derive(Debug)]
 pub struct Sync15StorageClient {
-    http_client: Client,
+    transport: Box<Transport>,
     // We update this when we make requests
     timestamp: Cell<ServerTimestamp>,
+    // The latest time the server (or tokenserver) asked us to back off until.
+    backoff: Cell<Option<SystemTime>>,
     tsc: token::TokenProvider,
 }
 
@@ -59,7 +74,7 @@ impl SetupStorageClient for Sync15StorageClient {
     }
 
     fn fetch_meta_global(&self) -> error::Result<BsoRecord<MetaGlobalRecord>> {
-        let mut resp = match self.relative_storage_request(Method::GET, "storage/meta/global") {
+        let resp = match self.relative_storage_request(Method::GET, "storage/meta/global") {
             Ok(r) => Ok(r),
             Err(ref e) if e.is_not_found() => Err(ErrorKind::NoMetaGlobal.into()),
             Err(e) => Err(e)
@@ -75,7 +90,7 @@ impl SetupStorageClient for Sync15StorageClient {
     }
 
     fn fetch_crypto_keys(&self) -> error::Result<EncryptedBso> {
-        let mut keys_resp = self.relative_storage_request(Method::GET, "storage/crypto/keys")?;
+        let keys_resp = self.relative_storage_request(Method::GET, "storage/crypto/keys")?;
         let keys: EncryptedBso = keys_resp.json()?;
         Ok(keys)
     }
@@ -85,7 +100,7 @@ impl SetupStorageClient for Sync15StorageClient {
     }
 
     fn wipe_all_remote(&self) -> error::Result<()> {
-        let s = self.tsc.api_endpoint(&self.http_client)?;
+        let s = self.tsc.api_endpoint(&*self.transport)?;
         let url = Url::parse(&s)?;
 
         let req = self.build_request(Method::DELETE, url)?;
@@ -95,22 +110,68 @@ impl SetupStorageClient for Sync15StorageClient {
             Err(e) => Err(e)
         }
     }
+
+    fn wipe_remote_collection(&self, collection: &str) -> error::Result<()> {
+        let url = CollectionRequest::new(collection)
+            .build_url(Url::parse(&self.tsc.api_endpoint(&*self.transport)?)?)?;
+        let req = self.build_request(Method::DELETE, url)?;
+        match self.exec_request(req, true) {
+            Ok(_) => Ok(()),
+            // Nothing to delete.
+            Err(ref e) if e.is_not_found() => Ok(()),
+            Err(e) => Err(e)
+        }
+    }
 }
 
 impl Sync15StorageClient {
     pub fn new(init_params: Sync15StorageClientInit) -> error::Result<Sync15StorageClient> {
         let client = Client::builder().timeout(Duration::from_secs(30)).build()?;
+        let transport = ReqwestTransport::from_client(client);
+        Ok(Sync15StorageClient::with_transport(init_params, Box::new(transport)))
+    }
+
+    /// Creates a client that makes all its requests, including those to the
+    /// tokenserver, through `transport`.
+    pub fn with_transport(
+        init_params: Sync15StorageClientInit,
+        transport: Box<Transport>,
+    ) -> Sync15StorageClient {
         let tsc = token::TokenProvider::new(
             init_params.tokenserver_url,
             init_params.access_token,
             init_params.key_id,
         );
         let timestamp = ServerTimestamp(0f64);
-        Ok(Sync15StorageClient {
-            http_client: client,
+        Sync15StorageClient {
+            transport,
             timestamp: Cell::new(timestamp),
+            backoff: Cell::new(None),
             tsc,
-        })
+        }
+    }
+
+    /// Uses `refresher` to get a new OAuth access token if the tokenserver
+    /// rejects the one in our `Sync15StorageClientInit`.
+    pub fn with_access_token_refresher(
+        mut self,
+        refresher: Box<token::AccessTokenRefresher>,
+    ) -> Sync15StorageClient {
+        self.tsc.set_access_token_refresher(refresher);
+        self
+    }
+
+    /// Returns the client's tokenserver token, so that it can be persisted
+    /// and given to `restore_cached_token` when the app restarts.
+    pub fn cached_token(&self) -> Option<token::CachedToken> {
+        self.tsc.cached_token()
+    }
+
+    /// Uses a token from `cached_token` instead of asking the tokenserver
+    /// for a new one, if it's still valid. Returns true if the token will be
+    /// used. Call this before making any other requests with this client.
+    pub fn restore_cached_token(&self, cached: token::CachedToken) -> error::Result<bool> {
+        self.tsc.restore_cached_token(cached)
     }
 
     #[inline]
@@ -118,32 +179,130 @@ impl Sync15StorageClient {
         return self.timestamp.get();
     }
 
+    /// Returns the time until which the server asked us not to sync, if it
+    /// asked us to back off during any request made by this client. Callers
+    /// should persist this (`sync_multiple` stores it in the `GlobalState`),
+    /// and not start another sync until it's passed.
+    #[inline]
+    pub fn backoff_until(&self) -> Option<SystemTime> {
+        self.backoff.get()
+    }
+
+    fn note_backoff(&self, until: SystemTime) {
+        if self.backoff.get().map_or(true, |current| current < until) {
+            warn!("Server requested backoff until {:?}", until);
+            self.backoff.set(Some(until));
+        }
+    }
+
     pub fn get_encrypted_records(
         &self,
         collection: &str,
         since: ServerTimestamp,
     ) -> error::Result<Vec<EncryptedBso>> {
-        let mut resp = self.collection_request(
-            Method::GET,
-            CollectionRequest::new(collection).full().newer_than(since),
-        )?;
-        Ok(resp.json()?)
+        let mut records = Vec::new();
+        self.get_encrypted_records_in_batches(collection, since, DOWNLOAD_BATCH_SIZE,
+                                              |batch| -> error::Result<()> {
+            records.extend(batch);
+            Ok(())
+        })?;
+        Ok(records)
+    }
+
+    /// Downloads the records in `collection` that changed since `since`,
+    /// `batch_size` at a time, oldest first, and passes each batch to
+    /// `on_batch` as it arrives, so that callers don't need to hold the
+    /// whole collection in memory. Fails with `DownloadInterrupted` if
+    /// another client changes the collection before we've fetched every
+    /// batch. Returns the collection's last modified time. Errors returned
+    /// by `on_batch` stop the download, and are returned as-is.
+    pub fn get_encrypted_records_in_batches<F, E>(
+        &self,
+        collection: &str,
+        since: ServerTimestamp,
+        batch_size: usize,
+        mut on_batch: F,
+    ) -> Result<ServerTimestamp, E>
+    where
+        F: FnMut(Vec<EncryptedBso>) -> Result<(), E>,
+        E: From<error::Error>,
+    {
+        let mut request = CollectionRequest::new(collection);
+        request.full()
+               .newer_than(since)
+               .sort_by(RequestOrder::Oldest)
+               .limit(batch_size);
+        // Set from the first page, so that the server rejects the requests
+        // for later pages if the collection changes underneath us.
+        let mut last_modified: Option<ServerTimestamp> = None;
+        loop {
+            let resp = self.fetch_page(&request, last_modified)?;
+            if last_modified.is_none() {
+                last_modified = resp.header(X_LAST_MODIFIED)
+                    .and_then(|s| ServerTimestamp::from_str(s).ok());
+            }
+            let records: Vec<EncryptedBso> = resp.json().map_err(error::Error::from)?;
+            on_batch(records)?;
+            match resp.header(X_WEAVE_NEXT_OFFSET) {
+                Some(offset) => request.offset(Some(offset.into())),
+                None => break,
+            };
+        }
+        Ok(last_modified.unwrap_or_default())
+    }
+
+    /// Deletes the records with the given IDs from `collection`, in batches
+    /// of up to 100 IDs. IDs that aren't on the server are ignored.
+    pub fn delete_records(&self, collection: &str, ids: &[String]) -> error::Result<()> {
+        let base = Url::parse(&self.tsc.api_endpoint(&*self.transport)?)?;
+        for chunk in ids.chunks(DELETE_BATCH_SIZE) {
+            let url = CollectionRequest::new(collection).ids(chunk).build_url(base.clone())?;
+            let req = self.build_request(Method::DELETE, url)?;
+            match self.exec_request(req, true) {
+                Ok(_) => {}
+                Err(ref e) if e.is_not_found() => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn fetch_page(
+        &self,
+        request: &CollectionRequest,
+        xius: Option<ServerTimestamp>,
+    ) -> error::Result<Response> {
+        let url = request.build_url(Url::parse(&self.tsc.api_endpoint(&*self.transport)?)?)?;
+        let mut req = self.build_request(Method::GET, url)?;
+        if let Some(ts) = xius {
+            req = req.header(X_IF_UNMODIFIED_SINCE, HeaderValue::from_str(&format!("{}", ts))?);
+        }
+        match self.exec_request(req, true) {
+            Err(ref e) if e.is_precondition_failed() => Err(ErrorKind::DownloadInterrupted.into()),
+            result => result,
+        }
     }
 
     #[inline]
-    fn authorized(&self, mut req: Request) -> error::Result<Request> {
-        let hawk_header_value = self.tsc.authorization(&self.http_client, &req)?;
-        req.headers_mut().insert(AUTHORIZATION, HeaderValue::from_str(&hawk_header_value)?);
-        Ok(req)
+    fn authorized(&self, req: Request) -> error::Result<Request> {
+        let hawk_header_value = match self.tsc.authorization(&*self.transport, &req) {
+            Ok(value) => value,
+            Err(e) => {
+                // The tokenserver can ask us to back off, too.
+                if let ErrorKind::BackoffError(until) = e.kind() {
+                    self.note_backoff(*until);
+                }
+                return Err(e);
+            }
+        };
+        Ok(req.header(AUTHORIZATION, HeaderValue::from_str(&hawk_header_value)?))
     }
 
     // TODO: probably want a builder-like API to do collection requests (e.g. something
     // that occupies roughly the same conceptual role as the Collection class in desktop)
     fn build_request(&self, method: Method, url: Url) -> error::Result<Request> {
-        self.authorized(self.http_client
-            .request(method, url)
-            .header(ACCEPT, "application/json")
-            .build()?)
+        self.authorized(Request::new(method, url)
+            .header(ACCEPT, HeaderValue::from_static("application/json")))
     }
 
     fn relative_storage_request<T>(
@@ -154,7 +313,7 @@ impl Sync15StorageClient {
     where
         T: AsRef<str>,
     {
-        let s = self.tsc.api_endpoint(&self.http_client)? + "/";
+        let s = self.tsc.api_endpoint(&*self.transport)? + "/";
         let url = Url::parse(&s)?.join(relative_path.as_ref())?;
         Ok(self.make_storage_request(method, url)?)
     }
@@ -165,43 +324,46 @@ impl Sync15StorageClient {
     }
 
     fn exec_request(&self, req: Request, require_success: bool) -> error::Result<Response> {
-        let resp = self.http_client.execute(req)?;
-
-        self.update_timestamp(resp.headers());
+        let resp = self.transport.execute(req)?;
+
+        self.update_timestamp(&resp.headers);
+
+        let status = resp.status.as_u16();
+        if let Some(until) = backoff_from_headers(&resp.headers, status, SystemTime::now()) {
+            self.note_backoff(until);
+            // A backoff header on a successful response means "finish what
+            // you're doing, then go away", but a 503 means the request
+            // failed, so we can't continue.
+            if status == 503 {
+                return Err(ErrorKind::BackoffError(until).into());
+            }
+        }
 
-        if require_success && !resp.status().is_success() {
+        if require_success && !resp.is_success() {
             error!(
                 "HTTP error {} ({}) during storage request to {}",
-                resp.status().as_u16(),
-                resp.status(),
-                resp.url().path()
+                status,
+                resp.status,
+                resp.url.path()
             );
             return Err(ErrorKind::StorageHttpError {
-                code: resp.status().as_u16(),
-                route: resp.url().path().into(),
+                code: status,
+                route: resp.url.path().into(),
             }.into());
         }
 
         // TODO:
-        // - handle backoff
         // - x-weave-quota?
         // - ... almost certainly other things too...
 
         Ok(resp)
     }
 
-    fn collection_request(&self, method: Method, r: &CollectionRequest) -> error::Result<Response> {
-        self.make_storage_request(
-            method.clone(),
-            r.build_url(Url::parse(&self.tsc.api_endpoint(&self.http_client)?)?)?,
-        )
-    }
-
     fn fetch_info<T>(&self, path: &str) -> error::Result<T>
     where
         for<'a> T: serde::de::Deserialize<'a>,
     {
-        let mut resp = self.relative_storage_request(Method::GET, path)?;
+        let resp = self.relative_storage_request(Method::GET, path)?;
         let result: T = resp.json()?;
         Ok(result)
     }
@@ -239,23 +401,40 @@ impl Sync15StorageClient {
         P: AsRef<str>,
         B: serde::ser::Serialize,
     {
-        let s = self.tsc.api_endpoint(&self.http_client)? + "/";
+        let s = self.tsc.api_endpoint(&*self.transport)? + "/";
         let url = Url::parse(&s)?.join(relative_path.as_ref())?;
 
-        let bytes = serde_json::to_vec(body)?;
-
-        let mut req = self.build_request(Method::PUT, url)?;
-        req.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let mut req = self.build_request(Method::PUT, url)?.json(body)?;
         if let Some(ts) = xius {
-            req.headers_mut().insert(X_IF_UNMODIFIED_SINCE, HeaderValue::from_str(&format!("{}", ts))?);
+            req = req.header(X_IF_UNMODIFIED_SINCE, HeaderValue::from_str(&format!("{}", ts))?);
         }
-        *req.body_mut() = Some(bytes.into());
         let _ = self.exec_request(req, true)?;
 
         Ok(())
     }
 }
 
+// Returns the time until which the server asked us to back off, if it did.
+// Both headers are in seconds; `X-Weave-Backoff` can be sent with any
+// response, while `Retry-After` is sent with 503s (and we assume a default
+// if a 503 has neither).
+fn backoff_from_headers(
+    headers: &header::HeaderMap,
+    status: u16,
+    now: SystemTime,
+) -> Option<SystemTime> {
+    let secs = [X_WEAVE_BACKOFF, RETRY_AFTER].iter()
+        .filter_map(|name| headers.get(*name))
+        .filter_map(|value| value.to_str().ok().and_then(|s| s.trim().parse::<f64>().ok()))
+        .filter(|secs| *secs >= 0.0)
+        .fold(None, |max: Option<f64>, secs| Some(max.map_or(secs, |max| max.max(secs))));
+    match secs {
+        Some(secs) => Some(now + Duration::from_millis((secs * 1000.0) as u64)),
+        None if status == 503 => Some(now + Duration::from_secs(DEFAULT_BACKOFF_SECS)),
+        None => None,
+    }
+}
+
 pub struct PostWrapper<'a> {
     client: &'a Sync15StorageClient,
     coll: String,
@@ -275,15 +454,157 @@ impl<'a> BatchPoster for PostWrapper<'a> {
             .commit(commit)
             .build_url(Url::parse(&self.client
                 .tsc
-                .api_endpoint(&self.client.http_client)?)?)?;
+                .api_endpoint(&*self.client.transport)?)?)?;
 
-        let mut req = self.client.build_request(Method::POST, url)?;
-        req.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
-        req.headers_mut().insert(X_IF_UNMODIFIED_SINCE, HeaderValue::from_str(&format!("{}", xius))?);
         // It's very annoying that we need to copy the body here, the request
         // shouldn't need to take ownership of it...
-        *req.body_mut() = Some(Vec::from(bytes).into());
-        let mut resp = self.client.exec_request(req, false)?;
-        Ok(PostResponse::from_response(&mut resp)?)
+        let req = self.client.build_request(Method::POST, url)?
+            .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+            .header(X_IF_UNMODIFIED_SINCE, HeaderValue::from_str(&format!("{}", xius))?)
+            .body(bytes);
+        let resp = self.client.exec_request(req, false)?;
+        Ok(PostResponse::from_response(&resp)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_support::{InMemoryTransport, MockResponse};
+    use mock_server::{MockBso, MockSyncServer};
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> header::HeaderMap {
+        let mut headers = header::HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_static(*value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_backoff_from_headers() {
+        let now = SystemTime::now();
+        assert_eq!(backoff_from_headers(&headers(&[]), 200, now), None);
+        assert_eq!(backoff_from_headers(&headers(&[(X_WEAVE_BACKOFF, "60")]), 200, now),
+                   Some(now + Duration::from_secs(60)));
+        // We use the longer of the two.
+        assert_eq!(backoff_from_headers(&headers(&[(X_WEAVE_BACKOFF, "60"),
+                                                   (RETRY_AFTER, "120.5")]), 503, now),
+                   Some(now + Duration::from_millis(120_500)));
+        assert_eq!(backoff_from_headers(&headers(&[(RETRY_AFTER, "not a number")]), 503, now),
+                   Some(now + Duration::from_secs(DEFAULT_BACKOFF_SECS)));
+        assert_eq!(backoff_from_headers(&headers(&[(RETRY_AFTER, "-1")]), 500, now), None);
+    }
+
+    #[test]
+    fn test_requests_use_transport() {
+        let transport = InMemoryTransport::new();
+        transport.respond(Method::GET, "/1.0/sync/1.5", MockResponse::new(200)
+            .header("X-Timestamp", "1500000000")
+            .json(&json!({
+                "id": "id",
+                "key": "key",
+                "api_endpoint": "https://sync.example.com/1.5/12345",
+                "uid": 12345,
+                "duration": 3600,
+                "hashed_fxa_uid": "hash",
+            })));
+        transport.respond(Method::GET, "/1.5/12345/info/collections", MockResponse::new(200)
+            .header(X_WEAVE_TIMESTAMP, "1500000001.50")
+            .header(X_WEAVE_BACKOFF, "60")
+            .json(&json!({ "bookmarks": 1500000001.50 })));
+
+        let client = Sync15StorageClient::with_transport(Sync15StorageClientInit {
+            key_id: "key-id".into(),
+            access_token: "access-token".into(),
+            tokenserver_url: Url::parse("https://token.example.com/1.0/sync/1.5").unwrap(),
+        }, Box::new(transport.clone()));
+
+        let collections = client.fetch_info_collections().expect("should fetch collections");
+        assert_eq!(collections.get("bookmarks"), Some(&ServerTimestamp(1500000001.50)));
+        assert!(client.backoff_until().is_some());
+        assert!(!transport.has_pending_responses());
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].headers[AUTHORIZATION], "Bearer access-token");
+        assert!(requests[1].headers[AUTHORIZATION].to_str().unwrap().starts_with("Hawk "));
+    }
+
+    fn encrypted_record(id: &str) -> MockBso {
+        MockBso {
+            id: id.into(),
+            modified: ServerTimestamp::default(),
+            sortindex: None,
+            ttl: None,
+            payload: json!({ "IV": "", "hmac": "", "ciphertext": id }).to_string(),
+        }
+    }
+
+    #[test]
+    fn test_download_in_batches() {
+        let server = MockSyncServer::new();
+        for id in &["a", "b", "c", "d", "e"] {
+            server.insert_records("history", vec![encrypted_record(id)]);
+        }
+        let client = server.client();
+
+        let mut batches: Vec<Vec<String>> = Vec::new();
+        let last_modified = client.get_encrypted_records_in_batches(
+            "history", ServerTimestamp(0.0), 2, |records| -> error::Result<()> {
+                batches.push(records.into_iter().map(|r| r.id).collect());
+                Ok(())
+            }).expect("should download");
+        assert_eq!(batches, vec![vec!["a", "b"], vec!["c", "d"], vec!["e"]]);
+        assert_eq!(last_modified, server.record("history", "e").unwrap().modified);
+
+        // If another client changes the collection while we're downloading
+        // it, the server rejects our request for the next page.
+        let mut pages = 0;
+        let err = client.get_encrypted_records_in_batches(
+            "history", ServerTimestamp(0.0), 2, |_| -> error::Result<()> {
+                pages += 1;
+                server.insert_records("history", vec![encrypted_record("f")]);
+                Ok(())
+            }).expect_err("should be interrupted");
+        match err.kind() {
+            ErrorKind::DownloadInterrupted => {}
+            kind => panic!("unexpected error {:?}", kind),
+        }
+        assert_eq!(pages, 1);
+    }
+
+    #[test]
+    fn test_delete_records() {
+        let server = MockSyncServer::new();
+        let ids: Vec<String> = (0..150).map(|i| format!("record{:03}", i)).collect();
+        server.insert_records("history", ids.iter().map(|id| encrypted_record(id)).collect());
+        server.insert_records("bookmarks", vec![encrypted_record("a")]);
+        let client = server.client();
+
+        // Fetch a token first, so that we only count storage requests.
+        client.fetch_info_collections().expect("should fetch collections");
+        // Deletes more IDs than fit in a single request.
+        let before = server.request_count();
+        client.delete_records("history", &ids[..120]).expect("should delete records");
+        assert_eq!(server.request_count() - before, 2);
+        let remaining: Vec<String> =
+            server.records("history").into_iter().map(|record| record.id).collect();
+        assert_eq!(remaining, &ids[120..]);
+
+        // Exactly as many IDs as fit in a request only need one.
+        let form_ids: Vec<String> =
+            (0..DELETE_BATCH_SIZE).map(|i| format!("form{:03}", i)).collect();
+        server.insert_records("forms", form_ids.iter().map(|id| encrypted_record(id)).collect());
+        let before = server.request_count();
+        client.delete_records("forms", &form_ids).expect("should delete records");
+        assert_eq!(server.request_count() - before, 1);
+        assert!(server.records("forms").is_empty());
+
+        client.wipe_remote_collection("history").expect("should wipe history");
+        assert!(server.records("history").is_empty());
+        assert_eq!(server.records("bookmarks").len(), 1);
+        // Wiping a collection that isn't there is fine.
+        client.wipe_remote_collection("history").expect("should wipe history again");
     }
 }
@@ -0,0 +1,397 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The clients engine. Every Sync client uploads a record describing itself
+//! to the "clients" collection, and other clients can add commands to that
+//! record (for example, asking it to wipe an engine, or to open a URL). This
+//! engine keeps our own record up to date, tracks the other clients, and
+//! hands any commands sent to us to a `CommandProcessor`.
+
+use std::collections::HashMap;
+
+use bso_record::Payload;
+use changeset::{CollectionUpdate, IncomingChangeset, OutgoingChangeset};
+use client::Sync15StorageClient;
+use error::Result;
+use state::GlobalState;
+use util::ServerTimestamp;
+
+/// The name of the collection on the server.
+pub const COLLECTION_NAME: &'static str = "clients";
+
+/// The record format for the "clients" collection.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientRecord {
+    pub id: String,
+
+    pub name: String,
+
+    #[serde(rename = "type")]
+    #[serde(default)]
+    pub typ: String,
+
+    #[serde(default)]
+    pub commands: Vec<CommandRecord>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fxa_device_id: Option<String>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub protocols: Vec<String>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_package: Option<String>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub application: Option<String>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+}
+
+/// A command in a client record, as it appears on the server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CommandRecord {
+    pub command: String,
+
+    #[serde(default)]
+    pub args: Vec<String>,
+
+    #[serde(rename = "flowID")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flow_id: Option<String>,
+}
+
+/// A command sent to us by another client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Wipe all local data for an engine.
+    Wipe(String),
+    /// Reset the sync state for an engine.
+    Reset(String),
+    /// Reset the sync state for all engines.
+    ResetAll,
+    /// Open a URL. `sender` is the id of the client which sent it.
+    DisplayUri { uri: String, sender: String, title: String },
+}
+
+impl Command {
+    /// Parses a command record. Returns `None` for commands we don't know
+    /// about, or which have the wrong arguments.
+    pub fn from_record(record: &CommandRecord) -> Option<Command> {
+        let args = &record.args;
+        match (record.command.as_str(), args.len()) {
+            ("wipeEngine", 1) => Some(Command::Wipe(args[0].clone())),
+            ("resetEngine", 1) => Some(Command::Reset(args[0].clone())),
+            ("resetAll", 0) => Some(Command::ResetAll),
+            ("displayURI", 2) | ("displayURI", 3) => Some(Command::DisplayUri {
+                uri: args[0].clone(),
+                sender: args[1].clone(),
+                title: args.get(2).cloned().unwrap_or_default(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// What happened to a command once it was handed to the processor. The
+/// command is removed from our record whatever the status, so it won't be
+/// seen again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandStatus {
+    Applied,
+    Ignored,
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    Desktop,
+    Mobile,
+}
+
+impl DeviceType {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            DeviceType::Desktop => "desktop",
+            DeviceType::Mobile => "mobile",
+        }
+    }
+}
+
+/// Describes this client. The id should be persisted by the application,
+/// so that we keep using the same record across restarts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub client_id: String,
+    pub client_name: String,
+    pub client_type: DeviceType,
+    pub fxa_device_id: Option<String>,
+}
+
+/// Implemented by the application to handle commands sent to this client.
+pub trait CommandProcessor {
+    fn settings(&self) -> &Settings;
+
+    fn apply_incoming_command(&mut self, command: Command) -> CommandStatus;
+}
+
+/// Another client, as described by its record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteClient {
+    pub id: String,
+    pub name: String,
+    pub typ: String,
+    pub fxa_device_id: Option<String>,
+}
+
+impl RemoteClient {
+    fn from_record(record: ClientRecord) -> RemoteClient {
+        RemoteClient {
+            id: record.id,
+            name: record.name,
+            typ: record.typ,
+            fxa_device_id: record.fxa_device_id,
+        }
+    }
+}
+
+pub struct Engine<'a> {
+    command_processor: &'a mut CommandProcessor,
+    remote_clients: HashMap<String, RemoteClient>,
+}
+
+impl<'a> Engine<'a> {
+    pub fn new(command_processor: &'a mut CommandProcessor) -> Engine<'a> {
+        Engine {
+            command_processor,
+            remote_clients: HashMap::new(),
+        }
+    }
+
+    /// The other clients, as of the last sync, keyed by id.
+    pub fn remote_clients(&self) -> &HashMap<String, RemoteClient> {
+        &self.remote_clients
+    }
+
+    /// Syncs the clients collection. The collection is small, so we always
+    /// fetch all of it rather than tracking a last sync time.
+    pub fn sync(&mut self, client: &Sync15StorageClient, state: &GlobalState) -> Result<()> {
+        info!("Syncing clients engine!");
+        let inbound = IncomingChangeset::fetch(
+            client,
+            state,
+            COLLECTION_NAME.into(),
+            ServerTimestamp::default()
+        )?;
+        let outgoing = self.process_incoming(inbound)?;
+        if outgoing.changes.is_empty() {
+            info!("Our client record is up to date");
+            return Ok(());
+        }
+        let upload_info =
+            CollectionUpdate::new_from_changeset(client, state, outgoing, false)?.upload()?;
+        info!("Uploaded our client record ({} succeeded, {} failed)",
+              upload_info.successful_ids.len(),
+              upload_info.failed_ids.len());
+        Ok(())
+    }
+
+    /// Applies the downloaded client records, and runs any commands in our
+    /// own record. Returns our record if it needs to be uploaded.
+    pub fn process_incoming(&mut self, inbound: IncomingChangeset) -> Result<OutgoingChangeset> {
+        let settings = self.command_processor.settings().clone();
+        let mut outgoing = OutgoingChangeset::new(inbound.collection, inbound.timestamp);
+        let mut remote_clients = HashMap::new();
+        let mut current_record = None;
+
+        for (payload, _) in inbound.changes {
+            if payload.is_tombstone() {
+                continue;
+            }
+            let record: ClientRecord = match payload.into_record() {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!("Ignoring invalid client record: {}", e);
+                    continue;
+                }
+            };
+            if record.id == settings.client_id {
+                current_record = Some(record);
+            } else {
+                remote_clients.insert(record.id.clone(), RemoteClient::from_record(record));
+            }
+        }
+        self.remote_clients = remote_clients;
+
+        let mut processed_commands = false;
+        if let Some(ref current) = current_record {
+            for command_record in &current.commands {
+                processed_commands = true;
+                let status = match Command::from_record(command_record) {
+                    Some(command) => self.command_processor.apply_incoming_command(command),
+                    None => CommandStatus::Unsupported,
+                };
+                info!("Processed command {:?}: {:?}", command_record.command, status);
+            }
+        }
+
+        let new_record = ClientRecord {
+            id: settings.client_id.clone(),
+            name: settings.client_name.clone(),
+            typ: settings.client_type.as_str().into(),
+            commands: Vec::new(),
+            fxa_device_id: settings.fxa_device_id.clone(),
+            version: current_record.as_ref().and_then(|r| r.version.clone()),
+            protocols: vec!["1.5".into()],
+            os: current_record.as_ref().and_then(|r| r.os.clone()),
+            app_package: current_record.as_ref().and_then(|r| r.app_package.clone()),
+            application: current_record.as_ref().and_then(|r| r.application.clone()),
+            device: current_record.as_ref().and_then(|r| r.device.clone()),
+        };
+        if processed_commands || current_record.as_ref() != Some(&new_record) {
+            outgoing.changes.push(Payload::from_record(new_record)?);
+        }
+        Ok(outgoing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    struct TestProcessor {
+        settings: Settings,
+        commands: Vec<Command>,
+    }
+
+    impl CommandProcessor for TestProcessor {
+        fn settings(&self) -> &Settings {
+            &self.settings
+        }
+
+        fn apply_incoming_command(&mut self, command: Command) -> CommandStatus {
+            let status = match command {
+                Command::DisplayUri { .. } => CommandStatus::Ignored,
+                _ => CommandStatus::Applied,
+            };
+            self.commands.push(command);
+            status
+        }
+    }
+
+    fn processor() -> TestProcessor {
+        TestProcessor {
+            settings: Settings {
+                client_id: "my-client".into(),
+                client_name: "My Phone".into(),
+                client_type: DeviceType::Mobile,
+                fxa_device_id: None,
+            },
+            commands: Vec::new(),
+        }
+    }
+
+    fn incoming(records: Vec<serde_json::Value>) -> IncomingChangeset {
+        let mut changeset = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(0.0));
+        for record in records {
+            changeset.changes.push((Payload::from_json(record).unwrap(), ServerTimestamp(1.0)));
+        }
+        changeset
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        let record = |command: &str, args: &[&str]| CommandRecord {
+            command: command.into(),
+            args: args.iter().map(|s| s.to_string()).collect(),
+            flow_id: None,
+        };
+        assert_eq!(Command::from_record(&record("wipeEngine", &["bookmarks"])),
+                   Some(Command::Wipe("bookmarks".into())));
+        assert_eq!(Command::from_record(&record("resetAll", &[])), Some(Command::ResetAll));
+        assert_eq!(Command::from_record(&record("displayURI", &["https://example.com", "other"])),
+                   Some(Command::DisplayUri {
+                       uri: "https://example.com".into(),
+                       sender: "other".into(),
+                       title: "".into(),
+                   }));
+        assert_eq!(Command::from_record(&record("wipeEngine", &[])), None);
+        assert_eq!(Command::from_record(&record("logout", &[])), None);
+    }
+
+    #[test]
+    fn test_first_sync_uploads_record() {
+        let mut processor = processor();
+        let mut engine = Engine::new(&mut processor);
+        let outgoing = engine.process_incoming(incoming(vec![
+            json!({"id": "other-client", "name": "Laptop", "type": "desktop"}),
+        ])).expect("should process");
+        assert_eq!(outgoing.changes.len(), 1);
+        let record: ClientRecord = outgoing.changes[0].clone().into_record().unwrap();
+        assert_eq!(record.id, "my-client");
+        assert_eq!(record.typ, "mobile");
+        assert_eq!(engine.remote_clients().len(), 1);
+        assert_eq!(engine.remote_clients()["other-client"].name, "Laptop");
+    }
+
+    #[test]
+    fn test_commands_are_processed_and_removed() {
+        let mut processor = processor();
+        {
+            let mut engine = Engine::new(&mut processor);
+            let outgoing = engine.process_incoming(incoming(vec![
+                json!({
+                    "id": "my-client",
+                    "name": "My Phone",
+                    "type": "mobile",
+                    "protocols": ["1.5"],
+                    "commands": [
+                        {"command": "wipeEngine", "args": ["history"]},
+                        {"command": "displayURI", "args": ["https://example.com", "other-client", "Example"]},
+                        {"command": "somethingNew", "args": []},
+                    ],
+                }),
+            ])).expect("should process");
+            assert_eq!(outgoing.changes.len(), 1);
+            let record: ClientRecord = outgoing.changes[0].clone().into_record().unwrap();
+            assert!(record.commands.is_empty());
+        }
+        assert_eq!(processor.commands, vec![
+            Command::Wipe("history".into()),
+            Command::DisplayUri {
+                uri: "https://example.com".into(),
+                sender: "other-client".into(),
+                title: "Example".into(),
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_up_to_date_record_not_uploaded() {
+        let mut processor = processor();
+        let mut engine = Engine::new(&mut processor);
+        let outgoing = engine.process_incoming(incoming(vec![
+            json!({"id": "my-client", "name": "My Phone", "type": "mobile", "protocols": ["1.5"]}),
+        ])).expect("should process");
+        assert!(outgoing.changes.is_empty());
+        assert!(engine.remote_clients().is_empty());
+    }
+}
@@ -4,7 +4,7 @@
 
 use bso_record::{Payload, EncryptedBso};
 use key_bundle::KeyBundle;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use error::Result;
 use record_types::CryptoKeysRecord;
 use util::ServerTimestamp;
@@ -57,4 +57,78 @@ impl CollectionKeys {
     pub fn key_for_collection<'a>(&'a self, collection: &str) -> &'a KeyBundle {
         self.collections.get(collection).unwrap_or(&self.default)
     }
+
+    /// Generates a new key for `collection`, replacing its existing
+    /// collection-specific key, if any.
+    pub fn add_collection_key(&mut self, collection: &str) -> Result<&KeyBundle> {
+        let key = KeyBundle::new_random()?;
+        self.collections.insert(collection.to_string(), key);
+        Ok(&self.collections[collection])
+    }
+
+    /// Removes the collection-specific key for `collection`, so that its
+    /// records are encrypted with the default key. Returns the removed key.
+    pub fn remove_collection_key(&mut self, collection: &str) -> Option<KeyBundle> {
+        self.collections.remove(collection)
+    }
+
+    /// Returns the names of all collections that use a different key than
+    /// they did in `previous`. This includes collections with their own keys
+    /// in either set, and the named `collections` if the default key changed.
+    pub fn changed_collections<'a, I>(
+        &self,
+        previous: &CollectionKeys,
+        collections: I,
+    ) -> HashSet<String>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        collections
+            .into_iter()
+            .chain(self.collections.keys().map(|name| name.as_str()))
+            .chain(previous.collections.keys().map(|name| name.as_str()))
+            .filter(|name| self.key_for_collection(name) != previous.key_for_collection(name))
+            .map(|name| name.to_string())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collection_keys() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let previous = CollectionKeys::new_random().unwrap();
+
+        let mut keys = previous.clone();
+        let passwords_key = keys.add_collection_key("passwords").unwrap().clone();
+        assert_ne!(passwords_key, keys.default);
+        assert_eq!(keys.key_for_collection("passwords"), &passwords_key);
+        assert_eq!(keys.key_for_collection("bookmarks"), &keys.default);
+        assert_eq!(
+            keys.changed_collections(&previous, vec!["bookmarks", "history"]),
+            vec!["passwords".to_string()].into_iter().collect::<HashSet<String>>()
+        );
+
+        // Collection-specific keys survive a round trip through `crypto/keys`.
+        let bso = keys.to_encrypted_bso(&root_key).unwrap();
+        let decrypted = CollectionKeys::from_encrypted_bso(bso, &root_key).unwrap();
+        assert_eq!(decrypted.default, keys.default);
+        assert_eq!(decrypted.collections, keys.collections);
+
+        assert_eq!(keys.remove_collection_key("passwords"), Some(passwords_key));
+        assert_eq!(keys.remove_collection_key("passwords"), None);
+        assert!(keys.changed_collections(&previous, vec!["passwords"]).is_empty());
+
+        // Changing the default key changes all collections without their
+        // own keys.
+        let mut keys = CollectionKeys::new_random().unwrap();
+        keys.collections.insert("passwords".into(), previous.default.clone());
+        assert_eq!(
+            keys.changed_collections(&previous, vec!["bookmarks", "passwords"]),
+            vec!["bookmarks".to_string()].into_iter().collect::<HashSet<String>>()
+        );
+    }
 }
@@ -4,6 +4,7 @@
 
 use std::time::SystemTime;
 use reqwest;
+use http_support;
 use failure::{Fail, Context, Backtrace, SyncFailure};
 use std::{fmt, result, string};
 use std::boxed::Box;
@@ -11,6 +12,8 @@ use openssl;
 use base64;
 use serde_json;
 use hawk;
+#[cfg(feature = "fxa")]
+use fxa_client;
 
 pub type Result<T> = result::Result<T, Error>;
 
@@ -48,6 +51,13 @@ impl Error {
             _ => false
         }
     }
+
+    pub fn is_precondition_failed(&self) -> bool {
+        match self.kind() {
+            ErrorKind::StorageHttpError { code: 412, .. } => true,
+            _ => false
+        }
+    }
 }
 
 impl From<ErrorKind> for Error {
@@ -90,9 +100,15 @@ pub enum ErrorKind {
     #[fail(display = "Outgoing record is too large to upload")]
     RecordTooLargeError,
 
+    #[fail(display = "Outgoing record couldn't be encrypted: {}", _0)]
+    RecordEncryptionError(String),
+
     #[fail(display = "The batch was not committed due to being interrupted")]
     BatchInterrupted,
 
+    #[fail(display = "The collection changed on the server while we were downloading it")]
+    DownloadInterrupted,
+
     // Do we want to record the concrete problems?
     #[fail(display = "Not all records were successfully uploaded")]
     RecordUploadFailed,
@@ -120,6 +136,15 @@ pub enum ErrorKind {
     #[fail(display = "Setup state machine disallowed state {}", _0)]
     DisallowedStateError(&'static str),
 
+    #[fail(display = "The Firefox Account has no OAuth token with the sync scope")]
+    NoSyncScope,
+
+    #[fail(display = "The Firefox Account's OAuth token has no sync key")]
+    NoSyncKey,
+
+    #[fail(display = "The store for {} doesn't support validation", _0)]
+    ValidationUnsupported(String),
+
     // Basically reimplement error_chain's foreign_links. (Ugh, this sucks)
 
     #[fail(display = "OpenSSL error: {}", _0)]
@@ -137,6 +162,9 @@ pub enum ErrorKind {
     #[fail(display = "Network error: {}", _0)]
     RequestError(#[fail(cause)] reqwest::Error),
 
+    #[fail(display = "Transport error: {}", _0)]
+    TransportError(#[fail(cause)] http_support::Error),
+
     #[fail(display = "HAWK error: {}", _0)]
     HawkError(#[fail(cause)] SyncFailure<hawk::Error>),
 
@@ -145,6 +173,10 @@ pub enum ErrorKind {
 
     #[fail(display = "Malformed header error: {}", _0)]
     MalformedHeader(#[fail(cause)] reqwest::header::InvalidHeaderValue),
+
+    #[cfg(feature = "fxa")]
+    #[fail(display = "Firefox Account error: {}", _0)]
+    FxaError(#[fail(cause)] fxa_client::errors::Error),
 }
 
 macro_rules! impl_from_error {
@@ -171,10 +203,16 @@ impl_from_error! {
     (JsonError, ::serde_json::Error),
     (BadCleartextUtf8, ::std::string::FromUtf8Error),
     (RequestError, ::reqwest::Error),
+    (TransportError, ::http_support::Error),
     (MalformedUrl, ::reqwest::UrlError),
     (MalformedHeader, ::reqwest::header::InvalidHeaderValue)
 }
 
+#[cfg(feature = "fxa")]
+impl_from_error! {
+    (FxaError, ::fxa_client::errors::Error)
+}
+
 // ::hawk::Error uses error_chain, and so it's not trivially compatible with failure.
 // We have to box it inside a SyncError (which allows errors to be accessed from multiple
 // threads at the same time, which failure requires for some reason...).
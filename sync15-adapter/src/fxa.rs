@@ -0,0 +1,166 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Helpers for syncing with the credentials of a Firefox Account that has
+//! been granted the "oldsync" OAuth scope. These are only built with the
+//! `fxa` feature. A typical caller does something like:
+//!
+//! ```ignore
+//! let credentials = SyncCredentials::from_account(&mut account.lock().unwrap())?;
+//! let client = Sync15StorageClient::new(credentials.client_init.clone())?
+//!     .with_access_token_refresher(Box::new(FxaAccessTokenRefresher::new(account.clone())));
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use fxa_client::{FirefoxAccount, OAuthInfo};
+use serde_json;
+
+use client::Sync15StorageClientInit;
+use error::{ErrorKind, Result};
+use http_support::Url;
+use key_bundle::KeyBundle;
+use token::AccessTokenRefresher;
+
+/// The OAuth scope that grants access to the tokenserver and the sync key.
+pub const SYNC_SCOPE: &str = "https://identity.mozilla.com/apps/oldsync";
+
+// One of the scoped keys in `OAuthInfo::keys`, which is a JSON object that
+// maps scopes to keys. There are other fields, but we don't need them.
+#[derive(Debug, Deserialize)]
+struct ScopedKey {
+    k: String,
+    kid: String,
+}
+
+/// Everything we need to sync, taken from a Firefox Account.
+#[derive(Debug, Clone)]
+pub struct SyncCredentials {
+    pub client_init: Sync15StorageClientInit,
+    pub root_sync_key: KeyBundle,
+}
+
+impl SyncCredentials {
+    /// Builds credentials from an OAuth token with the sync scope and its
+    /// scoped keys.
+    pub fn from_oauth_info(info: &OAuthInfo, tokenserver_url: Url) -> Result<SyncCredentials> {
+        let keys = info.keys.as_ref().ok_or_else(|| ErrorKind::NoSyncKey)?;
+        let mut keys: HashMap<String, ScopedKey> = serde_json::from_str(keys)?;
+        let key = keys.remove(SYNC_SCOPE).ok_or_else(|| ErrorKind::NoSyncKey)?;
+        Ok(SyncCredentials {
+            client_init: Sync15StorageClientInit {
+                key_id: key.kid,
+                access_token: info.access_token.clone(),
+                tokenserver_url,
+            },
+            root_sync_key: KeyBundle::from_ksync_base64(&key.k)?,
+        })
+    }
+
+    /// Fetches (or reuses) an OAuth token with the sync scope from `account`,
+    /// and builds credentials from it. Fails with `NoSyncScope` if the account
+    /// hasn't been granted the sync scope, in which case the user needs to go
+    /// through the OAuth flow again.
+    pub fn from_account(account: &mut FirefoxAccount) -> Result<SyncCredentials> {
+        let tokenserver_url = account.get_token_server_endpoint_url()?;
+        let info = account.get_oauth_token(&[SYNC_SCOPE])?
+            .ok_or_else(|| ErrorKind::NoSyncScope)?;
+        SyncCredentials::from_oauth_info(&info, tokenserver_url)
+    }
+}
+
+/// Refreshes access tokens that the tokenserver rejects, using the refresh
+/// token of a Firefox Account. The account is shared, since the application
+/// probably uses it for other things, too.
+pub struct FxaAccessTokenRefresher {
+    account: Arc<Mutex<FirefoxAccount>>,
+}
+
+impl FxaAccessTokenRefresher {
+    pub fn new(account: Arc<Mutex<FirefoxAccount>>) -> FxaAccessTokenRefresher {
+        FxaAccessTokenRefresher { account }
+    }
+}
+
+// FirefoxAccount doesn't implement Debug, and we wouldn't want to log its
+// tokens anyway.
+impl fmt::Debug for FxaAccessTokenRefresher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FxaAccessTokenRefresher")
+         .field("account", &"(omitted)")
+         .finish()
+    }
+}
+
+impl AccessTokenRefresher for FxaAccessTokenRefresher {
+    fn refresh_access_token(&self, rejected: &str) -> Result<String> {
+        let mut account = self.account.lock().unwrap();
+        // The account thinks the rejected token is still valid, so we need
+        // to tell it otherwise before it'll fetch a new one.
+        account.clear_access_token(rejected);
+        let info = account.get_oauth_token(&[SYNC_SCOPE])?
+            .ok_or_else(|| ErrorKind::NoSyncScope)?;
+        Ok(info.access_token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64;
+
+    fn oauth_info(keys: Option<String>) -> OAuthInfo {
+        OAuthInfo {
+            access_token: "access-token".into(),
+            keys,
+            refresh_token: Some("refresh-token".into()),
+            expires_at: 0,
+            scopes: vec![SYNC_SCOPE.into()],
+        }
+    }
+
+    #[test]
+    fn test_from_oauth_info() {
+        let ksync: Vec<u8> = (0..64).collect();
+        let keys = json!({
+            SYNC_SCOPE: {
+                "k": base64::encode_config(&ksync, base64::URL_SAFE_NO_PAD),
+                "kty": "oct",
+                "kid": "1234-abcd",
+                "scope": SYNC_SCOPE,
+            },
+        });
+        let url = Url::parse("https://token.example.com/1.0/sync/1.5").unwrap();
+        let credentials = SyncCredentials::from_oauth_info(
+            &oauth_info(Some(keys.to_string())), url.clone()).unwrap();
+        assert_eq!(credentials.client_init, Sync15StorageClientInit {
+            key_id: "1234-abcd".into(),
+            access_token: "access-token".into(),
+            tokenserver_url: url,
+        });
+        assert_eq!(credentials.root_sync_key.encryption_key(), &ksync[0..32]);
+        assert_eq!(credentials.root_sync_key.hmac_key(), &ksync[32..64]);
+    }
+
+    #[test]
+    fn test_from_oauth_info_without_sync_key() {
+        let url = Url::parse("https://token.example.com/1.0/sync/1.5").unwrap();
+        let err = SyncCredentials::from_oauth_info(&oauth_info(None), url.clone())
+            .expect_err("should fail without keys");
+        match err.kind() {
+            ErrorKind::NoSyncKey => {}
+            other => panic!("Unexpected error {:?}", other),
+        }
+
+        let keys = json!({ "profile": { "k": "", "kid": "" } });
+        let err = SyncCredentials::from_oauth_info(&oauth_info(Some(keys.to_string())), url)
+            .expect_err("should fail without a sync key");
+        match err.kind() {
+            ErrorKind::NoSyncKey => {}
+            other => panic!("Unexpected error {:?}", other),
+        }
+    }
+}
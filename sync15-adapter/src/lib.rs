@@ -8,6 +8,9 @@ extern crate openssl;
 extern crate reqwest;
 extern crate hawk;
 extern crate hyper;
+extern crate http_support;
+#[cfg(feature = "fxa")]
+extern crate fxa_client;
 
 extern crate failure;
 
@@ -23,7 +26,7 @@ extern crate serde_derive;
 #[macro_use]
 extern crate log;
 
-#[cfg_attr(test, macro_use)]
+#[macro_use]
 extern crate serde_json;
 
 extern crate url;
@@ -42,13 +45,23 @@ pub mod changeset;
 pub mod sync;
 pub mod client;
 pub mod state;
+pub mod clients;
+pub mod sync_multiple;
+pub mod telemetry;
+pub mod mock_server;
+pub mod validation;
+#[cfg(feature = "fxa")]
+pub mod fxa;
 
 // Re-export some of the types callers are likely to want for convenience.
 pub use bso_record::{BsoRecord, EncryptedBso, Payload, CleartextBso};
 pub use changeset::{RecordChangeset, IncomingChangeset, OutgoingChangeset};
 pub use error::{Result, Error, ErrorKind};
-pub use sync::{synchronize, Store};
+pub use sync::{synchronize, StagingStore, Store};
+pub use request::RecordRejection;
 pub use util::{ServerTimestamp, SERVER_EPOCH};
 pub use key_bundle::KeyBundle;
+pub use token::CachedToken;
 pub use client::{Sync15StorageClientInit, Sync15StorageClient};
 pub use state::{GlobalState, SetupStateMachine};
+pub use sync_multiple::{sync_multiple, EngineResult, SyncEngine, SyncResult};
@@ -0,0 +1,599 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! An in-memory Sync 1.5 storage server, and a tokenserver that always hands
+//! out a token for it, so that whole sync scenarios (including several
+//! devices syncing against the same account) can run in `cargo test`.
+//!
+//! The server implements `Transport`, so each "device" is a
+//! `Sync15StorageClient` made with `MockSyncServer::client`. It supports the
+//! parts of the storage API that we use: `info/collections`,
+//! `info/configuration`, single record GETs and PUTs (including
+//! `meta/global` and `crypto/keys`), collection GETs with `newer`, `older`,
+//! `ids`, `limit`, `offset` and `sort`, batched and unbatched POSTs,
+//! `X-If-Unmodified-Since`, and DELETEs. It doesn't check HAWK
+//! authorization, expire records with a TTL, or enforce quotas.
+
+use std::cmp::{self, Ordering};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http_support::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use http_support::{self, Request, Response, StatusCode, Transport, Url};
+use serde::ser::Serialize;
+use serde_json;
+
+use client::{Sync15StorageClient, Sync15StorageClientInit};
+use request::{InfoConfiguration, X_IF_UNMODIFIED_SINCE, X_LAST_MODIFIED, X_WEAVE_NEXT_OFFSET,
+              X_WEAVE_RECORDS, X_WEAVE_TIMESTAMP};
+use util::ServerTimestamp;
+
+/// The tokenserver URL used by clients made with `MockSyncServer::client`.
+pub const TOKENSERVER_URL: &str = "https://token.example.com/1.0/sync/1.5";
+
+const TOKENSERVER_HOST: &str = "token.example.com";
+const STORAGE_HOST: &str = "storage.example.com";
+const STORAGE_ENDPOINT: &str = "https://storage.example.com/1.5/12345";
+const STORAGE_PATH: &str = "/1.5/12345";
+
+/// A record, as stored by the server. The payload is kept as the string the
+/// client uploaded, since `meta/global` isn't encrypted but everything else
+/// is.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MockBso {
+    pub id: String,
+    #[serde(default)]
+    pub modified: ServerTimestamp,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sortindex: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u32>,
+    pub payload: String,
+}
+
+// The server formats timestamps to the hundredths place, so we keep them as
+// integers internally, to avoid float rounding when we compare them.
+type Centis = u64;
+
+fn to_centis(ts: ServerTimestamp) -> Centis {
+    (ts.0 * 100.0).round() as Centis
+}
+
+fn from_centis(centis: Centis) -> ServerTimestamp {
+    ServerTimestamp(centis as f64 / 100.0)
+}
+
+#[derive(Debug, Default)]
+struct MockCollection {
+    modified: Centis,
+    records: HashMap<String, (Centis, MockBso)>,
+}
+
+#[derive(Debug)]
+struct MockBatch {
+    collection: String,
+    records: Vec<MockBso>,
+}
+
+#[derive(Debug, Default)]
+struct ServerState {
+    config: InfoConfiguration,
+    // The time of the current request, which is also the time of any write
+    // it makes.
+    now: Centis,
+    // The last time the server changed anything.
+    modified: Centis,
+    collections: HashMap<String, MockCollection>,
+    batches: HashMap<String, MockBatch>,
+    next_batch_id: usize,
+    requests: usize,
+}
+
+impl ServerState {
+    fn update_now(&mut self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() * 100 + u64::from(d.subsec_millis()) / 10)
+            .unwrap_or(0);
+        self.now = cmp::max(now, self.modified);
+    }
+
+    // Returns a timestamp for a write, which is later than every previous
+    // write.
+    fn tick(&mut self) -> Centis {
+        self.modified = cmp::max(self.now, self.modified + 1);
+        self.now = self.modified;
+        self.modified
+    }
+
+    fn collection_modified(&self, collection: &str) -> Centis {
+        self.collections.get(collection).map_or(0, |c| c.modified)
+    }
+
+    fn write(&mut self, collection: &str, records: Vec<MockBso>) -> Centis {
+        let modified = self.tick();
+        let coll = self.collections.entry(collection.into()).or_insert_with(MockCollection::default);
+        coll.modified = modified;
+        for mut record in records {
+            record.modified = from_centis(modified);
+            coll.records.insert(record.id.clone(), (modified, record));
+        }
+        modified
+    }
+}
+
+// A response, before we add the headers common to every response and turn
+// it into a `Response`.
+struct Reply {
+    status: u16,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn new(status: u16) -> Self {
+        Reply { status, headers: HeaderMap::new(), body: Vec::new() }
+    }
+
+    fn error(status: u16) -> Self {
+        // The real server's error bodies are just an error code.
+        Reply::new(status).json(&0)
+    }
+
+    fn header(mut self, name: &'static str, value: &str) -> Self {
+        self.headers.insert(name, HeaderValue::from_str(value).expect("Invalid header value"));
+        self
+    }
+
+    fn json<T: Serialize>(mut self, body: &T) -> Self {
+        self.headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        self.body = serde_json::to_vec(body).expect("Couldn't serialize response");
+        self
+    }
+
+    fn last_modified(self, modified: Centis) -> Self {
+        self.header(X_LAST_MODIFIED, &from_centis(modified).to_string())
+    }
+}
+
+/// An in-memory Sync server. Clones share the same storage, so a test can
+/// hand clones to several clients and inspect the server afterwards.
+#[derive(Debug, Clone, Default)]
+pub struct MockSyncServer {
+    state: Arc<Mutex<ServerState>>,
+}
+
+impl MockSyncServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a server that reports `config` from `info/configuration`, for
+    /// testing upload limits.
+    pub fn with_configuration(config: InfoConfiguration) -> Self {
+        let server = Self::default();
+        server.state.lock().unwrap().config = config;
+        server
+    }
+
+    /// Returns the params for a client of this server. Pass them to
+    /// `Sync15StorageClient::with_transport` along with a clone of the
+    /// server.
+    pub fn client_init() -> Sync15StorageClientInit {
+        Sync15StorageClientInit {
+            key_id: "key-id".into(),
+            access_token: "access-token".into(),
+            tokenserver_url: Url::parse(TOKENSERVER_URL).unwrap(),
+        }
+    }
+
+    /// Creates a new client, which stands in for a new device, connected to
+    /// this server.
+    pub fn client(&self) -> Sync15StorageClient {
+        Sync15StorageClient::with_transport(Self::client_init(), Box::new(self.clone()))
+    }
+
+    /// Stores `records` in `collection`, as if another client uploaded them
+    /// in a single POST.
+    pub fn insert_records(&self, collection: &str, records: Vec<MockBso>) {
+        let mut state = self.state.lock().unwrap();
+        state.update_now();
+        state.write(collection, records);
+    }
+
+    /// Returns all the records in `collection`, sorted by ID.
+    pub fn records(&self, collection: &str) -> Vec<MockBso> {
+        let state = self.state.lock().unwrap();
+        let mut records: Vec<MockBso> = state.collections.get(collection)
+            .map(|c| c.records.values().map(|(_, record)| record.clone()).collect())
+            .unwrap_or_default();
+        records.sort_by(|a, b| a.id.cmp(&b.id));
+        records
+    }
+
+    /// Returns the record with `id` in `collection`, if there is one.
+    pub fn record(&self, collection: &str, id: &str) -> Option<MockBso> {
+        let state = self.state.lock().unwrap();
+        state.collections.get(collection)
+            .and_then(|c| c.records.get(id))
+            .map(|(_, record)| record.clone())
+    }
+
+    /// Returns the number of requests the server has handled, including
+    /// tokenserver requests.
+    pub fn request_count(&self) -> usize {
+        self.state.lock().unwrap().requests
+    }
+
+    fn handle(&self, request: &Request) -> Reply {
+        let mut state = self.state.lock().unwrap();
+        state.update_now();
+        state.requests += 1;
+        match request.url.host_str() {
+            Some(TOKENSERVER_HOST) => return handle_token(&state),
+            Some(STORAGE_HOST) => {}
+            _ => return Reply::error(404),
+        }
+        let path = request.url.path();
+        if !path.starts_with(STORAGE_PATH) {
+            return Reply::error(404);
+        }
+        let segments: Vec<&str> = path[STORAGE_PATH.len()..]
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect();
+        let reply = match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["info", "collections"]) => {
+                let collections: HashMap<&String, ServerTimestamp> = state.collections.iter()
+                    .filter(|(_, c)| !c.records.is_empty())
+                    .map(|(name, c)| (name, from_centis(c.modified)))
+                    .collect();
+                Reply::new(200).json(&collections)
+            }
+            ("GET", ["info", "configuration"]) => Reply::new(200).json(&state.config),
+            ("DELETE", []) | ("DELETE", ["storage"]) => {
+                state.collections.clear();
+                state.batches.clear();
+                let modified = state.tick();
+                Reply::new(200).json(&json!({})).last_modified(modified)
+            }
+            ("GET", ["storage", collection]) => handle_get_collection(&state, collection, &request.url),
+            ("POST", ["storage", collection]) => handle_post(&mut state, collection, request),
+            ("DELETE", ["storage", collection]) => handle_delete_collection(&mut state, collection, request),
+            ("GET", ["storage", collection, id]) => {
+                match state.collections.get(*collection).and_then(|c| c.records.get(*id)) {
+                    Some((_, record)) => Reply::new(200).json(record),
+                    None => Reply::error(404),
+                }
+            }
+            ("PUT", ["storage", collection, id]) => handle_put(&mut state, collection, id, request),
+            ("DELETE", ["storage", collection, id]) => {
+                if let Some(reply) = check_unmodified_since(&state, collection, request) {
+                    return finish(&state, reply);
+                }
+                let found = state.collections.get_mut(*collection)
+                    .map_or(false, |c| c.records.remove(*id).is_some());
+                if found {
+                    let modified = state.tick();
+                    state.collections.get_mut(*collection).unwrap().modified = modified;
+                    Reply::new(200).json(&json!({})).last_modified(modified)
+                } else {
+                    Reply::error(404)
+                }
+            }
+            _ => Reply::error(404),
+        };
+        finish(&state, reply)
+    }
+}
+
+impl Transport for MockSyncServer {
+    fn execute(&self, request: Request) -> http_support::Result<Response> {
+        let reply = self.handle(&request);
+        Ok(Response {
+            url: request.url,
+            status: StatusCode::from_u16(reply.status).expect("Invalid status code"),
+            headers: reply.headers,
+            body: reply.body,
+        })
+    }
+}
+
+// Adds the headers that every storage response has.
+fn finish(state: &ServerState, reply: Reply) -> Reply {
+    reply.header(X_WEAVE_TIMESTAMP, &from_centis(state.now).to_string())
+}
+
+fn handle_token(state: &ServerState) -> Reply {
+    let now = state.now / 100;
+    Reply::new(200)
+        .header("X-Timestamp", &now.to_string())
+        .json(&json!({
+            "id": "token-id",
+            "key": "token-key",
+            "api_endpoint": STORAGE_ENDPOINT,
+            "uid": 12345,
+            "duration": 3600,
+            "hashed_fxa_uid": "hashed-uid",
+        }))
+}
+
+fn query_param(url: &Url, name: &str) -> Option<String> {
+    url.query_pairs().find(|(k, _)| k == name).map(|(_, v)| v.into_owned())
+}
+
+fn timestamp_param(url: &Url, name: &str) -> Option<Centis> {
+    query_param(url, name)
+        .and_then(|s| ServerTimestamp::from_str(&s).ok())
+        .map(to_centis)
+}
+
+// Returns a 412 if the collection changed since the client's
+// `X-If-Unmodified-Since`.
+fn check_unmodified_since(state: &ServerState, collection: &str, request: &Request) -> Option<Reply> {
+    let xius = request.headers.get(X_IF_UNMODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| ServerTimestamp::from_str(s).ok())?;
+    let modified = state.collection_modified(collection);
+    if modified > to_centis(xius) {
+        Some(Reply::error(412).last_modified(modified))
+    } else {
+        None
+    }
+}
+
+fn handle_get_collection(state: &ServerState, collection: &str, url: &Url) -> Reply {
+    let newer = timestamp_param(url, "newer");
+    let older = timestamp_param(url, "older");
+    let ids: Option<Vec<String>> = query_param(url, "ids")
+        .map(|ids| ids.split(',').map(String::from).collect());
+    let mut records: Vec<&(Centis, MockBso)> = state.collections.get(collection)
+        .map(|c| c.records.values().collect())
+        .unwrap_or_default();
+    records.retain(|(modified, record)| {
+        newer.map_or(true, |newer| *modified > newer) &&
+        older.map_or(true, |older| *modified < older) &&
+        ids.as_ref().map_or(true, |ids| ids.contains(&record.id))
+    });
+
+    // Break ties by ID, so that paging with `offset` is stable.
+    let sort = query_param(url, "sort");
+    records.sort_by(|(a_modified, a), (b_modified, b)| {
+        let order = match sort.as_ref().map(String::as_str) {
+            Some("newest") => b_modified.cmp(a_modified),
+            Some("index") => b.sortindex.unwrap_or(0).cmp(&a.sortindex.unwrap_or(0)),
+            _ => a_modified.cmp(b_modified),
+        };
+        if order == Ordering::Equal { a.id.cmp(&b.id) } else { order }
+    });
+
+    let offset: usize = query_param(url, "offset").and_then(|s| s.parse().ok()).unwrap_or(0);
+    let limit: usize = query_param(url, "limit").and_then(|s| s.parse().ok()).unwrap_or(0);
+    let total = records.len();
+    let end = if limit > 0 { cmp::min(offset + limit, total) } else { total };
+    let page = if offset < end { &records[offset..end] } else { &[][..] };
+
+    let mut reply = if query_param(url, "full").is_some() {
+        let bsos: Vec<&MockBso> = page.iter().map(|(_, record)| record).collect();
+        Reply::new(200).json(&bsos)
+    } else {
+        let ids: Vec<&str> = page.iter().map(|(_, record)| record.id.as_str()).collect();
+        Reply::new(200).json(&ids)
+    };
+    reply = reply
+        .header(X_WEAVE_RECORDS, &page.len().to_string())
+        .last_modified(state.collection_modified(collection));
+    if end < total {
+        reply = reply.header(X_WEAVE_NEXT_OFFSET, &end.to_string());
+    }
+    reply
+}
+
+fn handle_put(state: &mut ServerState, collection: &str, id: &str, request: &Request) -> Reply {
+    if let Some(reply) = check_unmodified_since(state, collection, request) {
+        return reply;
+    }
+    let mut record: MockBso = match request.body.as_ref().and_then(|b| serde_json::from_slice(b).ok()) {
+        Some(record) => record,
+        None => return Reply::error(400),
+    };
+    record.id = id.into();
+    let modified = state.write(collection, vec![record]);
+    Reply::new(200).json(&from_centis(modified)).last_modified(modified)
+}
+
+fn handle_post(state: &mut ServerState, collection: &str, request: &Request) -> Reply {
+    if let Some(reply) = check_unmodified_since(state, collection, request) {
+        return reply;
+    }
+    let incoming: Vec<serde_json::Value> = match request.body.as_ref()
+        .and_then(|b| serde_json::from_slice(b).ok()) {
+        Some(records) => records,
+        None => return Reply::error(400),
+    };
+    if incoming.len() > state.config.max_post_records {
+        return Reply::error(413);
+    }
+
+    let mut success = Vec::new();
+    let mut failed = HashMap::new();
+    let mut valid = Vec::new();
+    for value in incoming {
+        let id = value["id"].as_str().unwrap_or("").to_string();
+        match serde_json::from_value::<MockBso>(value) {
+            Ok(ref record) if record.payload.len() > state.config.max_record_payload_bytes => {
+                failed.insert(id, "retry bytes".to_string());
+            }
+            Ok(record) => {
+                success.push(record.id.clone());
+                valid.push(record);
+            }
+            Err(_) => {
+                failed.insert(id, "invalid record".to_string());
+            }
+        }
+    }
+
+    let batch = query_param(&request.url, "batch");
+    let commit = query_param(&request.url, "commit").map_or(false, |c| c == "true");
+    let batch_id = match batch {
+        None => {
+            let modified = state.write(collection, valid);
+            return Reply::new(200)
+                .json(&json!({ "modified": from_centis(modified), "success": success, "failed": failed }))
+                .last_modified(modified);
+        }
+        Some(ref id) if id == "true" => {
+            state.next_batch_id += 1;
+            let id = state.next_batch_id.to_string();
+            state.batches.insert(id.clone(), MockBatch { collection: collection.into(), records: Vec::new() });
+            id
+        }
+        Some(id) => id,
+    };
+
+    match state.batches.get_mut(&batch_id) {
+        Some(ref batch) if batch.collection != collection => return Reply::error(400),
+        Some(batch) => batch.records.extend(valid),
+        None => return Reply::error(400),
+    }
+    if commit {
+        let batch = state.batches.remove(&batch_id).unwrap();
+        let modified = state.write(collection, batch.records);
+        Reply::new(200)
+            .json(&json!({ "modified": from_centis(modified), "success": success, "failed": failed }))
+            .last_modified(modified)
+    } else {
+        let modified = state.collection_modified(collection);
+        Reply::new(202)
+            .json(&json!({ "batch": batch_id, "success": success, "failed": failed }))
+            .last_modified(modified)
+    }
+}
+
+fn handle_delete_collection(state: &mut ServerState, collection: &str, request: &Request) -> Reply {
+    if let Some(reply) = check_unmodified_since(state, collection, request) {
+        return reply;
+    }
+    match query_param(&request.url, "ids") {
+        Some(ids) => {
+            let modified = state.tick();
+            if let Some(coll) = state.collections.get_mut(collection) {
+                for id in ids.split(',') {
+                    coll.records.remove(id);
+                }
+                coll.modified = modified;
+            }
+            Reply::new(200).json(&json!({ "modified": from_centis(modified) })).last_modified(modified)
+        }
+        None => {
+            state.collections.remove(collection);
+            let modified = state.tick();
+            Reply::new(200).json(&json!({ "modified": from_centis(modified) })).last_modified(modified)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bso_record::Payload;
+    use changeset::{CollectionUpdate, IncomingChangeset, OutgoingChangeset};
+    use error::ErrorKind;
+    use key_bundle::KeyBundle;
+    use state::{GlobalState, SetupStateMachine};
+
+    fn sync_to_ready(client: &Sync15StorageClient, root_key: &KeyBundle) -> GlobalState {
+        let mut state_machine = SetupStateMachine::for_full_sync(client, root_key);
+        state_machine.to_ready(GlobalState::default()).expect("should get to ready")
+    }
+
+    fn upload(client: &Sync15StorageClient, state: &GlobalState, ids: &[&str]) -> ::error::Result<()> {
+        let mut outgoing = OutgoingChangeset::new("bookmarks".into(),
+                                                  state.last_modified_or_zero("bookmarks"));
+        for id in ids {
+            let title = format!("Title of {}", id);
+            outgoing.changes.push(Payload::from_json(json!({ "id": id, "title": title }))?);
+        }
+        CollectionUpdate::new_from_changeset(client, state, outgoing, true)?.upload()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_two_devices() {
+        let server = MockSyncServer::new();
+        let root_key = KeyBundle::new_random().unwrap();
+
+        // The first device finds an empty server, so it uploads a fresh
+        // `meta/global` and `crypto/keys`.
+        let first = server.client();
+        let first_state = sync_to_ready(&first, &root_key);
+        assert!(server.record("meta", "global").is_some());
+        assert!(server.record("crypto", "keys").is_some());
+        upload(&first, &first_state, &["aaaaaaaaaaaa", "bbbbbbbbbbbb"]).expect("should upload");
+        let ids: Vec<String> = server.records("bookmarks").into_iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec!["aaaaaaaaaaaa", "bbbbbbbbbbbb"]);
+
+        // The second device uses the keys the first uploaded, and sees its
+        // records.
+        let second = server.client();
+        let second_state = sync_to_ready(&second, &root_key);
+        let incoming = IncomingChangeset::fetch(&second, &second_state, "bookmarks".into(),
+                                                ServerTimestamp(0.0)).expect("should fetch");
+        let mut titles: Vec<&str> = incoming.changes.iter()
+            .map(|(payload, _)| payload.data["title"].as_str().unwrap())
+            .collect();
+        titles.sort();
+        assert_eq!(titles, vec!["Title of aaaaaaaaaaaa", "Title of bbbbbbbbbbbb"]);
+        upload(&second, &second_state, &["cccccccccccc"]).expect("should upload");
+
+        // The first device hasn't seen that upload, so the server rejects its
+        // next one.
+        let err = upload(&first, &first_state, &["dddddddddddd"]).expect_err("should fail");
+        match err.kind() {
+            ErrorKind::BatchInterrupted => {}
+            kind => panic!("unexpected error {:?}", kind),
+        }
+        assert_eq!(server.records("bookmarks").len(), 3);
+    }
+
+    #[test]
+    fn test_collection_get() {
+        let server = MockSyncServer::new();
+        for id in &["a", "b", "c"] {
+            server.insert_records("history", vec![MockBso {
+                id: id.to_string(),
+                modified: ServerTimestamp(0.0),
+                sortindex: None,
+                ttl: None,
+                payload: "{}".into(),
+            }]);
+        }
+        let modified: Vec<ServerTimestamp> = server.records("history").iter()
+            .map(|r| r.modified)
+            .collect();
+
+        let get = |query: &str| {
+            let url = Url::parse(&format!("{}/storage/history?{}", STORAGE_ENDPOINT, query)).unwrap();
+            server.execute(Request::get(url)).unwrap()
+        };
+
+        let resp = get(&format!("newer={}", modified[0]));
+        assert_eq!(resp.json::<Vec<String>>().unwrap(), vec!["b", "c"]);
+
+        let resp = get("sort=newest&limit=2");
+        assert_eq!(resp.json::<Vec<String>>().unwrap(), vec!["c", "b"]);
+        assert_eq!(resp.header(X_WEAVE_NEXT_OFFSET), Some("2"));
+
+        let resp = get("sort=newest&limit=2&offset=2&full=1");
+        let records: Vec<MockBso> = resp.json().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id, "a");
+        assert_eq!(records[0].modified, modified[0]);
+        assert_eq!(resp.header(X_WEAVE_NEXT_OFFSET), None);
+        assert_eq!(resp.header(X_LAST_MODIFIED), Some(modified[2].to_string().as_str()));
+    }
+}
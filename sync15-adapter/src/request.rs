@@ -14,14 +14,18 @@ use std::str::FromStr;
 use url::{Url, UrlQuery, form_urlencoded::Serializer};
 use error::{self, Result, ErrorKind};
 use hyper::{StatusCode};
-use reqwest::Response;
+use http_support::Response;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum RequestOrder { Oldest, Newest, Index }
 
 pub const X_IF_UNMODIFIED_SINCE: &str = "X-If-Unmodified-Since";
 pub const X_WEAVE_TIMESTAMP: &str = "X-Weave-Timestamp";
-const X_LAST_MODIFIED: &str = "X-Last-Modified";
+pub const X_WEAVE_BACKOFF: &str = "X-Weave-Backoff";
+pub const RETRY_AFTER: &str = "Retry-After";
+pub const X_LAST_MODIFIED: &str = "X-Last-Modified";
+pub const X_WEAVE_NEXT_OFFSET: &str = "X-Weave-Next-Offset";
+pub const X_WEAVE_RECORDS: &str = "X-Weave-Records";
 
 impl fmt::Display for RequestOrder {
     #[inline]
@@ -45,6 +49,7 @@ pub struct CollectionRequest {
     pub order: Option<RequestOrder>,
     pub commit: bool,
     pub batch: Option<String>,
+    pub offset: Option<String>,
 }
 
 impl CollectionRequest {
@@ -60,6 +65,7 @@ impl CollectionRequest {
             order: None,
             commit: false,
             batch: None,
+            offset: None,
         }
     }
 
@@ -99,6 +105,15 @@ impl CollectionRequest {
         self
     }
 
+    /// Continues a download from where the previous page ended. `offset`
+    /// is the `X-Weave-Next-Offset` from the previous response, which is
+    /// opaque.
+    #[inline]
+    pub fn offset(&mut self, offset: Option<String>) -> &mut CollectionRequest {
+        self.offset = offset;
+        self
+    }
+
     #[inline]
     pub fn batch(&mut self, batch: Option<String>) -> &mut CollectionRequest {
         self.batch = batch;
@@ -136,6 +151,9 @@ impl CollectionRequest {
         if let Some(o) = self.order {
             pairs.append_pair("sort", &format!("{}", o));
         }
+        if let &Some(ref offset) = &self.offset {
+            pairs.append_pair("offset", offset);
+        }
         pairs.finish();
     }
 
@@ -234,6 +252,23 @@ pub struct InfoConfiguration {
 fn default_max_request_bytes() -> usize { 260 * 1024 }
 fn default_max_record_payload_bytes() -> usize { 256 * 1024 }
 
+impl InfoConfiguration {
+    /// Returns the size, in bytes, at which an encrypted record payload is
+    /// too large to upload. This is the smallest of the server's record,
+    /// request, and upload size limits.
+    pub fn record_payload_limit(&self) -> usize {
+        [
+            self.max_record_payload_bytes,
+            self.max_request_bytes,
+            self.max_post_bytes,
+            self.max_total_bytes,
+        ].iter()
+            .cloned()
+            .min()
+            .unwrap()
+    }
+}
+
 impl Default for InfoConfiguration {
     #[inline]
     fn default() -> InfoConfiguration {
@@ -265,7 +300,7 @@ impl Deref for InfoCollections {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct UploadResult {
     batch: Option<String>,
     /// Maps record id => why failed
@@ -285,12 +320,21 @@ pub struct PostResponse {
 }
 
 impl PostResponse {
-    pub fn from_response(r: &mut Response) -> Result<PostResponse> {
+    pub fn from_response(r: &Response) -> Result<PostResponse> {
+        let status = r.status;
+        if !status.is_success() {
+            // Error responses don't have an upload result, and might not have
+            // a timestamp. Callers only look at the status in this case.
+            return Ok(PostResponse {
+                status,
+                result: UploadResult::default(),
+                last_modified: ServerTimestamp::default(),
+            });
+        }
         let result: UploadResult = r.json()?;
         // TODO Can this happen in error cases?
-        let last_modified = r.headers().get(X_LAST_MODIFIED).and_then(|v| v.to_str().ok()).and_then(|s| ServerTimestamp::from_str(s).ok()).ok_or_else(||
+        let last_modified = r.headers.get(X_LAST_MODIFIED).and_then(|v| v.to_str().ok()).and_then(|s| ServerTimestamp::from_str(s).ok()).ok_or_else(||
             ErrorKind::MissingServerTimestamp)?;
-        let status = r.status();
         Ok(PostResponse { status, result, last_modified })
     }
 }
@@ -577,10 +621,44 @@ where
     }
 }
 
+/// Why an outgoing record wasn't uploaded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordRejection {
+    /// The encrypted payload is `size` bytes, but the server only accepts
+    /// payloads smaller than `limit` bytes.
+    TooLarge { size: usize, limit: usize },
+    /// The record couldn't be serialized or encrypted.
+    Unencryptable(String),
+}
+
+impl RecordRejection {
+    /// Returns the error to fail a fully atomic upload with.
+    pub fn into_error(self) -> error::Error {
+        match self {
+            RecordRejection::TooLarge { .. } => ErrorKind::RecordTooLargeError.into(),
+            RecordRejection::Unencryptable(reason) => {
+                ErrorKind::RecordEncryptionError(reason).into()
+            }
+        }
+    }
+}
+
+/// An outgoing record that we didn't upload, and why.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RejectedRecord {
+    pub id: String,
+    pub reason: RecordRejection,
+}
+
 #[derive(Clone)]
 pub struct UploadInfo {
     pub successful_ids: Vec<String>,
+    /// The IDs of all records that weren't uploaded, including the
+    /// `rejected` ones.
     pub failed_ids: Vec<String>,
+    /// Records that we didn't try to upload, because they were too large,
+    /// or couldn't be encrypted.
+    pub rejected: Vec<RejectedRecord>,
     pub modified_timestamp: ServerTimestamp,
 }
 
@@ -592,6 +670,7 @@ impl<Poster> PostQueue<Poster, NormalResponseHandler> {
             failed_ids: Vec::with_capacity(self.on_response.failed_ids.len() +
                                            self.on_response.pending_failed.len() +
                                            self.on_response.pending_success.len()),
+            rejected: Vec::new(),
             modified_timestamp: self.last_modified
         };
 
@@ -637,6 +716,9 @@ mod test {
         assert_eq!(complex.as_str(),
             "https://example.com/sync/storage/specific?full=1&limit=10&older=9876.54&newer=1234.56&sort=oldest");
 
+        let page = CollectionRequest::new("history").full().limit(100).offset(Some("200".into()))
+                                                    .build_url(base.clone()).unwrap();
+        assert_eq!(page.as_str(), "https://example.com/sync/storage/history?full=1&limit=100&offset=200");
     }
 
     #[derive(Debug, Clone)]
@@ -3,6 +3,7 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
 
 use bso_record::BsoRecord;
 use client::SetupStorageClient;
@@ -56,6 +57,10 @@ pub struct GlobalState {
     pub global: Option<BsoRecord<MetaGlobalRecord>>,
     pub keys: Option<CollectionKeys>,
     pub engine_state_changes: Vec<EngineStateChange>,
+    /// The time until which the server asked us not to sync, if it asked us
+    /// to back off.
+    #[serde(default)]
+    pub next_sync_after: Option<SystemTime>,
 }
 
 impl GlobalState {
@@ -71,6 +76,15 @@ impl GlobalState {
         }
     }
 
+    /// Returns a `BackoffError` if the server asked us to back off, and the
+    /// backoff hasn't expired yet.
+    pub fn check_backoff(&self) -> error::Result<()> {
+        match self.next_sync_after {
+            Some(until) if until > SystemTime::now() => Err(ErrorKind::BackoffError(until).into()),
+            _ => Ok(()),
+        }
+    }
+
     pub fn key_for_collection(&self, collection: &str) -> error::Result<&KeyBundle> {
         Ok(self.keys
             .as_ref()
@@ -82,6 +96,34 @@ impl GlobalState {
         self.collections.get(coll).cloned().unwrap_or(SERVER_EPOCH)
     }
 
+    /// Returns true if the engine has been declined, or was removed from
+    /// `meta/global` by another device since our last sync.
+    pub fn is_engine_declined(&self, name: &str) -> bool {
+        let declined = self.global
+            .as_ref()
+            .map(|global| global.declined.iter().any(|declined| declined == name))
+            .unwrap_or(false);
+        declined || self.engine_state_changes.iter().any(|change| match change {
+            EngineStateChange::Disable(disabled) => disabled == name,
+            _ => false,
+        })
+    }
+
+    /// Returns the names of collections whose keys changed since our last
+    /// sync. Their engines need to be reset, because the records they
+    /// uploaded were encrypted with a different key. When we change a key
+    /// with `rotate_keys`, we also delete the collection from the server,
+    /// and bump its sync ID, so that other devices reset, too.
+    pub fn collections_with_changed_keys(&self) -> HashSet<String> {
+        self.engine_state_changes
+            .iter()
+            .filter_map(|change| match change {
+                EngineStateChange::KeysChanged(name) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
     /// Returns a set of all engine names that should be reset locally.
     pub fn engines_that_need_local_reset(&self) -> HashSet<String> {
         let all_engines = self.global
@@ -97,7 +139,7 @@ impl GlobalState {
         let mut engines_to_reset = HashSet::new();
         for change in &self.engine_state_changes {
             match change {
-                EngineStateChange::Reset(name) => {
+                EngineStateChange::Reset(name) | EngineStateChange::KeysChanged(name) => {
                     engines_to_reset.insert(name.to_string());
                 }
                 EngineStateChange::ResetAll => {
@@ -146,6 +188,7 @@ fn resolve_global(
         global: Some(new_global),
         keys: previous_keys,
         engine_state_changes: changes,
+        next_sync_after: previous_state.next_sync_after,
     }
 }
 
@@ -185,36 +228,22 @@ fn engine_state_changes_from_new_global(
 fn resolve_keys(previous_state: GlobalState, new_keys: CollectionKeys) -> GlobalState {
     let mut changes = previous_state.engine_state_changes;
     match &previous_state.keys {
-        Some(previous_global) => {
-            if new_keys.default == previous_global.default {
-                // The default bundle is the same, so only reset
-                // engines with different collection-specific keys.
-                for (collection, key_bundle) in &previous_global.collections {
-                    if key_bundle != new_keys.key_for_collection(collection) {
-                        changes.push(EngineStateChange::Reset(collection.to_string()));
-                    }
-                }
-                for (collection, key_bundle) in &new_keys.collections {
-                    if key_bundle != previous_global.key_for_collection(collection) {
-                        changes.push(EngineStateChange::Reset(collection.to_string()));
-                    }
-                }
-            } else {
-                // The default bundle changed, so reset all engines
-                // except those with the same collection-specific
-                // keys.
-                let mut except = HashSet::new();
-                for (collection, key_bundle) in &previous_global.collections {
-                    if key_bundle == new_keys.key_for_collection(collection) {
-                        except.insert(collection.to_string());
-                    }
-                }
-                for (collection, key_bundle) in &new_keys.collections {
-                    if key_bundle != previous_global.key_for_collection(collection) {
-                        except.insert(collection.to_string());
-                    }
-                }
-                changes.push(EngineStateChange::ResetAllExcept(except));
+        Some(previous_keys) => {
+            // Reset engines for collections that use a different key, either
+            // because their collection-specific key changed, or because they
+            // use the default key, and the default changed.
+            let engine_names = previous_state
+                .global
+                .as_ref()
+                .map(|global| global.engines.keys().map(|name| name.as_str()).collect::<Vec<_>>())
+                .unwrap_or_else(Vec::new);
+            let mut changed = new_keys
+                .changed_collections(previous_keys, engine_names)
+                .into_iter()
+                .collect::<Vec<String>>();
+            changed.sort();
+            for name in changed {
+                changes.push(EngineStateChange::KeysChanged(name));
             }
         }
         None => changes.push(EngineStateChange::ResetAll),
@@ -225,6 +254,7 @@ fn resolve_keys(previous_state: GlobalState, new_keys: CollectionKeys) -> Global
         global: previous_state.global,
         keys: Some(new_keys),
         engine_state_changes: changes,
+        next_sync_after: previous_state.next_sync_after,
     }
 }
 
@@ -261,6 +291,139 @@ fn new_global_from_previous(
     })
 }
 
+/// Declines and undeclines engines by uploading a new `meta/global`.
+/// Declined engines are removed from `engines`, so other devices stop syncing
+/// them. Undeclined engines are added back with a fresh sync ID, so every
+/// device starts over. Returns the new global state, which should be used
+/// for the next sync.
+pub fn update_declined_engines(
+    client: &SetupStorageClient,
+    state: GlobalState,
+    decline: &[&str],
+    undecline: &[&str],
+) -> error::Result<GlobalState> {
+    let mut global = match state.global {
+        Some(ref global) => global.clone(),
+        None => return Err(ErrorKind::NoMetaGlobal.into()),
+    };
+    for name in decline {
+        global.engines.remove(*name);
+        if !global.declined.iter().any(|declined| declined == name) {
+            global.declined.push(name.to_string());
+        }
+    }
+    for name in undecline {
+        global.declined.retain(|declined| declined != name);
+        if !global.engines.contains_key(*name) {
+            let version = DEFAULT_ENGINES
+                .iter()
+                .find(|(default_name, _)| default_name == name)
+                .map(|(_, version)| *version)
+                .unwrap_or(1);
+            global.engines.insert(
+                name.to_string(),
+                MetaGlobalEngine {
+                    version,
+                    sync_id: random_guid()?,
+                },
+            );
+        }
+    }
+    client.put_meta_global(&global)?;
+    Ok(GlobalState {
+        global: Some(global),
+        ..state
+    })
+}
+
+/// Deletes an engine's collection from the server, and uploads a new
+/// `meta/global` with a fresh sync ID for the engine, so that other devices
+/// reset it, too. Returns the new global state, which flags the engine for
+/// a local reset. Unless the engine is also declined, its local records will
+/// be uploaded again on the next sync.
+pub fn wipe_remote_engine(
+    client: &SetupStorageClient,
+    state: GlobalState,
+    name: &str,
+) -> error::Result<GlobalState> {
+    let mut global = match state.global {
+        Some(ref global) => global.clone(),
+        None => return Err(ErrorKind::NoMetaGlobal.into()),
+    };
+    client.wipe_remote_collection(name)?;
+    if let Some(engine) = global.engines.get_mut(name) {
+        engine.sync_id = random_guid()?;
+    }
+    client.put_meta_global(&global)?;
+    let mut engine_state_changes = state.engine_state_changes.clone();
+    engine_state_changes.push(EngineStateChange::Reset(name.to_string()));
+    Ok(GlobalState {
+        global: Some(global),
+        engine_state_changes,
+        ..state
+    })
+}
+
+/// Replaces compromised collection keys by uploading a new `crypto/keys`.
+/// If `collections` is empty, the default key and all collection-specific
+/// keys are replaced; otherwise, only the named collections get fresh keys.
+/// Records encrypted with the old keys can't be decrypted with the new ones,
+/// so this also deletes every collection that uses a new key from the server,
+/// and gives its engine a new sync ID in `meta/global`, so that every device,
+/// including this one, resets the engine and reuploads its records on its
+/// next sync. Returns the new global state.
+pub fn rotate_keys(
+    client: &SetupStorageClient,
+    root_key: &KeyBundle,
+    state: GlobalState,
+    collections: &[&str],
+) -> error::Result<GlobalState> {
+    let previous_keys = match state.keys {
+        Some(ref keys) => keys.clone(),
+        None => return Err(ErrorKind::NoCryptoKeys.into()),
+    };
+    let mut global = match state.global {
+        Some(ref global) => global.clone(),
+        None => return Err(ErrorKind::NoMetaGlobal.into()),
+    };
+    let mut new_keys = if collections.is_empty() {
+        CollectionKeys::new_random()?
+    } else {
+        let mut new_keys = previous_keys.clone();
+        for name in collections {
+            new_keys.add_collection_key(name)?;
+        }
+        new_keys
+    };
+
+    // `meta` and `crypto` aren't encrypted with the collection keys, so we
+    // leave them alone.
+    let mut changed = {
+        let names = global.engines.keys()
+            .chain(state.collections.keys())
+            .map(|name| name.as_str())
+            .filter(|name| *name != "meta" && *name != "crypto");
+        new_keys.changed_collections(&previous_keys, names).into_iter().collect::<Vec<_>>()
+    };
+    changed.sort();
+
+    client.put_crypto_keys(&new_keys.to_encrypted_bso(root_key)?)?;
+    for name in &changed {
+        info!("Deleting {} from the server, since its key changed", name);
+        client.wipe_remote_collection(name)?;
+        if let Some(engine) = global.engines.get_mut(name) {
+            engine.sync_id = random_guid()?;
+        }
+    }
+    client.put_meta_global(&global)?;
+
+    // Keep the old `meta/global` and keys timestamp, so that the next sync
+    // fetches what we just uploaded, notices the new sync IDs, and resets
+    // the engines.
+    new_keys.timestamp = previous_keys.timestamp;
+    Ok(resolve_keys(state, new_keys))
+}
+
 pub struct SetupStateMachine<'client, 'keys> {
     client: &'client SetupStorageClient,
     root_key: &'keys KeyBundle,
@@ -365,6 +528,7 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                     global: state.global,
                     keys: state.keys,
                     engine_state_changes: Vec::new(),
+                    next_sync_after: state.next_sync_after,
                 }))
             }
 
@@ -376,6 +540,7 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                     global: state.global,
                     keys: state.keys,
                     engine_state_changes: state.engine_state_changes,
+                    next_sync_after: state.next_sync_after,
                 }))
             }
 
@@ -407,6 +572,7 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                         global: None,
                         keys: None,
                         engine_state_changes: state.engine_state_changes,
+                        next_sync_after: state.next_sync_after,
                     }),
                 })
             }
@@ -463,6 +629,7 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                         global: state.global,
                         keys: None,
                         engine_state_changes: state.engine_state_changes,
+                        next_sync_after: state.next_sync_after,
                     }),
                 })
             }
@@ -470,10 +637,24 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
             NeedsFreshCryptoKeys(state) => {
                 match self.client.fetch_crypto_keys() {
                     Ok(encrypted_bso) => {
-                        let new_keys =
-                            CollectionKeys::from_encrypted_bso(encrypted_bso, self.root_key)?;
-                        let new_state = resolve_keys(state, new_keys);
-                        Ok(Ready(new_state))
+                        match CollectionKeys::from_encrypted_bso(encrypted_bso, self.root_key) {
+                            Ok(new_keys) => {
+                                let new_state = resolve_keys(state, new_keys);
+                                Ok(Ready(new_state))
+                            }
+                            Err(err) => match err.kind() {
+                                // If the keys on the server fail HMAC
+                                // verification, they're corrupt, or were
+                                // uploaded with a different root key. Other
+                                // devices can't read them, either, so wipe
+                                // the server and upload fresh keys.
+                                ErrorKind::HmacMismatch => {
+                                    warn!("crypto/keys failed HMAC verification; starting over");
+                                    Ok(FreshStartRequired(state))
+                                }
+                                _ => Err(err),
+                            },
+                        }
                     }
                     Err(err) => match err.kind() {
                         // If the server doesn't have a `crypto/keys`, start over
@@ -513,6 +694,7 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                     global: None,
                     keys: None,
                     engine_state_changes: vec![EngineStateChange::ResetAll],
+                    next_sync_after: state.next_sync_after,
                 }))
             }
         }
@@ -616,12 +798,16 @@ pub enum EngineStateChange {
     Enable(String),
     Disable(String),
     Reset(String),
+    /// The collection's key changed, either in `crypto/keys` on the server,
+    /// or because we rotated it.
+    KeysChanged(String),
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::cell::RefCell;
     use bso_record::{BsoRecord, EncryptedBso, EncryptedPayload};
 
     struct InMemoryClient {
@@ -629,6 +815,31 @@ mod tests {
         info_collections: error::Result<InfoCollections>,
         meta_global: error::Result<BsoRecord<MetaGlobalRecord>>,
         crypto_keys: error::Result<BsoRecord<EncryptedPayload>>,
+        put_global: RefCell<Option<BsoRecord<MetaGlobalRecord>>>,
+        put_keys: RefCell<Option<EncryptedBso>>,
+        wiped: RefCell<Vec<String>>,
+    }
+
+    impl InMemoryClient {
+        /// Returns a client that fails all fetches, for tests that only
+        /// check what we upload.
+        fn empty() -> InMemoryClient {
+            let server_error = |route: &str| -> error::Error {
+                ErrorKind::StorageHttpError {
+                    code: 500,
+                    route: route.to_string(),
+                }.into()
+            };
+            InMemoryClient {
+                info_configuration: Err(server_error("info/configuration")),
+                info_collections: Err(server_error("info/collections")),
+                meta_global: Err(server_error("meta/global")),
+                crypto_keys: Err(server_error("crypto/keys")),
+                put_global: RefCell::new(None),
+                put_keys: RefCell::new(None),
+                wiped: RefCell::new(Vec::new()),
+            }
+        }
     }
 
     impl SetupStorageClient for InMemoryClient {
@@ -664,11 +875,9 @@ mod tests {
             }
         }
 
-        fn put_meta_global(&self, _global: &BsoRecord<MetaGlobalRecord>) -> error::Result<()> {
-            Err(ErrorKind::StorageHttpError {
-                code: 500,
-                route: "meta/global".to_string(),
-            }.into())
+        fn put_meta_global(&self, global: &BsoRecord<MetaGlobalRecord>) -> error::Result<()> {
+            *self.put_global.borrow_mut() = Some(global.clone());
+            Ok(())
         }
 
         fn fetch_crypto_keys(&self) -> error::Result<BsoRecord<EncryptedPayload>> {
@@ -682,16 +891,19 @@ mod tests {
             }
         }
 
-        fn put_crypto_keys(&self, _keys: &EncryptedBso) -> error::Result<()> {
-            Err(ErrorKind::StorageHttpError {
-                code: 500,
-                route: "crypto/keys".to_string(),
-            }.into())
+        fn put_crypto_keys(&self, keys: &EncryptedBso) -> error::Result<()> {
+            *self.put_keys.borrow_mut() = Some(keys.clone());
+            Ok(())
         }
 
         fn wipe_all_remote(&self) -> error::Result<()> {
             Ok(())
         }
+
+        fn wipe_remote_collection(&self, collection: &str) -> error::Result<()> {
+            self.wiped.borrow_mut().push(collection.to_string());
+            Ok(())
+        }
     }
 
     #[test]
@@ -734,6 +946,7 @@ mod tests {
                 },
             }),
             crypto_keys: keys.to_encrypted_bso(&root_key),
+            ..InMemoryClient::empty()
         };
 
         let state = GlobalState::default();
@@ -757,4 +970,250 @@ mod tests {
             "Should cycle through all states"
         );
     }
+
+    #[test]
+    fn test_decline_engines() {
+        let global = BsoRecord::new_record(
+            "global".into(),
+            "meta".into(),
+            new_global_from_previous(None).unwrap(),
+        );
+        let history_sync_id = global.engines["history"].sync_id.clone();
+        let state = GlobalState {
+            global: Some(global),
+            ..GlobalState::default()
+        };
+        assert!(!state.is_engine_declined("tabs"));
+
+        let client = InMemoryClient::empty();
+        let state = update_declined_engines(&client, state, &["tabs", "history"], &[])
+            .expect("should decline");
+        assert!(state.is_engine_declined("tabs"));
+        assert!(state.is_engine_declined("history"));
+        assert!(!state.is_engine_declined("bookmarks"));
+        let uploaded = client.put_global.borrow_mut().take().expect("should upload");
+        assert!(!uploaded.engines.contains_key("tabs"));
+        assert_eq!(uploaded.declined.len(), 2);
+
+        let state = update_declined_engines(&client, state, &[], &["history"])
+            .expect("should undecline");
+        assert!(state.is_engine_declined("tabs"));
+        assert!(!state.is_engine_declined("history"));
+        let uploaded = client.put_global.borrow_mut().take().expect("should upload");
+        assert_eq!(uploaded.declined, vec!["tabs".to_string()]);
+        // History gets a new sync ID, so other devices start over.
+        assert_ne!(uploaded.engines["history"].sync_id, history_sync_id);
+    }
+
+    #[test]
+    fn test_wipe_remote_engine() {
+        let global = BsoRecord::new_record(
+            "global".into(),
+            "meta".into(),
+            new_global_from_previous(None).unwrap(),
+        );
+        let passwords_sync_id = global.engines["passwords"].sync_id.clone();
+        let history_sync_id = global.engines["history"].sync_id.clone();
+        let state = GlobalState {
+            global: Some(global),
+            ..GlobalState::default()
+        };
+
+        let client = InMemoryClient::empty();
+        let state = wipe_remote_engine(&client, state, "passwords").expect("should wipe");
+        assert_eq!(*client.wiped.borrow(), vec!["passwords".to_string()]);
+        let uploaded = client.put_global.borrow_mut().take().expect("should upload");
+        assert_ne!(uploaded.engines["passwords"].sync_id, passwords_sync_id);
+        assert_eq!(uploaded.engines["history"].sync_id, history_sync_id);
+        assert_eq!(
+            state.engines_that_need_local_reset(),
+            vec!["passwords".to_string()].into_iter().collect::<HashSet<String>>()
+        );
+
+        // We need a `meta/global` to update.
+        let client = InMemoryClient::empty();
+        assert!(wipe_remote_engine(&client, GlobalState::default(), "passwords").is_err());
+        assert!(client.wiped.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_disabled_engines_are_declined() {
+        let state = GlobalState {
+            engine_state_changes: vec![EngineStateChange::Disable("forms".into())],
+            ..GlobalState::default()
+        };
+        assert!(state.is_engine_declined("forms"));
+        assert!(!state.is_engine_declined("history"));
+    }
+
+    #[test]
+    fn test_backoff_is_persisted() {
+        let until = SystemTime::now() + ::std::time::Duration::from_secs(60);
+        let state = GlobalState {
+            next_sync_after: Some(until),
+            ..GlobalState::default()
+        };
+        match state.check_backoff() {
+            Err(e) => match e.kind() {
+                ErrorKind::BackoffError(when) => assert_eq!(*when, until),
+                kind => panic!("unexpected error {:?}", kind),
+            },
+            Ok(()) => panic!("should be backing off"),
+        }
+        let state = GlobalState::from_persisted_string(&state.to_persistable_string())
+            .expect("should round-trip");
+        assert_eq!(state.next_sync_after, Some(until));
+
+        // Expired backoffs don't stop us syncing.
+        let state = GlobalState {
+            next_sync_after: Some(SystemTime::now() - ::std::time::Duration::from_secs(1)),
+            ..GlobalState::default()
+        };
+        assert!(state.check_backoff().is_ok());
+    }
+
+    #[test]
+    fn test_rotate_all_keys() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let keys = CollectionKeys::new_random().unwrap();
+        let state = GlobalState {
+            global: Some(BsoRecord::new_record(
+                "global".into(),
+                "meta".into(),
+                new_global_from_previous(None).unwrap(),
+            )),
+            keys: Some(keys.clone()),
+            ..GlobalState::default()
+        };
+
+        let client = InMemoryClient::empty();
+        let state = rotate_keys(&client, &root_key, state, &[]).expect("should rotate keys");
+        let uploaded = client.put_keys.borrow_mut().take().expect("should upload keys");
+        let new_keys = CollectionKeys::from_encrypted_bso(uploaded, &root_key)
+            .expect("should decrypt uploaded keys");
+        assert_ne!(new_keys.default, keys.default);
+        assert_eq!(state.keys.as_ref().unwrap().default, new_keys.default);
+        // Every engine needs to reupload its records with the new keys.
+        let reset = state.engines_that_need_local_reset();
+        assert_eq!(reset.len(), DEFAULT_ENGINES.len());
+        assert!(reset.contains("passwords"));
+
+        // ...So we delete their records, and give them new sync IDs.
+        let mut wiped = client.wiped.borrow().clone();
+        wiped.sort();
+        let mut engines: Vec<String> =
+            DEFAULT_ENGINES.iter().map(|(name, _)| name.to_string()).collect();
+        engines.sort();
+        assert_eq!(wiped, engines);
+        let previous_global = state.global.as_ref().unwrap();
+        let uploaded = client.put_global.borrow_mut().take().expect("should upload meta/global");
+        for (name, engine) in &uploaded.engines {
+            assert_ne!(engine.sync_id, previous_global.engines[name].sync_id);
+        }
+    }
+
+    #[test]
+    fn test_rotate_collection_keys() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let keys = CollectionKeys::new_random().unwrap();
+        let state = GlobalState {
+            global: Some(BsoRecord::new_record(
+                "global".into(),
+                "meta".into(),
+                new_global_from_previous(None).unwrap(),
+            )),
+            keys: Some(keys.clone()),
+            ..GlobalState::default()
+        };
+
+        let client = InMemoryClient::empty();
+        let state = rotate_keys(&client, &root_key, state, &["passwords"])
+            .expect("should rotate keys");
+        let uploaded = client.put_keys.borrow_mut().take().expect("should upload keys");
+        let new_keys = CollectionKeys::from_encrypted_bso(uploaded, &root_key)
+            .expect("should decrypt uploaded keys");
+        assert_eq!(new_keys.default, keys.default);
+        assert_ne!(new_keys.key_for_collection("passwords"), &keys.default);
+        assert_eq!(
+            state.engines_that_need_local_reset(),
+            vec!["passwords".to_string()].into_iter().collect::<HashSet<String>>()
+        );
+        assert_eq!(state.collections_with_changed_keys(), state.engines_that_need_local_reset());
+
+        // We can't rotate keys that we don't have.
+        let client = InMemoryClient::empty();
+        assert!(rotate_keys(&client, &root_key, GlobalState::default(), &[]).is_err());
+        assert!(client.put_keys.borrow().is_none());
+    }
+
+    #[test]
+    fn test_state_machine_corrupt_keys() {
+        let root_key = KeyBundle::new_random().unwrap();
+        // Keys encrypted with a different root key fail HMAC verification.
+        let wrong_root_key = KeyBundle::new_random().unwrap();
+        let make_client = || InMemoryClient {
+            info_configuration: Ok(InfoConfiguration::default()),
+            info_collections: Ok(InfoCollections::new(
+                vec![("meta", 123.456), ("crypto", 145.0)]
+                    .into_iter()
+                    .map(|(key, value)| (key.to_owned(), value.into()))
+                    .collect(),
+            )),
+            meta_global: Ok(BsoRecord::new_record(
+                "global".into(),
+                "meta".into(),
+                new_global_from_previous(None).unwrap(),
+            )),
+            crypto_keys: CollectionKeys::new_random()
+                .unwrap()
+                .to_encrypted_bso(&wrong_root_key),
+            ..InMemoryClient::empty()
+        };
+
+        // A full sync wipes the server and uploads fresh keys. Our in-memory
+        // client keeps serving the corrupt keys, so we end up needing to start
+        // over again, which is where we stop.
+        let client = make_client();
+        let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
+        match state_machine.to_ready(GlobalState::default()) {
+            Err(e) => match e.kind() {
+                ErrorKind::SetupStateCycleError => {}
+                kind => panic!("unexpected error {:?}", kind),
+            },
+            Ok(_) => panic!("should not get to ready"),
+        }
+        assert_eq!(
+            state_machine.sequence,
+            vec![
+                "InitialWithLiveToken",
+                "InitialWithLiveTokenAndConfig",
+                "InitialWithLiveTokenAndInfo",
+                "NeedsFreshMetaGlobal",
+                "ResolveMetaGlobal",
+                "HasMetaGlobal",
+                "NeedsFreshCryptoKeys",
+                "FreshStartRequired",
+                "InitialWithLiveTokenAndConfig",
+                "InitialWithLiveTokenAndInfo",
+                "NeedsFreshMetaGlobal",
+                "ResolveMetaGlobal",
+                "HasMetaGlobal",
+                "NeedsFreshCryptoKeys",
+            ]
+        );
+        let uploaded = client.put_keys.borrow_mut().take().expect("should upload keys");
+        assert!(CollectionKeys::from_encrypted_bso(uploaded, &root_key).is_ok());
+        assert!(client.put_global.borrow().is_some());
+
+        // A read-only sync can't upload new keys, so it bails.
+        let client = make_client();
+        let mut state_machine = SetupStateMachine::for_readonly_sync(&client, &root_key);
+        match state_machine.to_ready(GlobalState::default()) {
+            Err(e) => match e.kind() {
+                ErrorKind::DisallowedStateError(state) => assert_eq!(*state, "FreshStartRequired"),
+                kind => panic!("unexpected error {:?}", kind),
+            },
+            Ok(_) => panic!("should not get to ready"),
+        }
+    }
 }
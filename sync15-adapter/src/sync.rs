@@ -2,11 +2,16 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use bso_record::Payload;
 use changeset::{CollectionUpdate, IncomingChangeset, OutgoingChangeset};
 use client::Sync15StorageClient;
 use error;
+use request::RecordRejection;
 use state::GlobalState;
+use telemetry;
 use util::ServerTimestamp;
+use serde_json::Value as JsonValue;
+use std::fmt;
 
 /// Low-level store functionality. Stores that need custom reconciliation logic should use this.
 ///
@@ -18,9 +23,13 @@ use util::ServerTimestamp;
 pub trait Store {
     type Error;
 
+    /// Applies the incoming records, and returns the local changes to
+    /// upload. Stores should record how many records they applied,
+    /// reconciled, and failed to apply in `incoming_telemetry`.
     fn apply_incoming(
         &mut self,
-        inbound: IncomingChangeset
+        inbound: IncomingChangeset,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
     ) -> Result<OutgoingChangeset, Self::Error>;
 
     fn sync_finished(
@@ -28,36 +37,488 @@ pub trait Store {
         new_timestamp: ServerTimestamp,
         records_synced: &[String],
     ) -> Result<(), Self::Error>;
+
+    /// Discards all sync metadata (the last sync time, change flags, mirrors,
+    /// etc), so that the next sync starts from scratch, but keeps the local
+    /// data. Called when the engine's sync ID or keys change. Stores which
+    /// track the engine's sync ID should keep it, since they've already
+    /// adopted the new one by the time this is called.
+    fn reset(&mut self) -> Result<(), Self::Error>;
+
+    /// Called instead of syncing if the engine has been declined, or was
+    /// removed from `meta/global` by another device. Most stores don't need
+    /// to do anything.
+    fn engine_declined(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Returns every live local record, as it would be uploaded, so that
+    /// `validation::validate` can compare them with the server. Stores that
+    /// don't support validation return `None`.
+    fn all_local_records(&self) -> Result<Option<Vec<Payload>>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Returns a key describing what `record` is, so that
+    /// `validation::validate` can find server records which are duplicates
+    /// of each other under different IDs. By default, records are
+    /// duplicates if every field other than the ID is the same.
+    fn validation_key(&self, record: &Payload) -> String {
+        JsonValue::Object(record.data.clone()).to_string()
+    }
+
+    /// Called with each outgoing record that's too large to upload, or
+    /// couldn't be encrypted. Stores can return a smaller copy of the record
+    /// to upload instead; for example, one with fewer history visits. If
+    /// they return `None`, the record isn't uploaded, and `sync_finished`
+    /// won't include it in `records_synced`.
+    fn trim_rejected_record(
+        &mut self,
+        _record: &Payload,
+        _reason: &RecordRejection,
+    ) -> Option<Payload> {
+        None
+    }
+
+    /// Stores that would rather not hold every incoming record in memory at
+    /// once can implement `StagingStore`, and return themselves here. If
+    /// they do, `synchronize` stages the incoming records as they're
+    /// downloaded instead of calling `apply_incoming`.
+    fn as_staging_store(&mut self) -> Option<&mut StagingStore<Error = Self::Error>> {
+        None
+    }
+}
+
+/// A store which stages incoming records as they're downloaded (see
+/// `Store::as_staging_store`).
+pub trait StagingStore {
+    type Error;
+
+    /// The most records to pass to `stage_incoming` at once.
+    fn incoming_batch_size(&self) -> usize;
+
+    /// Stages a batch of incoming records; for example, by writing them to
+    /// a temporary table.
+    fn stage_incoming(
+        &mut self,
+        records: Vec<(Payload, ServerTimestamp)>,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> Result<(), Self::Error>;
+
+    /// Called once every incoming record has been staged. Applies and
+    /// reconciles them, and returns the local changes to upload, like
+    /// `Store::apply_incoming`. `timestamp` is the collection's last
+    /// modified time when the sync started.
+    fn apply_staged(
+        &mut self,
+        timestamp: ServerTimestamp,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> Result<OutgoingChangeset, Self::Error>;
 }
 
+/// Syncs a single collection, recording what happened in `telem_engine`.
 pub fn synchronize<E>(client: &Sync15StorageClient,
                    state: &GlobalState,
                    store: &mut Store<Error=E>,
                    collection: String,
                    timestamp: ServerTimestamp,
-                   fully_atomic: bool) -> Result<(), E>
+                   fully_atomic: bool,
+                   telem_engine: &mut telemetry::Engine) -> Result<(), E>
+where E: From<error::Error> + fmt::Display
+{
+    let result = do_synchronize(client, state, store, collection, timestamp, fully_atomic,
+                                telem_engine);
+    if let Err(e) = &result {
+        // Errors from the adapter itself were already classified, so this
+        // only records errors from the store.
+        telem_engine.failure(telemetry::SyncFailure::Unexpected { error: e.to_string() });
+    }
+    telem_engine.finished();
+    result
+}
+
+// Records a failure from the adapter before it's converted into the store's
+// error type, which we can't classify.
+fn sync_error<E: From<error::Error>>(telem_engine: &mut telemetry::Engine, e: error::Error) -> E {
+    telem_engine.failure(telemetry::SyncFailure::from(&e));
+    e.into()
+}
+
+fn do_synchronize<E>(client: &Sync15StorageClient,
+                     state: &GlobalState,
+                     store: &mut Store<Error=E>,
+                     collection: String,
+                     timestamp: ServerTimestamp,
+                     fully_atomic: bool,
+                     telem_engine: &mut telemetry::Engine) -> Result<(), E>
 where E: From<error::Error>
 {
+    if state.is_engine_declined(&collection) {
+        info!("Not syncing declined collection {}", collection);
+        return store.engine_declined();
+    }
 
-    info!("Syncing collection {}", collection);
-    let incoming_changes = IncomingChangeset::fetch(client, state, collection.clone(), timestamp)?;
-    let last_changed_remote = incoming_changes.timestamp;
+    // If the store was reset, its last sync time is gone too.
+    let timestamp = if state.engines_that_need_local_reset().contains(&collection) {
+        info!("Resetting collection {} before syncing", collection);
+        store.reset()?;
+        ServerTimestamp::default()
+    } else {
+        timestamp
+    };
 
-    info!("Downloaded {} remote changes", incoming_changes.changes.len());
-    let mut outgoing = store.apply_incoming(incoming_changes)?;
+    info!("Syncing collection {}", collection);
+    let last_changed_remote = state.last_modified_or_zero(&collection);
+    let mut incoming_telemetry = telemetry::EngineIncoming::new();
+    let staged_outgoing = match store.as_staging_store() {
+        Some(staging_store) => {
+            stream_incoming(client, state, staging_store, &collection, timestamp,
+                            &mut incoming_telemetry)
+                .map_err(|e| match e {
+                    StreamError::Sync(e) => sync_error::<E>(telem_engine, e),
+                    StreamError::Store(e) => e,
+                })?;
+            Some(staging_store.apply_staged(last_changed_remote, &mut incoming_telemetry)?)
+        }
+        None => None,
+    };
+    let mut outgoing = match staged_outgoing {
+        Some(outgoing) => outgoing,
+        None => {
+            let incoming_changes = IncomingChangeset::fetch(client, state, collection.clone(),
+                                                            timestamp)
+                .map_err(|e| sync_error::<E>(telem_engine, e))?;
+            info!("Downloaded {} remote changes", incoming_changes.changes.len());
+            store.apply_incoming(incoming_changes, &mut incoming_telemetry)?
+        }
+    };
+    telem_engine.incoming(incoming_telemetry);
 
     outgoing.timestamp = last_changed_remote;
 
     info!("Uploading {} outgoing changes", outgoing.changes.len());
-    let upload_info =
-        CollectionUpdate::new_from_changeset(client, state, outgoing, fully_atomic)?.upload()?;
+    let upload_info = CollectionUpdate::new_from_changeset_with_trim(
+        client, state, outgoing, fully_atomic,
+        |record, reason| store.trim_rejected_record(record, reason))
+        .and_then(|update| update.upload())
+        .map_err(|e| sync_error::<E>(telem_engine, e))?;
 
-    info!("Upload success ({} records success, {} records failed)",
+    info!("Upload success ({} records success, {} records failed, {} records rejected)",
           upload_info.successful_ids.len(),
-          upload_info.failed_ids.len());
+          upload_info.failed_ids.len(),
+          upload_info.rejected.len());
+    telem_engine.outgoing(telemetry::EngineOutgoing {
+        sent: upload_info.successful_ids.len() + upload_info.failed_ids.len(),
+        failed: upload_info.failed_ids.len(),
+    });
 
     store.sync_finished(upload_info.modified_timestamp, &upload_info.successful_ids)?;
 
     info!("Sync finished!");
     Ok(())
 }
+
+// Keeps errors from the adapter apart from the store's own, so that we can
+// still classify them for telemetry.
+enum StreamError<E> {
+    Sync(error::Error),
+    Store(E),
+}
+
+impl<E> From<error::Error> for StreamError<E> {
+    fn from(e: error::Error) -> Self {
+        StreamError::Sync(e)
+    }
+}
+
+// Downloads and decrypts the incoming records in batches, staging each batch
+// in the store as it arrives.
+fn stream_incoming<E>(client: &Sync15StorageClient,
+                      state: &GlobalState,
+                      store: &mut StagingStore<Error=E>,
+                      collection: &str,
+                      since: ServerTimestamp,
+                      incoming_telemetry: &mut telemetry::EngineIncoming)
+                      -> Result<(), StreamError<E>>
+{
+    let key = state.key_for_collection(collection)?;
+    let batch_size = store.incoming_batch_size();
+    let mut downloaded = 0;
+    client.get_encrypted_records_in_batches(collection, since, batch_size, |records| {
+        let mut batch = Vec::with_capacity(records.len());
+        for record in records {
+            batch.push(record.decrypt(key)?.into_timestamped_payload());
+        }
+        downloaded += batch.len();
+        store.stage_incoming(batch, incoming_telemetry).map_err(StreamError::Store)
+    })?;
+    info!("Downloaded {} remote changes", downloaded);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::ErrorKind;
+    use key_bundle::KeyBundle;
+    use client::SetupStorageClient;
+    use mock_server::{MockBso, MockSyncServer};
+    use request::InfoConfiguration;
+    use state::{self, SetupStateMachine};
+
+    // A store that streams incoming records, and keeps them in memory.
+    #[derive(Default)]
+    struct TestStore {
+        batch_size: Option<usize>,
+        records: Vec<Payload>,
+        batches: Vec<usize>,
+        staged: Vec<Payload>,
+        to_upload: Vec<Payload>,
+        uploaded: Vec<Payload>,
+        synced: Vec<String>,
+        resets: usize,
+    }
+
+    impl Store for TestStore {
+        type Error = error::Error;
+
+        fn apply_incoming(
+            &mut self,
+            inbound: IncomingChangeset,
+            incoming_telemetry: &mut telemetry::EngineIncoming,
+        ) -> error::Result<OutgoingChangeset> {
+            let records = inbound.changes.into_iter().map(|(payload, _)| payload).collect();
+            self.stage_incoming(records, incoming_telemetry)?;
+            self.apply_staged(inbound.timestamp, incoming_telemetry)
+        }
+
+        fn sync_finished(&mut self, _: ServerTimestamp, synced: &[String]) -> error::Result<()> {
+            self.uploaded.append(&mut self.to_upload);
+            self.synced = synced.to_vec();
+            Ok(())
+        }
+
+        // Like a real store, we reupload everything after a reset.
+        fn reset(&mut self) -> error::Result<()> {
+            self.resets += 1;
+            self.to_upload.append(&mut self.uploaded);
+            Ok(())
+        }
+
+        fn trim_rejected_record(
+            &mut self,
+            record: &Payload,
+            reason: &RecordRejection,
+        ) -> Option<Payload> {
+            match reason {
+                RecordRejection::TooLarge { .. } if record.id != "huge" => {
+                    let mut trimmed = record.clone();
+                    trimmed.data.remove("blob");
+                    Some(trimmed)
+                }
+                _ => None,
+            }
+        }
+
+        fn as_staging_store(&mut self) -> Option<&mut StagingStore<Error = error::Error>> {
+            if self.batch_size.is_some() {
+                Some(self)
+            } else {
+                None
+            }
+        }
+    }
+
+    impl StagingStore for TestStore {
+        type Error = error::Error;
+
+        fn incoming_batch_size(&self) -> usize {
+            self.batch_size.unwrap_or(1000)
+        }
+
+        fn stage_incoming(
+            &mut self,
+            records: Vec<(Payload, ServerTimestamp)>,
+            _: &mut telemetry::EngineIncoming,
+        ) -> error::Result<()> {
+            self.batches.push(records.len());
+            self.staged.extend(records.into_iter().map(|(payload, _)| payload));
+            Ok(())
+        }
+
+        fn apply_staged(
+            &mut self,
+            timestamp: ServerTimestamp,
+            incoming_telemetry: &mut telemetry::EngineIncoming,
+        ) -> error::Result<OutgoingChangeset> {
+            incoming_telemetry.applied(self.staged.len());
+            self.records.append(&mut self.staged);
+            let mut outgoing = OutgoingChangeset::new("bookmarks".into(), timestamp);
+            outgoing.changes = self.to_upload.clone();
+            Ok(outgoing)
+        }
+    }
+
+    fn payload(id: &str) -> Payload {
+        Payload::from_json(json!({ "id": id, "title": id })).unwrap()
+    }
+
+    fn sync(server: &MockSyncServer, root_key: &KeyBundle, store: &mut TestStore)
+            -> telemetry::Engine {
+        try_sync(server, root_key, store, true).expect("should sync")
+    }
+
+    fn try_sync(server: &MockSyncServer, root_key: &KeyBundle, store: &mut TestStore,
+                fully_atomic: bool) -> error::Result<telemetry::Engine> {
+        let client = server.client();
+        let state = SetupStateMachine::for_full_sync(&client, root_key)
+            .to_ready(GlobalState::default())
+            .expect("should get to ready");
+        let mut telem_engine = telemetry::Engine::new("bookmarks");
+        synchronize(&client, &state, store, "bookmarks".into(), ServerTimestamp(0.0),
+                    fully_atomic, &mut telem_engine)?;
+        Ok(telem_engine)
+    }
+
+    // Syncs `store` using the state from its last sync, like a device that
+    // has synced before.
+    fn sync_with_state(client: &Sync15StorageClient, root_key: &KeyBundle, store: &mut TestStore,
+                       state: GlobalState) -> error::Result<GlobalState> {
+        let state = SetupStateMachine::for_full_sync(client, root_key).to_ready(state)?;
+        let mut telem_engine = telemetry::Engine::new("bookmarks");
+        synchronize(client, &state, store, "bookmarks".into(), ServerTimestamp(0.0), true,
+                    &mut telem_engine)?;
+        Ok(state)
+    }
+
+    fn ids(records: &[Payload]) -> Vec<&str> {
+        let mut ids: Vec<&str> = records.iter().map(|p| p.id()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_sync_after_rotating_all_keys() {
+        let server = MockSyncServer::new();
+        let root_key = KeyBundle::new_random().unwrap();
+        let client = server.client();
+        let mut store = TestStore {
+            to_upload: vec![payload("a"), payload("b")],
+            ..TestStore::default()
+        };
+        let state = sync_with_state(&client, &root_key, &mut store, GlobalState::default())
+            .expect("should sync");
+        let resets = store.resets;
+
+        let state = state::rotate_keys(&client, &root_key, state, &[]).expect("should rotate");
+        assert!(server.records("bookmarks").is_empty());
+
+        // We should reset, and reupload our records with the new key...
+        sync_with_state(&client, &root_key, &mut store, state).expect("should sync again");
+        assert_eq!(store.resets, resets + 1);
+        assert_eq!(server.records("bookmarks").len(), 2);
+
+        // ...So that a new device can decrypt them.
+        let mut other = TestStore::default();
+        sync(&server, &root_key, &mut other);
+        assert_eq!(ids(&other.records), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_sync_after_rotating_collection_key() {
+        let server = MockSyncServer::new();
+        let root_key = KeyBundle::new_random().unwrap();
+        let client = server.client();
+        let mut store = TestStore {
+            to_upload: vec![payload("a"), payload("b")],
+            ..TestStore::default()
+        };
+        let state = sync_with_state(&client, &root_key, &mut store, GlobalState::default())
+            .expect("should sync");
+        let history = MockBso {
+            id: "history1".into(),
+            modified: ServerTimestamp::default(),
+            sortindex: None,
+            ttl: None,
+            payload: "{}".into(),
+        };
+        server.insert_records("history", vec![history.clone()]);
+        let previous_global = client.fetch_meta_global().unwrap();
+        let resets = store.resets;
+
+        let state = state::rotate_keys(&client, &root_key, state, &["bookmarks"])
+            .expect("should rotate");
+        assert!(server.records("bookmarks").is_empty());
+        // Collections that still use the same key are left alone.
+        assert_eq!(server.records("history").len(), 1);
+        let global = client.fetch_meta_global().unwrap();
+        assert_ne!(global.engines["bookmarks"].sync_id,
+                   previous_global.engines["bookmarks"].sync_id);
+        assert_eq!(global.engines["history"].sync_id, previous_global.engines["history"].sync_id);
+
+        sync_with_state(&client, &root_key, &mut store, state).expect("should sync again");
+        assert_eq!(store.resets, resets + 1);
+
+        let mut other = TestStore::default();
+        sync(&server, &root_key, &mut other);
+        assert_eq!(ids(&other.records), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_stream_incoming() {
+        let server = MockSyncServer::new();
+        let root_key = KeyBundle::new_random().unwrap();
+
+        let mut first = TestStore {
+            to_upload: vec![payload("a"), payload("b"), payload("c"), payload("d"), payload("e")],
+            ..TestStore::default()
+        };
+        sync(&server, &root_key, &mut first);
+        assert_eq!(server.records("bookmarks").len(), 5);
+
+        let mut second = TestStore { batch_size: Some(2), ..TestStore::default() };
+        let telem_engine = sync(&server, &root_key, &mut second);
+        assert_eq!(second.batches, vec![2, 2, 1]);
+        let mut ids: Vec<&str> = second.records.iter().map(|p| p.id()).collect();
+        ids.sort();
+        assert_eq!(ids, vec!["a", "b", "c", "d", "e"]);
+        assert_eq!(telem_engine.get_incoming().map(|i| i.get_applied()), Some(5));
+    }
+
+    #[test]
+    fn test_trim_rejected_records() {
+        let server = MockSyncServer::with_configuration(InfoConfiguration {
+            max_record_payload_bytes: 1024,
+            ..InfoConfiguration::default()
+        });
+        let root_key = KeyBundle::new_random().unwrap();
+        let blob = "x".repeat(2048);
+        let records = vec![
+            payload("small"),
+            Payload::from_json(json!({ "id": "big", "blob": blob })).unwrap(),
+            Payload::from_json(json!({ "id": "huge", "blob": blob })).unwrap(),
+        ];
+
+        // Fully atomic uploads fail if we can't trim every record.
+        let mut store = TestStore { to_upload: records.clone(), ..TestStore::default() };
+        match try_sync(&server, &root_key, &mut store, true) {
+            Err(e) => match e.kind() {
+                ErrorKind::RecordTooLargeError => {}
+                kind => panic!("unexpected error {:?}", kind),
+            },
+            Ok(_) => panic!("should refuse to upload a record that's too large"),
+        }
+        assert!(server.records("bookmarks").is_empty());
+
+        let mut store = TestStore { to_upload: records, ..TestStore::default() };
+        let telem_engine = try_sync(&server, &root_key, &mut store, false).expect("should sync");
+        let mut synced = store.synced.clone();
+        synced.sort();
+        assert_eq!(synced, vec!["big", "small"]);
+        let mut uploaded: Vec<String> =
+            server.records("bookmarks").into_iter().map(|record| record.id).collect();
+        uploaded.sort();
+        assert_eq!(uploaded, vec!["big", "small"]);
+        assert_eq!(telem_engine.get_outgoing()[0].failed, 1);
+    }
+}
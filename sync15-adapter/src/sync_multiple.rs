@@ -0,0 +1,390 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Syncs several engines at once. The setup state machine is only run once,
+//! and any engines it decides need resetting (for example, because their
+//! sync ID or keys changed) are reset by `synchronize` before they're
+//! synced.
+
+use std::time::SystemTime;
+
+use failure;
+
+use client::Sync15StorageClient;
+use clients::{Command, CommandStatus};
+use error;
+use key_bundle::KeyBundle;
+use state::{self, GlobalState, SetupStateMachine};
+use telemetry;
+
+/// An engine which can be synced by `sync_multiple`. Engines from different
+/// crates have different error types, so errors are returned as
+/// `failure::Error`s, which lets us sync them all together.
+pub trait SyncEngine {
+    /// The name of the collection this engine syncs, which is also its name
+    /// in `meta/global`.
+    fn collection_name(&self) -> &'static str;
+
+    /// Syncs the engine's collection, recording what happened in
+    /// `telem_engine`. `state` is always ready.
+    fn sync(
+        &mut self,
+        client: &Sync15StorageClient,
+        state: &GlobalState,
+        telem_engine: &mut telemetry::Engine,
+    ) -> Result<(), failure::Error>;
+
+    /// Discards the engine's sync metadata, so that its next sync starts
+    /// from scratch, but keeps its local data.
+    fn reset(&mut self) -> Result<(), failure::Error>;
+
+    /// Deletes all the engine's local data, as well as its sync metadata.
+    /// Called when another client asks us to wipe the engine (see
+    /// `apply_command`).
+    fn wipe(&mut self) -> Result<(), failure::Error>;
+}
+
+/// The outcome of syncing a single engine.
+#[derive(Debug)]
+pub struct EngineResult {
+    pub collection: String,
+    /// Whether the engine was reset before syncing.
+    pub was_reset: bool,
+    pub result: Result<(), failure::Error>,
+}
+
+#[derive(Debug)]
+pub struct SyncResult {
+    /// Whether we were able to start syncing. If this is an error (for
+    /// example, because the server asked us to back off, or we couldn't
+    /// fetch `meta/global`), no engines were synced.
+    pub service_result: error::Result<()>,
+    /// The new global state, which the caller should persist (see
+    /// `GlobalState::to_persistable_string`) and pass to the next sync. This
+    /// is returned even if the sync failed, so that we remember when the
+    /// server asked us to back off.
+    pub global_state: GlobalState,
+    /// A result for each engine, in the order the engines were passed.
+    pub engine_results: Vec<EngineResult>,
+}
+
+impl SyncResult {
+    pub fn is_success(&self) -> bool {
+        self.service_result.is_ok() && self.engine_results.iter().all(|r| r.result.is_ok())
+    }
+
+    /// The earliest time the next sync should start, if the server asked us
+    /// to back off.
+    pub fn next_sync_after(&self) -> Option<SystemTime> {
+        self.global_state.next_sync_after
+    }
+}
+
+/// Advances `global_state` to ready, then syncs each engine in order. If the
+/// server asked us to back off, or getting to ready fails, nothing is synced;
+/// otherwise a failure in one engine doesn't stop the others from syncing.
+///
+/// Telemetry for the sync is recorded in `telem_sync` whether or not it
+/// succeeds, so that failures can be reported, too.
+pub fn sync_multiple(
+    engines: &mut [&mut SyncEngine],
+    global_state: GlobalState,
+    client: &Sync15StorageClient,
+    root_sync_key: &KeyBundle,
+    telem_sync: &mut telemetry::SyncTelemetry,
+) -> SyncResult {
+    if let Err(e) = global_state.check_backoff() {
+        info!("Not syncing: {}", e);
+        telem_sync.failure(telemetry::SyncFailure::from(&e));
+        telem_sync.finished();
+        return SyncResult {
+            service_result: Err(e),
+            global_state,
+            engine_results: Vec::new(),
+        };
+    }
+
+    info!("Advancing state machine to ready (full)");
+    // If we can't get to ready, we hand back the state we started with.
+    let previous_state = global_state.clone();
+    let mut state_machine = SetupStateMachine::for_full_sync(client, root_sync_key);
+    let mut result = match state_machine.to_ready(global_state) {
+        Ok(global_state) => {
+            let engine_results = sync_engines(engines, &global_state, client, telem_sync);
+            SyncResult {
+                service_result: Ok(()),
+                global_state,
+                engine_results,
+            }
+        }
+        Err(e) => {
+            warn!("Failed to advance state machine to ready: {}", e);
+            telem_sync.failure(telemetry::SyncFailure::from(&e));
+            SyncResult {
+                service_result: Err(e),
+                global_state: previous_state,
+                engine_results: Vec::new(),
+            }
+        }
+    };
+
+    // Remember if the server asked us to back off during this sync, so that
+    // the next one waits.
+    if let Some(until) = client.backoff_until() {
+        result.global_state.next_sync_after = Some(until);
+    }
+    telem_sync.finished();
+    result
+}
+
+/// Deletes `engine`'s records from the server, gives it a new sync ID in
+/// `meta/global` so that other devices reset it, too, and resets its local
+/// sync state. `global_state` must be ready. Returns the new global state,
+/// which the caller should persist.
+pub fn wipe_and_reset_engine(
+    engine: &mut SyncEngine,
+    global_state: GlobalState,
+    client: &Sync15StorageClient,
+) -> Result<GlobalState, failure::Error> {
+    let global_state = state::wipe_remote_engine(client, global_state, engine.collection_name())?;
+    engine.reset()?;
+    Ok(global_state)
+}
+
+/// Applies a command sent to us by another client to `engines`. This is
+/// meant to be called from a `clients::CommandProcessor`. Commands for
+/// engines we don't have are ignored, and commands which aren't for engines
+/// (like `DisplayUri`) are unsupported, so the processor should handle those
+/// itself.
+pub fn apply_command(
+    engines: &mut [&mut SyncEngine],
+    command: &Command,
+) -> Result<CommandStatus, failure::Error> {
+    let (name, wipe) = match *command {
+        Command::Wipe(ref name) => (name, true),
+        Command::Reset(ref name) => (name, false),
+        Command::ResetAll => {
+            for engine in engines.iter_mut() {
+                engine.reset()?;
+            }
+            return Ok(CommandStatus::Applied);
+        }
+        Command::DisplayUri { .. } => return Ok(CommandStatus::Unsupported),
+    };
+    let engine = match engines.iter_mut().find(|engine| engine.collection_name() == *name) {
+        Some(engine) => engine,
+        None => {
+            info!("Ignoring command for unknown engine {}", name);
+            return Ok(CommandStatus::Ignored);
+        }
+    };
+    if wipe {
+        engine.wipe()?;
+    } else {
+        engine.reset()?;
+    }
+    Ok(CommandStatus::Applied)
+}
+
+fn sync_engines(
+    engines: &mut [&mut SyncEngine],
+    global_state: &GlobalState,
+    client: &Sync15StorageClient,
+    telem_sync: &mut telemetry::SyncTelemetry,
+) -> Vec<EngineResult> {
+    let engines_to_reset = global_state.engines_that_need_local_reset();
+    let mut results = Vec::with_capacity(engines.len());
+    for engine in engines.iter_mut() {
+        let collection = engine.collection_name();
+        let was_reset = engines_to_reset.contains(collection) &&
+                        !global_state.is_engine_declined(collection);
+        let mut telem_engine = telemetry::Engine::new(collection);
+        let result = engine.sync(client, global_state, &mut telem_engine);
+        match &result {
+            Ok(()) => info!("Sync of {} was successful!", collection),
+            Err(e) => {
+                warn!("Sync of {} failed! {:?}", collection, e);
+                // Engines that use `synchronize` will already have recorded
+                // a more specific failure.
+                telem_engine.failure(telemetry::SyncFailure::Unexpected { error: e.to_string() });
+            }
+        }
+        telem_engine.finished();
+        telem_sync.engine(telem_engine);
+        results.push(EngineResult {
+            collection: collection.into(),
+            was_reset,
+            result,
+        });
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::Sync15StorageClientInit;
+    use error::ErrorKind;
+    use mock_server::{MockBso, MockSyncServer};
+    use state::EngineStateChange;
+
+    #[derive(Default)]
+    struct TestEngine {
+        name: &'static str,
+        fail: bool,
+        syncs: usize,
+        resets: usize,
+        wipes: usize,
+    }
+
+    impl SyncEngine for TestEngine {
+        fn collection_name(&self) -> &'static str {
+            self.name
+        }
+
+        fn sync(
+            &mut self,
+            _client: &Sync15StorageClient,
+            _state: &GlobalState,
+            _telem_engine: &mut telemetry::Engine,
+        ) -> Result<(), failure::Error> {
+            self.syncs += 1;
+            if self.fail {
+                Err(error::Error::from(ErrorKind::RecordTooLargeError).into())
+            } else {
+                Ok(())
+            }
+        }
+
+        fn reset(&mut self) -> Result<(), failure::Error> {
+            self.resets += 1;
+            Ok(())
+        }
+
+        fn wipe(&mut self) -> Result<(), failure::Error> {
+            self.wipes += 1;
+            Ok(())
+        }
+    }
+
+    fn client() -> Sync15StorageClient {
+        Sync15StorageClient::new(Sync15StorageClientInit {
+            key_id: "key-id".into(),
+            access_token: "access-token".into(),
+            tokenserver_url: "http://localhost:1/token/1.0/sync/1.5".parse().unwrap(),
+        }).expect("should create client")
+    }
+
+    #[test]
+    fn test_sync_engines() {
+        let mut history = TestEngine { name: "history", ..TestEngine::default() };
+        let mut bookmarks = TestEngine { name: "bookmarks", fail: true, ..TestEngine::default() };
+        let mut tabs = TestEngine { name: "tabs", ..TestEngine::default() };
+        let state = GlobalState {
+            engine_state_changes: vec![EngineStateChange::Reset("bookmarks".into())],
+            ..GlobalState::default()
+        };
+        let mut telem = telemetry::SyncTelemetry::new();
+        let results = {
+            let mut engines: [&mut SyncEngine; 3] = [&mut history, &mut bookmarks, &mut tabs];
+            sync_engines(&mut engines, &state, &client(), &mut telem)
+        };
+
+        let collections: Vec<&str> = results.iter().map(|r| r.collection.as_str()).collect();
+        assert_eq!(collections, vec!["history", "bookmarks", "tabs"]);
+        assert!(results[0].result.is_ok());
+        // A failure in one engine doesn't stop the next one syncing.
+        assert!(results[1].result.is_err());
+        assert!(results[2].result.is_ok());
+        assert!(results[1].was_reset);
+        assert!(!results[0].was_reset);
+
+        assert_eq!(history.syncs, 1);
+        assert_eq!(bookmarks.syncs, 1);
+        assert_eq!(tabs.syncs, 1);
+
+        let telem_engines = telem.get_engines();
+        assert_eq!(telem_engines.len(), 3);
+        assert_eq!(telem_engines[1].name(), "bookmarks");
+        assert!(telem_engines[0].get_failure().is_none());
+        assert_eq!(telem_engines[1].get_failure(), Some(&telemetry::SyncFailure::Unexpected {
+            error: "Outgoing record is too large to upload".into(),
+        }));
+    }
+
+    #[test]
+    fn test_no_sync_during_backoff() {
+        let mut history = TestEngine { name: "history", ..TestEngine::default() };
+        let until = SystemTime::now() + ::std::time::Duration::from_secs(60);
+        let state = GlobalState {
+            next_sync_after: Some(until),
+            ..GlobalState::default()
+        };
+        let mut telem = telemetry::SyncTelemetry::new();
+        let result = {
+            let mut engines: [&mut SyncEngine; 1] = [&mut history];
+            sync_multiple(&mut engines, state, &client(), &KeyBundle::new_random().unwrap(),
+                          &mut telem)
+        };
+        assert!(!result.is_success());
+        assert!(result.engine_results.is_empty());
+        assert_eq!(result.next_sync_after(), Some(until));
+        assert_eq!(telem.get_failure(), Some(&telemetry::SyncFailure::Http { code: 503 }));
+        assert_eq!(history.syncs, 0);
+    }
+
+    #[test]
+    fn test_wipe_and_reset_engine() {
+        let server = MockSyncServer::new();
+        let client = server.client();
+        let root_key = KeyBundle::new_random().unwrap();
+        let state = SetupStateMachine::for_full_sync(&client, &root_key)
+            .to_ready(GlobalState::default())
+            .expect("should get to ready");
+        server.insert_records("passwords", vec![MockBso {
+            id: "aaaaaaaaaaaa".into(),
+            modified: Default::default(),
+            sortindex: None,
+            ttl: None,
+            payload: "{}".into(),
+        }]);
+        let sync_id = state.global.as_ref().unwrap().engines["passwords"].sync_id.clone();
+
+        let mut passwords = TestEngine { name: "passwords", ..TestEngine::default() };
+        let state = wipe_and_reset_engine(&mut passwords, state, &client).expect("should wipe");
+        assert_eq!(passwords.resets, 1);
+        assert!(server.records("passwords").is_empty());
+        let new_sync_id = state.global.as_ref().unwrap().engines["passwords"].sync_id.clone();
+        assert_ne!(new_sync_id, sync_id);
+
+        // The next sync picks up the new `meta/global` without resetting
+        // the engine again.
+        let state = SetupStateMachine::for_full_sync(&client, &root_key)
+            .to_ready(state)
+            .expect("should get to ready");
+        assert_eq!(state.global.as_ref().unwrap().engines["passwords"].sync_id, new_sync_id);
+        assert!(state.engines_that_need_local_reset().is_empty());
+    }
+
+    #[test]
+    fn test_apply_command() {
+        let mut history = TestEngine { name: "history", ..TestEngine::default() };
+        let mut bookmarks = TestEngine { name: "bookmarks", ..TestEngine::default() };
+        {
+            let mut engines: [&mut SyncEngine; 2] = [&mut history, &mut bookmarks];
+            let mut apply = |command| apply_command(&mut engines, &command).expect("should apply");
+            assert_eq!(apply(Command::Wipe("bookmarks".into())), CommandStatus::Applied);
+            assert_eq!(apply(Command::Reset("history".into())), CommandStatus::Applied);
+            assert_eq!(apply(Command::ResetAll), CommandStatus::Applied);
+            assert_eq!(apply(Command::Wipe("tabs".into())), CommandStatus::Ignored);
+            assert_eq!(apply(Command::DisplayUri {
+                uri: "https://example.com".into(),
+                sender: "other".into(),
+                title: "".into(),
+            }), CommandStatus::Unsupported);
+        }
+        assert_eq!((history.wipes, history.resets), (0, 2));
+        assert_eq!((bookmarks.wipes, bookmarks.resets), (1, 1));
+    }
+}
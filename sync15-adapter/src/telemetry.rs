@@ -0,0 +1,326 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Sync telemetry, in the shape of the "sync" object in Firefox's
+//! [sync ping](https://firefox-source-docs.mozilla.org/toolkit/components/telemetry/telemetry/data/sync-ping.html).
+//! We only collect the data; it's up to the app to serialize it to JSON and
+//! submit it.
+
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use error::{self, ErrorKind};
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+// Records when something started, and how long it took, in milliseconds.
+#[derive(Debug, Serialize)]
+struct Stopwatch {
+    when: f64,
+    #[serde(skip_serializing_if = "is_zero")]
+    took: usize,
+    #[serde(skip)]
+    started: Instant,
+}
+
+impl Stopwatch {
+    fn new() -> Self {
+        let when = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as f64 * 1000.0 + f64::from(d.subsec_millis()))
+            .unwrap_or(0.0);
+        Self {
+            when,
+            took: 0,
+            started: Instant::now(),
+        }
+    }
+
+    fn finished(&mut self) {
+        let elapsed = self.started.elapsed();
+        self.took = elapsed.as_secs() as usize * 1000 + elapsed.subsec_millis() as usize;
+    }
+}
+
+/// Why a sync, or an engine's sync, failed. Serializes to the sync ping's
+/// `failureReason` object.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "name")]
+pub enum SyncFailure {
+    #[serde(rename = "httperror")]
+    Http { code: u16 },
+
+    #[serde(rename = "autherror")]
+    Auth { from: &'static str },
+
+    #[serde(rename = "othererror")]
+    Other { error: String },
+
+    #[serde(rename = "unexpectederror")]
+    Unexpected { error: String },
+}
+
+impl<'a> From<&'a error::Error> for SyncFailure {
+    fn from(e: &'a error::Error) -> Self {
+        match e.kind() {
+            ErrorKind::TokenserverHttpError(401) => SyncFailure::Auth { from: "tokenserver" },
+            ErrorKind::TokenserverHttpError(code) => SyncFailure::Http { code: *code },
+            // Don't include the route, since it contains the user's storage URL.
+            ErrorKind::StorageHttpError { code, .. } => SyncFailure::Http { code: *code },
+            ErrorKind::BackoffError(_) => SyncFailure::Http { code: 503 },
+            // Network errors can include URLs, too.
+            ErrorKind::RequestError(_) | ErrorKind::TransportError(_) => {
+                SyncFailure::Other { error: "network error".into() }
+            }
+            ErrorKind::UnacceptableUrl(_) => SyncFailure::Other { error: "unacceptable url".into() },
+            ErrorKind::HmacMismatch => SyncFailure::Other { error: "hmac mismatch".into() },
+            ErrorKind::ClientUpgradeRequired => SyncFailure::Other { error: "client upgrade required".into() },
+            kind => SyncFailure::Unexpected { error: kind.to_string() },
+        }
+    }
+}
+
+/// What happened to the incoming records for an engine.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct EngineIncoming {
+    #[serde(skip_serializing_if = "is_zero")]
+    applied: usize,
+    #[serde(skip_serializing_if = "is_zero")]
+    failed: usize,
+    #[serde(rename = "newFailed", skip_serializing_if = "is_zero")]
+    new_failed: usize,
+    #[serde(skip_serializing_if = "is_zero")]
+    reconciled: usize,
+}
+
+impl EngineIncoming {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that `n` incoming records were applied locally.
+    pub fn applied(&mut self, n: usize) {
+        self.applied += n;
+    }
+
+    /// Records that `n` incoming records couldn't be applied, for example
+    /// because they were invalid.
+    pub fn failed(&mut self, n: usize) {
+        self.failed += n;
+    }
+
+    /// Records that `n` incoming records failed for the first time.
+    pub fn new_failed(&mut self, n: usize) {
+        self.new_failed += n;
+    }
+
+    /// Records that `n` incoming records were reconciled with local changes
+    /// or duplicates.
+    pub fn reconciled(&mut self, n: usize) {
+        self.reconciled += n;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.applied == 0 && self.failed == 0 && self.new_failed == 0 && self.reconciled == 0
+    }
+
+    pub fn get_applied(&self) -> usize {
+        self.applied
+    }
+
+    pub fn get_failed(&self) -> usize {
+        self.failed
+    }
+
+    pub fn get_reconciled(&self) -> usize {
+        self.reconciled
+    }
+}
+
+/// What happened to the records in a single upload.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct EngineOutgoing {
+    #[serde(skip_serializing_if = "is_zero")]
+    pub sent: usize,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub failed: usize,
+}
+
+/// Telemetry for syncing a single engine.
+#[derive(Debug, Serialize)]
+pub struct Engine {
+    name: String,
+    #[serde(flatten)]
+    stopwatch: Stopwatch,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    incoming: Option<EngineIncoming>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    outgoing: Vec<EngineOutgoing>,
+    #[serde(rename = "failureReason", skip_serializing_if = "Option::is_none")]
+    failure: Option<SyncFailure>,
+}
+
+impl Engine {
+    /// Starts timing a sync of the named engine.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.into(),
+            stopwatch: Stopwatch::new(),
+            incoming: None,
+            outgoing: Vec::new(),
+            failure: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn incoming(&mut self, incoming: EngineIncoming) {
+        if !incoming.is_empty() {
+            self.incoming = Some(incoming);
+        }
+    }
+
+    pub fn get_incoming(&self) -> Option<&EngineIncoming> {
+        self.incoming.as_ref()
+    }
+
+    pub fn outgoing(&mut self, outgoing: EngineOutgoing) {
+        self.outgoing.push(outgoing);
+    }
+
+    pub fn get_outgoing(&self) -> &[EngineOutgoing] {
+        &self.outgoing
+    }
+
+    /// Records why the engine failed to sync. Only the first failure is
+    /// kept, since later ones are usually caused by it.
+    pub fn failure(&mut self, failure: SyncFailure) {
+        if self.failure.is_none() {
+            self.failure = Some(failure);
+        }
+    }
+
+    pub fn get_failure(&self) -> Option<&SyncFailure> {
+        self.failure.as_ref()
+    }
+
+    /// Stops timing the sync.
+    pub fn finished(&mut self) {
+        self.stopwatch.finished();
+    }
+}
+
+/// Telemetry for a sync of one or more engines, including the setup state
+/// machine.
+#[derive(Debug, Serialize)]
+pub struct SyncTelemetry {
+    #[serde(flatten)]
+    stopwatch: Stopwatch,
+    engines: Vec<Engine>,
+    #[serde(rename = "failureReason", skip_serializing_if = "Option::is_none")]
+    failure: Option<SyncFailure>,
+}
+
+impl Default for SyncTelemetry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SyncTelemetry {
+    /// Starts timing a sync.
+    pub fn new() -> Self {
+        Self {
+            stopwatch: Stopwatch::new(),
+            engines: Vec::new(),
+            failure: None,
+        }
+    }
+
+    pub fn engine(&mut self, engine: Engine) {
+        self.engines.push(engine);
+    }
+
+    pub fn get_engines(&self) -> &[Engine] {
+        &self.engines
+    }
+
+    /// Records why the sync failed before any engines were synced; for
+    /// example, because we couldn't fetch `meta/global` or `crypto/keys`.
+    pub fn failure(&mut self, failure: SyncFailure) {
+        if self.failure.is_none() {
+            self.failure = Some(failure);
+        }
+    }
+
+    pub fn get_failure(&self) -> Option<&SyncFailure> {
+        self.failure.as_ref()
+    }
+
+    /// Stops timing the sync.
+    pub fn finished(&mut self) {
+        self.stopwatch.finished();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn test_failure_reasons() {
+        let failure = |kind: ErrorKind| SyncFailure::from(&error::Error::from(kind));
+        assert_eq!(failure(ErrorKind::TokenserverHttpError(401)),
+                   SyncFailure::Auth { from: "tokenserver" });
+        assert_eq!(failure(ErrorKind::StorageHttpError {
+            code: 500,
+            route: "https://example.com/1.5/12345/storage/bookmarks".into(),
+        }), SyncFailure::Http { code: 500 });
+        assert_eq!(failure(ErrorKind::NoMetaGlobal), SyncFailure::Unexpected {
+            error: "No meta/global record is present on the server".into(),
+        });
+    }
+
+    #[test]
+    fn test_engine_json() {
+        let mut engine = Engine::new("bookmarks");
+        let mut incoming = EngineIncoming::new();
+        incoming.applied(5);
+        incoming.failed(1);
+        engine.incoming(incoming);
+        engine.outgoing(EngineOutgoing { sent: 2, failed: 0 });
+        engine.failure(SyncFailure::Http { code: 412 });
+        engine.failure(SyncFailure::Http { code: 500 });
+        engine.finished();
+
+        let mut json = serde_json::to_value(&engine).unwrap();
+        assert!(json["when"].as_f64().unwrap() > 0.0);
+        json.as_object_mut().unwrap().remove("when");
+        json.as_object_mut().unwrap().remove("took");
+        assert_eq!(json, json!({
+            "name": "bookmarks",
+            "incoming": { "applied": 5, "failed": 1 },
+            "outgoing": [{ "sent": 2 }],
+            "failureReason": { "name": "httperror", "code": 412 },
+        }));
+    }
+
+    #[test]
+    fn test_empty_sync_json() {
+        let mut telem = SyncTelemetry::new();
+        let mut engine = Engine::new("tabs");
+        engine.incoming(EngineIncoming::new());
+        telem.engine(engine);
+        telem.failure(SyncFailure::Auth { from: "tokenserver" });
+
+        let json = serde_json::to_value(&telem).unwrap();
+        assert_eq!(json["engines"][0]["name"], "tabs");
+        assert!(json["engines"][0].get("incoming").is_none());
+        assert_eq!(json["failureReason"], json!({ "name": "autherror", "from": "tokenserver" }));
+    }
+}
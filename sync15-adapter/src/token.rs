@@ -4,15 +4,16 @@
 
 use hawk;
 
-use reqwest::{Client, Request, Url};
-use hyper::header::AUTHORIZATION;
+use http_support::{Request, Response, Transport, Url};
+use http_support::header::{HeaderValue, AUTHORIZATION};
 use error::{self, Result, ErrorKind};
 use std::fmt;
 use std::borrow::{Borrow, Cow};
 use std::str::FromStr;
-use std::time::{SystemTime, Duration};
+use std::time::{SystemTime, Duration, UNIX_EPOCH};
 use std::cell::{RefCell};
 use util::ServerTimestamp;
+use serde_json;
 
 /// Tokenserver's timestamp is X-Timestamp and not X-Weave-Timestamp.
 const RETRY_AFTER: &str = "Retry-After";
@@ -25,9 +26,13 @@ const X_KEY_ID: &str = "X-KeyID";
 
 const RETRY_AFTER_DEFAULT_MS: u64 = 10000;
 
+/// We don't restore cached tokens with less than this many seconds left to
+/// live, since they'd probably expire before we finished syncing.
+const CACHED_TOKEN_MIN_TIME_LEFT: u64 = 60;
+
 // The TokenserverToken is the token as received directly from the token server
 // and deserialized from JSON.
-#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 struct TokenserverToken {
     id: String,
     key: String,
@@ -47,53 +52,84 @@ struct TokenFetchResult {
 // The trait for fetching tokens - we'll provide a "real" implementation but
 // tests will re-implement it.
 trait TokenFetcher {
-    fn fetch_token(&self, request_client: &Client) -> super::Result<TokenFetchResult>;
+    fn fetch_token(&self, transport: &Transport) -> super::Result<TokenFetchResult>;
     // We allow the trait to tell us what the time is so tests can get funky.
     fn now(&self) -> SystemTime;
 }
 
+/// Gets a new OAuth access token when the tokenserver rejects the one we
+/// have. OAuth access tokens expire independently of tokenserver tokens, so
+/// long-lived clients will eventually need one of these.
+pub trait AccessTokenRefresher: fmt::Debug + Send {
+    /// Returns a new access token to use instead of `rejected`.
+    fn refresh_access_token(&self, rejected: &str) -> Result<String>;
+}
+
 // Our "real" token fetcher, implementing the TokenFetcher trait, which hits
 // the token server
 #[derive(Debug)]
 struct TokenServerFetcher {
     // The stuff needed to fetch a token.
     server_url: Url,
-    access_token: String,
+    access_token: RefCell<String>,
     key_id: String,
+    refresher: Option<Box<AccessTokenRefresher>>,
 }
 
 impl TokenServerFetcher {
     fn new(server_url: Url, access_token: String, key_id: String) -> TokenServerFetcher {
-        TokenServerFetcher { server_url, access_token, key_id }
+        TokenServerFetcher {
+            server_url,
+            access_token: RefCell::new(access_token),
+            key_id,
+            refresher: None,
+        }
+    }
+
+    fn request_token(&self, transport: &Transport) -> Result<Response> {
+        let bearer = format!("Bearer {}", self.access_token.borrow());
+        let req = Request::get(self.server_url.clone())
+                          .header(AUTHORIZATION, HeaderValue::from_str(&bearer)?)
+                          .header(X_KEY_ID, HeaderValue::from_str(&self.key_id)?);
+        Ok(transport.execute(req)?)
     }
 }
 
 impl TokenFetcher for TokenServerFetcher {
-    fn fetch_token(&self, request_client: &Client) -> Result<TokenFetchResult> {
-        let mut resp = request_client.get(self.server_url.clone())
-                                     .header(AUTHORIZATION, format!("Bearer {}", self.access_token))
-                                     .header(X_KEY_ID, self.key_id.clone())
-                                     .send()?;
-
-        if !resp.status().is_success() {
-            warn!("Non-success status when fetching token: {}", resp.status());
+    fn fetch_token(&self, transport: &Transport) -> Result<TokenFetchResult> {
+        let mut resp = self.request_token(transport)?;
+
+        if resp.status.as_u16() == 401 {
+            if let Some(ref refresher) = self.refresher {
+                // Our access token probably expired. Ask for a new one and
+                // try again, but only once - if the new token is rejected
+                // too, something else is wrong.
+                info!("Tokenserver rejected our access token; refreshing it");
+                let access_token = refresher.refresh_access_token(&self.access_token.borrow())?;
+                *self.access_token.borrow_mut() = access_token;
+                resp = self.request_token(transport)?;
+            }
+        }
+
+        if !resp.is_success() {
+            warn!("Non-success status when fetching token: {}", resp.status);
             // TODO: the body should be JSON and contain a status parameter we might need?
-            debug!("  Response body {}", resp.text().unwrap_or_else(|_| "???".into()));
+            debug!("  Response body {}", resp.text());
             // XXX - shouldn't we "chain" these errors - ie, a BackoffError could
             // have a TokenserverHttpError as its cause?
-            if let Some(header) = resp.headers().get(RETRY_AFTER) {
+            if let Some(header) = resp.headers.get(RETRY_AFTER) {
                 // XXX - We are silently dropping parsing errors here.
                 let ms = header.to_str().ok().and_then(|s| s.parse::<f64>().ok())
                     .map_or(RETRY_AFTER_DEFAULT_MS, |f| (f * 1000f64) as u64);
                 let when = self.now() + Duration::from_millis(ms);
                 return Err(ErrorKind::BackoffError(when).into());
             }
-            let status = resp.status().as_u16();
+            let status = resp.status.as_u16();
             return Err(ErrorKind::TokenserverHttpError(status).into());
         }
 
         let token: TokenserverToken = resp.json()?;
-        let server_timestamp = resp.headers()
+        let server_timestamp = resp.headers
                     .get(X_TIMESTAMP)
                     .and_then(|v| v.to_str().ok())
                     .and_then(|s| ServerTimestamp::from_str(s).ok())
@@ -106,6 +142,18 @@ impl TokenFetcher for TokenServerFetcher {
     }
 }
 
+// Returns the number of milliseconds between the Unix epoch and `time`,
+// which is negative if `time` is before the epoch.
+fn millis_since_epoch(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() * 1000 + u64::from(d.subsec_millis())) as i64,
+        Err(e) => {
+            let d = e.duration();
+            -((d.as_secs() * 1000 + u64::from(d.subsec_millis())) as i64)
+        }
+    }
+}
+
 // The context stored by our TokenProvider when it has a TokenState::Token
 // state.
 struct TokenContext {
@@ -113,6 +161,9 @@ struct TokenContext {
     credentials: hawk::Credentials,
     server_timestamp: ServerTimestamp,
     valid_until: SystemTime,
+    // How many milliseconds the server's clock was ahead of ours when we
+    // fetched the token. Negative if it was behind.
+    clock_skew_ms: i64,
 }
 
 // hawk::Credentials doesn't implement debug -_-
@@ -123,14 +174,46 @@ impl fmt::Debug for TokenContext {
          .field("credentials", &"(omitted)")
          .field("server_timestamp", &self.server_timestamp)
          .field("valid_until", &self.valid_until)
+         .field("clock_skew_ms", &self.clock_skew_ms)
          .finish()
     }
 }
 
 impl TokenContext {
-    fn new(token: TokenserverToken, credentials: hawk::Credentials,
-           server_timestamp: ServerTimestamp, valid_until: SystemTime) -> Self {
-        Self { token, credentials, server_timestamp, valid_until }
+    fn new(token: TokenserverToken, server_timestamp: ServerTimestamp,
+           valid_until: SystemTime, clock_skew_ms: i64) -> Result<Self> {
+        let credentials = hawk::Credentials {
+            id: token.id.clone(),
+            key: hawk::Key::new(token.key.as_bytes(), hawk::Digest::sha256())?,
+        };
+        Ok(Self { token, credentials, server_timestamp, valid_until, clock_skew_ms })
+    }
+
+    // Restores a context that was saved by a previous process. Returns None
+    // if the token expires within `CACHED_TOKEN_MIN_TIME_LEFT` of `now`.
+    fn from_cached(cached: CachedToken, now: SystemTime) -> Result<Option<Self>> {
+        // The expiry is in the server's clock, so convert it to ours.
+        let valid_until_ms = cached.expires_at.as_millis() as i64 - cached.clock_skew_ms;
+        let min_valid_until_ms = millis_since_epoch(now) +
+            (CACHED_TOKEN_MIN_TIME_LEFT * 1000) as i64;
+        if valid_until_ms <= min_valid_until_ms {
+            return Ok(None);
+        }
+        let valid_until = UNIX_EPOCH + Duration::from_millis(valid_until_ms as u64);
+        Ok(Some(TokenContext::new(cached.token, cached.server_timestamp,
+                                  valid_until, cached.clock_skew_ms)?))
+    }
+
+    fn to_cached(&self, tokenserver_url: &Url, key_id: &str) -> CachedToken {
+        let expires_at = ServerTimestamp(self.server_timestamp.0 + self.token.duration as f64);
+        CachedToken {
+            tokenserver_url: tokenserver_url.to_string(),
+            key_id: key_id.to_string(),
+            token: self.token.clone(),
+            server_timestamp: self.server_timestamp,
+            expires_at,
+            clock_skew_ms: self.clock_skew_ms,
+        }
     }
 
     fn is_valid(&self, now: SystemTime) -> bool {
@@ -144,7 +227,7 @@ impl TokenContext {
     }
 
     fn authorization(&self, req: &Request) -> Result<String> {
-        let url = req.url();
+        let url = &req.url;
 
         let path_and_query = match url.query() {
             None => Cow::from(url.path()),
@@ -160,7 +243,7 @@ impl TokenContext {
                 "Storage URL has no port and no default port is known for the protocol".into()))?;
 
         let header = hawk::RequestBuilder::new(
-            req.method().as_ref(),
+            req.method.as_ref(),
             host,
             port,
             path_and_query.borrow()
@@ -209,24 +292,43 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
 
     // Uses our fetcher to grab a new token and if successfull, derives other
     // info from that token into a usable TokenContext.
-    fn fetch_context(&self, request_client: &Client) -> Result<TokenContext> {
-        let result = self.fetcher.fetch_token(request_client)?;
+    fn fetch_context(&self, transport: &Transport) -> Result<TokenContext> {
+        let result = self.fetcher.fetch_token(transport)?;
         let token = result.token;
-        let valid_until = SystemTime::now() + Duration::from_secs(token.duration);
+        let now = self.fetcher.now();
+        let valid_until = now + Duration::from_secs(token.duration);
+        let clock_skew_ms = result.server_timestamp.as_millis() as i64 - millis_since_epoch(now);
 
-        let credentials = hawk::Credentials {
-            id: token.id.clone(),
-            key: hawk::Key::new(token.key.as_bytes(), hawk::Digest::sha256())?,
-        };
+        TokenContext::new(token, result.server_timestamp, valid_until, clock_skew_ms)
+    }
 
-        Ok(TokenContext::new(token, credentials, result.server_timestamp, valid_until))
+    // Returns the result of calling `func` with our current token, if we
+    // have one. Doesn't fetch a new token.
+    fn with_current_context<T, F>(&self, func: F) -> Option<T>
+            where F: FnOnce(&TokenContext) -> T {
+        match *self.current_state.borrow() {
+            TokenState::Token(ref context) => Some(func(context)),
+            _ => None,
+        }
+    }
+
+    // Uses `context` instead of fetching a token, unless we already have
+    // one (or failed to get one). Returns true if `context` will be used.
+    fn restore_context(&self, context: TokenContext) -> bool {
+        let state: &mut TokenState = &mut self.current_state.borrow_mut();
+        match state {
+            TokenState::NoToken => (),
+            _ => return false,
+        }
+        *state = TokenState::Token(context);
+        true
     }
 
     // Attempt to fetch a new token and return a new state reflecting that
     // operation. If it worked a TokenState will be returned, but errors may
     // cause other states.
-    fn fetch_token(&self, request_client: &Client, previous_endpoint: Option<&str>) -> TokenState {
-        match self.fetch_context(request_client) {
+    fn fetch_token(&self, transport: &Transport, previous_endpoint: Option<&str>) -> TokenState {
+        match self.fetch_context(transport) {
             Ok(tc) => {
                 // We got a new token - check that the endpoint is the same
                 // as a previous endpoint we saw (if any)
@@ -259,19 +361,19 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
     // Returns None if the current state should be used (eg, if we are
     // holding a token that remains valid) or Some() if the state has changed
     // (which may have changed to a state with a token or an error state)
-    fn advance_state(&self, request_client: &Client, state: &TokenState) -> Option<TokenState> {
+    fn advance_state(&self, transport: &Transport, state: &TokenState) -> Option<TokenState> {
         match state {
             TokenState::NoToken => {
-                Some(self.fetch_token(request_client, None))
+                Some(self.fetch_token(transport, None))
             },
             TokenState::Failed(_, existing_endpoint) => {
-                Some(self.fetch_token(request_client, existing_endpoint.as_ref().map(|e| e.as_str())))
+                Some(self.fetch_token(transport, existing_endpoint.as_ref().map(|e| e.as_str())))
             },
             TokenState::Token(existing_context) => {
                 if existing_context.is_valid(self.fetcher.now()) {
                     None
                 } else {
-                    Some(self.fetch_token(request_client, Some(existing_context.token.api_endpoint.as_str())))
+                    Some(self.fetch_token(transport, Some(existing_context.token.api_endpoint.as_str())))
                 }
             },
             TokenState::Backoff(ref until, ref existing_endpoint) => {
@@ -280,7 +382,7 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
                     None
                 } else {
                     // backoff period is over
-                    Some(self.fetch_token(request_client, existing_endpoint.as_ref().map(|e| e.as_str())))
+                    Some(self.fetch_token(transport, existing_endpoint.as_ref().map(|e| e.as_str())))
                 }
             },
             TokenState::NodeReassigned => {
@@ -290,13 +392,13 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
         }
     }
 
-    fn with_token<T, F>(&self, request_client: &Client, func: F) -> Result<T>
+    fn with_token<T, F>(&self, transport: &Transport, func: F) -> Result<T>
             where F: FnOnce(&TokenContext) -> Result<T> {
 
         // first get a mutable ref to our existing state, advance to the
         // state we will use, then re-stash that state for next time.
         let state: &mut TokenState = &mut self.current_state.borrow_mut();
-        match self.advance_state(request_client, state) {
+        match self.advance_state(transport, state) {
             Some(new_state) => *state = new_state,
             None => ()
         }
@@ -326,18 +428,46 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
         }
     }
 
-    fn authorization(&self, http_client: &Client, req: &Request) -> Result<String> {
-        self.with_token(http_client, |ctx| ctx.authorization(req))
+    fn authorization(&self, transport: &Transport, req: &Request) -> Result<String> {
+        self.with_token(transport, |ctx| ctx.authorization(req))
     }
 
-    fn api_endpoint(&self, http_client: &Client) -> Result<String> {
-        self.with_token(http_client, |ctx| Ok(ctx.token.api_endpoint.clone()))
+    fn api_endpoint(&self, transport: &Transport) -> Result<String> {
+        self.with_token(transport, |ctx| Ok(ctx.token.api_endpoint.clone()))
     }
     // TODO: we probably want a "drop_token/context" type method so that when
     // using a token with some validity fails the caller can force a new one
     // (in which case the new token request will probably fail with a 401)
 }
 
+/// A tokenserver token that can be saved when the process exits, and given
+/// to `TokenProvider::restore_cached_token` in the next one, so that we don't
+/// need to ask the tokenserver for a new token every time we start. Contains
+/// the HAWK key, so it should be stored as carefully as the OAuth tokens.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CachedToken {
+    // The token is only valid for the same tokenserver and sync key.
+    tokenserver_url: String,
+    key_id: String,
+    token: TokenserverToken,
+    server_timestamp: ServerTimestamp,
+    // When the token expires, according to the server's clock.
+    expires_at: ServerTimestamp,
+    // How many milliseconds the server's clock was ahead of ours when we
+    // fetched the token.
+    clock_skew_ms: i64,
+}
+
+impl CachedToken {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(data: &str) -> Result<CachedToken> {
+        Ok(serde_json::from_str(data)?)
+    }
+}
+
 // The public concrete object exposed by this module
 #[derive(Debug)]
 pub struct TokenProvider {
@@ -352,12 +482,44 @@ impl TokenProvider {
         }
     }
 
-    pub fn authorization(&self, http_client: &Client, req: &Request) -> Result<String> {
-        self.imp.authorization(http_client, req)
+    /// Sets the refresher we use to replace our access token if the
+    /// tokenserver rejects it.
+    pub fn set_access_token_refresher(&mut self, refresher: Box<AccessTokenRefresher>) {
+        self.imp.fetcher.refresher = Some(refresher);
     }
 
-    pub fn api_endpoint(&self, http_client: &Client) -> Result<String> {
-        self.imp.api_endpoint(http_client)
+    /// Returns our current token, so that it can be persisted and restored
+    /// later. Returns None if we haven't fetched a token yet, or couldn't.
+    pub fn cached_token(&self) -> Option<CachedToken> {
+        let fetcher = &self.imp.fetcher;
+        self.imp.with_current_context(|ctx| ctx.to_cached(&fetcher.server_url, &fetcher.key_id))
+    }
+
+    /// Uses a token saved with `cached_token` instead of fetching a new one,
+    /// if it's for the same tokenserver and key, and won't expire soon.
+    /// Returns true if the token will be used. This should be called before
+    /// making any requests, since we won't replace a token we already have.
+    pub fn restore_cached_token(&self, cached: CachedToken) -> Result<bool> {
+        let fetcher = &self.imp.fetcher;
+        if cached.tokenserver_url != fetcher.server_url.as_str() || cached.key_id != fetcher.key_id {
+            info!("Not restoring a cached token for a different tokenserver or key");
+            return Ok(false);
+        }
+        match TokenContext::from_cached(cached, fetcher.now())? {
+            Some(context) => Ok(self.imp.restore_context(context)),
+            None => {
+                info!("Not restoring a cached token that has expired, or will soon");
+                Ok(false)
+            }
+        }
+    }
+
+    pub fn authorization(&self, transport: &Transport, req: &Request) -> Result<String> {
+        self.imp.authorization(transport, req)
+    }
+
+    pub fn api_endpoint(&self, transport: &Transport) -> Result<String> {
+        self.imp.api_endpoint(transport)
     }
 }
 
@@ -365,10 +527,11 @@ impl TokenProvider {
 mod tests {
     use super::*;
     use std::cell::Cell;
-    use reqwest::Client;
+    use http_support::{InMemoryTransport, Method, MockResponse};
 
-    fn make_client() -> Client {
-        Client::builder().timeout(Duration::from_secs(30)).build().expect("can't build client")
+    fn make_client() -> InMemoryTransport {
+        // The test fetchers never make requests.
+        InMemoryTransport::new()
     }
 
     struct TestFetcher<FF, FN>
@@ -380,7 +543,7 @@ mod tests {
     impl<FF, FN> TokenFetcher for TestFetcher<FF, FN>
         where FF: Fn() -> Result<TokenFetchResult>,
               FN: Fn() -> SystemTime {
-        fn fetch_token(&self, _: &Client) -> Result<TokenFetchResult> {
+        fn fetch_token(&self, _: &Transport) -> Result<TokenFetchResult> {
             (self.fetch)()
         }
         fn now(&self) -> SystemTime {
@@ -492,4 +655,130 @@ mod tests {
         tsc.api_endpoint(&make_client()).expect("should re-fetch");
         assert_eq!(counter.get(), 2);
     }
+
+    #[derive(Debug)]
+    struct TestRefresher {
+        rejected: RefCell<Vec<String>>,
+    }
+
+    impl AccessTokenRefresher for TestRefresher {
+        fn refresh_access_token(&self, rejected: &str) -> Result<String> {
+            self.rejected.borrow_mut().push(rejected.to_string());
+            Ok("new-token".to_string())
+        }
+    }
+
+    #[test]
+    fn test_refresh_access_token() {
+        let transport = InMemoryTransport::new();
+        transport.respond(Method::GET, "/1.0/sync/1.5", MockResponse::new(401));
+        transport.respond(Method::GET, "/1.0/sync/1.5", MockResponse::new(200)
+            .header(X_TIMESTAMP, "1536000000")
+            .json(&json!({
+                "id": "id",
+                "key": "key",
+                "api_endpoint": "https://storage.example.com/1.5/123",
+                "uid": 123,
+                "duration": 300,
+                "hashed_fxa_uid": "hash",
+            })));
+
+        let url = Url::parse("https://token.example.com/1.0/sync/1.5").unwrap();
+        let mut provider = TokenProvider::new(url, "old-token".into(), "key-id".into());
+        provider.set_access_token_refresher(Box::new(TestRefresher {
+            rejected: RefCell::new(Vec::new()),
+        }));
+
+        let endpoint = provider.api_endpoint(&transport).expect("should refresh and retry");
+        assert_eq!(endpoint, "https://storage.example.com/1.5/123");
+        assert!(!transport.has_pending_responses());
+
+        let authorizations = transport.requests().iter().map(|req| {
+            req.headers[AUTHORIZATION].to_str().unwrap().to_string()
+        }).collect::<Vec<_>>();
+        assert_eq!(authorizations, vec!["Bearer old-token", "Bearer new-token"]);
+    }
+
+    #[test]
+    fn test_no_refresher() {
+        let transport = InMemoryTransport::new();
+        transport.respond(Method::GET, "/1.0/sync/1.5", MockResponse::new(401));
+
+        let url = Url::parse("https://token.example.com/1.0/sync/1.5").unwrap();
+        let provider = TokenProvider::new(url, "old-token".into(), "key-id".into());
+        let err = provider.api_endpoint(&transport).expect_err("should fail");
+        match err.kind() {
+            ErrorKind::TokenserverHttpError(401) => {}
+            other => panic!("Unexpected error {:?}", other),
+        }
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    fn respond_with_token(transport: &InMemoryTransport, server_time: SystemTime) {
+        let server_secs = millis_since_epoch(server_time) as f64 / 1000.0;
+        transport.respond(Method::GET, "/1.0/sync/1.5", MockResponse::new(200)
+            .header(X_TIMESTAMP, &server_secs.to_string())
+            .json(&json!({
+                "id": "id",
+                "key": "key",
+                "api_endpoint": "https://storage.example.com/1.5/123",
+                "uid": 123,
+                "duration": 300,
+                "hashed_fxa_uid": "hash",
+            })));
+    }
+
+    fn make_provider(key_id: &str) -> TokenProvider {
+        let url = Url::parse("https://token.example.com/1.0/sync/1.5").unwrap();
+        TokenProvider::new(url, "access-token".into(), key_id.into())
+    }
+
+    #[test]
+    fn test_cached_token() {
+        let transport = InMemoryTransport::new();
+        let provider = make_provider("key-id");
+        assert!(provider.cached_token().is_none());
+
+        // The server's clock is an hour behind ours. We should record that
+        // skew, since the cached token's `expires_at` is in server time, and
+        // is already in the past by our clock. Restoring it should convert
+        // it back to our clock, and still treat the token as valid.
+        respond_with_token(&transport, SystemTime::now() - Duration::from_secs(3600));
+        provider.api_endpoint(&transport).expect("should fetch a token");
+        let cached = provider.cached_token().expect("should have a token");
+        assert_eq!(cached.clock_skew_ms / 1000, -3600);
+
+        // A new provider should use the cached token instead of fetching one.
+        let cached = CachedToken::from_json(&cached.to_json().unwrap()).unwrap();
+        let provider = make_provider("key-id");
+        assert!(provider.restore_cached_token(cached.clone()).unwrap());
+        let endpoint = provider.api_endpoint(&transport).expect("should use the cached token");
+        assert_eq!(endpoint, "https://storage.example.com/1.5/123");
+        assert_eq!(transport.requests().len(), 1);
+        assert_eq!(provider.cached_token(), Some(cached.clone()));
+
+        // But not if we already have a token.
+        assert!(!provider.restore_cached_token(cached.clone()).unwrap());
+
+        // Or if it's for a different key.
+        let provider = make_provider("other-key-id");
+        assert!(!provider.restore_cached_token(cached).unwrap());
+        assert!(provider.cached_token().is_none());
+    }
+
+    #[test]
+    fn test_cached_token_expiring() {
+        let transport = InMemoryTransport::new();
+        let provider = make_provider("key-id");
+        respond_with_token(&transport, SystemTime::now());
+        provider.api_endpoint(&transport).expect("should fetch a token");
+        let mut cached = provider.cached_token().expect("should have a token");
+
+        // Pretend that the token was fetched 250 seconds ago, so it expires
+        // in less than `CACHED_TOKEN_MIN_TIME_LEFT`.
+        cached.expires_at = ServerTimestamp(cached.expires_at.0 - 250.0);
+        let provider = make_provider("key-id");
+        assert!(!provider.restore_cached_token(cached).unwrap());
+        assert!(provider.cached_token().is_none());
+    }
 }
@@ -0,0 +1,246 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use bso_record::Payload;
+use client::Sync15StorageClient;
+use error;
+use serde_json::Value as JsonValue;
+use state::GlobalState;
+use sync::Store;
+use util::ServerTimestamp;
+
+/// A record that exists both locally and on the server, but with different
+/// contents.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RecordDifference {
+    pub id: String,
+    /// The names of the fields that differ. We don't include the values,
+    /// since they might be sensitive.
+    pub fields: Vec<String>,
+}
+
+/// The result of comparing a collection on the server with a store's local
+/// records. Only contains record IDs and field names, so it's safe to log.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ValidationReport {
+    pub collection: String,
+    /// The number of live records on the server.
+    pub server_records: usize,
+    /// The number of live records in the store.
+    pub local_records: usize,
+    /// IDs of local records that aren't on the server, or were deleted there.
+    pub missing_on_server: Vec<String>,
+    /// IDs of server records that the store doesn't have.
+    pub missing_locally: Vec<String>,
+    pub differences: Vec<RecordDifference>,
+    /// Groups of IDs of server records which are duplicates of each other,
+    /// according to `Store::validation_key`.
+    pub server_duplicates: Vec<Vec<String>>,
+    /// IDs that the store returned more than once.
+    pub local_duplicates: Vec<String>,
+}
+
+impl ValidationReport {
+    /// Returns true if the server and the store agree.
+    pub fn is_ok(&self) -> bool {
+        self.missing_on_server.is_empty()
+            && self.missing_locally.is_empty()
+            && self.differences.is_empty()
+            && self.server_duplicates.is_empty()
+            && self.local_duplicates.is_empty()
+    }
+}
+
+/// Downloads and decrypts every record in `collection`, and compares them
+/// with the store's `all_local_records`. This doesn't change anything
+/// locally or on the server, so it's safe to run outside of a sync; but
+/// unsynced local changes will show up as problems. Fails with
+/// `ErrorKind::ValidationUnsupported` if the store doesn't support it.
+pub fn validate<E>(
+    client: &Sync15StorageClient,
+    state: &GlobalState,
+    store: &Store<Error = E>,
+    collection: &str,
+) -> Result<ValidationReport, E>
+where
+    E: From<error::Error>,
+{
+    let key = state.key_for_collection(collection)?;
+    let mut server = Vec::new();
+    for record in client.get_encrypted_records(collection, ServerTimestamp(0.0))? {
+        let payload = record.decrypt(key)?.payload;
+        if !payload.is_tombstone() {
+            server.push(payload);
+        }
+    }
+    let local = match store.all_local_records()? {
+        Some(local) => local.into_iter().filter(|payload| !payload.is_tombstone()).collect(),
+        None => return Err(error::Error::from(
+            error::ErrorKind::ValidationUnsupported(collection.into())).into()),
+    };
+    Ok(compare(collection, server, local, |record| store.validation_key(record)))
+}
+
+// Groups the IDs of records with the same key, ignoring records which don't
+// have duplicates.
+fn find_duplicates<F>(records: &[Payload], key: F) -> Vec<Vec<String>>
+where
+    F: Fn(&Payload) -> String,
+{
+    let mut by_key: HashMap<String, Vec<String>> = HashMap::new();
+    for record in records {
+        by_key.entry(key(record)).or_insert_with(Vec::new).push(record.id.clone());
+    }
+    let mut duplicates: Vec<Vec<String>> = by_key
+        .into_iter()
+        .map(|(_, mut ids)| {
+            ids.sort();
+            ids
+        })
+        .filter(|ids| ids.len() > 1)
+        .collect();
+    duplicates.sort();
+    duplicates
+}
+
+// Indexes records by ID, returning the IDs that occur more than once.
+fn index_by_id(records: Vec<Payload>) -> (HashMap<String, Payload>, Vec<String>) {
+    let mut by_id = HashMap::with_capacity(records.len());
+    let mut duplicates = BTreeSet::new();
+    for record in records {
+        if let Some(previous) = by_id.insert(record.id.clone(), record) {
+            duplicates.insert(previous.id);
+        }
+    }
+    (by_id, duplicates.into_iter().collect())
+}
+
+fn differing_fields(a: &Payload, b: &Payload) -> Vec<String> {
+    let names: BTreeSet<&String> = a.data.keys().chain(b.data.keys()).collect();
+    names
+        .into_iter()
+        .filter(|name| {
+            // Treat missing fields as null, since some clients omit them.
+            let a_value = a.data.get(*name).unwrap_or(&JsonValue::Null);
+            let b_value = b.data.get(*name).unwrap_or(&JsonValue::Null);
+            a_value != b_value
+        })
+        .map(|name| name.to_string())
+        .collect()
+}
+
+fn compare<F>(collection: &str, server: Vec<Payload>, local: Vec<Payload>, key: F)
+              -> ValidationReport
+where
+    F: Fn(&Payload) -> String,
+{
+    let server_count = server.len();
+    let local_count = local.len();
+    let server_duplicates = find_duplicates(&server, key);
+    // The server can't have two records with the same ID.
+    let (server, _) = index_by_id(server);
+    let (local, local_duplicates) = index_by_id(local);
+
+    let server_ids: HashSet<&String> = server.keys().collect();
+    let local_ids: HashSet<&String> = local.keys().collect();
+
+    let mut missing_on_server: Vec<String> = local_ids
+        .difference(&server_ids)
+        .map(|id| id.to_string())
+        .collect();
+    missing_on_server.sort();
+
+    let mut missing_locally: Vec<String> = server_ids
+        .difference(&local_ids)
+        .map(|id| id.to_string())
+        .collect();
+    missing_locally.sort();
+
+    let mut differences: Vec<RecordDifference> = server_ids
+        .intersection(&local_ids)
+        .filter_map(|id| {
+            let fields = differing_fields(&server[*id], &local[*id]);
+            if fields.is_empty() {
+                None
+            } else {
+                Some(RecordDifference { id: id.to_string(), fields })
+            }
+        })
+        .collect();
+    differences.sort_by(|a, b| a.id.cmp(&b.id));
+
+    ValidationReport {
+        collection: collection.into(),
+        server_records: server_count,
+        local_records: local_count,
+        missing_on_server,
+        missing_locally,
+        differences,
+        server_duplicates,
+        local_duplicates,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(value: JsonValue) -> Payload {
+        Payload::from_json(value).unwrap()
+    }
+
+    // Like `Store::validation_key`'s default.
+    fn content_key(record: &Payload) -> String {
+        JsonValue::Object(record.data.clone()).to_string()
+    }
+
+    #[test]
+    fn test_compare() {
+        let server = vec![
+            payload(json!({ "id": "same", "hostname": "https://example.com" })),
+            payload(json!({ "id": "changed", "hostname": "https://example.net", "password": "a" })),
+            payload(json!({ "id": "server-only", "hostname": "https://example.org" })),
+            payload(json!({ "id": "dupe-1", "hostname": "https://example.edu" })),
+            payload(json!({ "id": "dupe-2", "hostname": "https://example.edu" })),
+        ];
+        let local = vec![
+            payload(json!({ "id": "same", "hostname": "https://example.com", "httpRealm": null })),
+            payload(json!({ "id": "changed", "hostname": "https://example.org", "password": "b" })),
+            payload(json!({ "id": "local-only", "hostname": "https://example.com" })),
+            payload(json!({ "id": "dupe-1", "hostname": "https://example.edu" })),
+            payload(json!({ "id": "local-only", "hostname": "https://example.com" })),
+        ];
+        let report = compare("passwords", server, local, content_key);
+        assert!(!report.is_ok());
+        assert_eq!(report.server_records, 5);
+        assert_eq!(report.local_records, 5);
+        assert_eq!(report.missing_on_server, vec!["local-only"]);
+        assert_eq!(report.missing_locally, vec!["dupe-2", "server-only"]);
+        assert_eq!(report.differences, vec![RecordDifference {
+            id: "changed".into(),
+            fields: vec!["hostname".into(), "password".into()],
+        }]);
+        assert_eq!(report.server_duplicates, vec![vec!["dupe-1", "dupe-2"]]);
+        assert_eq!(report.local_duplicates, vec!["local-only"]);
+
+        let records = vec![payload(json!({ "id": "same", "hostname": "https://example.com" }))];
+        assert!(compare("passwords", records.clone(), records, content_key).is_ok());
+    }
+
+    #[test]
+    fn test_find_duplicates() {
+        let records = vec![
+            payload(json!({ "id": "a", "hostname": "https://example.com", "username": "x" })),
+            payload(json!({ "id": "b", "hostname": "https://example.com", "username": "y" })),
+            payload(json!({ "id": "c", "hostname": "https://example.com", "username": "x" })),
+        ];
+        // Records are duplicates if everything but their IDs is the same.
+        assert_eq!(find_duplicates(&records, content_key), vec![vec!["a", "c"]]);
+        // Stores can say which fields matter.
+        let by_hostname = |record: &Payload| record.data["hostname"].to_string();
+        assert_eq!(find_duplicates(&records, by_hostname), vec![vec!["a", "b", "c"]]);
+    }
+}
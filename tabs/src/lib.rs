@@ -0,0 +1,39 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+extern crate sync15_adapter as sync;
+extern crate places;
+
+#[macro_use]
+extern crate log;
+
+#[cfg(test)]
+extern crate env_logger;
+
+extern crate failure;
+
+#[macro_use]
+extern crate failure_derive;
+
+extern crate url;
+
+extern crate serde;
+#[cfg_attr(test, macro_use)]
+extern crate serde_json;
+
+#[macro_use]
+extern crate serde_derive;
+
+pub mod error;
+mod record;
+mod storage;
+mod store;
+
+pub use error::*;
+pub use record::{TabsRecord, TabsRecordTab};
+pub use storage::{ClientRemoteTabs, RemoteTab, TabsStorage};
+pub use store::TabsStore;
+
+// The name of the collection on the server.
+pub const COLLECTION_NAME: &'static str = "tabs";
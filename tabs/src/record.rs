@@ -0,0 +1,60 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// A record in the "tabs" collection. Each client uploads a single record,
+// with the same id as its record in the "clients" collection, listing all
+// its open tabs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TabsRecord {
+    pub id: String,
+    pub client_name: String,
+    pub tabs: Vec<TabsRecordTab>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TabsRecordTab {
+    pub title: String,
+    // The most recent URL is first.
+    pub url_history: Vec<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    // Seconds since the epoch, as desktop does it.
+    #[serde(default)]
+    pub last_used: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn test_roundtrip() {
+        let json = json!({
+            "id": "client-id",
+            "clientName": "My Phone",
+            "tabs": [{
+                "title": "Example",
+                "urlHistory": ["https://example.com/2", "https://example.com/1"],
+                "icon": "https://example.com/favicon.ico",
+                "lastUsed": 1540000000,
+            }, {
+                "title": "No icon",
+                "urlHistory": ["https://example.org/"],
+            }],
+        });
+        let record: TabsRecord = serde_json::from_value(json.clone()).expect("should parse");
+        assert_eq!(record.client_name, "My Phone");
+        assert_eq!(record.tabs[0].url_history.len(), 2);
+        assert_eq!(record.tabs[1].icon, None);
+        assert_eq!(record.tabs[1].last_used, 0);
+
+        let round_tripped = serde_json::to_value(&record).expect("should serialize");
+        assert_eq!(round_tripped["tabs"][0], json["tabs"][0]);
+        assert!(round_tripped["tabs"][1].get("icon").is_none());
+    }
+}
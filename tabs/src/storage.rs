@@ -0,0 +1,234 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::HashMap;
+
+use url::Url;
+
+use places::api::matcher::{MatchReason, SearchParams, SearchProvider, SearchResult};
+use places;
+use sync::ServerTimestamp;
+
+use record::{TabsRecord, TabsRecordTab};
+
+// A single open tab, either on this device or another one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteTab {
+    pub title: String,
+    // The most recent URL is first.
+    pub url_history: Vec<String>,
+    pub icon: Option<String>,
+    // Milliseconds since the epoch.
+    pub last_used: u64,
+}
+
+impl RemoteTab {
+    pub(crate) fn from_record_tab(tab: TabsRecordTab) -> Self {
+        Self {
+            title: tab.title,
+            url_history: tab.url_history,
+            icon: tab.icon,
+            last_used: tab.last_used.saturating_mul(1000),
+        }
+    }
+
+    pub(crate) fn to_record_tab(&self) -> TabsRecordTab {
+        TabsRecordTab {
+            title: self.title.clone(),
+            url_history: self.url_history.clone(),
+            icon: self.icon.clone(),
+            last_used: self.last_used / 1000,
+        }
+    }
+}
+
+// The tabs open on another device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientRemoteTabs {
+    pub client_id: String,
+    pub client_name: String,
+    pub remote_tabs: Vec<RemoteTab>,
+}
+
+impl ClientRemoteTabs {
+    pub(crate) fn from_record(record: TabsRecord) -> Self {
+        Self {
+            client_id: record.id,
+            client_name: record.client_name,
+            remote_tabs: record.tabs.into_iter().map(RemoteTab::from_record_tab).collect(),
+        }
+    }
+}
+
+// Tabs aren't persisted - the app tells us what's open locally every time it
+// changes, and we re-download everyone else's tabs after a restart.
+#[derive(Debug, Default)]
+pub struct TabsStorage {
+    local_tabs: Option<Vec<RemoteTab>>,
+    local_tabs_changed: bool,
+    remote_tabs: HashMap<String, ClientRemoteTabs>,
+    last_sync: Option<ServerTimestamp>,
+}
+
+impl TabsStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Replaces the set of tabs open on this device. They'll be uploaded on
+    // the next sync.
+    pub fn update_local_state(&mut self, local_state: Vec<RemoteTab>) {
+        self.local_tabs = Some(local_state);
+        self.local_tabs_changed = true;
+    }
+
+    // Returns the tabs open on other devices, most recently used device
+    // first.
+    pub fn get_remote_tabs(&self) -> Vec<ClientRemoteTabs> {
+        let mut clients: Vec<ClientRemoteTabs> = self.remote_tabs.values().cloned().collect();
+        let last_used = |client: &ClientRemoteTabs| {
+            client.remote_tabs.iter().map(|tab| tab.last_used).max().unwrap_or(0)
+        };
+        clients.sort_by(|a, b| last_used(b).cmp(&last_used(a)));
+        clients
+    }
+
+    // The local tabs we should upload, if they've changed since the last
+    // upload. Tabs without a URL are useless to other devices, so we skip
+    // them.
+    pub(crate) fn prepare_local_tabs_for_upload(&self) -> Option<Vec<RemoteTab>> {
+        if !self.local_tabs_changed {
+            return None;
+        }
+        self.local_tabs.as_ref().map(|tabs| {
+            let mut tabs: Vec<RemoteTab> = tabs.iter()
+                .filter(|tab| !tab.url_history.is_empty())
+                .cloned()
+                .collect();
+            tabs.sort_by(|a, b| b.last_used.cmp(&a.last_used));
+            tabs
+        })
+    }
+
+    pub(crate) fn note_local_tabs_uploaded(&mut self) {
+        self.local_tabs_changed = false;
+    }
+
+    pub(crate) fn replace_remote_tabs(&mut self, client: ClientRemoteTabs) {
+        self.remote_tabs.insert(client.client_id.clone(), client);
+    }
+
+    pub(crate) fn remove_remote_tabs(&mut self, client_id: &str) {
+        self.remote_tabs.remove(client_id);
+    }
+
+    pub fn get_last_sync(&self) -> Option<ServerTimestamp> {
+        self.last_sync
+    }
+
+    pub(crate) fn set_last_sync(&mut self, last_sync: ServerTimestamp) {
+        self.last_sync = Some(last_sync);
+    }
+
+    // Forget everything we know about the server.
+    pub fn reset(&mut self) {
+        self.remote_tabs.clear();
+        self.last_sync = None;
+        self.local_tabs_changed = self.local_tabs.is_some();
+    }
+}
+
+// Offers tabs open on other devices as autocomplete matches. A tab matches if
+// its title or current URL contains the search string.
+impl SearchProvider for TabsStorage {
+    fn search(&self, params: &SearchParams) -> places::Result<Vec<SearchResult>> {
+        let needle = params.search_string.to_lowercase();
+        let mut results = Vec::new();
+        for client in self.get_remote_tabs() {
+            for tab in &client.remote_tabs {
+                if results.len() >= params.limit as usize {
+                    return Ok(results);
+                }
+                let url = match tab.url_history.first().and_then(|url| Url::parse(url).ok()) {
+                    Some(url) => url,
+                    None => continue,
+                };
+                if !tab.title.to_lowercase().contains(&needle) &&
+                   !url.as_str().to_lowercase().contains(&needle) {
+                    continue;
+                }
+                results.push(SearchResult {
+                    search_string: params.search_string.clone(),
+                    url,
+                    title: tab.title.clone(),
+                    icon_url: tab.icon.as_ref().and_then(|icon| Url::parse(icon).ok()),
+                    frecency: 0,
+                    reasons: vec![MatchReason::RemoteTab(client.client_name.clone())],
+                });
+            }
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tab(title: &str, url: &str, last_used: u64) -> RemoteTab {
+        RemoteTab {
+            title: title.into(),
+            url_history: vec![url.into()],
+            icon: None,
+            last_used,
+        }
+    }
+
+    #[test]
+    fn test_local_tabs_for_upload() {
+        let mut storage = TabsStorage::new();
+        assert!(storage.prepare_local_tabs_for_upload().is_none());
+        storage.update_local_state(vec![
+            tab("Old", "https://example.com/old", 1000),
+            RemoteTab { title: "Empty".into(), url_history: vec![], icon: None, last_used: 3000 },
+            tab("New", "https://example.com/new", 2000),
+        ]);
+        let tabs = storage.prepare_local_tabs_for_upload().expect("should have tabs");
+        let titles: Vec<&str> = tabs.iter().map(|t| t.title.as_str()).collect();
+        assert_eq!(titles, vec!["New", "Old"]);
+
+        storage.note_local_tabs_uploaded();
+        assert!(storage.prepare_local_tabs_for_upload().is_none());
+    }
+
+    #[test]
+    fn test_search() {
+        let mut storage = TabsStorage::new();
+        storage.replace_remote_tabs(ClientRemoteTabs {
+            client_id: "laptop".into(),
+            client_name: "My Laptop".into(),
+            remote_tabs: vec![
+                tab("Example Domain", "https://example.com/", 1000),
+                tab("Something else", "https://mozilla.org/", 2000),
+                tab("Bad URL", "not a url", 3000),
+            ],
+        });
+        let results = storage.search(&SearchParams {
+            search_string: "EXAMPLE".into(),
+            limit: 10,
+        }).expect("should search");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].url.as_str(), "https://example.com/");
+        match results[0].reasons[0] {
+            MatchReason::RemoteTab(ref name) => assert_eq!(name, "My Laptop"),
+            ref reason => panic!("unexpected reason {:?}", reason),
+        }
+
+        let results = storage.search(&SearchParams {
+            search_string: "https".into(),
+            limit: 1,
+        }).expect("should search");
+        assert_eq!(results.len(), 1);
+    }
+}
@@ -0,0 +1,226 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use failure;
+use sync::{self, Store, IncomingChangeset, OutgoingChangeset, Payload, ServerTimestamp,
+           GlobalState, Sync15StorageClient, SyncEngine};
+use sync::telemetry;
+
+use error::*;
+use record::TabsRecord;
+use storage::{ClientRemoteTabs, TabsStorage};
+use COLLECTION_NAME;
+
+// A `sync15_adapter::Store` for tabs. Our own record uses the same id as our
+// record in the "clients" collection, so the caller needs to tell us what
+// that is.
+pub struct TabsStore<'a> {
+    pub storage: &'a mut TabsStorage,
+    local_id: String,
+    local_name: String,
+}
+
+impl<'a> TabsStore<'a> {
+    pub fn new(storage: &'a mut TabsStorage, local_id: &str, local_name: &str) -> Self {
+        Self {
+            storage,
+            local_id: local_id.into(),
+            local_name: local_name.into(),
+        }
+    }
+
+    // Sync tabs. The caller is responsible for getting `state` to ready
+    // (ie, via `sync::SetupStateMachine`).
+    pub fn sync(
+        &mut self,
+        client: &Sync15StorageClient,
+        state: &GlobalState,
+        telem_engine: &mut telemetry::Engine,
+    ) -> Result<()> {
+        let ts = self.storage.get_last_sync().unwrap_or_default();
+        info!("Syncing tabs engine!");
+        let result = sync::synchronize(
+            client,
+            state,
+            self,
+            COLLECTION_NAME.into(),
+            ts,
+            false,
+            telem_engine
+        );
+        match &result {
+            Ok(()) => info!("Tabs sync was successful!"),
+            Err(e) => warn!("Tabs sync failed! {:?}", e),
+        }
+        result
+    }
+
+    fn do_apply_incoming(
+        &mut self,
+        inbound: IncomingChangeset,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> Result<OutgoingChangeset> {
+        for (payload, _) in inbound.changes {
+            if payload.id == self.local_id {
+                // Our own record - we always know better than the server.
+                continue;
+            }
+            if payload.is_tombstone() {
+                self.storage.remove_remote_tabs(&payload.id);
+                incoming_telemetry.applied(1);
+                continue;
+            }
+            let record: TabsRecord = match payload.into_record() {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!("Ignoring invalid tabs record: {}", e);
+                    incoming_telemetry.failed(1);
+                    continue;
+                }
+            };
+            self.storage.replace_remote_tabs(ClientRemoteTabs::from_record(record));
+            incoming_telemetry.applied(1);
+        }
+
+        let mut outgoing = OutgoingChangeset::new(inbound.collection, inbound.timestamp);
+        if let Some(local_tabs) = self.storage.prepare_local_tabs_for_upload() {
+            let record = TabsRecord {
+                id: self.local_id.clone(),
+                client_name: self.local_name.clone(),
+                tabs: local_tabs.iter().map(|tab| tab.to_record_tab()).collect(),
+            };
+            outgoing.changes.push(Payload::from_record(record)?);
+        }
+        Ok(outgoing)
+    }
+}
+
+impl<'a> Store for TabsStore<'a> {
+    type Error = Error;
+
+    fn apply_incoming(
+        &mut self,
+        inbound: IncomingChangeset,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> Result<OutgoingChangeset> {
+        self.do_apply_incoming(inbound, incoming_telemetry)
+    }
+
+    fn sync_finished(
+        &mut self,
+        new_timestamp: ServerTimestamp,
+        records_synced: &[String],
+    ) -> Result<()> {
+        if records_synced.iter().any(|id| *id == self.local_id) {
+            self.storage.note_local_tabs_uploaded();
+        }
+        self.storage.set_last_sync(new_timestamp);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.storage.reset();
+        Ok(())
+    }
+}
+
+impl<'a> SyncEngine for TabsStore<'a> {
+    fn collection_name(&self) -> &'static str {
+        COLLECTION_NAME
+    }
+
+    fn sync(
+        &mut self,
+        client: &Sync15StorageClient,
+        state: &GlobalState,
+        telem_engine: &mut telemetry::Engine,
+    ) -> ::std::result::Result<(), failure::Error> {
+        Ok(TabsStore::sync(self, client, state, telem_engine)?)
+    }
+
+    fn reset(&mut self) -> ::std::result::Result<(), failure::Error> {
+        self.storage.reset();
+        Ok(())
+    }
+
+    // The only data we keep is other clients' tabs, which is all reset
+    // discards anyway.
+    fn wipe(&mut self) -> ::std::result::Result<(), failure::Error> {
+        self.storage.reset();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use env_logger;
+    use serde_json;
+    use storage::RemoteTab;
+    use sync::telemetry::EngineIncoming;
+
+    fn incoming(records: Vec<serde_json::Value>) -> IncomingChangeset {
+        let mut changeset = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(0.0));
+        for record in records {
+            changeset.changes.push((Payload::from_json(record).unwrap(), ServerTimestamp(1.0)));
+        }
+        changeset
+    }
+
+    #[test]
+    fn test_apply_incoming() {
+        let _ = env_logger::try_init();
+        let mut storage = TabsStorage::new();
+        storage.update_local_state(vec![RemoteTab {
+            title: "Local".into(),
+            url_history: vec!["https://example.com/local".into()],
+            icon: None,
+            last_used: 1_540_000_000_000,
+        }]);
+        {
+            let mut store = TabsStore::new(&mut storage, "my-client-id", "My Phone");
+            let outgoing = store.apply_incoming(incoming(vec![
+                json!({"id": "my-client-id", "clientName": "Stale", "tabs": []}),
+                json!({"id": "laptop", "clientName": "My Laptop", "tabs": [{
+                    "title": "Remote",
+                    "urlHistory": ["https://example.com/remote"],
+                    "lastUsed": 1540000000,
+                }]}),
+            ]), &mut EngineIncoming::new()).expect("should apply");
+            assert_eq!(outgoing.changes.len(), 1);
+            let record: TabsRecord = outgoing.changes[0].clone().into_record().unwrap();
+            assert_eq!(record.id, "my-client-id");
+            assert_eq!(record.client_name, "My Phone");
+            assert_eq!(record.tabs[0].last_used, 1_540_000_000);
+
+            store.sync_finished(ServerTimestamp(2.0), &["my-client-id".to_string()])
+                .expect("should finish");
+            let outgoing = store.apply_incoming(incoming(vec![
+                json!({"id": "laptop", "deleted": true}),
+            ]), &mut EngineIncoming::new()).expect("should apply");
+            assert!(outgoing.changes.is_empty(), "nothing changed locally");
+        }
+        assert!(storage.get_remote_tabs().is_empty());
+        assert_eq!(storage.get_last_sync(), Some(ServerTimestamp(2.0)));
+    }
+
+    #[test]
+    fn test_remote_tabs() {
+        let mut storage = TabsStorage::new();
+        {
+            let mut store = TabsStore::new(&mut storage, "my-client-id", "My Phone");
+            store.apply_incoming(incoming(vec![
+                json!({"id": "laptop", "clientName": "My Laptop", "tabs": [{
+                    "title": "Remote",
+                    "urlHistory": ["https://example.com/remote"],
+                    "lastUsed": 1540000000,
+                }]}),
+            ]), &mut EngineIncoming::new()).expect("should apply");
+        }
+        let clients = storage.get_remote_tabs();
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].client_name, "My Laptop");
+        assert_eq!(clients[0].remote_tabs[0].last_used, 1_540_000_000_000);
+    }
+}